@@ -95,7 +95,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let shapes = cc_image.extract_shapes();
     println!("Extracted {} shapes", shapes.len());
 
-    let (bitmaps, _parents, blits) = shapes_to_encoder_format(shapes, height as i32);
+    let (bitmaps, _parents, blits) = shapes_to_encoder_format(shapes, height as i32)?;
     println!(
         "Converted to {} bitmaps, {} blits",
         bitmaps.len(),
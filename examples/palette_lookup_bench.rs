@@ -0,0 +1,40 @@
+//! Compares linear and kd-tree nearest-color lookups for a large palette.
+//!
+//! Run with `cargo run --release --example palette_lookup_bench`.
+
+use djvu_encoder::image::image_formats::Pixel;
+use djvu_encoder::image::palette::Palette;
+use std::time::Instant;
+
+fn main() {
+    let mut state = 0x2468_ace1u32;
+    let mut next = || {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 24) as u8
+    };
+
+    let colors: Vec<Pixel> = (0..4096)
+        .map(|_| Pixel::new(next(), next(), next()))
+        .collect();
+    let pixels: Vec<Pixel> = (0..200_000)
+        .map(|_| Pixel::new(next(), next(), next()))
+        .collect();
+    let palette = Palette::from_colors(colors);
+
+    let start = Instant::now();
+    let linear: Vec<u16> = pixels.iter().map(|p| palette.color_to_index(p)).collect();
+    let linear_time = start.elapsed();
+
+    let start = Instant::now();
+    let indexed = palette.pixels_to_indices(&pixels);
+    let indexed_time = start.elapsed();
+
+    assert_eq!(linear, indexed);
+    println!(
+        "{} pixels, {} colors: linear {:?}, kd-tree {:?}",
+        pixels.len(),
+        palette.len(),
+        linear_time,
+        indexed_time
+    );
+}
@@ -1,5 +1,6 @@
 // src/annotations.rs
 
+use crate::image::image_formats::Pixel;
 use std::fmt;
 use std::io::Write;
 use thiserror::Error;
@@ -10,10 +11,16 @@ pub enum AnnotationError {
     Io(#[from] std::io::Error),
     #[error("Invalid shape coordinates for annotation: {0}")]
     InvalidShape(&'static str),
+    #[error("Malformed annotation data: {0}")]
+    Parse(String),
 }
 
 /// Represents the shape of a hyperlink area.
-#[derive(Debug, Clone)]
+///
+/// Coordinates are image pixels with the origin at the top-left corner and
+/// `y` growing downwards, like every other coordinate in this crate. DjVu
+/// stores map areas bottom-up; [`Annotations::encode`] does the conversion.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AnnotationShape {
     Rect { x: u32, y: u32, w: u32, h: u32 },
     Oval { x: u32, y: u32, w: u32, h: u32 },
@@ -37,8 +44,36 @@ impl fmt::Display for AnnotationShape {
     }
 }
 
+impl AnnotationShape {
+    /// Returns this shape in DjVu's bottom-up coordinates for a page
+    /// `page_height` pixels tall: a box's `y` becomes the `ymin` of its lower
+    /// edge, and polygon vertices are mirrored.
+    pub fn to_djvu_coords(&self, page_height: u32) -> Self {
+        match self {
+            Self::Rect { x, y, w, h } => Self::Rect {
+                x: *x,
+                y: flip_box_y(*y, *h, page_height),
+                w: *w,
+                h: *h,
+            },
+            Self::Oval { x, y, w, h } => Self::Oval {
+                x: *x,
+                y: flip_box_y(*y, *h, page_height),
+                w: *w,
+                h: *h,
+            },
+            Self::Polygon { points } => Self::Polygon {
+                points: points
+                    .iter()
+                    .map(|&(x, y)| (x, page_height.saturating_sub(y)))
+                    .collect(),
+            },
+        }
+    }
+}
+
 /// Represents a single hyperlink or clickable map area.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Hyperlink {
     pub shape: AnnotationShape,
     pub url: String,
@@ -47,11 +82,141 @@ pub struct Hyperlink {
     // Note: Border and highlight options are omitted for simplicity but can be added here.
 }
 
+/// A text note (a "sticky note" over the page), written as a `text` map area.
+///
+/// The box is given top-down, like [`AnnotationShape`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextAnnotation {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+    /// Note body; may span several lines.
+    pub text: String,
+    /// Fill color of the note box. Transparent when `None`.
+    pub background: Option<Pixel>,
+    /// Show the note collapsed to a pushpin icon until clicked.
+    pub pushpin: bool,
+}
+
+impl TextAnnotation {
+    pub fn new(x: u32, y: u32, w: u32, h: u32, text: impl Into<String>) -> Self {
+        Self {
+            x,
+            y,
+            w,
+            h,
+            text: text.into(),
+            background: None,
+            pushpin: false,
+        }
+    }
+
+    pub fn with_background(mut self, color: Pixel) -> Self {
+        self.background = Some(color);
+        self
+    }
+
+    pub fn with_pushpin(mut self, pushpin: bool) -> Self {
+        self.pushpin = pushpin;
+        self
+    }
+
+    /// Returns this note with its box in DjVu's bottom-up coordinates; see
+    /// [`AnnotationShape::to_djvu_coords`].
+    pub fn to_djvu_coords(&self, page_height: u32) -> Self {
+        Self {
+            y: flip_box_y(self.y, self.h, page_height),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for TextAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `(maparea <url> <comment> (text x y w h) <options...>)`; notes have no
+        // URL and carry their body in the comment field.
+        write!(
+            f,
+            "(maparea \"\" \"{}\" (text {} {} {} {}) (none)",
+            escape_str(&self.text),
+            self.x,
+            self.y,
+            self.w,
+            self.h
+        )?;
+        if let Some(c) = self.background {
+            write!(f, " (backclr #{:02X}{:02X}{:02X})", c.r, c.g, c.b)?;
+        }
+        if self.pushpin {
+            write!(f, " (pushpin)")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Initial zoom of the page in a viewer (the `zoom` token).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zoom {
+    /// Fit the whole page
+    Page,
+    /// Fit the page width
+    Width,
+    /// One image pixel per screen pixel
+    OneToOne,
+    /// Stretch to the window
+    Stretch,
+    /// Fixed magnification in percent
+    Percent(u16),
+}
+
+impl fmt::Display for Zoom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Page => write!(f, "page"),
+            Self::Width => write!(f, "width"),
+            Self::OneToOne => write!(f, "one2one"),
+            Self::Stretch => write!(f, "stretch"),
+            Self::Percent(p) => write!(f, "d{}", p),
+        }
+    }
+}
+
+/// Initial display mode of the page in a viewer (the `mode` token).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayMode {
+    Color,
+    Bw,
+    Foreground,
+    Background,
+}
+
+impl fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Color => write!(f, "color"),
+            Self::Bw => write!(f, "bw"),
+            Self::Foreground => write!(f, "fore"),
+            Self::Background => write!(f, "back"),
+        }
+    }
+}
+
+/// How a viewer first shows the page. Unset fields leave the choice to the
+/// viewer.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitialView {
+    pub zoom: Option<Zoom>,
+    pub mode: Option<DisplayMode>,
+}
+
 /// Represents the full set of annotations for a page.
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Annotations {
     pub hyperlinks: Vec<Hyperlink>,
+    pub notes: Vec<TextAnnotation>,
     pub metadata: Vec<(String, String)>,
+    pub initial_view: InitialView,
 }
 
 impl Annotations {
@@ -62,7 +227,17 @@ impl Annotations {
     /// Encodes the annotations into the LISP-like format required for an ANTa/ANTz chunk.
     /// The output of this function should be compressed (e.g., with bzip2) before
     /// being stored in a final DjVu file as an 'ANTz' chunk.
-    pub fn encode(&self, writer: &mut impl Write) -> Result<(), AnnotationError> {
+    ///
+    /// Shapes and notes are stored top-down; they are converted to DjVu's
+    /// bottom-up coordinates using `page_height`.
+    pub fn encode(&self, writer: &mut impl Write, page_height: u32) -> Result<(), AnnotationError> {
+        if let Some(zoom) = self.initial_view.zoom {
+            write!(writer, "(zoom {})", zoom)?;
+        }
+        if let Some(mode) = self.initial_view.mode {
+            write!(writer, "(mode {})", mode)?;
+        }
+
         for link in &self.hyperlinks {
             let url_part = format!(
                 "(url \"{}\" \"{}\")",
@@ -70,7 +245,7 @@ impl Annotations {
                 escape_str(&link.target)
             );
             let comment_part = format!("\"{}\"", escape_str(&link.comment));
-            let shape_part = format!("{}", link.shape);
+            let shape_part = format!("{}", link.shape.to_djvu_coords(page_height));
 
             // The full format is `(maparea <url> <comment> <shape> <options...>)`
             let maparea = format!(
@@ -80,6 +255,10 @@ impl Annotations {
             writer.write_all(maparea.as_bytes())?;
         }
 
+        for note in &self.notes {
+            writer.write_all(note.to_djvu_coords(page_height).to_string().as_bytes())?;
+        }
+
         if !self.metadata.is_empty() {
             let mut meta_str = String::from("(metadata");
             for (key, value) in &self.metadata {
@@ -91,9 +270,525 @@ impl Annotations {
 
         Ok(())
     }
+
+    /// Reads annotations back from `ANTa` text such as [`Self::encode`]
+    /// writes (for `ANTz`, decompress the chunk with BZZ first).
+    ///
+    /// Understands `zoom`, `mode`, `metadata` and `maparea` entries. Map
+    /// areas with `rect`, `oval` or `poly` shapes become hyperlinks and
+    /// `text` shapes become notes; their coordinates are flipped back to
+    /// top-down using `page_height`. Entries and map-area options this type
+    /// does not model, such as `(background ...)` or `(border ...)`, are
+    /// skipped. Tokens may be separated by any whitespace, and `;` starts a
+    /// comment running to the end of the line.
+    pub fn parse(s: &str, page_height: u32) -> Result<Self, AnnotationError> {
+        let mut annotations = Annotations::new();
+        for expr in read_sexprs(s)? {
+            let Sexpr::List(items) = expr else {
+                return Err(malformed("top-level entries must be lists"));
+            };
+            let Some((Sexpr::Atom(head), args)) = items.split_first() else {
+                return Err(malformed("entry without a name"));
+            };
+            match head.as_str() {
+                "zoom" => {
+                    annotations.initial_view.zoom = Some(match atom(args.first())? {
+                        "page" => Zoom::Page,
+                        "width" => Zoom::Width,
+                        "one2one" => Zoom::OneToOne,
+                        "stretch" => Zoom::Stretch,
+                        other => other
+                            .strip_prefix('d')
+                            .and_then(|p| p.parse().ok())
+                            .map(Zoom::Percent)
+                            .ok_or_else(|| malformed(format!("unknown zoom '{other}'")))?,
+                    })
+                }
+                "mode" => {
+                    annotations.initial_view.mode = Some(match atom(args.first())? {
+                        "color" => DisplayMode::Color,
+                        "bw" => DisplayMode::Bw,
+                        "fore" => DisplayMode::Foreground,
+                        "back" => DisplayMode::Background,
+                        other => return Err(malformed(format!("unknown mode '{other}'"))),
+                    })
+                }
+                "metadata" => {
+                    for entry in args {
+                        match entry {
+                            Sexpr::List(kv) => match kv.as_slice() {
+                                [Sexpr::Atom(key), Sexpr::Str(value)] => {
+                                    annotations.metadata.push((key.clone(), value.clone()))
+                                }
+                                _ => return Err(malformed("metadata entries are (key \"value\")")),
+                            },
+                            _ => return Err(malformed("metadata entries are (key \"value\")")),
+                        }
+                    }
+                }
+                "maparea" => annotations.parse_maparea(args, page_height)?,
+                _ => {}
+            }
+        }
+        Ok(annotations)
+    }
+
+    /// Adds the hyperlink or note of a `(maparea url comment shape ...)`.
+    fn parse_maparea(&mut self, args: &[Sexpr], page_height: u32) -> Result<(), AnnotationError> {
+        let (url, target) = match args.first() {
+            Some(Sexpr::Str(url)) => (url.clone(), String::new()),
+            Some(Sexpr::List(items)) => match items.as_slice() {
+                [Sexpr::Atom(head), Sexpr::Str(url), Sexpr::Str(target)] if head == "url" => {
+                    (url.clone(), target.clone())
+                }
+                _ => return Err(malformed("maparea url must be \"...\" or (url ...)")),
+            },
+            _ => return Err(malformed("maparea without a url")),
+        };
+        let Some(Sexpr::Str(comment)) = args.get(1) else {
+            return Err(malformed("maparea without a comment"));
+        };
+        let Some(Sexpr::List(shape)) = args.get(2) else {
+            return Err(malformed("maparea without a shape"));
+        };
+        let Some((Sexpr::Atom(kind), coords)) = shape.split_first() else {
+            return Err(malformed("maparea shape without a name"));
+        };
+        let coords = coords
+            .iter()
+            .map(|c| {
+                let c = atom(Some(c))?;
+                c.parse::<u32>()
+                    .map_err(|_| malformed(format!("bad coordinate '{c}'")))
+            })
+            .collect::<Result<Vec<u32>, _>>()?;
+        let options = &args[3..];
+
+        let rect = || match coords[..] {
+            [x, y, w, h] => Ok((x, y, w, h)),
+            _ => Err(AnnotationError::InvalidShape("expected x y w h")),
+        };
+        let shape = match kind.as_str() {
+            "rect" => {
+                let (x, y, w, h) = rect()?;
+                AnnotationShape::Rect { x, y, w, h }
+            }
+            "oval" => {
+                let (x, y, w, h) = rect()?;
+                AnnotationShape::Oval { x, y, w, h }
+            }
+            "poly" => {
+                if coords.is_empty() || coords.len() % 2 != 0 {
+                    return Err(AnnotationError::InvalidShape("expected x y pairs"));
+                }
+                AnnotationShape::Polygon {
+                    points: coords.chunks(2).map(|p| (p[0], p[1])).collect(),
+                }
+            }
+            "text" => {
+                let (x, y, w, h) = rect()?;
+                let mut note = TextAnnotation::new(x, y, w, h, comment.clone());
+                for option in options {
+                    let Sexpr::List(items) = option else { continue };
+                    match items.as_slice() {
+                        [Sexpr::Atom(name)] if name == "pushpin" => note.pushpin = true,
+                        [Sexpr::Atom(name), Sexpr::Atom(color)] if name == "backclr" => {
+                            note.background = Some(parse_color(color)?);
+                        }
+                        _ => {}
+                    }
+                }
+                self.notes.push(note.to_djvu_coords(page_height));
+                return Ok(());
+            }
+            other => return Err(malformed(format!("unknown maparea shape '{other}'"))),
+        };
+        self.hyperlinks.push(Hyperlink {
+            shape: shape.to_djvu_coords(page_height),
+            url,
+            comment: comment.clone(),
+            target,
+        });
+        Ok(())
+    }
+}
+
+impl Annotations {
+    /// Adds `other` (e.g. document-wide annotations) to these page-level
+    /// ones, the way a viewer unions a shared `ANTa` with the page's own.
+    ///
+    /// Hyperlinks and notes are appended. Metadata is appended too, except
+    /// for keys this page already sets, and initial-view settings from
+    /// `other` only fill in what the page leaves unset: on any conflict the
+    /// page-level value wins.
+    pub fn merge(&mut self, other: &Annotations) {
+        self.hyperlinks.extend(other.hyperlinks.iter().cloned());
+        self.notes.extend(other.notes.iter().cloned());
+        for (key, value) in &other.metadata {
+            if !self.metadata.iter().any(|(k, _)| k == key) {
+                self.metadata.push((key.clone(), value.clone()));
+            }
+        }
+        self.initial_view.zoom = self.initial_view.zoom.or(other.initial_view.zoom);
+        self.initial_view.mode = self.initial_view.mode.or(other.initial_view.mode);
+    }
+}
+
+/// Bottom-up `ymin` of a top-down box starting at row `y` with height `h`.
+fn flip_box_y(y: u32, h: u32, page_height: u32) -> u32 {
+    page_height.saturating_sub(y.saturating_add(h))
+}
+
+/// One parsed S-expression of the annotation format.
+#[derive(Debug)]
+enum Sexpr {
+    /// A bare token such as `rect`, `150` or `#FFFFCC`
+    Atom(String),
+    /// A quoted string, unescaped
+    Str(String),
+    List(Vec<Sexpr>),
+}
+
+fn malformed(message: impl Into<String>) -> AnnotationError {
+    AnnotationError::Parse(message.into())
+}
+
+/// The text of `expr` if it is an atom.
+fn atom(expr: Option<&Sexpr>) -> Result<&str, AnnotationError> {
+    match expr {
+        Some(Sexpr::Atom(a)) => Ok(a),
+        _ => Err(malformed("expected a token")),
+    }
+}
+
+/// Parses a `#RRGGBB` color.
+fn parse_color(s: &str) -> Result<Pixel, AnnotationError> {
+    let hex = s
+        .strip_prefix('#')
+        .filter(|h| h.len() == 6)
+        .and_then(|h| u32::from_str_radix(h, 16).ok())
+        .ok_or_else(|| malformed(format!("bad color '{s}'")))?;
+    Ok(Pixel::new((hex >> 16) as u8, (hex >> 8) as u8, hex as u8))
+}
+
+/// Splits annotation text into its top-level S-expressions.
+///
+/// Strings undo [`escape_str`] and also accept the octal `\ooo` escapes
+/// DjVuLibre writes for non-ASCII bytes.
+fn read_sexprs(s: &str) -> Result<Vec<Sexpr>, AnnotationError> {
+    let mut chars = s.chars().peekable();
+    let mut stack: Vec<Vec<Sexpr>> = vec![Vec::new()];
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' => stack.push(Vec::new()),
+            ')' => {
+                if stack.len() < 2 {
+                    return Err(malformed("unbalanced ')'"));
+                }
+                let list = stack.pop().unwrap();
+                stack.last_mut().unwrap().push(Sexpr::List(list));
+            }
+            '"' => {
+                let mut bytes = Vec::new();
+                loop {
+                    let c = chars
+                        .next()
+                        .ok_or_else(|| malformed("unterminated string"))?;
+                    let c = match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => '\n',
+                            Some('r') => '\r',
+                            Some('t') => '\t',
+                            Some(d @ '0'..='7') => {
+                                let mut value = d.to_digit(8).unwrap();
+                                for _ in 0..2 {
+                                    match chars.next_if(|c| c.is_digit(8)) {
+                                        Some(d) => value = value * 8 + d.to_digit(8).unwrap(),
+                                        None => break,
+                                    }
+                                }
+                                bytes.push(value as u8);
+                                continue;
+                            }
+                            Some(other) => other,
+                            None => return Err(malformed("unterminated string")),
+                        },
+                        c => c,
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                let text = String::from_utf8_lossy(&bytes).into_owned();
+                stack.last_mut().unwrap().push(Sexpr::Str(text));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token = String::from(c);
+                while let Some(c) =
+                    chars.next_if(|&c| !c.is_whitespace() && !matches!(c, '(' | ')' | '"' | ';'))
+                {
+                    token.push(c);
+                }
+                stack.last_mut().unwrap().push(Sexpr::Atom(token));
+            }
+        }
+    }
+    if stack.len() != 1 {
+        return Err(malformed("unbalanced '('"));
+    }
+    Ok(stack.pop().unwrap())
 }
 
 /// Escapes a string for use inside the LISP-like annotation format.
 fn escape_str(s: &str) -> String {
-    s.replace('\\', "\\\\").replace('"', "\\\"")
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads back the first quoted string after `start`, undoing `escape_str`.
+    fn read_string(s: &str, start: usize) -> String {
+        let mut chars = s[start..].chars().skip_while(|&c| c != '"').skip(1);
+        let mut out = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next().unwrap() {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    other => out.push(other),
+                },
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_multiline_note_round_trip() {
+        let body = "First line\nSecond \"quoted\" line\n\tC:\\path";
+        let mut annotations = Annotations::new();
+        annotations.notes.push(
+            TextAnnotation::new(10, 20, 200, 80, body)
+                .with_background(Pixel::new(0xff, 0xff, 0xcc))
+                .with_pushpin(true),
+        );
+
+        // On a 120-pixel page the box spans rows 20..100 top-down, which is
+        // also 20..100 bottom-up.
+        let mut out = Vec::new();
+        annotations.encode(&mut out, 120).unwrap();
+        let sexpr = String::from_utf8(out).unwrap();
+
+        assert_eq!(
+            sexpr,
+            "(maparea \"\" \"First line\\nSecond \\\"quoted\\\" line\\n\\tC:\\\\path\" \
+             (text 10 20 200 80) (none) (backclr #FFFFCC) (pushpin))"
+        );
+        assert!(!sexpr.contains('\n'));
+        let comment_start = "(maparea \"\" ".len();
+        assert_eq!(read_string(&sexpr, comment_start), body);
+    }
+
+    #[test]
+    fn test_merge_shared_view_with_page_links() {
+        let mut shared = Annotations::new();
+        shared.initial_view.zoom = Some(Zoom::Width);
+        shared.initial_view.mode = Some(DisplayMode::Color);
+        shared
+            .metadata
+            .push(("Author".to_string(), "Shared".to_string()));
+
+        let mut page = Annotations::new();
+        page.initial_view.mode = Some(DisplayMode::Bw);
+        page.hyperlinks.push(Hyperlink {
+            shape: AnnotationShape::Rect {
+                x: 1,
+                y: 2,
+                w: 3,
+                h: 4,
+            },
+            url: "https://example.com".to_string(),
+            comment: String::new(),
+            target: String::new(),
+        });
+        page.metadata
+            .push(("Author".to_string(), "Page".to_string()));
+        page.merge(&shared);
+
+        let mut out = Vec::new();
+        page.encode(&mut out, 100).unwrap();
+        let sexpr = String::from_utf8(out).unwrap();
+        assert!(sexpr.starts_with("(zoom width)(mode bw)"), "{sexpr}");
+        assert!(sexpr.contains("(url \"https://example.com\" \"\")"));
+        assert!(sexpr.contains("(Author \"Page\")"));
+        assert!(!sexpr.contains("Shared"));
+    }
+
+    #[test]
+    fn test_link_near_page_top_is_flipped() {
+        let page_height = 1000;
+        let mut annotations = Annotations::new();
+        annotations.hyperlinks.push(Hyperlink {
+            shape: AnnotationShape::Rect {
+                x: 50,
+                y: 10,
+                w: 100,
+                h: 20,
+            },
+            url: "https://example.com".to_string(),
+            comment: String::new(),
+            target: String::new(),
+        });
+        annotations.hyperlinks.push(Hyperlink {
+            shape: AnnotationShape::Polygon {
+                points: vec![(0, 0), (10, 0), (10, 10)],
+            },
+            url: "#2".to_string(),
+            comment: String::new(),
+            target: String::new(),
+        });
+
+        let mut out = Vec::new();
+        annotations.encode(&mut out, page_height).unwrap();
+        let sexpr = String::from_utf8(out).unwrap();
+
+        let rect = &sexpr[sexpr.find("(rect ").unwrap() + "(rect ".len()..];
+        let fields: Vec<u32> = rect[..rect.find(')').unwrap()]
+            .split(' ')
+            .map(|v| v.parse().unwrap())
+            .collect();
+        assert_eq!(fields, [50, 970, 100, 20]);
+        assert!(
+            page_height - fields[1] <= 30,
+            "ymin {} not near top",
+            fields[1]
+        );
+        assert!(sexpr.contains("(poly 0 1000 10 1000 10 990)"), "{sexpr}");
+    }
+
+    #[test]
+    fn test_note_without_options() {
+        let note = TextAnnotation::new(1, 2, 3, 4, "hi");
+        assert_eq!(
+            note.to_string(),
+            "(maparea \"\" \"hi\" (text 1 2 3 4) (none))"
+        );
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let page_height = 800;
+        let mut annotations = Annotations::new();
+        annotations.initial_view.zoom = Some(Zoom::Percent(150));
+        annotations.initial_view.mode = Some(DisplayMode::Bw);
+        let link = |shape, url: &str, target: &str| Hyperlink {
+            shape,
+            url: url.to_string(),
+            comment: "Go \"there\"".to_string(),
+            target: target.to_string(),
+        };
+        annotations.hyperlinks = vec![
+            link(
+                AnnotationShape::Rect {
+                    x: 10,
+                    y: 20,
+                    w: 30,
+                    h: 40,
+                },
+                "https://example.com",
+                "_blank",
+            ),
+            link(
+                AnnotationShape::Oval {
+                    x: 100,
+                    y: 700,
+                    w: 50,
+                    h: 25,
+                },
+                "#3",
+                "",
+            ),
+            link(
+                AnnotationShape::Polygon {
+                    points: vec![(0, 0), (60, 10), (30, 90)],
+                },
+                "#+1",
+                "",
+            ),
+        ];
+        annotations.notes.push(
+            TextAnnotation::new(5, 6, 70, 80, "Line one\nC:\\notes")
+                .with_background(Pixel::new(0xff, 0xee, 0x00))
+                .with_pushpin(true),
+        );
+        annotations.notes.push(TextAnnotation::new(1, 2, 3, 4, ""));
+        annotations
+            .metadata
+            .push(("Title".to_string(), "A \"quoted\" title".to_string()));
+
+        let mut first = Vec::new();
+        annotations.encode(&mut first, page_height).unwrap();
+        let first = String::from_utf8(first).unwrap();
+        let parsed = Annotations::parse(&first, page_height).unwrap();
+        assert_eq!(parsed, annotations);
+
+        let mut second = Vec::new();
+        parsed.encode(&mut second, page_height).unwrap();
+        assert_eq!(String::from_utf8(second).unwrap(), first);
+    }
+
+    #[test]
+    fn test_parse_tolerates_layout_and_unknown_entries() {
+        let text = "; written by hand\n\
+                    (background #ffffff)\n\
+                    (zoom  page) (mode color)\n\
+                    (maparea \"http://a.example\" \"\"\n  (rect 1 2 3 4) ; link\n  (border #ff0000))\n\
+                    (metadata (Author \"Ann \\303\\251\"))";
+        let parsed = Annotations::parse(text, 10).unwrap();
+        assert_eq!(parsed.initial_view.zoom, Some(Zoom::Page));
+        assert_eq!(parsed.initial_view.mode, Some(DisplayMode::Color));
+        assert_eq!(parsed.hyperlinks.len(), 1);
+        assert_eq!(
+            parsed.hyperlinks[0].shape,
+            AnnotationShape::Rect {
+                x: 1,
+                y: 4,
+                w: 3,
+                h: 4
+            }
+        );
+        assert_eq!(
+            parsed.metadata,
+            [("Author".to_string(), "Ann é".to_string())]
+        );
+
+        for bad in [
+            "(zoom page",
+            "(zoom page))",
+            "(maparea \"x\")",
+            "(zoom huge)",
+            "\"x\"",
+        ] {
+            assert!(
+                matches!(Annotations::parse(bad, 10), Err(AnnotationError::Parse(_))),
+                "{bad}"
+            );
+        }
+    }
 }
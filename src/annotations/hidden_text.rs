@@ -17,6 +17,26 @@ pub enum HiddenTextError {
     Io(#[from] std::io::Error),
     #[error("Coordinate value {0} out of range for 16-bit encoding")]
     CoordinateOutOfRange(i32),
+    #[error("Word box at ({x}, {y}) size {w}x{h} extends past the {page_width}x{page_height} page")]
+    BoxOutOfBounds {
+        x: u16,
+        y: u16,
+        w: u16,
+        h: u16,
+        page_width: u16,
+        page_height: u16,
+    },
+}
+
+/// How `HiddenText::from_word_boxes_with_policy` treats word boxes that
+/// extend past the page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfBoundsPolicy {
+    /// Trim boxes to the page; boxes entirely off-page are dropped
+    #[default]
+    Clamp,
+    /// Fail with `HiddenTextError::BoxOutOfBounds`
+    Error,
 }
 
 /// The type of a zone in the document hierarchy.
@@ -120,7 +140,9 @@ impl HiddenText {
     /// Creates a HiddenText layer from a list of word bounding boxes.
     ///
     /// **IMPORTANT**: Input coordinates are expected in top-left origin (hOCR format).
-    /// This function converts them to DjVu's bottom-left coordinate system.
+    /// This function converts them to DjVu's bottom-left coordinate system, so a
+    /// word at `y_top` ends up with `ymin = page_height - (y_top + height)`.
+    /// Words keep their input order, which should be reading order.
     ///
     /// # Arguments
     /// * `page_width`, `page_height` - Page dimensions in pixels
@@ -139,11 +161,29 @@ impl HiddenText {
     ///     ]
     /// );
     /// ```
+    ///
+    /// Boxes that extend past the page are clamped to it and zero-size boxes
+    /// are dropped; see [`HiddenText::from_word_boxes_with_policy`].
     pub fn from_word_boxes(
         page_width: u16,
         page_height: u16,
         words: Vec<(String, u16, u16, u16, u16)>, // (text, x, y_top, w, h)
     ) -> Self {
+        Self::from_word_boxes_with_policy(page_width, page_height, words, OutOfBoundsPolicy::Clamp)
+            .expect("clamping never rejects a word box")
+    }
+
+    /// Like [`HiddenText::from_word_boxes`], with a choice of what to do when
+    /// an OCR box extends past the page.
+    ///
+    /// Zero-size boxes (including those clamped down to nothing) are always
+    /// dropped, since they cannot be selected and confuse some viewers.
+    pub fn from_word_boxes_with_policy(
+        page_width: u16,
+        page_height: u16,
+        words: Vec<(String, u16, u16, u16, u16)>, // (text, x, y_top, w, h)
+        policy: OutOfBoundsPolicy,
+    ) -> Result<Self, HiddenTextError> {
         let mut root = Zone::new(
             ZoneKind::Page,
             BoundingBox {
@@ -156,7 +196,31 @@ impl HiddenText {
 
         // Convert from top-left origin (hOCR) to bottom-left origin (DjVu)
         // and add all words as direct children of the page
-        for (text, x, y_top, w, h) in words {
+        for (text, x, y_top, mut w, mut h) in words {
+            let right = x as u32 + w as u32;
+            let bottom = y_top as u32 + h as u32;
+            if right > page_width as u32 || bottom > page_height as u32 {
+                match policy {
+                    OutOfBoundsPolicy::Error => {
+                        return Err(HiddenTextError::BoxOutOfBounds {
+                            x,
+                            y: y_top,
+                            w,
+                            h,
+                            page_width,
+                            page_height,
+                        });
+                    }
+                    OutOfBoundsPolicy::Clamp => {
+                        w = w.min(page_width.saturating_sub(x));
+                        h = h.min(page_height.saturating_sub(y_top));
+                    }
+                }
+            }
+            if w == 0 || h == 0 {
+                continue;
+            }
+
             // Convert Y coordinate: djvu_y_bottom = page_height - (y_top + h)
             let djvu_y = page_height.saturating_sub(y_top.saturating_add(h));
 
@@ -164,7 +228,7 @@ impl HiddenText {
             root.children.push(word_zone);
         }
 
-        Self { root_zone: root }
+        Ok(Self { root_zone: root })
     }
 
     /// Encodes the hidden text structure into the binary format for a TXTa/TXTz chunk.
@@ -309,3 +373,95 @@ fn write_i16(writer: &mut impl Write, val: i32) -> Result<(), std::io::Error> {
     let val_u16 = (val + 0x8000) as u16;
     writer.write_all(&val_u16.to_be_bytes())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_box_clamped_to_page() {
+        let text = HiddenText::from_word_boxes(
+            100,
+            50,
+            vec![
+                ("edge".to_string(), 80, 10, 40, 20),
+                ("offpage".to_string(), 120, 10, 10, 10),
+                ("empty".to_string(), 10, 10, 0, 5),
+            ],
+        );
+
+        assert_eq!(text.root_zone.children.len(), 1);
+        let bbox = text.root_zone.children[0].bbox;
+        assert_eq!((bbox.x, bbox.w), (80, 20));
+        assert_eq!(bbox.xmax(), 100);
+        // y_top 10 + h 20 -> DjVu bottom edge at 50 - 30
+        assert_eq!((bbox.y, bbox.h), (20, 20));
+    }
+
+    /// Decodes the zone records of an encoded text layer back to absolute
+    /// `(kind, x, ymin, w, h)` boxes, depth first. Handles only a page whose
+    /// children are all words.
+    fn decode_flat_zones(data: &[u8]) -> Vec<(u8, i32, i32, i32, i32)> {
+        let text_len = u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize;
+        let mut pos = 3 + text_len;
+        assert_eq!(data[pos], 1, "version");
+        pos += 1;
+        let read_i16 = |p: usize| u16::from_be_bytes([data[p], data[p + 1]]) as i32 - 0x8000;
+
+        let mut zones: Vec<(u8, i32, i32, i32, i32)> = Vec::new();
+        while pos < data.len() {
+            let kind = data[pos];
+            let (dx, dy, w, h) = (
+                read_i16(pos + 1),
+                read_i16(pos + 3),
+                read_i16(pos + 5),
+                read_i16(pos + 7),
+            );
+            let (x, y) = match zones.as_slice() {
+                [] => (dx, dy),
+                [page] => (page.1 + dx, page.2 + page.4 - dy - h),
+                [.., prev] => (prev.1 + prev.3 + dx, prev.2 + dy),
+            };
+            zones.push((kind, x, y, w, h));
+            pos += 17;
+        }
+        zones
+    }
+
+    #[test]
+    fn test_word_boxes_encode_bottom_up() {
+        let text = HiddenText::from_word_boxes(
+            400,
+            1000,
+            vec![
+                ("top".to_string(), 50, 10, 100, 30),
+                ("bottom".to_string(), 50, 900, 120, 40),
+            ],
+        );
+        let mut out = Vec::new();
+        text.encode(&mut out).unwrap();
+
+        let zones = decode_flat_zones(&out);
+        assert_eq!(zones.len(), 3);
+        assert_eq!(zones[0], (ZoneKind::Page as u8, 0, 0, 400, 1000));
+        // The upper word comes first and sits near the top of the page,
+        // which in DjVu coordinates means a large ymin.
+        assert_eq!(zones[1], (ZoneKind::Word as u8, 50, 960, 100, 30));
+        assert_eq!(zones[2], (ZoneKind::Word as u8, 50, 60, 120, 40));
+        assert!(zones[1].2 > zones[2].2);
+    }
+
+    #[test]
+    fn test_word_box_error_policy() {
+        let result = HiddenText::from_word_boxes_with_policy(
+            100,
+            50,
+            vec![("edge".to_string(), 80, 10, 40, 20)],
+            OutOfBoundsPolicy::Error,
+        );
+        assert!(matches!(
+            result,
+            Err(HiddenTextError::BoxOutOfBounds { x: 80, w: 40, .. })
+        ));
+    }
+}
@@ -2,5 +2,7 @@ pub mod annotations;
 pub mod hidden_text;
 pub mod string;
 
-pub use annotations::{AnnotationShape, Annotations, Hyperlink};
-pub use hidden_text::HiddenText;
+pub use annotations::{
+    AnnotationShape, Annotations, DisplayMode, Hyperlink, InitialView, TextAnnotation, Zoom,
+};
+pub use hidden_text::{HiddenText, OutOfBoundsPolicy};
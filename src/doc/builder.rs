@@ -28,15 +28,26 @@
 //! std::fs::write("output.djvu", djvu_bytes)?;
 //! ```
 
-use crate::annotations::{Annotations, hidden_text::HiddenText};
-use crate::doc::encoder::DocumentEncoder;
+use crate::annotations::{Annotations, TextAnnotation, hidden_text::HiddenText};
+use crate::doc::djvu_dir::{Bookmark, PageId};
+use crate::doc::encoder::{Component, DocumentEncoder};
 use crate::doc::page_collection::PageCollection;
-use crate::doc::page_encoder::PageEncodeParams;
-use crate::doc::page_encoder::{EncodedPage, PageComponents, Rect};
+use crate::doc::page_encoder::{BzzLevel, PageEncodeParams};
+use crate::doc::page_encoder::{EncodeDiagnostic, EncodedPage, PageComponents, Rect};
+use crate::doc::thumbnails;
 use crate::encode::symbol_dict::BitImage;
-use crate::image::image_formats::{Bitmap, Pixmap};
-use crate::{DjvuError, Result};
-use std::sync::Arc;
+use crate::iff::bs_byte_stream::bzz_compress;
+use crate::iff::iff::{IffWriter, IffWriterExt};
+use crate::image::image_formats::{Bitmap, GrayPixel, Pixmap};
+use crate::{DjvuError, EncodeStage, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+#[cfg(feature = "image")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 // ============================================================================
 // Image Layers
@@ -117,6 +128,38 @@ impl ImageLayer {
         (self.x, self.y, self.width, self.height)
     }
 
+    /// Crops the layer to the part inside a `page_width`x`page_height` page
+    ///
+    /// Returns the layer unchanged when it already fits, and `None` when no
+    /// part of it lies on the page.
+    pub fn clip_to(self, page_width: u32, page_height: u32) -> Option<Self> {
+        let width = self.width.min(page_width.saturating_sub(self.x));
+        let height = self.height.min(page_height.saturating_sub(self.y));
+        if width == 0 || height == 0 {
+            return None;
+        }
+        if (width, height) == (self.width, self.height) {
+            return Some(self);
+        }
+        let data = match self.data {
+            LayerData::Background(pixmap) => {
+                LayerData::Background(Pixmap::from_fn(width, height, |x, y| {
+                    pixmap.get_pixel(x, y)
+                }))
+            }
+            LayerData::Foreground(bitmap) => {
+                LayerData::Foreground(crop_bitmap(&bitmap, width, height))
+            }
+            LayerData::Mask(bitmap) => LayerData::Mask(crop_bitmap(&bitmap, width, height)),
+        };
+        Some(Self {
+            width,
+            height,
+            data,
+            ..self
+        })
+    }
+
     /// Checks if this layer overlaps with another layer
     pub fn overlaps_with(&self, other: &ImageLayer) -> bool {
         let (x1, y1, w1, h1) = self.bounds();
@@ -151,6 +194,8 @@ pub struct PageBuilder {
     layers: Vec<ImageLayer>,
     text_layer: Option<HiddenText>,
     annotations: Option<Annotations>,
+    includes: Vec<String>,
+    clip_layers: bool,
 }
 
 impl PageBuilder {
@@ -167,9 +212,21 @@ impl PageBuilder {
             layers: Vec::new(),
             text_layer: None,
             annotations: None,
+            includes: Vec::new(),
+            clip_layers: false,
         }
     }
 
+    /// Crops layers that extend past the page instead of rejecting them
+    ///
+    /// With clipping on, [`Self::build`] trims every layer with
+    /// [`ImageLayer::clip_to`] and drops those lying entirely off the page,
+    /// which suits pages composed from fixed-size tiles.
+    pub fn with_clipping(mut self, clip: bool) -> Self {
+        self.clip_layers = clip;
+        self
+    }
+
     /// Adds an image layer to the page
     pub fn add_layer(mut self, layer: ImageLayer) -> Self {
         self.layers.push(layer);
@@ -231,6 +288,61 @@ impl PageBuilder {
         false
     }
 
+    /// Adds a mask layer covering the black pixels of the foreground
+    ///
+    /// The foreground already stencils the text out of the background; the
+    /// mask also tells the IW44 coder which background pixels are hidden
+    /// under it, so their bits go to the visible ones instead. When
+    /// [`Self::needs_masking`] reports an overlap and no mask layer was
+    /// added, this sets the mask to the black pixels of all foreground
+    /// layers combined, spanning their bounding box. Otherwise the builder
+    /// is returned unchanged. The mask equals the foreground, so shapes stay
+    /// black and the page can still be coded with
+    /// [`MaskCodec::Mmr`](crate::doc::page_encoder::MaskCodec::Mmr).
+    pub fn auto_mask(self) -> Self {
+        let has_mask = self
+            .layers
+            .iter()
+            .any(|layer| matches!(layer.data, LayerData::Mask(_)));
+        if has_mask || !self.needs_masking() {
+            return self;
+        }
+
+        let foregrounds: Vec<(&ImageLayer, &Bitmap)> = self
+            .layers
+            .iter()
+            .filter_map(|layer| match &layer.data {
+                LayerData::Foreground(bitmap) => Some((layer, bitmap)),
+                _ => None,
+            })
+            .collect();
+        let x0 = foregrounds.iter().map(|(l, _)| l.x).min().unwrap_or(0);
+        let y0 = foregrounds.iter().map(|(l, _)| l.y).min().unwrap_or(0);
+        let x1 = foregrounds
+            .iter()
+            .map(|(l, _)| l.x + l.width)
+            .max()
+            .unwrap_or(0);
+        let y1 = foregrounds
+            .iter()
+            .map(|(l, _)| l.y + l.height)
+            .max()
+            .unwrap_or(0);
+
+        let mut mask = Bitmap::from_pixel(x1 - x0, y1 - y0, GrayPixel::white());
+        for (layer, bitmap) in foregrounds {
+            for y in 0..layer.height {
+                for x in 0..layer.width {
+                    // Same threshold as the JB2 conversion of the layer
+                    if bitmap.get_pixel(x, y).y < 128 {
+                        mask.put_pixel(layer.x - x0 + x, layer.y - y0 + y, GrayPixel::black());
+                    }
+                }
+            }
+        }
+        self.with_mask(mask, x0, y0)
+    }
+
     /// Adds OCR/hidden text layer from coordinate-based word list
     ///
     /// # Arguments
@@ -264,7 +376,10 @@ impl PageBuilder {
     ///
     /// # Arguments
     /// * `url` - Target URL
-    /// * `x`, `y`, `w`, `h` - Rectangular clickable area
+    /// * `x`, `y`, `w`, `h` - Rectangular clickable area in page pixels, with
+    ///   `(x, y)` its top-left corner (origin at the top-left of the page,
+    ///   `y` growing downwards). It is flipped to DjVu's bottom-up
+    ///   coordinates when the annotation chunk is written.
     /// * `comment` - Optional tooltip/comment text
     ///
     /// # Example
@@ -299,15 +414,39 @@ impl PageBuilder {
         self
     }
 
+    /// Adds a text note to the page
+    ///
+    /// Notes are merged with any hyperlinks into the page's annotation chunk.
+    pub fn with_note(mut self, note: TextAnnotation) -> Self {
+        let mut annotations = self.annotations.take().unwrap_or_default();
+        annotations.notes.push(note);
+        self.annotations = Some(annotations);
+        self
+    }
+
     /// Adds custom annotations (for advanced usage)
     pub fn with_annotations(mut self, annotations: Annotations) -> Self {
         self.annotations = Some(annotations);
         self
     }
 
+    /// References a shared `FORM:DJVI` include registered with
+    /// [`DjvuDocument::add_include`]
+    pub fn with_include(mut self, id: impl Into<String>) -> Self {
+        self.includes.push(id.into());
+        self
+    }
+
     /// Consumes the builder and returns the constructed page
-    pub fn build(self) -> Result<Page> {
-        if self.layers.is_empty() {
+    pub fn build(mut self) -> Result<Page> {
+        if self.clip_layers {
+            let (width, height) = (self.width, self.height);
+            self.layers = std::mem::take(&mut self.layers)
+                .into_iter()
+                .filter_map(|layer| layer.clip_to(width, height))
+                .collect();
+        }
+        if self.layers.is_empty() && self.includes.is_empty() {
             return Err(DjvuError::InvalidOperation(
                 "Page must have at least one layer".to_string(),
             ));
@@ -330,6 +469,7 @@ impl PageBuilder {
             layers: self.layers,
             text_layer: self.text_layer,
             annotations: self.annotations,
+            includes: self.includes,
         })
     }
 }
@@ -343,6 +483,7 @@ pub struct Page {
     layers: Vec<ImageLayer>,
     text_layer: Option<HiddenText>,
     annotations: Option<Annotations>,
+    includes: Vec<String>,
 }
 
 impl Page {
@@ -358,6 +499,42 @@ impl Page {
         &self.layers
     }
 
+    /// Each hyperlink and note as it is written to this page's annotation
+    /// chunk, in DjVu coordinates, so equal keys display identically
+    fn annotation_keys(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let key = |single: Annotations| -> Result<String> {
+            let mut buf = Vec::new();
+            single
+                .encode(&mut buf, self.height)
+                .map_err(|e| DjvuError::encoding(EncodeStage::Annotations, e))?;
+            Ok(String::from_utf8_lossy(&buf).into_owned())
+        };
+        let Some(annotations) = &self.annotations else {
+            return Ok(Default::default());
+        };
+        let links = annotations
+            .hyperlinks
+            .iter()
+            .map(|link| {
+                key(Annotations {
+                    hyperlinks: vec![link.clone()],
+                    ..Annotations::new()
+                })
+            })
+            .collect::<Result<_>>()?;
+        let notes = annotations
+            .notes
+            .iter()
+            .map(|note| {
+                key(Annotations {
+                    notes: vec![note.clone()],
+                    ..Annotations::new()
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((links, notes))
+    }
+
     /// Converts this page to PageComponents for internal encoding
     pub(crate) fn to_components(&self) -> Result<PageComponents> {
         let mut components = PageComponents::new_with_dimensions(self.width, self.height);
@@ -388,6 +565,7 @@ impl Page {
         if let Some(ref annot) = self.annotations {
             components.annotations = Some(annot.clone());
         }
+        components.includes = self.includes.clone();
 
         Ok(components)
     }
@@ -411,6 +589,94 @@ fn bitmap_to_bitimage(bitmap: &Bitmap) -> Result<BitImage> {
     Ok(bit_image)
 }
 
+/// Helper: the top-left `width`x`height` corner of `bitmap`
+fn crop_bitmap(bitmap: &Bitmap, width: u32, height: u32) -> Bitmap {
+    let pixels = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| bitmap.get_pixel(x, y))
+        .collect();
+    Bitmap::from_vec(width, height, pixels)
+}
+
+/// Helper: `id`, or `id` with `_2`, `_3`, ... before its extension, whichever
+/// is first not `taken`
+fn unique_id(id: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(id) {
+        return id.to_string();
+    }
+    let (stem, ext) = id.rfind('.').map_or((id, ""), |dot| id.split_at(dot));
+    (2..)
+        .map(|n| format!("{stem}_{n}{ext}"))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// Helper: rewrite a bookmark tree's page destinations as `#N` of the page
+/// `move_page` sends them to. Destinations are `#N` or one of the `#id`s
+/// in `page_ids`, which maps them to 0-based page indices.
+fn remap_bookmark(
+    bookmark: &mut Bookmark,
+    page_ids: &HashMap<String, usize>,
+    move_page: &dyn Fn(usize) -> usize,
+) {
+    let page = match bookmark.dest.strip_prefix('#').map(str::parse::<usize>) {
+        Some(Ok(number)) if number > 0 => Some(number - 1),
+        _ => page_ids.get(&bookmark.dest).copied(),
+    };
+    if let Some(page) = page {
+        bookmark.dest = format!("#{}", move_page(page) + 1);
+    }
+    for child in &mut bookmark.children {
+        remap_bookmark(child, page_ids, move_page);
+    }
+}
+
+/// Helper: validate a `FORM:<form_type>` component and return it with the
+/// `AT&T` prefix
+fn normalize_form(bytes: Vec<u8>, form_type: &[u8; 4]) -> Result<Vec<u8>> {
+    let has_magic = bytes.starts_with(b"AT&T");
+    let body = if has_magic { &bytes[4..] } else { &bytes[..] };
+    let kind = String::from_utf8_lossy(form_type);
+
+    if body.len() < 12 || &body[0..4] != b"FORM" || &body[8..12] != form_type {
+        return Err(DjvuError::InvalidArg(format!(
+            "Expected a FORM:{kind} component"
+        )));
+    }
+    let form_size = u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize;
+    if form_size < 4 || 8 + form_size > body.len() {
+        return Err(DjvuError::InvalidArg(format!(
+            "FORM:{} declares {} bytes but only {} are present",
+            kind,
+            form_size,
+            body.len().saturating_sub(8)
+        )));
+    }
+
+    if has_magic {
+        return Ok(bytes);
+    }
+    let mut data = Vec::with_capacity(bytes.len() + 4);
+    data.extend_from_slice(b"AT&T");
+    data.extend_from_slice(&bytes);
+    Ok(data)
+}
+
+/// Helper: read the page dimensions from the leading INFO chunk of a
+/// normalized `AT&TFORM:DJVU` component
+fn form_djvu_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    // AT&T(4) FORM(4) size(4) DJVU(4), then INFO: width(2,BE) height(2,BE) ...
+    let info = &data[16..];
+    if info.len() < 12 || &info[0..4] != b"INFO" {
+        return Err(DjvuError::InvalidArg(
+            "Raw page is missing its leading INFO chunk".to_string(),
+        ));
+    }
+    let width = u16::from_be_bytes([info[8], info[9]]) as u32;
+    let height = u16::from_be_bytes([info[10], info[11]]) as u32;
+    Ok((width, height))
+}
+
 // ============================================================================
 // Document Builder
 // ============================================================================
@@ -421,8 +687,157 @@ fn bitmap_to_bitimage(bitmap: &Bitmap) -> Result<BitImage> {
 pub struct DjvuBuilder {
     collection: Arc<PageCollection>,
     params: PageEncodeParams,
-    dpi: u32,
+    /// Set by `with_dpi`/`with_cancel`; applied over `params` at build, so
+    /// `with_params` can come before or after them
+    dpi: Option<u32>,
+    cancel: Option<Arc<AtomicBool>>,
     gamma: Option<f32>,
+    dedup_pages: bool,
+    page_naming: PageNaming,
+    default_annotations: Option<Arc<Annotations>>,
+    metadata: Option<DocumentMetadata>,
+    creation_date: Option<String>,
+}
+
+/// Document information written as a `(metadata ...)` annotation
+///
+/// Keys use the PDF document-information names (`Title`, `Author`, ...)
+/// that djvused and viewers display. Empty fields are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentMetadata {
+    pub title: String,
+    pub author: String,
+    pub subject: String,
+    pub keywords: String,
+    pub created: Option<String>,
+    /// Defaults to `djvu_encoder` followed by [`crate::DJVU_VERSION`]
+    pub producer: String,
+}
+
+impl Default for DocumentMetadata {
+    fn default() -> Self {
+        Self {
+            title: String::new(),
+            author: String::new(),
+            subject: String::new(),
+            keywords: String::new(),
+            created: None,
+            producer: format!("djvu_encoder {}", crate::DJVU_VERSION),
+        }
+    }
+}
+
+impl DocumentMetadata {
+    /// Returns annotations holding only these metadata entries
+    pub fn to_annotations(&self) -> Annotations {
+        let fields = [
+            ("Title", self.title.as_str()),
+            ("Author", &self.author),
+            ("Subject", &self.subject),
+            ("Keywords", &self.keywords),
+            ("CreationDate", self.created.as_deref().unwrap_or("")),
+            ("Producer", &self.producer),
+        ];
+        let mut annotations = Annotations::new();
+        annotations.metadata = fields
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        annotations
+    }
+
+    /// Fills the fields left empty here from `other`
+    fn fill_from(&mut self, other: &DocumentMetadata) {
+        for (field, theirs) in [
+            (&mut self.title, &other.title),
+            (&mut self.author, &other.author),
+            (&mut self.subject, &other.subject),
+            (&mut self.keywords, &other.keywords),
+            (&mut self.producer, &other.producer),
+        ] {
+            if field.is_empty() {
+                field.clone_from(theirs);
+            }
+        }
+        if self.created.is_none() {
+            self.created.clone_from(&other.created);
+        }
+    }
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DDTHH:MM:SSZ`
+fn iso8601_utc(unix_seconds: i64) -> String {
+    let days = unix_seconds.div_euclid(86_400);
+    let secs = unix_seconds.rem_euclid(86_400);
+    // Civil date from days since 1970-01-01, in 400-year eras starting
+    // on March 1st so the leap day ends the year
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// How component ids of pages in a bundled document are formed
+///
+/// Page `i` (0-based) becomes `{prefix}{i + 1}.{extension}`, with the
+/// number zero-padded to the digits of the page count (at least four) so
+/// that every name has the same length and lexicographic order matches page
+/// order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageNaming {
+    pub prefix: String,
+    pub extension: String,
+}
+
+impl Default for PageNaming {
+    fn default() -> Self {
+        Self {
+            prefix: "p".to_string(),
+            extension: "djvu".to_string(),
+        }
+    }
+}
+
+impl PageNaming {
+    /// Returns the component id of page `index` in a document of `total` pages
+    pub fn file_name(&self, index: usize, total: usize) -> String {
+        let width = total.to_string().len().max(4);
+        format!("{}{:0width$}.{}", self.prefix, index + 1, self.extension)
+    }
+}
+
+/// What [`DjvuDocument::add_pages_from_paths`] does with a file that cannot
+/// be read or decoded
+#[cfg(feature = "image")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnLoadError {
+    /// Return the error without adding any page
+    #[default]
+    Abort,
+    /// Leave the file out and import the rest
+    Skip,
+}
+
+/// Options for [`DjvuDocument::add_pages_from_paths`]
+#[cfg(feature = "image")]
+#[derive(Default)]
+pub struct ImportOptions<'a> {
+    pub on_error: OnLoadError,
+    /// Called with (images encoded, images to encode) after each image;
+    /// with the `rayon` feature it runs on worker threads
+    pub progress: Option<&'a (dyn Fn(usize, usize) + Sync)>,
 }
 
 impl DjvuBuilder {
@@ -434,12 +849,22 @@ impl DjvuBuilder {
         Self {
             collection: Arc::new(PageCollection::new(total_pages)),
             params: PageEncodeParams::default(),
-            dpi: 300,
+            dpi: None,
+            cancel: None,
             gamma: Some(2.2),
+            dedup_pages: false,
+            page_naming: PageNaming::default(),
+            default_annotations: None,
+            metadata: None,
+            creation_date: None,
         }
     }
 
     /// Sets encoding parameters
+    ///
+    /// Replaces every setting made through the other `with_*` methods except
+    /// [`Self::with_dpi`] and [`Self::with_cancel`], which take precedence
+    /// over `params.dpi` and `params.cancel` in either order.
     pub fn with_params(mut self, params: PageEncodeParams) -> Self {
         self.params = params;
         self
@@ -447,12 +872,14 @@ impl DjvuBuilder {
 
     /// Sets DPI (dots per inch)
     pub fn with_dpi(mut self, dpi: u32) -> Self {
-        self.dpi = dpi;
-        self.params.dpi = dpi;
+        self.dpi = Some(dpi);
         self
     }
 
     /// Sets gamma correction value
+    ///
+    /// Only 2.2 is supported; encoding fails with [`DjvuError::Unsupported`]
+    /// for any other value.
     pub fn with_gamma(mut self, gamma: f32) -> Self {
         self.gamma = Some(gamma);
         self
@@ -472,18 +899,174 @@ impl DjvuBuilder {
     }
 
     /// Sets target quality in decibels (overrides quality setting)
+    ///
+    /// Values outside 20–120 dB, NaN included, make page encoding fail.
     pub fn with_decibels(mut self, db: f32) -> Self {
         self.params.decibels = Some(db);
         self
     }
 
+    /// Sets the DjVu `(major, minor)` version written to each page's INFO chunk
+    pub fn with_info_version(mut self, major: u8, minor: u8) -> Self {
+        self.params.info_version = (major, minor);
+        self
+    }
+
+    /// Stores byte-identical pages (blank scans, separator sheets) once
+    ///
+    /// When enabled, `finalize` moves the content of duplicate pages into a
+    /// single shared include that each duplicate references via INCL. The
+    /// page count and order are unaffected.
+    pub fn with_page_dedup(mut self, dedup: bool) -> Self {
+        self.dedup_pages = dedup;
+        self
+    }
+
+    /// Sets the prefix and extension of page component ids (default `p`
+    /// and `djvu`, giving `p0001.djvu`); see [`PageNaming`]
+    pub fn with_page_naming(
+        mut self,
+        prefix: impl Into<String>,
+        extension: impl Into<String>,
+    ) -> Self {
+        self.page_naming = PageNaming {
+            prefix: prefix.into(),
+            extension: extension.into(),
+        };
+        self
+    }
+
+    /// Keeps at most `limit_bytes` of encoded pages in memory
+    ///
+    /// Pages that would exceed the limit are written to a temporary spill
+    /// file as they arrive, so parallel encodes of large documents don't
+    /// hold every page at once. [`DjvuDocument::write_to`] then streams the
+    /// bundle, reading each page back only when it is written.
+    /// [`DjvuDocument::finalize`] still returns the whole document in memory,
+    /// as does page deduplication, which compares all pages.
+    pub fn with_page_memory_cache(mut self, limit_bytes: usize) -> Self {
+        Arc::get_mut(&mut self.collection)
+            .expect("the builder owns its page collection")
+            .set_memory_limit(limit_bytes);
+        self
+    }
+
+    /// Sets a flag that aborts encoding once set from another thread
+    ///
+    /// It is polled before each page and between a page's chunks, including
+    /// pages encoded in parallel through [`DjvuDocument::encode_page`];
+    /// interrupted calls return [`DjvuError::Cancelled`]. Pages already added
+    /// stay in the document, so clearing the flag lets work resume.
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Sets the BZZ block sizes for the compressed chunks
+    ///
+    /// Larger blocks mostly help the DIRM of documents with many pages or
+    /// long page names. Returns [`DjvuError::InvalidArg`] if a size is
+    /// outside [`BzzLevel::MIN`]`..=`[`BzzLevel::MAX`].
+    pub fn with_bzz_level(mut self, level: BzzLevel) -> Result<Self> {
+        level.validate()?;
+        self.params.bzz = level;
+        Ok(self)
+    }
+
+    /// Sets annotations applied to every page, such as a footer link or a
+    /// common initial zoom
+    ///
+    /// They are merged into each page's own annotations when the page is
+    /// encoded, via [`Annotations::merge`], so page-level values win on
+    /// conflict. Raw pages are left untouched.
+    pub fn with_default_annotations(mut self, annotations: Annotations) -> Self {
+        self.default_annotations = Some(Arc::new(annotations));
+        self
+    }
+
+    /// Sets the document title, author, subject and keywords, stored once
+    /// in the shared annotation component
+    ///
+    /// The producer is set to this crate and version; use
+    /// [`Self::with_metadata`] to control every field.
+    pub fn set_metadata(
+        self,
+        title: impl Into<String>,
+        author: impl Into<String>,
+        subject: impl Into<String>,
+        keywords: impl Into<String>,
+    ) -> Self {
+        self.with_metadata(DocumentMetadata {
+            title: title.into(),
+            author: author.into(),
+            subject: subject.into(),
+            keywords: keywords.into(),
+            ..DocumentMetadata::default()
+        })
+    }
+
+    /// Sets the document metadata, stored once in the shared annotation
+    /// component
+    ///
+    /// The `(metadata ...)` annotation is added to `shared_anno.iff` when the
+    /// document is assembled (see [`DjvuDocument::dedup_shared_annotations`]),
+    /// creating that component if no page shares annotations; viewers read
+    /// document metadata from it. This makes the output a bundled or
+    /// indirect DJVM even for a single page. Metadata keys given through
+    /// [`Self::with_default_annotations`] stay on every page and override
+    /// the shared ones in viewers that merge the two.
+    pub fn with_metadata(mut self, metadata: DocumentMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Sets the `CreationDate` metadata entry from a Unix timestamp in
+    /// seconds, written as ISO-8601 UTC (`2024-05-17T09:30:00Z`)
+    ///
+    /// Overrides `created` of any metadata given before or after, and like
+    /// the other metadata is stored once in the shared annotation component.
+    /// Without a date the entry is left out, unless the `creation-date`
+    /// feature is on: it then stamps documents that have metadata with the
+    /// time [`Self::build`] is called. Documents without metadata get no
+    /// date, so their output stays reproducible.
+    pub fn with_creation_date(mut self, unix_seconds: i64) -> Self {
+        self.creation_date = Some(iso8601_utc(unix_seconds));
+        self
+    }
+
     /// Consumes the builder and returns the document
-    pub fn build(self) -> DjvuDocument {
+    pub fn build(mut self) -> DjvuDocument {
+        if let Some(dpi) = self.dpi {
+            self.params.dpi = dpi;
+        }
+        if let Some(cancel) = self.cancel {
+            self.params.cancel = Some(cancel);
+        }
+        #[cfg(feature = "creation-date")]
+        if self.creation_date.is_none()
+            && self.metadata.as_ref().is_some_and(|m| m.created.is_none())
+        {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            self.creation_date = Some(iso8601_utc(now));
+        }
+        if let Some(created) = self.creation_date.take() {
+            self.metadata
+                .get_or_insert_with(DocumentMetadata::default)
+                .created = Some(created);
+        }
         DjvuDocument {
             collection: self.collection,
             params: self.params,
-            dpi: self.dpi,
             gamma: self.gamma,
+            dedup_pages: self.dedup_pages,
+            page_naming: self.page_naming,
+            default_annotations: self.default_annotations,
+            metadata: self.metadata,
+            includes: Mutex::new(Vec::new()),
+            bookmarks: Mutex::new(Vec::new()),
+            diagnostics: Mutex::new(Vec::new()),
         }
     }
 }
@@ -494,8 +1077,15 @@ impl DjvuBuilder {
 pub struct DjvuDocument {
     collection: Arc<PageCollection>,
     params: PageEncodeParams,
-    dpi: u32,
     gamma: Option<f32>,
+    dedup_pages: bool,
+    page_naming: PageNaming,
+    default_annotations: Option<Arc<Annotations>>,
+    /// Written to the shared annotation component at assembly
+    metadata: Option<DocumentMetadata>,
+    includes: Mutex<Vec<(String, Vec<u8>)>>,
+    bookmarks: Mutex<Vec<Bookmark>>,
+    diagnostics: Mutex<Vec<EncodeDiagnostic>>,
 }
 
 impl DjvuDocument {
@@ -509,6 +1099,16 @@ impl DjvuDocument {
         self.collection.ready_count()
     }
 
+    /// Number of pages added so far (same as [`Self::pages_ready`])
+    pub fn page_count(&self) -> usize {
+        self.pages_ready()
+    }
+
+    /// True if no page has been added yet
+    pub fn is_empty(&self) -> bool {
+        self.pages_ready() == 0
+    }
+
     /// Check if a specific page is ready
     pub fn is_page_ready(&self, page_num: usize) -> bool {
         self.collection.is_page_ready(page_num)
@@ -526,16 +1126,236 @@ impl DjvuDocument {
     /// [`Self::add_encoded_page`] to insert the result into the document.
     pub fn encode_page(&self, page: Page) -> Result<EncodedPage> {
         let page_num = page.page_number();
-        let components = page.to_components()?;
-        EncodedPage::from_components(page_num, components, &self.params, self.dpi, self.gamma)
+        let components = self.page_components(&page)?;
+        EncodedPage::from_components(page_num, components, &self.params, self.gamma)
+    }
+
+    /// Like [`Self::encode_page`], but at `dpi` instead of the document's
+    /// resolution. The value goes into the page's INFO chunk and scales the
+    /// JB2 connected-component thresholds.
+    pub fn encode_page_with_dpi(&self, page: Page, dpi: u32) -> Result<EncodedPage> {
+        let page_num = page.page_number();
+        let components = self.page_components(&page)?;
+        let params = PageEncodeParams {
+            dpi,
+            ..self.params.clone()
+        };
+        EncodedPage::from_components(page_num, components, &params, self.gamma)
+    }
+
+    /// Converts `page` for encoding, attaching the document's default
+    /// annotations.
+    fn page_components(&self, page: &Page) -> Result<PageComponents> {
+        let mut components = page.to_components()?;
+        components.shared_annotations = self.default_annotations.clone();
+        Ok(components)
     }
 
     /// Insert an already-encoded page into the document (thread-safe, out-of-order).
     ///
     /// Cheap. The expensive work belongs in [`Self::encode_page`].
     pub fn add_encoded_page(&self, encoded: EncodedPage) -> Result<()> {
+        self.params.check_cancelled()?;
         let page_num = encoded.page_num;
-        self.collection.insert_page(page_num, encoded)
+        let diagnostics = encoded.diagnostics.clone();
+        self.collection.insert_page(page_num, encoded)?;
+        self.diagnostics.lock().unwrap().extend(diagnostics);
+        Ok(())
+    }
+
+    /// Non-fatal observations collected from the pages added so far, such as
+    /// grayscale pages forced into color mode. Each is also logged with
+    /// `log::warn!` when the page is encoded.
+    pub fn diagnostics(&self) -> Vec<EncodeDiagnostic> {
+        self.diagnostics.lock().unwrap().clone()
+    }
+
+    /// Insert a pre-assembled `FORM:DJVU` component as page `page_num`.
+    ///
+    /// For callers that build page chunks themselves and only need the
+    /// document bundled. The blob may start with or without the `AT&T`
+    /// magic; it must contain a single `FORM:DJVU` whose first chunk is INFO.
+    pub fn add_raw_page(&self, page_num: usize, form_djvu_bytes: Vec<u8>) -> Result<()> {
+        let data = normalize_form(form_djvu_bytes, b"DJVU")?;
+        let (width, height) = form_djvu_dimensions(&data)?;
+        self.add_encoded_page(EncodedPage::new(page_num, data, width, height))
+    }
+
+    /// Like [`Self::add_raw_page`], for pages whose INCL chunks name files
+    /// that are not registered yet.
+    ///
+    /// `loader` is called with each missing include id and returns that
+    /// file's `FORM:DJVI` bytes, typically read from beside the page in an
+    /// indirect document. Includes that include further files are resolved
+    /// the same way. Loader errors are returned unchanged, and nothing is
+    /// added unless every include resolves.
+    pub fn add_raw_page_with_loader<F>(
+        &self,
+        page_num: usize,
+        form_djvu_bytes: Vec<u8>,
+        mut loader: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<Vec<u8>>,
+    {
+        // Checked before any include is registered
+        if page_num >= self.total_pages() {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page number {} is out of range for {} pages",
+                page_num,
+                self.total_pages()
+            )));
+        }
+        if self.is_page_ready(page_num) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page {} has already been added",
+                page_num
+            )));
+        }
+        let data = normalize_form(form_djvu_bytes, b"DJVU")?;
+        let (width, height) = form_djvu_dimensions(&data)?;
+
+        let malformed = |kind: &str| DjvuError::InvalidArg(format!("Malformed {kind} component"));
+        let mut queue = DocumentEncoder::include_ids(&data).ok_or_else(|| malformed("page"))?;
+        let mut loaded: Vec<Component> = Vec::new();
+        let mut next = 0;
+        while let Some(id) = queue.get(next).cloned() {
+            next += 1;
+            let known = self.includes.lock().unwrap().iter().any(|(i, _)| *i == id);
+            if known || loaded.iter().any(|(i, _)| *i == id) {
+                continue;
+            }
+            let include = normalize_form(loader(&id)?, b"DJVI")?;
+            queue.extend(
+                DocumentEncoder::include_ids(&include).ok_or_else(|| malformed("include"))?,
+            );
+            loaded.push((id, include));
+        }
+
+        for (id, include) in loaded {
+            self.add_include(id, include)?;
+        }
+        self.add_encoded_page(EncodedPage::new(page_num, data, width, height))
+    }
+
+    /// Register a shared `FORM:DJVI` include file under `id`.
+    ///
+    /// Pages reference it with [`PageBuilder::with_include`]; the component is
+    /// bundled once no matter how many pages use it. Forces a bundled DJVM
+    /// even for single-page documents.
+    pub fn add_include(&self, id: impl Into<String>, form_djvi_bytes: Vec<u8>) -> Result<()> {
+        let id = id.into();
+        let data = normalize_form(form_djvi_bytes, b"DJVI")?;
+
+        let total = self.total_pages();
+        let clashes_with_page = (0..total).any(|i| self.page_naming.file_name(i, total) == id);
+        let mut includes = self.includes.lock().unwrap();
+        if id.is_empty() || clashes_with_page || includes.iter().any(|(i, _)| *i == id) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Include id '{id}' is empty or already in use"
+            )));
+        }
+        includes.push((id, data));
+        Ok(())
+    }
+
+    /// Moves hyperlinks and notes repeated across `pages`, such as a
+    /// running footer link, into one shared annotation component
+    ///
+    /// An annotation is a candidate when it appears, with the same DjVu
+    /// coordinates, on at least `min_fraction` (above 0, up to 1) of the
+    /// pages and on two or more. Candidates are taken most common first as
+    /// long as at least that many pages still carry every one taken. Those
+    /// pages lose their copies and include the shared component instead,
+    /// so what each page displays is unchanged; the other pages keep theirs.
+    ///
+    /// The component is registered as `shared_anno.iff` and listed in DIRM
+    /// as shared annotations. Call this before the pages are added; it
+    /// fails if a shared annotation component already exists. Returns the
+    /// number of annotations shared, which is 0 when nothing qualifies.
+    pub fn dedup_shared_annotations(&self, pages: &mut [Page], min_fraction: f32) -> Result<usize> {
+        if !(min_fraction > 0.0 && min_fraction <= 1.0) {
+            return Err(DjvuError::InvalidArg(format!(
+                "Shared annotation threshold must be in (0, 1], got {min_fraction}"
+            )));
+        }
+        let keys = pages
+            .iter()
+            .map(Page::annotation_keys)
+            .collect::<Result<Vec<_>>>()?;
+        let needed = ((min_fraction * pages.len() as f32).ceil() as usize).max(2);
+
+        // Keys in order of first appearance, with the number of pages carrying each
+        let mut order: Vec<&str> = Vec::new();
+        let mut pages_with: HashMap<&str, usize> = HashMap::new();
+        for (links, notes) in &keys {
+            let unique: HashSet<&str> = links.iter().chain(notes).map(String::as_str).collect();
+            for key in links.iter().chain(notes).map(String::as_str) {
+                pages_with.entry(key).or_insert_with(|| {
+                    order.push(key);
+                    0
+                });
+            }
+            for key in unique {
+                *pages_with.get_mut(key).unwrap() += 1;
+            }
+        }
+        let mut counts: Vec<(&str, usize)> =
+            order.into_iter().map(|k| (k, pages_with[k])).collect();
+        // Stable, so equally common annotations keep their page order
+        counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+        let mut shared: Vec<&str> = Vec::new();
+        let mut carriers: Vec<usize> = (0..pages.len()).collect();
+        for (key, count) in counts {
+            if count < needed {
+                break;
+            }
+            let has = |i: &usize| keys[*i].0.iter().chain(&keys[*i].1).any(|k| k == key);
+            let remaining: Vec<usize> = carriers.iter().copied().filter(has).collect();
+            if remaining.len() >= needed {
+                shared.push(key);
+                carriers = remaining;
+            }
+        }
+        if shared.is_empty() {
+            return Ok(0);
+        }
+
+        let antz = bzz_compress(shared.concat().as_bytes(), self.params.bzz.annotations)
+            .map_err(|e| DjvuError::encoding(EncodeStage::Annotations, e))?;
+        let mut djvi = Vec::new();
+        let mut iff = IffWriter::new(io::Cursor::new(&mut djvi));
+        iff.put_chunk("FORM:DJVI")?;
+        iff.write_chunk(*b"ANTz", &antz)?;
+        iff.close_chunk()?;
+        drop(iff);
+        self.add_include(DocumentEncoder::SHARED_ANNO_ID, djvi)?;
+
+        for i in carriers {
+            let (links, notes) = &keys[i];
+            let page = &mut pages[i];
+            if let Some(annotations) = page.annotations.as_mut() {
+                let mut links = links.iter();
+                annotations
+                    .hyperlinks
+                    .retain(|_| !shared.contains(&links.next().unwrap().as_str()));
+                let mut notes = notes.iter();
+                annotations
+                    .notes
+                    .retain(|_| !shared.contains(&notes.next().unwrap().as_str()));
+                let emptied = annotations.hyperlinks.is_empty()
+                    && annotations.notes.is_empty()
+                    && annotations.metadata.is_empty()
+                    && annotations.initial_view == Default::default();
+                if emptied {
+                    page.annotations = None;
+                }
+            }
+            page.includes
+                .push(DocumentEncoder::SHARED_ANNO_ID.to_string());
+        }
+        Ok(shared.len())
     }
 
     /// Add a page (thread-safe, out-of-order).
@@ -549,22 +1369,2028 @@ impl DjvuDocument {
         self.add_encoded_page(encoded)
     }
 
-    /// Finalize and return DjVu file bytes
-    pub fn finalize(&self) -> Result<Vec<u8>> {
-        if !self.is_complete() {
+    /// Add a page scanned at its own resolution (thread-safe, out-of-order).
+    ///
+    /// For mixed-resolution documents, e.g. a 300-DPI text scan combined
+    /// with a 600-DPI plate. Other pages keep the document DPI.
+    pub fn add_page_with_dpi(&self, page: Page, dpi: u32) -> Result<()> {
+        let encoded = self.encode_page_with_dpi(page, dpi)?;
+        self.add_encoded_page(encoded)
+    }
+
+    /// Add a page with a human-readable title (e.g. "Chapter 3")
+    ///
+    /// The title is stored in the page's DIRM record, where viewers show it
+    /// in the thumbnail/page panel. Titled documents are always bundled as
+    /// DJVM, even with a single page, since only DJVM has a directory.
+    pub fn add_page_titled(&self, page: Page, title: impl Into<String>) -> Result<()> {
+        let page_num = page.page_number();
+        self.add_page(page)?;
+        self.collection.set_page_title(page_num, title.into())
+    }
+
+    /// Appends one page per image file, in the order of `paths`
+    ///
+    /// Each image is loaded with the `image` crate and becomes a page of its
+    /// own pixel size holding only an IW44 background, as if built with
+    /// [`PageBuilder::with_background`]. Images are loaded and encoded in
+    /// parallel with the `rayon` feature. The document grows by the number
+    /// of pages added, so it can start out with zero pages.
+    ///
+    /// Files that fail to load are handled per [`ImportOptions::on_error`];
+    /// the skipped paths are returned. Image headers are read up front, so
+    /// unreadable files are dealt with before anything is encoded and
+    /// progress counts only the images that are. The first error stops the
+    /// import; no page is added unless every image loads (or is skipped)
+    /// and encodes.
+    #[cfg(feature = "image")]
+    pub fn add_pages_from_paths(
+        &mut self,
+        paths: &[PathBuf],
+        options: &ImportOptions,
+    ) -> Result<Vec<PathBuf>> {
+        let load_error = |path: &Path, e: ::image::ImageError| {
+            DjvuError::InvalidArg(format!("Cannot load '{}': {e}", path.display()))
+        };
+        let mut readable = Vec::with_capacity(paths.len());
+        for (i, path) in paths.iter().enumerate() {
+            match ::image::image_dimensions(path) {
+                Ok(_) => readable.push(i),
+                Err(_) if options.on_error == OnLoadError::Skip => {}
+                Err(e) => return Err(load_error(path, e)),
+            }
+        }
+
+        let done = AtomicUsize::new(0);
+        let import = |&i: &usize| -> Result<Option<EncodedPage>> {
+            let path = &paths[i];
+            let rgb = match ::image::open(path) {
+                Ok(image) => image.to_rgb8(),
+                Err(_) if options.on_error == OnLoadError::Skip => return Ok(None),
+                Err(e) => return Err(load_error(path, e)),
+            };
+            let (width, height) = rgb.dimensions();
+            let pixels = rgb
+                .pixels()
+                .map(|p| crate::Pixel::new(p[0], p[1], p[2]))
+                .collect();
+            let page = PageBuilder::new(0, width, height)
+                .with_background(Pixmap::from_vec(width, height, pixels))?
+                .build()?;
+            let encoded = self.encode_page(page)?;
+            let count = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(progress) = options.progress {
+                progress(count, readable.len());
+            }
+            Ok(Some(encoded))
+        };
+
+        // Collecting into a Result stops at the first error
+        #[cfg(feature = "rayon")]
+        let results: Vec<_> = {
+            use rayon::prelude::*;
+            readable.par_iter().map(import).collect::<Result<_>>()?
+        };
+        #[cfg(not(feature = "rayon"))]
+        let results: Vec<_> = readable.iter().map(import).collect::<Result<_>>()?;
+
+        let mut loaded: Vec<Option<EncodedPage>> = paths.iter().map(|_| None).collect();
+        for (&i, page) in readable.iter().zip(results) {
+            loaded[i] = page;
+        }
+        let mut pages = Vec::new();
+        let mut skipped = Vec::new();
+        for (path, page) in paths.iter().zip(loaded) {
+            match page {
+                Some(page) => pages.push(page),
+                None => skipped.push(path.clone()),
+            }
+        }
+
+        let first = self.total_pages();
+        let collection = Arc::get_mut(&mut self.collection).ok_or_else(|| {
+            DjvuError::InvalidOperation("Cannot grow a shared page collection".to_string())
+        })?;
+        collection.append(PageCollection::new(pages.len()))?;
+        for (i, mut page) in pages.into_iter().enumerate() {
+            page.page_num = first + i;
+            for diagnostic in &mut page.diagnostics {
+                match diagnostic {
+                    EncodeDiagnostic::GrayInColorMode { page_num } => *page_num = first + i,
+                }
+            }
+            self.add_encoded_page(page)?;
+        }
+        Ok(skipped)
+    }
+
+    /// Like [`Self::add_pages_from_paths`] for every image file directly in
+    /// `dir`, sorted by file name
+    ///
+    /// Files are picked by extension (`.png`, `.jpg`, `.tif`, ...), so
+    /// `page001.png` ... `page120.png` come out in page order as long as the
+    /// numbers are zero-padded.
+    #[cfg(feature = "image")]
+    pub fn add_pages_from_directory(
+        &mut self,
+        dir: impl AsRef<Path>,
+        options: &ImportOptions,
+    ) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && ::image::ImageFormat::from_path(&path).is_ok() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        self.add_pages_from_paths(&paths, options)
+    }
+
+    /// Add a top-level bookmark (outline entry)
+    ///
+    /// `dest` is usually a page number such as `#3` (1-based). Bookmarks are
+    /// written to a NAVM chunk, which forces a bundled DJVM document.
+    pub fn add_bookmark(&self, bookmark: Bookmark) {
+        self.bookmarks.lock().unwrap().push(bookmark);
+    }
+
+    /// The top-level bookmarks added so far
+    pub fn bookmarks(&self) -> Vec<Bookmark> {
+        self.bookmarks.lock().unwrap().clone()
+    }
+
+    /// Append all pages of `other` after this document's pages
+    ///
+    /// For assembling a book from separately encoded sections. `other` must
+    /// be complete; this document may still have pending pages. Its include
+    /// files (shared backgrounds and dictionaries) are carried over, renamed
+    /// when their ids clash with ids already in use, and `other`'s INCL
+    /// chunks are rewritten to match. Its bookmarks are appended with page
+    /// destinations (`#2`, or one of `other`'s page ids) moved past this
+    /// document's pages. When the merged page count needs longer page ids,
+    /// this document's bookmarks to its own page ids become page numbers so
+    /// they keep working. Page titles travel with their pages; encoding
+    /// parameters stay this document's.
+    ///
+    /// `other`'s default annotations are already part of its pages, and
+    /// annotations it shares between pages stay with them in its (possibly
+    /// renamed) shared component. Its metadata fills the fields this
+    /// document leaves empty. Thumbnails from
+    /// [`Self::regenerate_thumbnails`] of either document are dropped. Fails,
+    /// changing nothing, if one of this document's include ids equals a page
+    /// id of the merged document.
+    pub fn merge(&mut self, other: DjvuDocument) -> Result<()> {
+        if !other.is_complete() {
             return Err(DjvuError::InvalidOperation(format!(
-                "Document incomplete: {} of {} pages ready",
-                self.pages_ready(),
-                self.total_pages()
+                "Cannot merge an incomplete document: {} of {} pages ready",
+                other.pages_ready(),
+                other.total_pages()
+            )));
+        }
+        let offset = self.total_pages();
+        let other_total = other.total_pages();
+        let total = offset + other_total;
+        let naming = &self.page_naming;
+        if let Some((id, _)) = self
+            .includes
+            .get_mut()
+            .unwrap()
+            .iter()
+            .find(|(id, _)| (0..total).any(|i| naming.file_name(i, total) == *id))
+        {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Include id '{id}' would clash with a page of the merged document"
             )));
         }
+        let collection = Arc::get_mut(&mut self.collection).ok_or_else(|| {
+            DjvuError::InvalidOperation("Cannot merge into a shared page collection".to_string())
+        })?;
+        let mut other_collection = Arc::try_unwrap(other.collection).map_err(|_| {
+            DjvuError::InvalidOperation("Cannot merge a shared page collection".to_string())
+        })?;
 
-        let pages = self
-            .collection
-            .take_all()
-            .ok_or_else(|| DjvuError::InvalidOperation("Failed to collect pages".to_string()))?;
+        // Thumbnails follow page order, which merging changes
+        let includes = self.includes.get_mut().unwrap();
+        includes.retain(|(id, _)| id != DocumentEncoder::THUMBNAILS_ID);
+        let mut renames = HashMap::new();
+        for (id, data) in other.includes.into_inner().unwrap() {
+            if id == DocumentEncoder::THUMBNAILS_ID {
+                continue;
+            }
+            let taken = |candidate: &str| {
+                includes.iter().any(|(i, _)| i == candidate)
+                    || (0..total).any(|i| naming.file_name(i, total) == candidate)
+            };
+            let new_id = unique_id(&id, taken);
+            if new_id != id {
+                renames.insert(id, new_id.clone());
+            }
+            includes.push((new_id, data));
+        }
+        if !renames.is_empty() {
+            other_collection
+                .rewrite_pages(|page| DocumentEncoder::rename_includes(page, &renames))?;
+        }
+        collection.append(other_collection)?;
+
+        let page_ids: HashMap<String, usize> = (0..other_total)
+            .map(|i| {
+                (
+                    format!("#{}", other.page_naming.file_name(i, other_total)),
+                    i,
+                )
+            })
+            .collect();
+        let bookmarks = self.bookmarks.get_mut().unwrap();
+        if naming.file_name(0, offset) != naming.file_name(0, total) {
+            let own_ids: HashMap<String, usize> = (0..offset)
+                .map(|i| (format!("#{}", naming.file_name(i, offset)), i))
+                .collect();
+            for bookmark in bookmarks.iter_mut() {
+                remap_bookmark(bookmark, &own_ids, &|page| page);
+            }
+        }
+        for mut bookmark in other.bookmarks.into_inner().unwrap() {
+            remap_bookmark(&mut bookmark, &page_ids, &|page| page + offset);
+            bookmarks.push(bookmark);
+        }
+
+        if let Some(theirs) = &other.metadata {
+            self.metadata
+                .get_or_insert_with(|| theirs.clone())
+                .fill_from(theirs);
+        }
+
+        let diagnostics = self.diagnostics.get_mut().unwrap();
+        diagnostics.extend(
+            other
+                .diagnostics
+                .into_inner()
+                .unwrap()
+                .into_iter()
+                .map(|d| match d {
+                    EncodeDiagnostic::GrayInColorMode { page_num } => {
+                        EncodeDiagnostic::GrayInColorMode {
+                            page_num: page_num + offset,
+                        }
+                    }
+                }),
+        );
+        Ok(())
+    }
+
+    /// Component ids of the pages, in page order
+    ///
+    /// These are the DIRM ids the pages get when the document is assembled.
+    pub fn page_ids(&self) -> Vec<PageId> {
+        let total = self.total_pages();
+        (0..total)
+            .map(|i| self.page_naming.file_name(i, total))
+            .collect()
+    }
+
+    /// Rearranges the pages so that the page now at index `order[i]` becomes
+    /// page `i`
+    ///
+    /// `order` must list every current page index exactly once. Pending
+    /// pages may be reordered too; a page added later still goes to the
+    /// index it is added at. Titles, bookmark destinations and diagnostics
+    /// follow their pages, while page ids stay positional (see
+    /// [`DjvuDocument::page_ids`]). Thumbnails from
+    /// [`Self::regenerate_thumbnails`] are dropped.
+    pub fn reorder_pages(&mut self, order: &[usize]) -> Result<()> {
+        let collection = Arc::get_mut(&mut self.collection).ok_or_else(|| {
+            DjvuError::InvalidOperation("Cannot reorder a shared page collection".to_string())
+        })?;
+        collection.reorder(order)?;
+        self.includes
+            .get_mut()
+            .unwrap()
+            .retain(|(id, _)| id != DocumentEncoder::THUMBNAILS_ID);
+
+        let mut new_index = vec![0; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            new_index[old] = new;
+        }
+        let page_ids: HashMap<String, usize> = self
+            .page_ids()
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| (format!("#{id}"), i))
+            .collect();
+        for bookmark in self.bookmarks.get_mut().unwrap() {
+            remap_bookmark(bookmark, &page_ids, &|page| {
+                new_index.get(page).copied().unwrap_or(page)
+            });
+        }
+        for diagnostic in self.diagnostics.get_mut().unwrap() {
+            match diagnostic {
+                EncodeDiagnostic::GrayInColorMode { page_num } => {
+                    *page_num = new_index[*page_num];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders every page again and stores their thumbnails, at most
+    /// `size` pixels on the longer side, in a `THUM` component
+    ///
+    /// Works on finished pages, raw ones from [`Self::add_raw_page`]
+    /// included: each page is decoded from its BG44, FG44, Sjbz (with the
+    /// Djbz of the page or of its includes) and FGbz chunks and drawn the
+    /// way a viewer would. The component is listed in DIRM with the
+    /// thumbnails type ahead of the pages, replaces the one an earlier call
+    /// made, and makes even a single page a bundled document. Reordering or
+    /// merging pages drops it, since its thumbnails follow page order.
+    ///
+    /// Fails, changing nothing, if a page is missing or cannot be rendered;
+    /// pages with JPEG, JPEG 2000 or MMR layers are not supported.
+    pub fn regenerate_thumbnails(&self, size: u32) -> Result<()> {
+        self.check_complete()?;
+        let mut includes = self.includes.lock().unwrap();
+        let pages = (0..self.total_pages())
+            .map(|i| {
+                let page = self.collection.get_page(i)?.ok_or_else(|| {
+                    DjvuError::InvalidOperation(format!("Page {i} could not be read back"))
+                })?;
+                thumbnails::page_thumbnail(&page, &includes, size)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let form = thumbnails::thumbnails_form(&pages)?;
+        let id = DocumentEncoder::THUMBNAILS_ID;
+        match includes.iter_mut().find(|(i, _)| i == id) {
+            Some((_, data)) => *data = form,
+            None => includes.push((id.to_string(), form)),
+        }
+        Ok(())
+    }
+
+    /// Finalize and return DjVu file bytes
+    ///
+    /// Fails with [`DjvuError::NotFound`], leaving the pages in place, if a
+    /// page or include references an include id that was never registered.
+    pub fn finalize(&self) -> Result<Vec<u8>> {
+        let (pages, includes) = self.collect_components()?;
+        let titles = self.page_titles();
+        let bookmarks = self.bookmarks.lock().unwrap();
+        DocumentEncoder::assemble_pages(
+            &pages,
+            &includes,
+            &titles,
+            &self.page_naming,
+            &bookmarks,
+            self.params.bzz,
+        )
+    }
+
+    /// Finalize and write an indirect document into the new directory `dir`
+    ///
+    /// `dir` receives the index file `idx_name` plus one file per page and
+    /// include, named by its component id; pages always end in `.djvu`, so
+    /// page naming with another extension is rejected. Everything is first
+    /// written to a staging directory next to `dir` that is renamed into
+    /// place at the end, so `dir` must not exist yet and never appears half
+    /// written. The pages stay in the document. File system failures name
+    /// the path and operation involved and keep their
+    /// [`std::io::ErrorKind`].
+    pub fn write_indirect(&self, dir: impl AsRef<Path>, idx_name: &str) -> Result<()> {
+        let dir = dir.as_ref();
+        self.check_indirect_names(idx_name)?;
+        if dir.exists() {
+            return Err(Self::io_error(
+                dir,
+                "create directory",
+                io::ErrorKind::AlreadyExists.into(),
+            ));
+        }
+        let parent = match dir.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let dir_name = dir.file_name().ok_or_else(|| {
+            DjvuError::InvalidArg(format!("{} does not name a directory", dir.display()))
+        })?;
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let mut staging_name = std::ffi::OsString::from(".");
+        staging_name.push(dir_name);
+        staging_name.push(format!(
+            ".tmp{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let staging = parent.join(staging_name);
+        fs::create_dir(&staging).map_err(|e| Self::io_error(&staging, "create directory", e))?;
+
+        let write_all = || -> Result<()> {
+            let (index, files) = self.assemble_indirect()?;
+            for (name, bytes) in std::iter::once((idx_name, &index))
+                .chain(files.iter().map(|(n, b)| (n.as_str(), b)))
+            {
+                let path = staging.join(name);
+                fs::write(&path, bytes).map_err(|e| Self::io_error(&path, "write", e))?;
+            }
+            fs::rename(&staging, dir).map_err(|e| Self::io_error(dir, "create directory", e))
+        };
+        write_all().inspect_err(|_| {
+            let _ = fs::remove_dir_all(&staging);
+        })
+    }
+
+    /// Finalize an indirect document in memory, without touching the file
+    /// system
+    ///
+    /// Returns the same files [`Self::write_indirect`] would create, keyed by
+    /// name: the index under `idx_name` plus every page and include under
+    /// its component id. Suits callers that keep the files elsewhere, such
+    /// as object storage. Names are checked as for `write_indirect`.
+    pub fn finalize_indirect(&self, idx_name: &str) -> Result<HashMap<String, Vec<u8>>> {
+        self.check_indirect_names(idx_name)?;
+        let (index, files) = self.assemble_indirect()?;
+        Ok(std::iter::once((idx_name.to_string(), index))
+            .chain(files)
+            .collect())
+    }
+
+    /// Checks that the index and every component can be stored as plain,
+    /// distinct `.djvu` files next to each other
+    fn check_indirect_names(&self, idx_name: &str) -> Result<()> {
+        Self::check_file_name(idx_name, "index name")?;
+        if self.page_naming.extension != "djvu" {
+            return Err(DjvuError::InvalidArg(format!(
+                "indirect page files must end in .djvu, not .{}",
+                self.page_naming.extension
+            )));
+        }
+        let total = self.total_pages();
+        let includes = self.includes.lock().unwrap();
+        let shared_anno = self
+            .metadata
+            .as_ref()
+            .map(|_| DocumentEncoder::SHARED_ANNO_ID.to_string());
+        let names = includes
+            .iter()
+            .map(|(id, _)| id.clone())
+            .chain(shared_anno)
+            .chain((0..total).map(|i| self.page_naming.file_name(i, total)));
+        for name in names {
+            Self::check_file_name(&name, "component id")?;
+            if name == idx_name {
+                return Err(DjvuError::InvalidArg(format!(
+                    "index name {idx_name:?} clashes with a component"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Assembles the index and component files of an indirect document from
+    /// the finished components, leaving the pages in the collection
+    fn assemble_indirect(&self) -> Result<(Vec<u8>, Vec<Component>)> {
+        self.check_complete()?;
+        let mut includes = self.assembly_includes()?;
+        self.check_include_refs(&includes)?;
+        let pages = self
+            .collection
+            .collect_all()?
+            .ok_or_else(|| DjvuError::InvalidOperation("Failed to collect pages".to_string()))?;
+        let shared: Vec<Vec<u8>>;
+        let pages: Vec<&[u8]> = if self.dedup_pages {
+            let mut owned: Vec<Vec<u8>> = pages.iter().map(|p| p.to_vec()).collect();
+            DocumentEncoder::share_duplicate_pages(&mut owned, &mut includes)?;
+            shared = owned;
+            shared.iter().map(Vec::as_slice).collect()
+        } else {
+            pages.iter().map(|p| p.as_slice()).collect()
+        };
+        let titles = self.page_titles();
+        let bookmarks = self.bookmarks.lock().unwrap();
+        DocumentEncoder::assemble_indirect(
+            &pages,
+            &includes,
+            &titles,
+            &self.page_naming,
+            &bookmarks,
+            self.params.bzz,
+        )
+    }
+
+    /// Rejects names that can't be used as a file inside the output directory
+    fn check_file_name(name: &str, what: &str) -> Result<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+            return Err(DjvuError::InvalidArg(format!(
+                "{what} {name:?} is not a plain file name"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Wraps `err` with the operation and path that failed, keeping its kind
+    fn io_error(path: &Path, action: &str, err: io::Error) -> DjvuError {
+        let reason = match err.kind() {
+            io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+            _ => err.to_string(),
+        };
+        DjvuError::Io(io::Error::new(
+            err.kind(),
+            format!("cannot {action} {}: {reason}", path.display()),
+        ))
+    }
+
+    /// The registered includes as they are written out: the document
+    /// metadata goes into the shared annotation component, which is added
+    /// in front when no page shares annotations
+    fn assembly_includes(&self) -> Result<Vec<Component>> {
+        let mut includes = self.includes.lock().unwrap().clone();
+        let Some(metadata) = &self.metadata else {
+            return Ok(includes);
+        };
+        let annotations = metadata.to_annotations();
+        if annotations.metadata.is_empty() {
+            return Ok(includes);
+        }
+        let mut text = Vec::new();
+        annotations
+            .encode(&mut text, 0)
+            .map_err(|e| DjvuError::encoding(EncodeStage::Annotations, e))?;
+        let level = self.params.bzz.annotations;
+        let shared = DocumentEncoder::SHARED_ANNO_ID;
+        match includes.iter_mut().find(|(id, _)| id == shared) {
+            Some((_, djvi)) => {
+                *djvi = DocumentEncoder::append_annotations(Some(djvi), &text, level)?
+            }
+            None => includes.insert(
+                0,
+                (
+                    shared.to_string(),
+                    DocumentEncoder::append_annotations(None, &text, level)?,
+                ),
+            ),
+        }
+        Ok(includes)
+    }
+
+    /// Fails with [`DjvuError::NotFound`] if a ready page, or one of
+    /// `includes`, has an INCL chunk naming an id that is not in `includes`
+    fn check_include_refs(&self, includes: &[Component]) -> Result<()> {
+        let known: HashSet<&str> = includes.iter().map(|(id, _)| id.as_str()).collect();
+        let check = |what: String, data: &[u8]| -> Result<()> {
+            let ids = DocumentEncoder::include_ids(data).unwrap_or_default();
+            match ids.iter().find(|id| !known.contains(id.as_str())) {
+                Some(id) => Err(DjvuError::NotFound(format!(
+                    "{what} includes '{id}', which was never registered with add_include"
+                ))),
+                None => Ok(()),
+            }
+        };
+        for (id, data) in includes {
+            check(format!("Include '{id}'"), data)?;
+        }
+        for i in 0..self.total_pages() {
+            if let Some(page) = self.collection.get_page(i)? {
+                check(format!("Page {i}"), &page)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails unless the document has pages, all of them ready, and encoding
+    /// was not cancelled
+    fn check_complete(&self) -> Result<()> {
+        self.params.check_cancelled()?;
+        if self.total_pages() == 0 {
+            return Err(DjvuError::InvalidOperation("no pages".to_string()));
+        }
+        if !self.is_complete() {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Document incomplete: {} of {} pages ready",
+                self.pages_ready(),
+                self.total_pages()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Takes the finished pages with their includes (sharing duplicate pages
+    /// when enabled), ready for assembly
+    fn collect_components(&self) -> Result<(Vec<Vec<u8>>, Vec<Component>)> {
+        self.check_complete()?;
+
+        let mut includes = self.assembly_includes()?;
+        self.check_include_refs(&includes)?;
+        let mut pages = self
+            .collection
+            .take_all()?
+            .ok_or_else(|| DjvuError::InvalidOperation("Failed to collect pages".to_string()))?;
+
+        if self.dedup_pages {
+            DocumentEncoder::share_duplicate_pages(&mut pages, &mut includes)?;
+        }
+        Ok((pages, includes))
+    }
+
+    /// DIRM title of every page, `None` where none was set
+    fn page_titles(&self) -> Vec<Option<String>> {
+        (0..self.total_pages())
+            .map(|i| self.collection.page_title(i))
+            .collect()
+    }
+
+    /// Finalize and write the DjVu file to `writer`
+    ///
+    /// With [`DjvuBuilder::with_page_memory_cache`] the pages are streamed
+    /// out one at a time instead of assembling the whole file in memory.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        if self.collection.memory_limit().is_some() && !self.dedup_pages {
+            return self.stream_to(writer);
+        }
+        let bytes = self.finalize()?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Most encoded pages held in memory at once so far; bounded by
+    /// [`DjvuBuilder::with_page_memory_cache`]
+    pub fn peak_buffered_pages(&self) -> usize {
+        self.collection.peak_buffered_pages()
+    }
+
+    /// Writes the document page by page, so that pages spilled to disk are
+    /// only read back one at a time
+    fn stream_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        self.check_complete()?;
+
+        let includes = self.assembly_includes()?;
+        self.check_include_refs(&includes)?;
+        let titles = self.page_titles();
+        let bookmarks = self.bookmarks.lock().unwrap().clone();
+        let total = self.total_pages();
+        if total == 1 && includes.is_empty() && titles[0].is_none() && bookmarks.is_empty() {
+            writer.write_all(&self.collection.take_page(0)?)?;
+            return Ok(());
+        }
+
+        // Stored pages carry the AT&T magic, which the bundle drops
+        let sizes: Vec<usize> = includes
+            .iter()
+            .map(|(_, data)| data.len() - 4)
+            .chain((0..total).map(|i| self.collection.page_len(i).unwrap_or(4) - 4))
+            .collect();
+        let entries =
+            DocumentEncoder::djvm_entries(&sizes, &includes, total, &titles, &self.page_naming);
+        DocumentEncoder::write_djvm(writer, &entries, &bookmarks, self.params.bzz, |i| {
+            if i < includes.len() {
+                Ok(includes[i].1.clone())
+            } else {
+                self.collection.take_page(i - includes.len())
+            }
+        })
+    }
+
+    /// Takes the finished pages out one at a time, in page order, as
+    /// `(id, FORM:DJVU bytes)`
+    ///
+    /// Each blob is a standalone DjVu file that can be sent on as soon as
+    /// it is taken, and pages spilled by [`DjvuBuilder::with_page_memory_cache`]
+    /// are read back one by one. Once a page is missing (or encoding is
+    /// cancelled) the iterator yields that error and stops. Page
+    /// deduplication does not apply. Record each id and blob length for
+    /// [`DjvuDocument::finalize_dirm`].
+    pub fn encode_pages_iter(&self) -> impl Iterator<Item = Result<(PageId, Vec<u8>)>> + '_ {
+        let mut failed = false;
+        self.page_ids()
+            .into_iter()
+            .enumerate()
+            .map_while(move |(i, id)| {
+                if failed {
+                    return None;
+                }
+                let page = self
+                    .params
+                    .check_cancelled()
+                    .and_then(|()| self.collection.take_page(i));
+                failed = page.is_err();
+                Some(page.map(|data| (id, data)))
+            })
+    }
+
+    /// Builds the start of the bundled document for pages taken through
+    /// [`DjvuDocument::encode_pages_iter`], given each page's `(id, blob
+    /// length)` in page order
+    ///
+    /// The result holds the DJVM header, DIRM, NAVM and the includes.
+    /// Appending every page blob without its 4-byte `AT&T` magic, each
+    /// followed by a zero byte when the rest is odd-sized, gives the bytes
+    /// [`DjvuDocument::write_to`] writes for a multi-component document.
+    pub fn finalize_dirm(&self, page_metas: &[(PageId, usize)]) -> Result<Vec<u8>> {
+        let ids = self.page_ids();
+        if page_metas.len() != ids.len()
+            || page_metas
+                .iter()
+                .zip(&ids)
+                .any(|((id, len), expected)| id != expected || *len < 4)
+        {
+            return Err(DjvuError::InvalidArg(format!(
+                "page metadata must list all {} page ids in order with their blob lengths",
+                ids.len()
+            )));
+        }
+
+        // The pages have been taken already, so only the includes are checked
+        let includes = self.assembly_includes()?;
+        self.check_include_refs(&includes)?;
+        let titles = self.page_titles();
+        let bookmarks = self.bookmarks.lock().unwrap().clone();
+        // Blobs carry the AT&T magic, which the bundle drops
+        let sizes: Vec<usize> = includes
+            .iter()
+            .map(|(_, data)| data.len() - 4)
+            .chain(page_metas.iter().map(|(_, len)| len - 4))
+            .collect();
+        let entries =
+            DocumentEncoder::djvm_entries(&sizes, &includes, ids.len(), &titles, &self.page_naming);
+        let mut output = DocumentEncoder::djvm_header(&entries, &bookmarks, self.params.bzz)?;
+        for (_, data) in &includes {
+            output.extend_from_slice(&data[4..]);
+            if output.len() % 2 != 0 {
+                output.push(0);
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn white_page(page_num: usize) -> Page {
+        PageBuilder::new(page_num, 64, 48)
+            .with_background(Pixmap::from_pixel(64, 48, crate::Pixel::white()))
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_add_raw_page_round_trip() {
+        let source = DjvuBuilder::new(1).build();
+        let encoded = source.encode_page(white_page(0)).unwrap();
+        let bytes = encoded.data.as_ref().clone();
+
+        let doc = DjvuBuilder::new(2).build();
+        doc.add_raw_page(0, bytes.clone()).unwrap();
+        // Bare FORM without the AT&T magic is accepted too
+        doc.add_raw_page(1, bytes[4..].to_vec()).unwrap();
+        assert!(doc.is_complete());
+
+        let out = doc.finalize().unwrap();
+        assert_eq!(&out[0..8], b"AT&TFORM");
+        assert_eq!(&out[12..16], b"DJVM");
+        assert_eq!(out.windows(4).filter(|w| *w == b"DJVU").count(), 2);
+    }
+
+    #[test]
+    fn test_bundle_has_one_magic() {
+        let doc = DjvuBuilder::new(3).build();
+        for page_num in 0..3 {
+            doc.add_page(white_page(page_num)).unwrap();
+        }
+        let out = doc.finalize().unwrap();
+        assert!(out.starts_with(b"AT&TFORM"));
+        assert_eq!(out.windows(4).filter(|w| *w == b"AT&T").count(), 1);
+    }
+
+    #[test]
+    fn test_add_raw_page_with_loader() {
+        use crate::encode::jb2::encoder::JB2Encoder;
+        use crate::iff::iff::{IffWriter, IffWriterExt};
+
+        let mut glyph = BitImage::new(8, 8).unwrap();
+        for i in 0..8 {
+            glyph.set_usize(i, i, true);
+        }
+        let djbz = JB2Encoder::new(Vec::new())
+            .encode_dictionary(&[glyph], &[-1], 0)
+            .unwrap();
+        let mut dict = Vec::new();
+        let mut iff = IffWriter::new(io::Cursor::new(&mut dict));
+        iff.write_magic_bytes().unwrap();
+        iff.put_chunk("FORM:DJVI").unwrap();
+        iff.write_chunk(*b"Djbz", &djbz).unwrap();
+        iff.close_chunk().unwrap();
+        drop(iff);
+
+        let source = DjvuBuilder::new(1).build();
+        let page = PageBuilder::new(0, 64, 64)
+            .with_include("dict.djvi")
+            .build()
+            .unwrap();
+        let bytes = source.encode_page(page).unwrap().data.as_ref().clone();
+
+        let doc = DjvuBuilder::new(1).build();
+        let out_of_range = doc.add_raw_page_with_loader(1, bytes.clone(), |_| Ok(dict.clone()));
+        assert!(matches!(out_of_range, Err(DjvuError::InvalidArg(_))));
+        assert!(doc.includes.lock().unwrap().is_empty());
+        let missing = doc.add_raw_page_with_loader(0, bytes.clone(), |id| {
+            Err(DjvuError::InvalidArg(format!("no file {id}")))
+        });
+        assert!(missing.is_err());
+        assert!(!doc.is_complete());
+
+        let mut requested = Vec::new();
+        doc.add_raw_page_with_loader(0, bytes, |id| {
+            requested.push(id.to_string());
+            Ok(dict.clone())
+        })
+        .unwrap();
+        assert_eq!(requested, ["dict.djvi"]);
+
+        let out = doc.finalize().unwrap();
+        assert_eq!(&out[12..16], b"DJVM");
+        assert!(out.windows(4).any(|w| w == b"DJVI"));
+        assert!(out.windows(4).any(|w| w == b"Djbz"));
+        assert!(out.windows(4).any(|w| w == b"INCL"));
+        assert!(out.windows(9).any(|w| w == b"dict.djvi"));
+    }
+
+    #[test]
+    fn test_auto_mask_matches_foreground() {
+        let glyph = |w: u32, h: u32| {
+            Bitmap::from_vec(
+                w,
+                h,
+                (0..w * h)
+                    .map(|i| GrayPixel::new(if i % 3 == 0 { 0 } else { 255 }))
+                    .collect(),
+            )
+        };
+        let builder = PageBuilder::new(0, 64, 48)
+            .with_background(Pixmap::from_pixel(64, 48, crate::Pixel::white()))
+            .unwrap()
+            .with_foreground(glyph(20, 10), 8, 6)
+            .with_foreground(glyph(5, 4), 40, 30);
+        assert!(builder.needs_masking());
+
+        let builder = builder.auto_mask();
+        let masks: Vec<&ImageLayer> = builder
+            .layers()
+            .iter()
+            .filter(|l| matches!(l.data, LayerData::Mask(_)))
+            .collect();
+        assert_eq!(masks.len(), 1);
+        let LayerData::Mask(mask) = &masks[0].data else {
+            unreachable!()
+        };
+        assert_eq!((masks[0].x, masks[0].y), (8, 6));
+        assert_eq!(mask.dimensions(), (37, 28));
+
+        // Black exactly where some foreground layer is black
+        let foreground_black = |px: u32, py: u32| {
+            builder.layers().iter().any(|l| match &l.data {
+                LayerData::Foreground(fg) => {
+                    (l.x..l.x + l.width).contains(&px)
+                        && (l.y..l.y + l.height).contains(&py)
+                        && fg.get_pixel(px - l.x, py - l.y).y < 128
+                }
+                _ => false,
+            })
+        };
+        for y in 0..28 {
+            for x in 0..37 {
+                assert_eq!(mask.get_pixel(x, y).y < 128, foreground_black(x + 8, y + 6));
+            }
+        }
+
+        // A mask is only added once
+        let again = builder.auto_mask();
+        let count = again
+            .layers()
+            .iter()
+            .filter(|l| matches!(l.data, LayerData::Mask(_)))
+            .count();
+        assert_eq!(count, 1);
+        let page = again.build().unwrap();
+
+        // An identical mask needs no per-shape colors, which MMR can't code
+        let params = PageEncodeParams {
+            mask_codec: crate::doc::page_encoder::MaskCodec::Mmr,
+            ..PageEncodeParams::default()
+        };
+        let doc = DjvuBuilder::new(1).with_params(params).build();
+        doc.add_page(page).unwrap();
+        let out = doc.finalize().unwrap();
+        assert!(out.windows(4).any(|w| w == b"Smmr"));
+    }
+
+    #[test]
+    fn test_footer_link_moves_to_shared_annotations() {
+        let mut pages: Vec<Page> = (0..3)
+            .map(|n| {
+                let page = PageBuilder::new(n, 64, 48)
+                    .with_background(Pixmap::from_pixel(64, 48, crate::Pixel::white()))
+                    .unwrap()
+                    .with_hyperlink("https://example.com/home", 4, 40, 56, 6, "Home");
+                let page = if n == 0 {
+                    page.with_hyperlink("https://example.com/toc", 4, 4, 20, 6, "")
+                } else {
+                    page
+                };
+                page.build().unwrap()
+            })
+            .collect();
+
+        let doc = DjvuBuilder::new(3).build();
+        assert!(doc.dedup_shared_annotations(&mut pages, 0.0).is_err());
+        assert_eq!(doc.dedup_shared_annotations(&mut pages, 1.0).unwrap(), 1);
+        for page in pages {
+            doc.add_page(page).unwrap();
+        }
+        let bundle = doc.finalize().unwrap();
+
+        // The shared component comes first, then page 0's own link
+        let texts = antz_texts(&bundle);
+        assert_eq!(texts.len(), 2, "{texts:?}");
+        assert!(texts[0].contains("example.com/home"), "{texts:?}");
+        assert!(!texts[1].contains("example.com/home"), "{texts:?}");
+        assert!(texts[1].contains("example.com/toc"), "{texts:?}");
+        assert_eq!(bundle.windows(4).filter(|w| *w == b"INCL").count(), 3);
+        assert!(bundle.windows(15).any(|w| w == b"shared_anno.iff"));
+    }
+
+    #[test]
+    fn test_cancel_between_pages() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let doc = DjvuBuilder::new(3).with_cancel(cancel.clone()).build();
+        doc.add_page(white_page(0)).unwrap();
+
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        for n in 1..3 {
+            assert!(matches!(
+                doc.add_page(white_page(n)),
+                Err(DjvuError::Cancelled)
+            ));
+        }
+        assert_eq!(doc.pages_ready(), 1);
+        assert!(matches!(doc.finalize(), Err(DjvuError::Cancelled)));
+
+        // The document is intact and resumes once the flag is cleared
+        cancel.store(false, std::sync::atomic::Ordering::Relaxed);
+        for n in 1..3 {
+            doc.add_page(white_page(n)).unwrap();
+        }
+        assert_eq!(&doc.finalize().unwrap()[12..16], b"DJVM");
+    }
+
+    #[test]
+    fn test_dirm_offsets_point_at_components() {
+        // Titles grow the BZZ part of DIRM, moving its size around the
+        // assembler's initial estimate
+        for title_len in 0..48 {
+            let doc = DjvuBuilder::new(2).build();
+            doc.add_page_titled(white_page(0), "t".repeat(title_len))
+                .unwrap();
+            doc.add_page(white_page(1)).unwrap();
+            let out = doc.finalize().unwrap();
+
+            let dirm = Dirm::parse(&out);
+            assert!(dirm.bundled);
+            assert_eq!(dirm.count, 2);
+            for (i, &offset) in dirm.offsets.iter().enumerate() {
+                assert_eq!(
+                    &out[offset..offset + 4],
+                    b"FORM",
+                    "entry {i} with a {title_len}-byte title"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_page_memory_cache_bounds_buffered_pages() {
+        let page_size = DjvuBuilder::new(1)
+            .build()
+            .encode_page(white_page(0))
+            .unwrap()
+            .data
+            .len();
+        let total = 24;
+        let make = |cache: bool| {
+            let builder = DjvuBuilder::new(total);
+            let builder = if cache {
+                builder.with_page_memory_cache(3 * page_size)
+            } else {
+                builder
+            };
+            let doc = builder.build();
+            doc.add_bookmark(Bookmark {
+                title: "Start".to_string(),
+                dest: "#1".to_string(),
+                children: Vec::new(),
+            });
+            doc.add_include("shared.djvi", b"AT&TFORM\0\0\0\x04DJVI".to_vec())
+                .unwrap();
+            doc
+        };
+
+        let cached = make(true);
+        std::thread::scope(|scope| {
+            for worker in 0..4 {
+                let doc = &cached;
+                scope.spawn(move || {
+                    for n in (worker..total).step_by(4) {
+                        let title = format!("Page {n}");
+                        doc.add_page_titled(white_page(n), title).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(cached.is_complete());
+        assert!(cached.peak_buffered_pages() <= 3);
+        let mut streamed = Vec::new();
+        cached.write_to(&mut streamed).unwrap();
+
+        let plain = make(false);
+        for n in 0..total {
+            plain
+                .add_page_titled(white_page(n), format!("Page {n}"))
+                .unwrap();
+        }
+        assert_eq!(plain.peak_buffered_pages(), total);
+        assert_eq!(streamed, plain.finalize().unwrap());
+    }
+
+    #[test]
+    fn test_default_annotations_on_every_page() {
+        let mut defaults = Annotations::new();
+        defaults.hyperlinks.push(crate::annotations::Hyperlink {
+            shape: crate::annotations::AnnotationShape::Rect {
+                x: 0,
+                y: 90,
+                w: 100,
+                h: 10,
+            },
+            url: "https://example.com/footer".to_string(),
+            comment: String::new(),
+            target: String::new(),
+        });
+        defaults.initial_view.zoom = Some(crate::annotations::Zoom::Width);
+        let doc = DjvuBuilder::new(2)
+            .with_default_annotations(defaults)
+            .build();
+
+        let mut page0 = Annotations::new();
+        page0.initial_view.zoom = Some(crate::annotations::Zoom::Page);
+        let pages = [
+            PageBuilder::new(0, 100, 100)
+                .with_background(Pixmap::from_pixel(100, 100, crate::Pixel::white()))
+                .unwrap()
+                .with_annotations(page0)
+                .with_hyperlink("https://example.com/page", 10, 10, 20, 20, "")
+                .build()
+                .unwrap(),
+            white_page(1),
+        ];
+
+        let antz: Vec<String> = pages
+            .into_iter()
+            .map(|page| {
+                let mut texts = antz_texts(&doc.encode_page(page).unwrap().data);
+                assert_eq!(texts.len(), 1, "{texts:?}");
+                texts.pop().unwrap()
+            })
+            .collect();
+
+        for text in &antz {
+            assert!(text.contains("https://example.com/footer"), "{text}");
+        }
+        assert!(antz[0].contains("https://example.com/page"));
+        assert!(antz[0].starts_with("(zoom page)"), "{}", antz[0]);
+        assert!(!antz[1].contains("https://example.com/page"));
+        assert!(antz[1].starts_with("(zoom width)"), "{}", antz[1]);
+    }
+
+    #[test]
+    fn test_layer_clipped_at_right_edge() {
+        let tile = Pixmap::from_fn(40, 30, |x, y| crate::Pixel::new(x as u8, y as u8, 0));
+        let builder = PageBuilder::new(0, 64, 48).add_layer(ImageLayer::background(tile, 32, 0));
+        assert!(builder.build().is_err());
+
+        let tile = Pixmap::from_fn(40, 30, |x, y| crate::Pixel::new(x as u8, y as u8, 0));
+        let page = PageBuilder::new(0, 64, 48)
+            .add_layer(ImageLayer::background(tile, 32, 0))
+            .with_clipping(true)
+            .build()
+            .unwrap();
+        let layer = &page.layers()[0];
+        assert_eq!(layer.bounds(), (32, 0, 32, 30));
+        match &layer.data {
+            LayerData::Background(pixmap) => {
+                assert_eq!(pixmap.dimensions(), (32, 30));
+                assert_eq!(pixmap.get_pixel(31, 29), crate::Pixel::new(31, 29, 0));
+            }
+            _ => panic!("expected a background layer"),
+        }
+    }
+
+    #[test]
+    fn test_layer_off_page_is_dropped() {
+        let text = Bitmap::from_pixel(16, 16, crate::GrayPixel::black());
+        let page = PageBuilder::new(0, 64, 48)
+            .with_foreground(text.clone(), 0, 0)
+            .with_foreground(text.clone(), 64, 10)
+            .with_mask(text.clone(), 10, 100)
+            .with_clipping(true)
+            .build()
+            .unwrap();
+        assert_eq!(page.layers().len(), 1);
+        assert_eq!(page.layers()[0].bounds(), (0, 0, 16, 16));
+
+        let only_off_page = PageBuilder::new(0, 64, 48)
+            .with_foreground(text, 80, 0)
+            .with_clipping(true);
+        assert!(only_off_page.build().is_err());
+    }
+
+    #[test]
+    fn test_bzz_level_shrinks_large_dirm() {
+        let naming = PageNaming {
+            prefix: "annual-report-scanned-volume-".to_string(),
+            extension: "djvu".to_string(),
+        };
+        let count = 800;
+        let sizes = vec![1000; count];
+        let entries = DocumentEncoder::djvm_entries(&sizes, &[], count, &[], &naming);
+        let header_len = |k| {
+            DocumentEncoder::djvm_header(&entries, &[], BzzLevel::uniform(k))
+                .unwrap()
+                .len()
+        };
+        assert!(header_len(BzzLevel::MAX) < header_len(BzzLevel::MIN));
+
+        assert!(
+            DjvuBuilder::new(1)
+                .with_bzz_level(BzzLevel::uniform(4))
+                .is_err()
+        );
+        let bad = BzzLevel {
+            navm: BzzLevel::MAX + 1,
+            ..BzzLevel::default()
+        };
+        assert!(DjvuBuilder::new(1).with_bzz_level(bad).is_err());
+    }
+
+    #[test]
+    fn test_pages_iter_with_dirm_matches_write_to() {
+        let build = || {
+            let doc = DjvuBuilder::new(3).build();
+            for i in 0..3 {
+                doc.add_page(white_page(i)).unwrap();
+            }
+            doc.add_bookmark(Bookmark {
+                title: "Start".to_string(),
+                dest: "#1".to_string(),
+                children: Vec::new(),
+            });
+            doc.add_include("shared.djvi", b"AT&TFORM\0\0\0\x05DJVIx".to_vec())
+                .unwrap();
+            doc
+        };
+        let mut expected = Vec::new();
+        build().write_to(&mut expected).unwrap();
+
+        let doc = build();
+        let pages: Vec<(PageId, Vec<u8>)> = doc.encode_pages_iter().map(Result::unwrap).collect();
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|(_, blob)| blob.starts_with(b"AT&TFORM")));
+        let metas: Vec<(PageId, usize)> = pages
+            .iter()
+            .map(|(id, blob)| (id.clone(), blob.len()))
+            .collect();
+        let mut bundle = doc.finalize_dirm(&metas).unwrap();
+        for (_, blob) in &pages {
+            bundle.extend_from_slice(&blob[4..]);
+            if bundle.len() % 2 != 0 {
+                bundle.push(0);
+            }
+        }
+        assert_eq!(bundle, expected);
+
+        // The pages have been taken
+        let mut again = doc.encode_pages_iter();
+        assert!(again.next().unwrap().is_err());
+        assert!(again.next().is_none());
+        assert!(doc.finalize_dirm(&metas[..2]).is_err());
+    }
+
+    /// Decompressed text of every ANTz chunk in `bytes`, in file order
+    fn antz_texts(bytes: &[u8]) -> Vec<String> {
+        let mut texts = Vec::new();
+        let mut at = 0;
+        while let Some(pos) = bytes[at..].windows(4).position(|w| w == b"ANTz") {
+            let start = at + pos + 8;
+            let len = u32::from_be_bytes(bytes[start - 4..start].try_into().unwrap()) as usize;
+            let raw =
+                crate::iff::bs_byte_stream::bzz_decompress(&bytes[start..start + len]).unwrap();
+            texts.push(String::from_utf8(raw).unwrap());
+            at = start + len;
+        }
+        texts
+    }
+
+    /// The DIRM chunk of a DJVM file, split into its parts
+    struct Dirm {
+        bundled: bool,
+        count: usize,
+        /// Component offsets, empty for indirect documents
+        offsets: Vec<usize>,
+        /// BZZ-decompressed records: 3-byte sizes, flag bytes, then the ids
+        records: Vec<u8>,
+    }
+
+    impl Dirm {
+        fn parse(file: &[u8]) -> Self {
+            let start = file.windows(4).position(|w| w == b"DIRM").unwrap() + 8;
+            let len = u32::from_be_bytes(file[start - 4..start].try_into().unwrap()) as usize;
+            let dirm = &file[start..start + len];
+            let bundled = dirm[0] & 0x80 != 0;
+            let count = u16::from_be_bytes([dirm[1], dirm[2]]) as usize;
+            let table = if bundled { 4 * count } else { 0 };
+            let offsets = dirm[3..3 + table]
+                .chunks(4)
+                .map(|o| u32::from_be_bytes(o.try_into().unwrap()) as usize)
+                .collect();
+            let records = crate::iff::bs_byte_stream::bzz_decompress(&dirm[3 + table..]).unwrap();
+            Self {
+                bundled,
+                count,
+                offsets,
+                records,
+            }
+        }
+
+        /// Component type of each file, without the name and title bits
+        fn flags(&self) -> Vec<u8> {
+            let flags = &self.records[3 * self.count..4 * self.count];
+            flags.iter().map(|f| f & 0x3f).collect()
+        }
+    }
+
+    #[test]
+    fn test_set_metadata_in_bundle() {
+        let doc = DjvuBuilder::new(2)
+            .set_metadata("Field Notes", "A. Writer", "Birds", "birds, notes")
+            .build();
+        doc.add_page(white_page(0)).unwrap();
+        doc.add_page(white_page(1)).unwrap();
+        let bundle = doc.finalize().unwrap();
+
+        // Stored once, in the shared component ahead of the pages
+        let texts = antz_texts(&bundle);
+        assert_eq!(texts.len(), 1, "{texts:?}");
+        let text = &texts[0];
+        assert!(text.contains("(Title \"Field Notes\")"), "{text}");
+        assert!(text.contains("(Author \"A. Writer\")"), "{text}");
+        assert!(text.contains("(Keywords \"birds, notes\")"), "{text}");
+        assert!(text.contains("(Producer \"djvu_encoder 0.1.0\")"), "{text}");
+        #[cfg(not(feature = "creation-date"))]
+        assert!(!text.contains("CreationDate"), "{text}");
+        let djvi = bundle.windows(4).position(|w| w == b"DJVI").unwrap();
+        assert!(djvi < bundle.windows(4).position(|w| w == b"DJVU").unwrap());
+    }
+
+    #[test]
+    fn test_metadata_joins_shared_annotations() {
+        let mut pages: Vec<Page> = (0..2)
+            .map(|n| {
+                PageBuilder::new(n, 64, 48)
+                    .with_background(Pixmap::from_pixel(64, 48, crate::Pixel::white()))
+                    .unwrap()
+                    .with_hyperlink("https://example.com/home", 4, 40, 56, 6, "Home")
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        let doc = DjvuBuilder::new(2)
+            .set_metadata("Field Notes", "", "", "")
+            .build();
+        assert_eq!(doc.dedup_shared_annotations(&mut pages, 1.0).unwrap(), 1);
+        for page in pages {
+            doc.add_page(page).unwrap();
+        }
+
+        let files = doc.finalize_indirect("index.djvu").unwrap();
+        let texts = antz_texts(&files[DocumentEncoder::SHARED_ANNO_ID]);
+        assert_eq!(texts.len(), 1, "{texts:?}");
+        assert!(texts[0].contains("example.com/home"), "{texts:?}");
+        assert!(texts[0].contains("(Title \"Field Notes\")"), "{texts:?}");
+        for page in ["p0001.djvu", "p0002.djvu"] {
+            assert!(antz_texts(&files[page]).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_creation_date_is_iso8601() {
+        assert_eq!(iso8601_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601_utc(-1), "1969-12-31T23:59:59Z");
+
+        let doc = DjvuBuilder::new(1)
+            .with_creation_date(1_715_938_200)
+            .set_metadata("Field Notes", "", "", "")
+            .build();
+        doc.add_page(white_page(0)).unwrap();
+        let bundle = doc.finalize().unwrap();
+
+        let texts = antz_texts(&bundle);
+        assert_eq!(texts.len(), 1, "{texts:?}");
+        let text = &texts[0];
+        assert!(text.contains("(Title \"Field Notes\")"), "{text}");
+        let date = text.split("(CreationDate \"").nth(1).unwrap();
+        let date = &date[..date.find('"').unwrap()];
+        assert_eq!(date, "2024-05-17T09:30:00Z");
+        let parsed = chrono::DateTime::parse_from_rfc3339(date).unwrap();
+        assert_eq!(parsed.timestamp(), 1_715_938_200);
+    }
+
+    #[cfg(feature = "creation-date")]
+    #[test]
+    fn test_build_stamps_creation_date() {
+        let unix_now = || {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64
+        };
+        let before = unix_now();
+        let doc = DjvuBuilder::new(2)
+            .set_metadata("Field Notes", "", "", "")
+            .build();
+        let after = unix_now();
+        doc.add_page(white_page(0)).unwrap();
+        doc.add_page(white_page(1)).unwrap();
+        let bundle = doc.finalize().unwrap();
+
+        let texts = antz_texts(&bundle);
+        let dated: Vec<&String> = texts
+            .iter()
+            .filter(|t| t.contains("CreationDate"))
+            .collect();
+        assert_eq!(dated.len(), 1, "{texts:?}");
+        let date = dated[0].split("(CreationDate \"").nth(1).unwrap();
+        let date = &date[..date.find('"').unwrap()];
+        let stamped = chrono::DateTime::parse_from_rfc3339(date)
+            .unwrap()
+            .timestamp();
+        assert!((before..=after).contains(&stamped), "{date}");
+
+        // Without metadata nothing is stamped
+        let plain = DjvuBuilder::new(1).build();
+        plain.add_page(white_page(0)).unwrap();
+        assert!(antz_texts(&plain.finalize().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_shared_background_include() {
+        let noisy = Pixmap::from_fn(128, 128, |x, y| {
+            let v = ((x * 37 + y * 91) ^ (x * y)) as u8;
+            crate::Pixel::new(v, v / 2, 255 - v)
+        });
+        let page_with_bg = |n| {
+            PageBuilder::new(n, 128, 128)
+                .with_background(noisy.clone())
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+
+        let separate = DjvuBuilder::new(2).build();
+        separate.add_page(page_with_bg(0)).unwrap();
+        separate.add_page(page_with_bg(1)).unwrap();
+        let separate = separate.finalize().unwrap();
+
+        let include = PageComponents::new()
+            .with_background(noisy.clone())
+            .unwrap()
+            .encode_background_include(&PageEncodeParams::default())
+            .unwrap();
+        let shared = DjvuBuilder::new(2).build();
+        shared.add_include("bg.djvi", include).unwrap();
+        for n in 0..2 {
+            let page = PageBuilder::new(n, 128, 128)
+                .with_include("bg.djvi")
+                .build()
+                .unwrap();
+            shared.add_page(page).unwrap();
+        }
+        let shared = shared.finalize().unwrap();
+
+        assert!(shared.windows(4).any(|w| w == b"DJVI"));
+        assert_eq!(shared.windows(4).filter(|w| *w == b"INCL").count(), 2);
+        assert!(shared.len() < separate.len());
+    }
+
+    #[test]
+    fn test_merge_sections() {
+        let section = |widths: &[u32], title: &str| {
+            let doc = DjvuBuilder::new(widths.len()).build();
+            let djvi = b"AT&TFORM\0\0\0\x04DJVI".to_vec();
+            doc.add_include("dict.djvi", djvi).unwrap();
+            for (n, &w) in widths.iter().enumerate() {
+                let page = PageBuilder::new(n, w, 48)
+                    .with_include("dict.djvi")
+                    .build()
+                    .unwrap();
+                doc.add_page(page).unwrap();
+            }
+            doc.add_bookmark(Bookmark {
+                title: title.to_string(),
+                dest: "#1".to_string(),
+                children: vec![Bookmark {
+                    title: format!("{title}, page 2"),
+                    dest: "#p0002.djvu".to_string(),
+                    children: Vec::new(),
+                }],
+            });
+            doc
+        };
+
+        let mut doc = section(&[10, 20], "Part 1");
+        doc.merge(section(&[30, 40, 50], "Part 2")).unwrap();
+        assert_eq!(doc.total_pages(), 5);
+
+        let dests: Vec<(String, String)> = doc
+            .bookmarks()
+            .iter()
+            .map(|b| (b.dest.clone(), b.children[0].dest.clone()))
+            .collect();
+        assert_eq!(
+            dests,
+            [
+                ("#1".to_string(), "#p0002.djvu".to_string()),
+                ("#3".to_string(), "#4".to_string())
+            ]
+        );
+
+        let out = doc.finalize().unwrap();
+        assert!(out.windows(4).any(|w| w == b"NAVM"));
+        let chunks = |id: &[u8]| -> Vec<Vec<u8>> {
+            out.windows(4)
+                .enumerate()
+                .filter(|(_, w)| *w == id)
+                .map(|(i, _)| {
+                    let len = u32::from_be_bytes(out[i + 4..i + 8].try_into().unwrap()) as usize;
+                    out[i + 8..i + 8 + len].to_vec()
+                })
+                .collect()
+        };
+        let widths: Vec<u16> = chunks(b"INFO")
+            .iter()
+            .map(|info| u16::from_be_bytes([info[0], info[1]]))
+            .collect();
+        assert_eq!(widths, [10, 20, 30, 40, 50]);
+        let incl: Vec<Vec<u8>> = chunks(b"INCL");
+        assert_eq!(incl[..2], [b"dict.djvi".to_vec(), b"dict.djvi".to_vec()]);
+        assert!(incl[2..].iter().all(|id| id == b"dict_2.djvi"));
+        assert_eq!(out.windows(4).filter(|w| *w == b"DJVI").count(), 2);
+    }
+
+    #[test]
+    fn test_merge_keeps_ids_and_metadata() {
+        let raw_page = |doc: &DjvuDocument, n| {
+            let page = PageBuilder::new(n, 8, 8)
+                .with_background(Pixmap::from_pixel(8, 8, crate::Pixel::white()))
+                .unwrap()
+                .build()
+                .unwrap();
+            doc.encode_page(page).unwrap().data.as_ref().clone()
+        };
+
+        // Merging would give page 3 the id of an existing include
+        let mut doc = DjvuBuilder::new(2).build();
+        let blob = raw_page(&doc, 0);
+        doc.add_include("p0003.djvu", b"AT&TFORM\0\0\0\x04DJVI".to_vec())
+            .unwrap();
+        let other = DjvuBuilder::new(1).build();
+        other.add_raw_page(0, blob.clone()).unwrap();
+        assert!(matches!(
+            doc.merge(other),
+            Err(DjvuError::InvalidOperation(_))
+        ));
+        assert_eq!(doc.total_pages(), 2);
+
+        // 10 000 pages need five-digit ids, so bookmarks to the old ids
+        // become page numbers
+        let mut doc = DjvuBuilder::new(2)
+            .set_metadata("Collected Papers", "", "", "")
+            .build();
+        doc.add_raw_page(0, blob.clone()).unwrap();
+        doc.add_raw_page(1, blob.clone()).unwrap();
+        doc.add_bookmark(Bookmark {
+            title: "Second".to_string(),
+            dest: "#p0002.djvu".to_string(),
+            children: Vec::new(),
+        });
+        let other = DjvuBuilder::new(9_998)
+            .set_metadata("Volume 2", "A. Writer", "", "")
+            .build();
+        for n in 0..9_998 {
+            other.add_raw_page(n, blob.clone()).unwrap();
+        }
+        doc.merge(other).unwrap();
+        assert_eq!(doc.bookmarks()[0].dest, "#2");
+        assert_eq!(doc.page_ids()[1], "p00002.djvu");
+
+        let metadata = doc.metadata.as_ref().unwrap();
+        assert_eq!(metadata.title, "Collected Papers");
+        assert_eq!(metadata.author, "A. Writer");
+    }
+
+    #[test]
+    fn test_identical_pages_are_shared() {
+        let blank = |n| {
+            PageBuilder::new(n, 600, 800)
+                .with_background(Pixmap::from_pixel(600, 800, crate::Pixel::white()))
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+        let finalize = |dedup| {
+            let doc = DjvuBuilder::new(2).with_page_dedup(dedup).build();
+            doc.add_page(blank(0)).unwrap();
+            doc.add_page(blank(1)).unwrap();
+            assert_eq!(doc.page_count(), 2);
+            doc.finalize().unwrap()
+        };
+
+        let plain = finalize(false);
+        let shared = finalize(true);
+
+        assert!(shared.len() < plain.len());
+        assert_eq!(shared.windows(4).filter(|w| *w == b"DJVU").count(), 2);
+        assert_eq!(shared.windows(4).filter(|w| *w == b"DJVI").count(), 1);
+        assert_eq!(shared.windows(4).filter(|w| *w == b"INCL").count(), 2);
+        assert_eq!(shared.windows(4).filter(|w| *w == b"BG44").count(), 1);
+    }
+
+    #[test]
+    fn test_titled_page_forces_bundle() {
+        let doc = DjvuBuilder::new(1).build();
+        doc.add_page_titled(white_page(0), "Chapter 3").unwrap();
+        let out = doc.finalize().unwrap();
+
+        assert_eq!(&out[12..16], b"DJVM");
+        assert_eq!(&out[16..20], b"DIRM");
+        assert_eq!(doc.collection.page_title(0).as_deref(), Some("Chapter 3"));
+    }
+
+    #[test]
+    fn test_mixed_resolution_pages() {
+        let doc = DjvuBuilder::new(2).build();
+        doc.add_page(white_page(0)).unwrap();
+        doc.add_page_with_dpi(white_page(1), 600).unwrap();
+        let out = doc.finalize().unwrap();
+
+        // INFO payload: width(2) height(2) minor(1) major(1) dpi(2, LE) ...
+        let dpis: Vec<u16> = out
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == b"INFO")
+            .map(|(i, _)| u16::from_le_bytes([out[i + 14], out[i + 15]]))
+            .collect();
+        assert_eq!(dpis, [300, 600]);
+    }
+
+    #[test]
+    fn test_with_params_keeps_dpi_and_cancel() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let doc = DjvuBuilder::new(2)
+            .with_dpi(600)
+            .with_cancel(cancel.clone())
+            .with_params(PageEncodeParams::default())
+            .build();
+        doc.add_page(white_page(0)).unwrap();
+        let page = doc.collection.get_page(0).unwrap().unwrap();
+        let info = page.windows(4).position(|w| w == b"INFO").unwrap();
+        assert_eq!(u16::from_le_bytes([page[info + 14], page[info + 15]]), 600);
+
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(matches!(
+            doc.add_page(white_page(1)),
+            Err(DjvuError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_gray_page_in_color_mode_is_diagnosed() {
+        let gray = Pixmap::from_fn(64, 48, |x, y| {
+            let v = (x * 3 + y) as u8;
+            crate::Pixel::new(v, v, v)
+        });
+        let colorful = Pixmap::from_fn(64, 48, |x, y| {
+            crate::Pixel::new((x * 4) as u8, 255 - (y * 5) as u8, 128)
+        });
+        let page = |n: usize, bg: Pixmap| {
+            PageBuilder::new(n, 64, 48)
+                .with_background(bg)
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+
+        let doc = DjvuBuilder::new(2).build();
+        assert_eq!(doc.params.color, crate::ColorMode::Color);
+        doc.add_page(page(0, gray)).unwrap();
+        doc.add_page(page(1, colorful)).unwrap();
+        assert_eq!(
+            doc.diagnostics(),
+            [EncodeDiagnostic::GrayInColorMode { page_num: 0 }]
+        );
+    }
+
+    #[test]
+    fn test_finalize_rejects_unregistered_include() {
+        let doc = DjvuBuilder::new(1).build();
+        let page = PageBuilder::new(0, 64, 48)
+            .with_include("missing.djvi")
+            .build()
+            .unwrap();
+        doc.add_page(page).unwrap();
+        assert!(matches!(doc.finalize(), Err(DjvuError::NotFound(_))));
+        assert!(matches!(
+            doc.write_to(Vec::new()),
+            Err(DjvuError::NotFound(_))
+        ));
+
+        // Nothing was taken, so registering the include fixes it
+        doc.add_include("missing.djvi", b"AT&TFORM\0\0\0\x04DJVI".to_vec())
+            .unwrap();
+        assert!(doc.finalize().is_ok());
+    }
+
+    #[test]
+    fn test_add_include_rejects_duplicates() {
+        let doc = DjvuBuilder::new(1).build();
+        let djvi = b"AT&TFORM\0\0\0\x04DJVI".to_vec();
+        doc.add_include("a.djvi", djvi.clone()).unwrap();
+        assert!(doc.add_include("a.djvi", djvi.clone()).is_err());
+        assert!(doc.add_include("p0001.djvu", djvi).is_err());
+    }
+
+    #[test]
+    fn test_page_names_sort_in_page_order() {
+        let naming = PageNaming::default();
+        let names: Vec<String> = (0..10_000).map(|i| naming.file_name(i, 10_000)).collect();
+        assert_eq!(names[0], "p00001.djvu");
+        assert_eq!(names[9_999], "p10000.djvu");
+        assert!(names.iter().all(|n| n.len() == names[0].len()));
+        assert!(names.windows(2).all(|w| w[0] < w[1]));
+
+        // Short documents keep the four-digit names
+        assert_eq!(naming.file_name(0, 12), "p0001.djvu");
+    }
+
+    #[test]
+    fn test_custom_page_naming() {
+        let doc = DjvuBuilder::new(2).with_page_naming("scan", "djv").build();
+        let djvi = b"AT&TFORM\0\0\0\x04DJVI".to_vec();
+        assert!(doc.add_include("scan0002.djv", djvi.clone()).is_err());
+        doc.add_include("p0002.djvu", djvi).unwrap();
+
+        for n in 0..2 {
+            doc.add_page(white_page(n)).unwrap();
+        }
+        let out = doc.finalize().unwrap();
+        let id = b"scan0001.djv";
+        let names = Dirm::parse(&out).records;
+        assert!(names.windows(id.len()).any(|w| w == id));
+    }
+
+    #[test]
+    fn test_write_indirect() {
+        let doc = DjvuBuilder::new(2).build();
+        doc.add_include("shared.djvi", b"AT&TFORM\0\0\0\x04DJVI".to_vec())
+            .unwrap();
+        for n in 0..2 {
+            doc.add_page(white_page(n)).unwrap();
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("book");
+
+        match doc.write_indirect(&dir, "") {
+            Err(DjvuError::InvalidArg(_)) => {}
+            other => panic!("expected empty index name to fail, got {other:?}"),
+        }
+        doc.write_indirect(&dir, "index.djvu").unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["index.djvu", "p0001.djvu", "p0002.djvu", "shared.djvi"]
+        );
+        // No staging directory is left behind
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+        let index = std::fs::read(dir.join("index.djvu")).unwrap();
+        assert_eq!(&index[12..20], b"DJVMDIRM");
+        // Indirect DIRM: no bundled flag and no offset table
+        let dirm = Dirm::parse(&index);
+        assert!(!dirm.bundled);
+        assert_eq!(dirm.count, 3);
+        for page in ["p0001.djvu", "p0002.djvu"] {
+            let bytes = std::fs::read(dir.join(page)).unwrap();
+            assert_eq!(&bytes[..8], b"AT&TFORM");
+            assert_eq!(&bytes[12..16], b"DJVU");
+        }
+
+        // The directory is only ever created whole
+        match doc.write_indirect(&dir, "index.djvu") {
+            Err(DjvuError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists),
+            other => panic!("expected existing directory to fail, got {other:?}"),
+        }
+        // Pages are only borrowed, so the document can be written again
+        doc.write_indirect(tmp.path().join("copy"), "index.djvu")
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("p0002.djvu")).unwrap(),
+            std::fs::read(tmp.path().join("copy/p0002.djvu")).unwrap()
+        );
+        // A document that can't be assembled leaves nothing behind
+        let incomplete = DjvuBuilder::new(2).build();
+        incomplete.add_page(white_page(0)).unwrap();
+        assert!(
+            incomplete
+                .write_indirect(tmp.path().join("partial"), "index.djvu")
+                .is_err()
+        );
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 2);
+        let renamed = DjvuBuilder::new(1).with_page_naming("p", "djv").build();
+        renamed.add_page(white_page(0)).unwrap();
+        assert!(
+            renamed
+                .write_indirect(tmp.path().join("other"), "index.djvu")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_finalize_indirect_in_memory() {
+        let doc = DjvuBuilder::new(3).build();
+        doc.add_include("shared.djvi", b"AT&TFORM\0\0\0\x04DJVI".to_vec())
+            .unwrap();
+        for n in 0..3 {
+            doc.add_page(white_page(n)).unwrap();
+        }
+        assert!(matches!(
+            doc.finalize_indirect("p0002.djvu"),
+            Err(DjvuError::InvalidArg(_))
+        ));
+
+        let files = doc.finalize_indirect("index.djvu").unwrap();
+        let mut names: Vec<&str> = files.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "index.djvu",
+                "p0001.djvu",
+                "p0002.djvu",
+                "p0003.djvu",
+                "shared.djvi"
+            ]
+        );
+        assert_eq!(&files["index.djvu"][12..20], b"DJVMDIRM");
+        for page in ["p0001.djvu", "p0002.djvu", "p0003.djvu"] {
+            assert_eq!(&files[page][..8], b"AT&TFORM");
+            assert_eq!(&files[page][12..16], b"DJVU");
+        }
+    }
+
+    #[test]
+    fn test_indirect_types_shared_annotations() {
+        let mut pages: Vec<Page> = (0..2)
+            .map(|n| {
+                PageBuilder::new(n, 64, 48)
+                    .with_background(Pixmap::from_pixel(64, 48, crate::Pixel::white()))
+                    .unwrap()
+                    .with_hyperlink("https://example.com/home", 4, 40, 56, 6, "Home")
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        let doc = DjvuBuilder::new(2).build();
+        assert_eq!(doc.dedup_shared_annotations(&mut pages, 1.0).unwrap(), 1);
+        for page in pages {
+            doc.add_page(page).unwrap();
+        }
+
+        let files = doc.finalize_indirect("index.djvu").unwrap();
+        assert!(files.contains_key(DocumentEncoder::SHARED_ANNO_ID));
+        // The include comes first
+        assert_eq!(Dirm::parse(&files["index.djvu"]).flags(), [3, 1, 1]);
+    }
+
+    #[test]
+    fn test_regenerate_thumbnails() {
+        // Page 0 comes encoded by another document, page 1 is drawn here:
+        // black ink over the left half of a white page
+        let red = PageBuilder::new(0, 64, 48)
+            .with_background(Pixmap::from_pixel(64, 48, crate::Pixel::new(200, 0, 0)))
+            .unwrap()
+            .build()
+            .unwrap();
+        let blob = DjvuBuilder::new(1)
+            .build()
+            .encode_page(red)
+            .unwrap()
+            .data
+            .as_ref()
+            .clone();
+        let mut doc = DjvuBuilder::new(2).build();
+        doc.add_raw_page(0, blob).unwrap();
+        let inked = PageBuilder::new(1, 64, 48)
+            .with_background(Pixmap::from_pixel(64, 48, crate::Pixel::white()))
+            .unwrap()
+            .with_foreground(Bitmap::from_pixel(32, 48, GrayPixel { y: 0 }), 0, 0)
+            .build()
+            .unwrap();
+        doc.add_page(inked).unwrap();
+        let files = doc.finalize_indirect("index.djvu").unwrap();
+        assert!(!files.contains_key(DocumentEncoder::THUMBNAILS_ID));
+
+        // A second call replaces the component of the first
+        doc.regenerate_thumbnails(32).unwrap();
+        doc.regenerate_thumbnails(16).unwrap();
+        let files = doc.finalize_indirect("index.djvu").unwrap();
+        assert_eq!(Dirm::parse(&files["index.djvu"]).flags(), [2, 1, 1]);
+
+        let (form, chunks) =
+            DocumentEncoder::form_chunks(&files[DocumentEncoder::THUMBNAILS_ID]).unwrap();
+        assert_eq!(&form, b"THUM");
+        let thumbnails: Vec<Pixmap> = chunks
+            .iter()
+            .map(|(id, data)| {
+                assert_eq!(id, b"TH44");
+                let image = crate::encode::iw44::Iw44Image::decode([*data]).unwrap();
+                image.to_pixmap().unwrap()
+            })
+            .collect();
+        assert_eq!(thumbnails.len(), 2);
+        assert_eq!(thumbnails[0].dimensions(), (16, 12));
+        let p = thumbnails[0].get_pixel(8, 6);
+        assert!(p.r > 150 && p.g < 60 && p.b < 60, "{p:?}");
+        let (left, right) = (
+            thumbnails[1].get_pixel(3, 6),
+            thumbnails[1].get_pixel(12, 6),
+        );
+        assert!(left.r < 60 && right.r > 200, "{left:?} {right:?}");
+
+        // Page order changes leave the thumbnails stale, so they go
+        doc.reorder_pages(&[1, 0]).unwrap();
+        let files = doc.finalize_indirect("index.djvu").unwrap();
+        assert!(!files.contains_key(DocumentEncoder::THUMBNAILS_ID));
+    }
+
+    #[test]
+    fn test_reorder_pages_reverse() {
+        let mut doc = DjvuBuilder::new(3).build();
+        for (n, w) in [10, 20, 30].into_iter().enumerate() {
+            let page = PageBuilder::new(n, w, 48)
+                .with_background(Pixmap::from_pixel(w, 48, crate::Pixel::white()))
+                .unwrap()
+                .build()
+                .unwrap();
+            doc.add_page_titled(page, format!("width {w}")).unwrap();
+        }
+        doc.add_bookmark(Bookmark {
+            title: "Last".to_string(),
+            dest: "#p0003.djvu".to_string(),
+            children: Vec::new(),
+        });
+
+        for bad in [&[0, 1][..], &[0, 1, 1], &[0, 1, 3]] {
+            assert!(matches!(
+                doc.reorder_pages(bad),
+                Err(DjvuError::InvalidArg(_))
+            ));
+        }
+        doc.reorder_pages(&[2, 1, 0]).unwrap();
+        assert_eq!(doc.page_ids(), ["p0001.djvu", "p0002.djvu", "p0003.djvu"]);
+        assert_eq!(doc.bookmarks()[0].dest, "#1");
+
+        let out = doc.finalize().unwrap();
+        let dirm = Dirm::parse(&out);
+        // Each DIRM entry points at a FORM:DJVU whose INFO starts with its width
+        let widths: Vec<u16> = dirm
+            .offsets
+            .iter()
+            .map(|&o| {
+                assert_eq!(&out[o..o + 4], b"FORM");
+                assert_eq!(&out[o + 12..o + 16], b"INFO");
+                u16::from_be_bytes([out[o + 20], out[o + 21]])
+            })
+            .collect();
+        assert_eq!(widths, [30, 20, 10]);
+
+        let text = String::from_utf8_lossy(&dirm.records);
+        let p1 = text.find("p0001.djvu").unwrap();
+        assert!(text[p1..].starts_with("p0001.djvu\0width 30\0"));
+    }
+
+    #[test]
+    fn test_empty_document_is_rejected() {
+        let doc = DjvuBuilder::new(0).build();
+        assert!(doc.is_empty());
+        assert_eq!(doc.page_count(), 0);
+        match doc.write_to(Vec::new()) {
+            Err(DjvuError::InvalidOperation(msg)) => assert_eq!(msg, "no pages"),
+            other => panic!("expected 'no pages' error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_page_count_tracks_added_pages() {
+        let doc = DjvuBuilder::new(3).build();
+        assert!(doc.is_empty());
+        doc.add_page(white_page(2)).unwrap();
+        doc.add_page(white_page(0)).unwrap();
+        assert_eq!(doc.page_count(), 2);
+        assert!(!doc.is_empty());
+        doc.add_page(white_page(1)).unwrap();
+        assert_eq!(doc.page_count(), 3);
+
+        let mut out = Vec::new();
+        doc.write_to(&mut out).unwrap();
+        assert_eq!(&out[12..16], b"DJVM");
+    }
+
+    #[test]
+    fn test_add_raw_page_rejects_non_djvu() {
+        let doc = DjvuBuilder::new(1).build();
+        let err = doc.add_raw_page(0, b"AT&TFORM\0\0\0\x04DJVI".to_vec());
+        assert!(matches!(err, Err(DjvuError::InvalidArg(_))));
+        assert!(doc.add_raw_page(0, vec![0; 3]).is_err());
+        assert!(!doc.is_page_ready(0));
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_import_directory_in_file_name_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        // Written out of order, each with its own width
+        for (name, width) in [
+            ("page003.png", 30),
+            ("page001.png", 10),
+            ("page002.png", 20),
+        ] {
+            ::image::RgbImage::from_pixel(width, 16, ::image::Rgb([200, 120, 40]))
+                .save(tmp.path().join(name))
+                .unwrap();
+        }
+        fs::write(tmp.path().join("page004.png"), b"not a png").unwrap();
+        fs::write(tmp.path().join("notes.txt"), b"scanned 2024").unwrap();
+
+        let mut doc = DjvuBuilder::new(0).build();
+        let calls = Mutex::new(Vec::new());
+        let progress = |done: usize, total: usize| calls.lock().unwrap().push((done, total));
+        let options = ImportOptions {
+            on_error: OnLoadError::Skip,
+            progress: Some(&progress),
+        };
+        let skipped = doc.add_pages_from_directory(tmp.path(), &options).unwrap();
+
+        assert_eq!(skipped, vec![tmp.path().join("page004.png")]);
+        assert_eq!(doc.total_pages(), 3);
+        assert!(doc.is_complete());
+        let widths: Vec<u32> = (0..3)
+            .map(|i| doc.collection.get_metadata(i).unwrap().0)
+            .collect();
+        assert_eq!(widths, vec![10, 20, 30]);
+        // The unreadable file is not part of the total
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort();
+        assert_eq!(calls, vec![(1, 3), (2, 3), (3, 3)]);
+        assert!(doc.finalize().unwrap().starts_with(b"AT&TFORM"));
 
-        // Use internal encoder to assemble the document
-        DocumentEncoder::assemble_pages(&pages)
+        // Without skipping, the bad file stops the import before any encoding
+        let mut strict = DjvuBuilder::new(0).build();
+        let calls = Mutex::new(Vec::new());
+        let progress = |done: usize, total: usize| calls.lock().unwrap().push((done, total));
+        let options = ImportOptions {
+            on_error: OnLoadError::Abort,
+            progress: Some(&progress),
+        };
+        assert!(
+            strict
+                .add_pages_from_directory(tmp.path(), &options)
+                .is_err()
+        );
+        assert_eq!(strict.total_pages(), 0);
+        assert!(calls.into_inner().unwrap().is_empty());
     }
 }
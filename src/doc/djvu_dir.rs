@@ -1,6 +1,9 @@
+use crate::doc::page_encoder::BzzLevel;
 use crate::iff::bs_byte_stream::bzz_compress;
 use crate::iff::byte_stream::{ByteStream, MemoryStream};
 use crate::utils::error::{DjvuError, Result};
+use crate::utils::write_ext::WriteBytesExtU24;
+use byteorder::BigEndian;
 
 use std::collections::HashMap;
 use std::io::Write; // Added for write_all support
@@ -182,6 +185,16 @@ impl File {
     }
 }
 
+/// A page of a [`DjVmDir`] as listed by [`DjVmDir::get_pages`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageInfo {
+    pub id: PageId,
+    /// The page title, or its id when it has none (see [`File::get_title`])
+    pub title: String,
+    /// 0-based page number
+    pub page_num: usize,
+}
+
 /// Directory for a multipage DjVu document (DIRM chunk)
 pub struct DjVmDir {
     data: Mutex<DjVmDirData>,
@@ -195,6 +208,46 @@ pub struct DjVmDirData {
     id2file: HashMap<String, Arc<File>>,
 }
 
+impl DjVmDirData {
+    /// Swaps each file keyed by its old `Arc` for the new one in every
+    /// lookup table
+    ///
+    /// The tables share one `Arc` per file, so a file is never mutated in
+    /// place; it is copied, changed and swapped in everywhere at once.
+    fn replace_files(&mut self, replacements: &HashMap<*const File, Arc<File>>) {
+        if replacements.is_empty() {
+            return;
+        }
+        let slots = self
+            .files_list
+            .iter_mut()
+            .chain(self.page2file.iter_mut())
+            .chain(self.name2file.values_mut())
+            .chain(self.id2file.values_mut());
+        for slot in slots {
+            if let Some(new) = replacements.get(&Arc::as_ptr(slot)) {
+                *slot = Arc::clone(new);
+            }
+        }
+    }
+
+    /// Sets `page_num` of every page to its position in `page2file`
+    fn renumber_pages(&mut self) {
+        let replacements = self
+            .page2file
+            .iter()
+            .enumerate()
+            .filter(|(i, file)| file.page_num != *i as i32)
+            .map(|(i, file)| {
+                let mut renumbered = File::clone(file);
+                renumbered.page_num = i as i32;
+                (Arc::as_ptr(file), Arc::new(renumbered))
+            })
+            .collect();
+        self.replace_files(&replacements);
+    }
+}
+
 impl Clone for DjVmDir {
     fn clone(&self) -> Self {
         DjVmDir {
@@ -205,6 +258,8 @@ impl Clone for DjVmDir {
 
 impl DjVmDir {
     const VERSION: u8 = 1;
+    /// Flag bit marking a record whose title follows its id
+    const HAS_TITLE: u8 = 0x40;
 
     pub fn new() -> Arc<Self> {
         Arc::new(DjVmDir {
@@ -230,6 +285,22 @@ impl DjVmDir {
         self.data.lock().unwrap().page2file.len()
     }
 
+    /// Lists the pages in page order with their ids and titles
+    pub fn get_pages(&self) -> Vec<PageInfo> {
+        self.data
+            .lock()
+            .unwrap()
+            .page2file
+            .iter()
+            .enumerate()
+            .map(|(page_num, file)| PageInfo {
+                id: file.id.clone(),
+                title: file.get_title(),
+                page_num,
+            })
+            .collect()
+    }
+
     pub fn get_shared_anno_file(&self) -> Option<Arc<File>> {
         self.data
             .lock()
@@ -242,33 +313,29 @@ impl DjVmDir {
 
     pub fn set_file_title(&self, id: &str, title: &str) -> Result<()> {
         let mut data = self.data.lock().unwrap();
-        if let Some(file) = data.id2file.get_mut(id) {
-            Arc::get_mut(file).unwrap().set_title(title);
+        if let Some(file) = data.id2file.get(id).cloned() {
+            let mut retitled = File::clone(&file);
+            retitled.set_title(title);
+            let replacements = HashMap::from([(Arc::as_ptr(&file), Arc::new(retitled))]);
+            data.replace_files(&replacements);
             Ok(())
         } else {
-            Err(DjvuError::InvalidArg(format!("File not found: {}", id)))
+            Err(DjvuError::NotFound(format!("File {}", id)))
         }
     }
 
-    pub fn add_file(&self, file: Arc<File>) {
+    pub fn add_file(&self, mut file: Arc<File>) {
         let mut data = self.data.lock().unwrap();
-        let file_id = file.id.clone();
-        let file_name = file.name.clone();
-
-        data.files_list.push(Arc::clone(&file));
-        data.id2file.insert(file_id, Arc::clone(&file));
-        data.name2file.insert(file_name, Arc::clone(&file));
-
         if file.is_page() {
-            let page_num = data.page2file.len() as i32;
-            // Safely get the last file and set its page number
-            if let Some(last_file) = data.files_list.last_mut() {
-                if let Some(file_mut) = Arc::get_mut(last_file) {
-                    file_mut.page_num = page_num;
-                }
-            }
-            data.page2file.push(file);
+            // Number the page before the tables share it; copies the file
+            // if the caller still holds it
+            Arc::make_mut(&mut file).page_num = data.page2file.len() as i32;
+            data.page2file.push(Arc::clone(&file));
         }
+
+        data.files_list.push(Arc::clone(&file));
+        data.id2file.insert(file.id.clone(), Arc::clone(&file));
+        data.name2file.insert(file.name.clone(), file);
     }
 
     pub fn remove_file(&self, id: &str) -> Option<Arc<File>> {
@@ -278,10 +345,7 @@ impl DjVmDir {
             data.files_list.retain(|f| f.id != id);
             if file.is_page() {
                 data.page2file.retain(|f| f.id != id);
-                // Re-number pages
-                for (i, page_file) in data.page2file.iter_mut().enumerate() {
-                    Arc::get_mut(page_file).unwrap().page_num = i as i32;
-                }
+                data.renumber_pages();
             }
             Some(file)
         } else {
@@ -296,12 +360,12 @@ impl DjVmDir {
             .files_list
             .iter()
             .position(|f| f.id == id)
-            .ok_or_else(|| DjvuError::Stream(format!("File not found: {}", id)))?;
+            .ok_or_else(|| DjvuError::NotFound(format!("File {}", id)))?;
         let file = data.files_list.remove(file_idx);
 
         if !file.is_page() {
             data.files_list.insert(file_idx, file); // Put it back if not a page
-            return Err(DjvuError::Stream(format!(
+            return Err(DjvuError::InvalidArg(format!(
                 "File with ID {} is not a page and cannot be moved in page list.",
                 id
             )));
@@ -318,11 +382,6 @@ impl DjVmDir {
         let new_pos = new_pos.min(data.page2file.len());
         data.page2file.insert(new_pos, Arc::clone(&file));
 
-        // Update page_num for all affected pages
-        for i in 0..data.page2file.len() {
-            Arc::get_mut(&mut data.page2file[i]).unwrap().page_num = i as i32;
-        }
-
         // Re-insert into files_list at an appropriate position (e.g., after other pages)
         // This part might need more sophisticated logic depending on how files_list is used.
         // For now, let's just re-insert it at the end of the page section.
@@ -332,6 +391,7 @@ impl DjVmDir {
             .rposition(|f| f.is_page())
             .map_or(0, |idx| idx + 1);
         data.files_list.insert(last_page_idx, file);
+        data.renumber_pages();
 
         Ok(())
     }
@@ -341,6 +401,17 @@ impl DjVmDir {
         stream: &mut dyn ByteStream,
         bundled: bool,
         _do_rename: bool,
+    ) -> Result<()> {
+        self.encode_with_block_size(stream, bundled, BzzLevel::default().dirm)
+    }
+
+    /// Like [`encode_explicit`](Self::encode_explicit), compressing the
+    /// records with a `block_size_k` KB BZZ block
+    pub fn encode_with_block_size(
+        &self,
+        stream: &mut dyn ByteStream,
+        bundled: bool,
+        block_size_k: usize,
     ) -> Result<()> {
         let data = self.data.lock().unwrap();
 
@@ -360,47 +431,62 @@ impl DjVmDir {
         }
 
         // Prepare BZZ-encoded data according to DjVu spec
+        let bzz_buffer = Self::encode_records(&data.files_list)?;
+
+        // Use proper BZZ compression for the DIRM data according to DjVu spec
+        let compressed = bzz_compress(bzz_buffer.as_slice(), block_size_k)?;
+
+        stream.write_all(&compressed)?;
+
+        Ok(())
+    }
+
+    /// Builds the BZZ-compressed part of DIRM: sizes, flags, then each file's
+    /// zero-terminated id followed by its title when it has one.
+    fn encode_records(files: &[Arc<File>]) -> Result<MemoryStream> {
         let mut bzz_buffer = MemoryStream::new();
 
         // 1. Write sizes (3 bytes each, as INT24)
-        for file in &data.files_list {
-            // Write size as 3-byte big-endian integer (INT24)
-            let size = file.size;
-            ByteStream::write_u8(&mut bzz_buffer, (size >> 16) as u8)?;
-            ByteStream::write_u8(&mut bzz_buffer, (size >> 8) as u8)?;
-            ByteStream::write_u8(&mut bzz_buffer, size as u8)?;
-        }
+        let sizes: Vec<u32> = files.iter().map(|file| file.size).collect();
+        WriteBytesExtU24::write_u24_slice::<BigEndian>(&mut bzz_buffer, &sizes)?;
 
         // 2. Write flags (1 byte each)
-        for file in &data.files_list {
-            let flags = match file.file_type {
+        for file in files {
+            let mut flags = match file.file_type {
                 FileType::Page => 0x01,
                 FileType::Include => 0x00,
                 FileType::Thumbnails => 0x02,
                 FileType::SharedAnno => 0x03,
             };
+            if Self::writes_title(file) {
+                flags |= Self::HAS_TITLE;
+            }
             ByteStream::write_u8(&mut bzz_buffer, flags)?;
         }
 
-        // 3. Write zero-terminated IDs
-        for file in &data.files_list {
+        // 3. Write zero-terminated IDs, each followed by its title if flagged
+        for file in files {
             bzz_buffer.write_all(file.id.as_bytes())?;
             ByteStream::write_u8(&mut bzz_buffer, 0)?; // Null terminator
+            if Self::writes_title(file) {
+                bzz_buffer.write_all(file.title.as_bytes())?;
+                ByteStream::write_u8(&mut bzz_buffer, 0)?;
+            }
         }
 
-        // Use proper BZZ compression for the DIRM data according to DjVu spec
-        let compressed = bzz_compress(bzz_buffer.as_slice(), 50)?; // 50KB block size for small DIRM
-
-        stream.write_all(&compressed)?;
+        Ok(bzz_buffer)
+    }
 
-        Ok(())
+    /// DjVuLibre only stores a title when it is set and differs from the id
+    fn writes_title(file: &File) -> bool {
+        !file.title.is_empty() && file.title != file.id
     }
 
     pub fn encode(&self, stream: &mut dyn ByteStream, do_rename: bool) -> Result<()> {
         let data = self.data.lock().unwrap();
         let bundled = data.files_list.iter().all(|f| f.offset > 0);
         if data.files_list.iter().any(|f| (f.offset > 0) != bundled) {
-            return Err(DjvuError::Stream(
+            return Err(DjvuError::InvalidOperation(
                 "Mixed bundled and indirect records".into(),
             ));
         }
@@ -419,14 +505,15 @@ impl DjVmDir {
     }
 
     pub fn page_to_file(&self, page_num: i32) -> Result<Arc<File>> {
-        let page_id = self.page_to_id(page_num).ok_or_else(|| {
-            DjvuError::InvalidOperation(format!("Page number {} not found", page_num))
-        })?;
+        let page_id = self
+            .page_to_id(page_num)
+            .ok_or_else(|| DjvuError::NotFound(format!("Page number {}", page_num)))?;
 
         let data = self.data.lock().unwrap();
-        data.id2file.get(&page_id).cloned().ok_or_else(|| {
-            DjvuError::InvalidOperation(format!("File for page {} not found", page_num))
-        })
+        data.id2file
+            .get(&page_id)
+            .cloned()
+            .ok_or_else(|| DjvuError::NotFound(format!("File for page {}", page_num)))
     }
 
     pub fn pos_to_file(&self, fileno: i32) -> Option<(Arc<File>, Option<i32>)> {
@@ -471,14 +558,12 @@ impl DjVmDir {
             if file.is_page() {
                 if let Some(page_pos) = data.page2file.iter().position(|f| Arc::ptr_eq(f, &file)) {
                     data.page2file.remove(page_pos);
-                    for i in page_pos..data.page2file.len() {
-                        Arc::get_mut(&mut data.page2file[i]).unwrap().page_num = i as i32;
-                    }
+                    data.renumber_pages();
                 }
             }
             Ok(())
         } else {
-            Err(DjvuError::Stream(format!("File not found: {}", id)))
+            Err(DjvuError::NotFound(format!("File {}", id)))
         }
     }
 
@@ -693,7 +778,9 @@ impl DjVmDir0 {
     /// Adds a file to the directory
     pub fn add_file(&mut self, name: &str, iff_file: bool, offset: u32, size: u32) -> Result<()> {
         if name.contains('/') {
-            return Err(DjvuError::Stream("File name cannot contain slashes".into()));
+            return Err(DjvuError::InvalidArg(
+                "File name cannot contain slashes".into(),
+            ));
         }
         let file = FileRec::new(name, iff_file, offset, size);
         self.name2file.insert(name.to_string(), Arc::clone(&file));
@@ -721,6 +808,10 @@ pub struct DjVmNav {
 }
 
 impl DjVmNav {
+    /// Deepest bookmark nesting [`Self::encode`] accepts; top-level
+    /// bookmarks are at depth 1.
+    pub const MAX_DEPTH: usize = 32;
+
     /// Creates a new, empty navigation structure.
     pub fn new() -> Self {
         Self::default()
@@ -738,6 +829,27 @@ impl DjVmNav {
         count_recursive(&self.bookmarks)
     }
 
+    /// Rejects trees nested deeper than [`Self::MAX_DEPTH`].
+    ///
+    /// Bookmarks own their children, so a tree cannot contain a cycle; a
+    /// runaway depth is what would overflow the stack in the recursive
+    /// encoder. The walk itself uses an explicit stack for that reason.
+    fn check_depth(&self) -> Result<()> {
+        let mut stack: Vec<(&Bookmark, usize)> = self.bookmarks.iter().map(|b| (b, 1)).collect();
+        while let Some((bookmark, depth)) = stack.pop() {
+            if depth > Self::MAX_DEPTH {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Bookmark '{}' is nested {} deep (at most {} levels allowed)",
+                    bookmark.title,
+                    depth,
+                    Self::MAX_DEPTH
+                )));
+            }
+            stack.extend(bookmark.children.iter().map(|child| (child, depth + 1)));
+        }
+        Ok(())
+    }
+
     /// Writes a 24-bit big-endian integer
     fn write_int24<W: std::io::Write>(writer: &mut W, value: u32) -> std::io::Result<()> {
         // INT24 is 3 bytes big-endian
@@ -752,6 +864,7 @@ impl DjVmNav {
         if self.bookmarks.is_empty() {
             return Ok(());
         }
+        self.check_depth()?;
 
         // Write total bookmark count (UINT16 big-endian)
         let total = self.count_bookmarks();
@@ -795,3 +908,161 @@ impl DjVmNav {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_title_sets_flag_and_string() {
+        let files = vec![
+            File::new_with_offset(
+                "p0001.djvu",
+                "p0001.djvu",
+                "Chapter 3",
+                FileType::Page,
+                20,
+                100,
+            ),
+            File::new_with_offset("p0002.djvu", "p0002.djvu", "", FileType::Page, 120, 100),
+        ];
+        let records = DjVmDir::encode_records(&files).unwrap();
+        let bytes = records.as_slice();
+
+        // Two INT24 sizes, then one flag byte per file
+        assert_eq!(bytes[6], 0x01 | DjVmDir::HAS_TITLE);
+        assert_eq!(bytes[7], 0x01);
+        assert_eq!(&bytes[8..], b"p0001.djvu\0Chapter 3\0p0002.djvu\0");
+    }
+
+    #[test]
+    fn test_remove_and_move_pages_renumber_shared_files() {
+        let dir = DjVmDir::new();
+        for id in ["p1.djvu", "p2.djvu", "p3.djvu", "p4.djvu"] {
+            dir.add_file(File::new(id, id, "", FileType::Page));
+        }
+        let held = dir.get_file_by_id("p3.djvu").unwrap();
+        assert_eq!(held.page_num, 2);
+
+        let removed = dir.remove_file("p2.djvu").unwrap();
+        assert_eq!(removed.id, "p2.djvu");
+        dir.move_file_to_page_pos("p4.djvu", 0).unwrap();
+        dir.set_file_title("p3.djvu", "Last").unwrap();
+
+        let page_nums = |dir: &DjVmDir| -> Vec<(String, i32)> {
+            (0..dir.get_pages_num() as i32)
+                .map(|n| dir.page_to_file(n).unwrap())
+                .map(|f| (f.id.clone(), f.page_num))
+                .collect()
+        };
+        let expected =
+            [("p4.djvu", 0), ("p1.djvu", 1), ("p3.djvu", 2)].map(|(id, n)| (id.to_string(), n));
+        assert_eq!(page_nums(&dir), expected);
+        // Every table sees the same renumbered copy
+        for file in dir.get_files_list() {
+            let by_id = dir.get_file_by_id(&file.id).unwrap();
+            assert!(Arc::ptr_eq(&file, &by_id));
+        }
+        assert_eq!(dir.get_file_by_id("p3.djvu").unwrap().get_title(), "Last");
+        // Handles taken earlier keep their old state
+        assert_eq!(held.page_num, 2);
+
+        dir.delete_file("p4.djvu").unwrap();
+        let expected = [("p1.djvu", 0), ("p3.djvu", 1)].map(|(id, n)| (id.to_string(), n));
+        assert_eq!(page_nums(&dir), expected);
+    }
+
+    #[test]
+    fn test_get_pages_in_order_with_titles() {
+        let dir = DjVmDir::new();
+        let include = File::new("shared.djvi", "shared.djvi", "", FileType::Include);
+        dir.insert_file(include, -1).unwrap();
+        for (id, title) in [
+            ("cover.djvu", "Cover"),
+            ("p2.djvu", "Contents"),
+            ("p3.djvu", ""),
+        ] {
+            dir.insert_file(File::new(id, id, title, FileType::Page), -1)
+                .unwrap();
+        }
+
+        let pages = dir.get_pages();
+        let listed: Vec<(&str, &str, usize)> = pages
+            .iter()
+            .map(|p| (p.id.as_str(), p.title.as_str(), p.page_num))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("cover.djvu", "Cover", 0),
+                ("p2.djvu", "Contents", 1),
+                ("p3.djvu", "p3.djvu", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bundled_offsets_are_big_endian() {
+        let dir = DjVmDir::new();
+        let offsets = [0x0000_0010, 0x0102_0304, 0xA0B0_C0D0];
+        for (i, &offset) in offsets.iter().enumerate() {
+            let id = format!("p{i:04}.djvu");
+            let file = File::new_with_offset(&id, &id, "", FileType::Page, offset, 8);
+            dir.insert_file(file, -1).unwrap();
+        }
+        let mut stream = MemoryStream::new();
+        dir.encode_explicit(&mut stream, true, false).unwrap();
+        let bytes = stream.as_slice();
+
+        assert_eq!(bytes[0], DjVmDir::VERSION | 0x80);
+        assert_eq!(&bytes[1..3], &[0, 3]);
+        assert_eq!(&bytes[7..11], &[0x01, 0x02, 0x03, 0x04]);
+        let mut table = std::io::Cursor::new(bytes[3..3 + 4 * offsets.len()].to_vec());
+        assert_eq!(table.read_u32_slice(offsets.len()).unwrap(), offsets);
+
+        // Unbundled directories carry no offset table
+        let mut stream = MemoryStream::new();
+        dir.encode_explicit(&mut stream, false, false).unwrap();
+        let bytes = stream.as_slice();
+        assert_eq!(bytes[0], DjVmDir::VERSION);
+        let records = crate::iff::bs_byte_stream::bzz_decompress(&bytes[3..]).unwrap();
+        assert_eq!(&records[..3], &[0, 0, 8]);
+    }
+
+    fn bookmark_chain(depth: usize) -> Bookmark {
+        let mut bookmark = Bookmark {
+            title: format!("Level {depth}"),
+            dest: "#1".to_string(),
+            children: Vec::new(),
+        };
+        for level in (1..depth).rev() {
+            bookmark = Bookmark {
+                title: format!("Level {level}"),
+                dest: "#1".to_string(),
+                children: vec![bookmark],
+            };
+        }
+        bookmark
+    }
+
+    #[test]
+    fn test_nav_rejects_deep_bookmark_chain() {
+        let nav = DjVmNav {
+            bookmarks: vec![bookmark_chain(40)],
+        };
+        let mut out = Vec::new();
+        let err = nav.encode(&mut out).unwrap_err();
+        assert!(matches!(err, DjvuError::InvalidArg(ref msg) if msg.contains("Level 33")));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_nav_accepts_max_depth() {
+        let nav = DjVmNav {
+            bookmarks: vec![bookmark_chain(DjVmNav::MAX_DEPTH), bookmark_chain(3)],
+        };
+        let mut out = Vec::new();
+        nav.encode(&mut out).unwrap();
+        assert_eq!(&out[..2], &35u16.to_be_bytes());
+    }
+}
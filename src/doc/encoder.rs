@@ -3,14 +3,32 @@
 //! This module handles the low-level encoding and assembly of DjVu documents.
 //! It is used internally by the public builder API and not exposed directly.
 
-use crate::doc::djvu_dir::{DjVmDir, File as DjVuFile, FileType};
-// NAVM-related imports disabled for now - keep for future use
-// use crate::doc::djvu_dir::{Bookmark, DjVmNav};
-// use crate::iff::bs_byte_stream::bzz_compress;
-// use crate::iff::MemoryStream;
-use crate::Result;
-use byteorder::{BigEndian, WriteBytesExt};
-use std::io::Write;
+use crate::doc::builder::PageNaming;
+use crate::doc::djvu_dir::{Bookmark, DjVmDir, DjVmNav, File as DjVuFile, FileType};
+use crate::doc::page_encoder::BzzLevel;
+use crate::iff::bs_byte_stream::{bzz_compress, bzz_decompress};
+use crate::iff::byte_stream::{ByteStream, CountingStream, MemoryStream};
+use crate::iff::iff::{IffWriter, IffWriterExt};
+use crate::{DjvuError, Result};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Write};
+
+/// A named component file: its id and `FORM` bytes
+pub(crate) type Component = (String, Vec<u8>);
+
+/// A chunk inside a `FORM`: its id and data, without header or padding
+pub(crate) type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// One component of a bundled document as listed in DIRM
+pub(crate) struct DjvmEntry<'a> {
+    pub id: String,
+    pub file_type: FileType,
+    /// Size without the `AT&T` magic
+    pub size: usize,
+    pub title: &'a str,
+}
 
 /// Internal document encoder
 ///
@@ -18,186 +36,508 @@ use std::io::Write;
 pub(crate) struct DocumentEncoder;
 
 impl DocumentEncoder {
+    /// Include id of the shared annotation component, listed in DIRM with
+    /// the shared-annotation type (the name DjVuLibre gives it)
+    pub const SHARED_ANNO_ID: &'static str = "shared_anno.iff";
+
+    /// Include id of the `FORM:THUM` component holding a TH44 thumbnail
+    /// per page, listed in DIRM with the thumbnails type. Viewers match its
+    /// thumbnails to the pages that follow it, so it must stay ahead of
+    /// the first page.
+    pub const THUMBNAILS_ID: &'static str = "thumbnails.thumb";
+
     /// Assembles encoded pages into a complete DjVu document
     ///
+    /// `includes` are `(id, FORM:DJVI)` components referenced from pages via
+    /// INCL chunks; they are bundled ahead of the pages. `titles` holds an
+    /// optional DIRM title per page (missing entries mean no title).
+    /// `naming` gives the component ids of the pages, and non-empty
+    /// `bookmarks` are written to a NAVM chunk. `bzz` sets the block sizes
+    /// used to compress DIRM and NAVM.
+    ///
     /// Returns the complete document as bytes (single-page DJVU or multi-page DJVM)
-    pub fn assemble_pages(pages: &[Vec<u8>]) -> Result<Vec<u8>> {
+    pub fn assemble_pages(
+        pages: &[Vec<u8>],
+        includes: &[(String, Vec<u8>)],
+        titles: &[Option<String>],
+        naming: &PageNaming,
+        bookmarks: &[Bookmark],
+        bzz: BzzLevel,
+    ) -> Result<Vec<u8>> {
         let mut output = Vec::new();
 
         if pages.is_empty() {
-            return Ok(output);
+            return Err(DjvuError::InvalidOperation("no pages".to_string()));
         }
 
-        if pages.len() == 1 {
+        let has_titles = titles.iter().any(Option::is_some);
+        if pages.len() == 1 && includes.is_empty() && !has_titles && bookmarks.is_empty() {
             // Single-page document: write directly
             output.write_all(&pages[0])?;
             return Ok(output);
         }
 
         // Multi-page document: create DJVM
-        Self::assemble_djvm(&mut output, pages)?;
+        Self::assemble_djvm(&mut output, pages, includes, titles, naming, bookmarks, bzz)?;
         Ok(output)
     }
 
-    /// Assembles a multi-page DJVM document
-    fn assemble_djvm(writer: &mut Vec<u8>, pages: &[Vec<u8>]) -> Result<()> {
-        // Build cheap slice references, stripping the AT&T prefix where present.
-        // No cloning — just pointer + length.
-        let page_chunks: Vec<&[u8]> = pages
+    /// Assembles an indirect document: an index holding the directory (and
+    /// NAVM when there are bookmarks) plus one standalone file per component
+    ///
+    /// Returns the index bytes and `(file name, bytes)` for every include
+    /// and page, in DIRM order. Component files carry the `AT&T` magic, and
+    /// DIRM stores no offsets since each component lives in its own file.
+    pub fn assemble_indirect(
+        pages: &[&[u8]],
+        includes: &[(String, Vec<u8>)],
+        titles: &[Option<String>],
+        naming: &PageNaming,
+        bookmarks: &[Bookmark],
+        bzz: BzzLevel,
+    ) -> Result<(Vec<u8>, Vec<Component>)> {
+        if pages.is_empty() {
+            return Err(DjvuError::InvalidOperation("no pages".to_string()));
+        }
+        bzz.validate()?;
+
+        let dirm = DjVmDir::new();
+        let bodies: Vec<&[u8]> = includes
             .iter()
-            .map(|p| {
-                if p.starts_with(b"AT&TFORM") {
-                    &p[4..] // Slice — zero allocation
-                } else {
-                    p.as_slice()
-                }
-            })
+            .map(|(_, data)| Self::strip_magic(data))
+            .chain(pages.iter().map(|p| Self::strip_magic(p)))
             .collect();
+        let sizes: Vec<usize> = bodies.iter().map(|b| b.len()).collect();
+        let entries = Self::djvm_entries(&sizes, includes, pages.len(), titles, naming);
+        let mut files = Vec::with_capacity(entries.len());
+        for (entry, body) in entries.into_iter().zip(bodies) {
+            let file = DjVuFile::new_with_offset(
+                &entry.id,
+                &entry.id,
+                entry.title,
+                entry.file_type,
+                0,
+                entry.size as u32,
+            );
+            dirm.insert_file(file, -1)?;
+            let mut bytes = Vec::with_capacity(4 + body.len());
+            bytes.extend_from_slice(b"AT&T");
+            bytes.extend_from_slice(body);
+            files.push((entry.id, bytes));
+        }
 
-        // NAVM feature disabled for now - keep code for future use
-        // Create automatic navigation bookmarks for multi-page documents
-        // let navigation = Self::create_default_navigation(pages.len())?;
-        // let mut nav_stream = MemoryStream::new();
-        // navigation.encode(&mut nav_stream)?;
-        // let nav_raw = nav_stream.into_vec();
-        // BZZ-compress the navigation data as required by DjVu spec
-        // let nav_data = bzz_compress(&nav_raw, 100)
-        //     .map_err(|e| crate::DjvuError::EncodingError(format!("BZZ compress NAVM failed: {e}")))?;
-        // let nav_chunk_size = 8 + nav_data.len() + (nav_data.len() % 2);
-        let nav_chunk_size = 0; // NAVM disabled
-
-        // Create directory and calculate offsets
-        let dirm = DjVmDir::new();
+        let mut dirm_stream = crate::iff::MemoryStream::new();
+        dirm.encode_with_block_size(&mut dirm_stream, false, bzz.dirm)?;
+        let dirm_data = dirm_stream.into_vec();
 
-        // Estimate DIRM size conservatively
-        let estimated_dirm_size = 3 + (4 * page_chunks.len()) + 80;
-        let dirm_chunk_size = 8 + estimated_dirm_size + (estimated_dirm_size % 2);
+        let mut index = Vec::new();
+        let mut iff = IffWriter::new(Cursor::new(&mut index));
+        iff.write_magic_bytes()?;
+        iff.put_chunk("FORM:DJVM")?;
+        iff.write_chunk(*b"DIRM", &dirm_data)?;
+        if !bookmarks.is_empty() {
+            let navigation = DjVmNav {
+                bookmarks: bookmarks.to_vec(),
+            };
+            let mut nav_raw = Vec::new();
+            navigation.encode(&mut nav_raw)?;
+            iff.write_chunk(*b"NAVM", &bzz_compress(&nav_raw, bzz.navm)?)?;
+        }
+        iff.close_chunk()?;
+        drop(iff);
 
-        // Calculate initial page offsets (after DIRM + NAVM chunks)
-        // Offsets in DIRM are ABSOLUTE file positions (confirmed by analyzing working files).
-        // The base is AT&T(4) + FORM(4) + size(4) + DJVM(4) = 16 bytes.
-        let base_offset = 16u32;
-        let mut current_offset = base_offset + dirm_chunk_size as u32 + nav_chunk_size as u32;
-        let mut file_offsets = Vec::new();
+        Ok((index, files))
+    }
 
-        for (i, page_chunk) in page_chunks.iter().enumerate() {
-            if current_offset % 2 != 0 {
-                current_offset += 1;
+    /// Replaces byte-identical pages with a shared include
+    ///
+    /// Pages are grouped by a hash of their bytes (confirmed by comparison).
+    /// For every group of two or more, everything except the INFO chunk moves
+    /// into one new `FORM:DJVI` component appended to `includes`, and each
+    /// duplicate becomes `INFO` + `INCL`. Page order, and so DIRM page
+    /// numbering, is unchanged. Groups too small to save space are left alone.
+    pub fn share_duplicate_pages(
+        pages: &mut [Vec<u8>],
+        includes: &mut Vec<(String, Vec<u8>)>,
+    ) -> Result<()> {
+        let mut groups: HashMap<u64, Vec<Vec<usize>>> = HashMap::new();
+        for (i, page) in pages.iter().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            page.hash(&mut hasher);
+            let buckets = groups.entry(hasher.finish()).or_default();
+            match buckets.iter_mut().find(|g| pages[g[0]] == *page) {
+                Some(group) => group.push(i),
+                None => buckets.push(vec![i]),
             }
+        }
 
-            file_offsets.push(current_offset);
-            current_offset += page_chunk.len() as u32;
+        let mut duplicates: Vec<Vec<usize>> = groups
+            .into_values()
+            .flatten()
+            .filter(|g| g.len() > 1)
+            .collect();
+        duplicates.sort_by_key(|g| g[0]);
 
-            let page_id = format!("p{:04}.djvu", i + 1);
-            let file = DjVuFile::new_with_offset(
-                &page_id,
-                &page_id,
-                "",
-                FileType::Page,
-                file_offsets[i],
-                page_chunk.len() as u32,
-            );
-            dirm.insert_file(file, -1)?;
+        let mut next_id = 1;
+        for group in duplicates {
+            let Some((info, shared)) = Self::split_info(&pages[group[0]]) else {
+                continue;
+            };
+
+            let id = loop {
+                let candidate = format!("dup{:04}.djvi", next_id);
+                next_id += 1;
+                if !includes.iter().any(|(i, _)| *i == candidate) {
+                    break candidate;
+                }
+            };
+
+            // Each duplicate gains an INCL chunk and the include needs its own
+            // FORM header and DIRM entry; skip groups where that outweighs the
+            // bytes saved.
+            let incl_chunk = 8 + id.len() + id.len() % 2;
+            let overhead = group.len() * incl_chunk + 16 + 9 + id.len();
+            if (group.len() - 1) * shared.len() <= overhead {
+                continue;
+            }
+
+            let mut djvi = Vec::with_capacity(16 + shared.len());
+            let mut iff = IffWriter::new(Cursor::new(&mut djvi));
+            iff.write_magic_bytes()?;
+            iff.put_chunk("FORM:DJVI")?;
+            iff.write_all(&shared)?;
+            iff.close_chunk()?;
+            drop(iff);
+
+            let mut page = Vec::with_capacity(16 + info.len() + incl_chunk);
+            let mut iff = IffWriter::new(Cursor::new(&mut page));
+            iff.write_magic_bytes()?;
+            iff.put_chunk("FORM:DJVU")?;
+            iff.write_all(&info)?;
+            iff.write_chunk(*b"INCL", id.as_bytes())?;
+            iff.close_chunk()?;
+            drop(iff);
+
+            for &i in &group {
+                pages[i] = page.clone();
+            }
+            includes.push((id, djvi));
         }
+        Ok(())
+    }
 
-        // Encode DIRM to get actual size
-        let mut dirm_stream = crate::iff::MemoryStream::new();
-        dirm.encode_explicit(&mut dirm_stream, true, true)?;
-        let dirm_data = dirm_stream.into_vec();
+    /// Ids named by the top-level INCL chunks of a `FORM:DJVU` or
+    /// `FORM:DJVI` component, in file order. Returns `None` for blobs that
+    /// don't parse.
+    pub fn include_ids(component: &[u8]) -> Option<Vec<String>> {
+        let (_, chunks) = Self::form_chunks(component)?;
+        chunks
+            .into_iter()
+            .filter(|(id, _)| id == b"INCL")
+            .map(|(_, data)| std::str::from_utf8(data).ok().map(str::to_string))
+            .collect()
+    }
 
-        // Check if estimate was accurate enough
-        let actual_dirm_chunk_size = 8 + dirm_data.len() + (dirm_data.len() % 2);
-        let final_dirm_data;
+    /// Rewrites the INCL chunks of a `FORM:DJVU` page whose ids appear in
+    /// `renames`, leaving every other chunk untouched. Returns `None` for
+    /// blobs that don't parse.
+    pub fn rename_includes(page: &[u8], renames: &HashMap<String, String>) -> Option<Vec<u8>> {
+        let (form, chunks) = Self::form_chunks(page)?;
+        if form != *b"DJVU" {
+            return None;
+        }
 
-        if (actual_dirm_chunk_size as i32 - dirm_chunk_size as i32).abs() > 16 {
-            // Re-calculate with correct DIRM size
-            let corrected_dirm = DjVmDir::new();
-            current_offset = base_offset + actual_dirm_chunk_size as u32 + nav_chunk_size as u32;
-            let mut corrected_offsets = Vec::new();
+        let mut out = Vec::with_capacity(page.len());
+        let mut iff = IffWriter::new(Cursor::new(&mut out));
+        iff.write_magic_bytes().ok()?;
+        iff.put_chunk("FORM:DJVU").ok()?;
+        for (id, data) in chunks {
+            let renamed = if id == *b"INCL" {
+                std::str::from_utf8(data)
+                    .ok()
+                    .and_then(|old| renames.get(old))
+            } else {
+                None
+            };
+            match renamed {
+                Some(new_id) => iff.write_chunk(*b"INCL", new_id.as_bytes()).ok()?,
+                None => iff.write_chunk(id, data).ok()?,
+            }
+        }
+        iff.close_chunk().ok()?;
+        drop(iff);
+        Some(out)
+    }
 
-            for (i, page_chunk) in page_chunks.iter().enumerate() {
-                if current_offset % 2 != 0 {
-                    current_offset += 1;
-                }
+    /// Splits a `FORM:DJVU` page into its INFO chunk and all other chunks,
+    /// each with padding. Returns `None` for blobs that don't parse.
+    fn split_info(page: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (form, chunks) = Self::form_chunks(page)?;
+        if form != *b"DJVU" {
+            return None;
+        }
 
-                corrected_offsets.push(current_offset);
-                current_offset += page_chunk.len() as u32;
+        let mut info = Vec::new();
+        let mut rest = Vec::new();
+        for (id, data) in chunks {
+            let target = if id == *b"INFO" { &mut info } else { &mut rest };
+            target.extend_from_slice(&id);
+            target.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            target.extend_from_slice(data);
+            if !data.len().is_multiple_of(2) {
+                target.push(0);
+            }
+        }
 
-                let page_id = format!("p{:04}.djvu", i + 1);
-                let file = DjVuFile::new_with_offset(
-                    &page_id,
-                    &page_id,
-                    "",
-                    FileType::Page,
-                    corrected_offsets[i],
-                    page_chunk.len() as u32,
-                );
-                corrected_dirm.insert_file(file, -1)?;
+        (!info.is_empty() && !rest.is_empty()).then_some((info, rest))
+    }
+
+    /// Adds the annotation text `text` to a `FORM:DJVI` component, or to an
+    /// empty one when `djvi` is `None`
+    ///
+    /// The component's existing annotation chunks are folded into a single
+    /// `ANTz` compressed with `level`, their text first; other chunks are
+    /// kept in order ahead of it.
+    pub fn append_annotations(djvi: Option<&[u8]>, text: &[u8], level: usize) -> Result<Vec<u8>> {
+        let malformed = || DjvuError::InvalidArg("Malformed shared annotation component".into());
+        let chunks = match djvi {
+            Some(djvi) => match Self::form_chunks(djvi) {
+                Some((form, chunks)) if form == *b"DJVI" => chunks,
+                _ => return Err(malformed()),
+            },
+            None => Vec::new(),
+        };
+
+        let mut annotations = Vec::new();
+        let mut out = Vec::new();
+        let mut iff = IffWriter::new(Cursor::new(&mut out));
+        iff.write_magic_bytes()?;
+        iff.put_chunk("FORM:DJVI")?;
+        for (id, data) in chunks {
+            match &id {
+                b"ANTa" => annotations.extend_from_slice(data),
+                b"ANTz" => annotations.extend(bzz_decompress(data)?),
+                _ => iff.write_chunk(id, data)?,
             }
+        }
+        annotations.extend_from_slice(text);
+        iff.write_chunk(*b"ANTz", &bzz_compress(&annotations, level)?)?;
+        iff.close_chunk()?;
+        drop(iff);
+        Ok(out)
+    }
 
-            // Re-encode with corrected offsets
-            let mut corrected_stream = crate::iff::MemoryStream::new();
-            corrected_dirm.encode_explicit(&mut corrected_stream, true, true)?;
-            final_dirm_data = corrected_stream.into_vec();
-        } else {
-            final_dirm_data = dirm_data;
+    /// Form type and top-level chunks, as `(id, data)` without headers or
+    /// padding, of a `FORM` component with or without the `AT&T` magic.
+    /// Returns `None` for blobs that don't parse.
+    pub fn form_chunks(component: &[u8]) -> Option<([u8; 4], Vec<Chunk<'_>>)> {
+        let body = Self::strip_magic(component);
+        if body.len() < 12 || &body[0..4] != b"FORM" {
+            return None;
         }
+        let form_end = 8 + u32::from_be_bytes(body[4..8].try_into().ok()?) as usize;
+        let form = body[8..12].try_into().ok()?;
+        let chunks = body.get(12..form_end)?;
+
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= chunks.len() {
+            let len = u32::from_be_bytes(chunks[pos + 4..pos + 8].try_into().ok()?) as usize;
+            let end = pos + 8 + len;
+            let chunk = chunks.get(pos..end)?;
+            out.push((chunk[0..4].try_into().ok()?, &chunk[8..]));
+            pos = end + len % 2;
+        }
+        Some((form, out))
+    }
+
+    /// Assembles a multi-page DJVM document
+    fn assemble_djvm(
+        writer: &mut Vec<u8>,
+        pages: &[Vec<u8>],
+        includes: &[(String, Vec<u8>)],
+        titles: &[Option<String>],
+        naming: &PageNaming,
+        bookmarks: &[Bookmark],
+        bzz: BzzLevel,
+    ) -> Result<()> {
+        // Build cheap slice references, stripping the AT&T prefix where present.
+        // No cloning — just pointer + length.
+        let components: Vec<&[u8]> = includes
+            .iter()
+            .map(|(_, data)| Self::strip_magic(data))
+            .chain(pages.iter().map(|p| Self::strip_magic(p)))
+            .collect();
+        let sizes: Vec<usize> = components.iter().map(|c| c.len()).collect();
+        let entries = Self::djvm_entries(&sizes, includes, pages.len(), titles, naming);
+        Self::write_djvm(writer, &entries, bookmarks, bzz, |i| Ok(components[i]))
+    }
+
+    /// Lists the DIRM entries of a bundled document: `includes` first, then
+    /// `page_count` pages. `sizes` holds each component's size without the
+    /// `AT&T` magic, in the same order.
+    pub fn djvm_entries<'a>(
+        sizes: &[usize],
+        includes: &[(String, Vec<u8>)],
+        page_count: usize,
+        titles: &'a [Option<String>],
+        naming: &PageNaming,
+    ) -> Vec<DjvmEntry<'a>> {
+        let ids = includes
+            .iter()
+            .map(|(id, _)| {
+                let file_type = match id.as_str() {
+                    Self::SHARED_ANNO_ID => FileType::SharedAnno,
+                    Self::THUMBNAILS_ID => FileType::Thumbnails,
+                    _ => FileType::Include,
+                };
+                (id.clone(), file_type, "")
+            })
+            .chain((0..page_count).map(|i| {
+                (
+                    naming.file_name(i, page_count),
+                    FileType::Page,
+                    titles.get(i).and_then(|t| t.as_deref()).unwrap_or(""),
+                )
+            }));
+        ids.zip(sizes)
+            .map(|((id, file_type, title), &size)| DjvmEntry {
+                id,
+                file_type,
+                size,
+                title,
+            })
+            .collect()
+    }
 
-        // Calculate total size
-        let total_dirm_chunk_size = 8 + final_dirm_data.len() + (final_dirm_data.len() % 2);
-        let pages_total_size: usize = page_chunks.iter().map(|p| p.len()).sum();
+    /// Writes a bundled DJVM document to `writer`, fetching component `i`
+    /// from `component` only when it is due so that callers can stream
+    /// components from disk.
+    ///
+    /// The layout is computed from the entry sizes up front; each component
+    /// must match its declared size once its `AT&T` magic is stripped.
+    pub fn write_djvm<W, C, F>(
+        mut writer: W,
+        entries: &[DjvmEntry],
+        bookmarks: &[Bookmark],
+        bzz: BzzLevel,
+        mut component: F,
+    ) -> Result<()>
+    where
+        W: Write,
+        C: AsRef<[u8]>,
+        F: FnMut(usize) -> Result<C>,
+    {
+        let header = Self::djvm_header(entries, bookmarks, bzz)?;
+        writer.write_all(&header)?;
 
-        // Calculate padding
-        let mut padding_bytes = 0;
-        let mut pos = base_offset as usize + total_dirm_chunk_size + nav_chunk_size;
-        for page_chunk in &page_chunks {
+        // Components are complete FORMs, so only an odd-sized one needs a
+        // pad byte before the next one starts (or at the very end)
+        let mut pos = header.len();
+        for (i, entry) in entries.iter().enumerate() {
+            let data = component(i)?;
+            let data = Self::strip_magic(data.as_ref());
+            if data.len() != entry.size {
+                return Err(DjvuError::InvalidOperation(format!(
+                    "Component '{}' is {} bytes, expected {}",
+                    entry.id,
+                    data.len(),
+                    entry.size
+                )));
+            }
             if pos % 2 != 0 {
-                padding_bytes += 1;
+                writer.write_all(&[0])?;
                 pos += 1;
             }
-            pos += page_chunk.len();
-        }
-
-        let total_djvm_payload =
-            total_dirm_chunk_size + nav_chunk_size + pages_total_size + padding_bytes;
-
-        // Write DJVM header
-        writer.write_all(b"AT&TFORM")?;
-        writer.write_u32::<BigEndian>((4 + total_djvm_payload) as u32)?;
-        writer.write_all(b"DJVM")?;
-
-        // Write DIRM chunk
-        writer.write_all(b"DIRM")?;
-        writer.write_u32::<BigEndian>(final_dirm_data.len() as u32)?;
-        writer.write_all(&final_dirm_data)?;
-        if final_dirm_data.len() % 2 != 0 {
-            writer.write_u8(0)?; // padding
-        }
-
-        // NAVM chunk disabled - keep code for future use
-        // Write NAVM chunk (automatic navigation bookmarks)
-        // if !nav_data.is_empty() {
-        //     writer.write_all(b"NAVM")?;
-        //     writer.write_u32::<BigEndian>(nav_data.len() as u32)?;
-        //     writer.write_all(&nav_data)?;
-        //     if nav_data.len() % 2 != 0 {
-        //         writer.write_u8(0)?; // padding
-        //     }
-        // }
-
-        // Write page chunks with alignment
-        let mut written_pos = base_offset as usize + total_dirm_chunk_size + nav_chunk_size;
-        for page_data in &page_chunks {
-            if written_pos % 2 != 0 {
-                writer.write_u8(0)?;
-                written_pos += 1;
+            writer.write_all(data)?;
+            pos += data.len();
+        }
+        if pos % 2 != 0 {
+            writer.write_all(&[0])?;
+        }
+        Ok(())
+    }
+
+    /// Everything of a bundled DJVM ahead of its first component: the magic,
+    /// the FORM header sized for all of `entries`, DIRM with each
+    /// component's absolute offset, and NAVM when there are bookmarks
+    pub fn djvm_header(
+        entries: &[DjvmEntry],
+        bookmarks: &[Bookmark],
+        bzz: BzzLevel,
+    ) -> Result<Vec<u8>> {
+        bzz.validate()?;
+        // NAVM: BZZ-compressed bookmarks, written right after DIRM
+        let nav_data = if bookmarks.is_empty() {
+            Vec::new()
+        } else {
+            let navigation = DjVmNav {
+                bookmarks: bookmarks.to_vec(),
+            };
+            let mut nav_raw = Vec::new();
+            navigation.encode(&mut nav_raw)?;
+            bzz_compress(&nav_raw, bzz.navm)?
+        };
+        let nav_chunk_size = if nav_data.is_empty() {
+            0
+        } else {
+            8 + nav_data.len().next_multiple_of(2)
+        };
+
+        // Offsets in DIRM are ABSOLUTE file positions (confirmed by analyzing
+        // working files). The base is AT&T(4) + FORM(4) + size(4) + DJVM(4).
+        // The DIRM size doesn't depend on the offset values (fixed-width
+        // fields), so encoding once with placeholder offsets gives the real
+        // size and the second encoding is exact. The first pass only counts
+        // bytes, so nothing is buffered for it.
+        let base_offset = 16;
+        let encode_dirm = |dirm_chunk_size: usize, stream: &mut dyn ByteStream| -> Result<usize> {
+            let dirm = DjVmDir::new();
+            let mut offset = base_offset + dirm_chunk_size + nav_chunk_size;
+            for entry in entries {
+                offset += offset % 2;
+                let file = DjVuFile::new_with_offset(
+                    &entry.id,
+                    &entry.id,
+                    entry.title,
+                    entry.file_type,
+                    offset as u32,
+                    entry.size as u32,
+                );
+                dirm.insert_file(file, -1)?;
+                offset += entry.size;
             }
+            dirm.encode_with_block_size(stream, true, bzz.dirm)?;
+            Ok(offset)
+        };
+        let mut counter = CountingStream::new();
+        encode_dirm(0, &mut counter)?;
+        let dirm_chunk_size = 8 + counter.count().next_multiple_of(2);
+        let mut dirm_stream = MemoryStream::new();
+        let end = encode_dirm(dirm_chunk_size, &mut dirm_stream)?;
+        let dirm_data = dirm_stream.into_vec();
 
-            writer.write_all(page_data)?;
-            written_pos += page_data.len();
+        // The FORM size covers the components the caller appends; DIRM and
+        // NAVM are complete chunks, padded by the writer
+        let mut header = Vec::with_capacity(base_offset + dirm_chunk_size + nav_chunk_size);
+        let mut iff = IffWriter::new(Cursor::new(&mut header));
+        iff.write_magic_bytes()?;
+        iff.put_chunk_sized("FORM:DJVM", (end - 12) as u64)?;
+        iff.write_chunk(*b"DIRM", &dirm_data)?;
+        if !nav_data.is_empty() {
+            iff.write_chunk(*b"NAVM", &nav_data)?;
         }
+        drop(iff);
+        Ok(header)
+    }
 
-        Ok(())
+    /// Returns the component without its leading `AT&T` magic, if present.
+    fn strip_magic(data: &[u8]) -> &[u8] {
+        if data.starts_with(b"AT&TFORM") {
+            &data[4..] // Slice — zero allocation
+        } else {
+            data
+        }
     }
 
     // NAVM feature disabled - keep code for future use
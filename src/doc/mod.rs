@@ -8,11 +8,20 @@ pub mod builder;
 
 // Private encoder implementation
 pub(crate) mod encoder;
+pub(crate) mod thumbnails;
 
 // Re-export public builder API
-pub use builder::{DjvuBuilder, DjvuDocument, ImageLayer, LayerData, Page, PageBuilder};
+pub use builder::{
+    DjvuBuilder, DjvuDocument, DocumentMetadata, ImageLayer, LayerData, Page, PageBuilder,
+    PageNaming,
+};
+#[cfg(feature = "image")]
+pub use builder::{ImportOptions, OnLoadError};
 
 // Re-export types needed by the builder
-pub use djvu_dir::{Bookmark, DjVmDir, DjVmNav, File as DjVuFile, FileType};
+pub use djvu_dir::{Bookmark, DjVmDir, DjVmNav, File as DjVuFile, FileType, PageInfo};
 pub use page_collection::{DocumentStatus, PageCollection};
-pub use page_encoder::{EncodedPage, PageComponents, PageEncodeParams, PageLayer, Rect};
+pub use page_encoder::{
+    BzzLevel, ColorMode, EncodeDiagnostic, EncodedPage, Jb2Coding, MaskCodec, MaskMorphology,
+    PageComponents, PageEncodeParams, PageLayer, Rect,
+};
@@ -2,21 +2,98 @@ use crate::doc::djvu_dir::DjVmNav;
 use crate::doc::page_encoder::{EncodedPage, PageComponents, PageEncodeParams};
 use crate::{DjvuError, Result};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 enum PageSlot {
     Pending,
     Ready(Arc<Vec<u8>>),
+    /// Moved to the spill file to keep memory under the limit
+    Spilled {
+        offset: u64,
+        len: usize,
+    },
+}
+
+impl PageSlot {
+    fn is_ready(&self) -> bool {
+        !matches!(self, PageSlot::Pending)
+    }
+}
+
+/// Temporary file holding the pages that did not fit the memory limit.
+/// Removed when dropped.
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    end: u64,
+}
+
+impl SpillFile {
+    fn create() -> io::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            ".djvu_encoder-{}-{}.spill",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self { path, file, end: 0 })
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<u64> {
+        let offset = self.end;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.end += data.len() as u64;
+        Ok(offset)
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Bytes and pages currently held in memory, against the optional limit
+#[derive(Default)]
+struct MemoryBudget {
+    limit: Option<usize>,
+    bytes: usize,
+    pages: usize,
+    peak_pages: usize,
 }
 
 /// Thread-safe, out-of-order page collection with per-slot locking.
 ///
 /// Each page slot has its own `RwLock`, so concurrent insertions to different
 /// pages never contend with each other.
+///
+/// With a memory limit, pages that would push the encoded bytes held in
+/// memory past it are written to a temporary spill file instead and read
+/// back when the document is assembled.
 pub struct PageCollection {
     slots: Vec<RwLock<PageSlot>>,
     metadata: Vec<RwLock<Option<PageMetadata>>>,
     total_pages: usize,
+    budget: Mutex<MemoryBudget>,
+    spill: Mutex<Option<SpillFile>>,
 }
 
 #[derive(Clone)]
@@ -24,6 +101,7 @@ pub struct PageMetadata {
     width: u32,
     height: u32,
     id: Option<String>,
+    title: Option<String>,
 }
 
 impl PageCollection {
@@ -38,9 +116,76 @@ impl PageCollection {
             slots,
             metadata,
             total_pages,
+            budget: Mutex::new(MemoryBudget::default()),
+            spill: Mutex::new(None),
         }
     }
 
+    /// Caps the encoded bytes kept in memory; later pages beyond it go to
+    /// a spill file.
+    pub fn set_memory_limit(&mut self, limit_bytes: usize) {
+        self.budget.get_mut().unwrap().limit = Some(limit_bytes);
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.budget.lock().unwrap().limit
+    }
+
+    /// Most pages held in memory at once so far
+    pub fn peak_buffered_pages(&self) -> usize {
+        self.budget.lock().unwrap().peak_pages
+    }
+
+    /// Counts `len` bytes against the limit, or returns false when they
+    /// don't fit and the page should be spilled.
+    fn reserve(&self, len: usize) -> bool {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.limit.is_some_and(|limit| budget.bytes + len > limit) {
+            return false;
+        }
+        budget.bytes += len;
+        budget.pages += 1;
+        budget.peak_pages = budget.peak_pages.max(budget.pages);
+        true
+    }
+
+    fn release(&self, len: usize) {
+        let mut budget = self.budget.lock().unwrap();
+        budget.bytes -= len;
+        budget.pages -= 1;
+    }
+
+    /// Stores `data` in memory if the budget allows, else in the spill file
+    fn store(&self, data: Arc<Vec<u8>>) -> Result<PageSlot> {
+        if self.reserve(data.len()) {
+            return Ok(PageSlot::Ready(data));
+        }
+        let mut spill = self.spill.lock().unwrap();
+        let file = match &mut *spill {
+            Some(file) => file,
+            None => spill.insert(SpillFile::create()?),
+        };
+        let offset = file.append(&data)?;
+        Ok(PageSlot::Spilled {
+            offset,
+            len: data.len(),
+        })
+    }
+
+    fn load(&self, slot: &PageSlot) -> Result<Option<Arc<Vec<u8>>>> {
+        Ok(match slot {
+            PageSlot::Pending => None,
+            PageSlot::Ready(data) => Some(Arc::clone(data)),
+            PageSlot::Spilled { offset, len } => {
+                let mut spill = self.spill.lock().unwrap();
+                let file = spill.as_mut().ok_or_else(|| {
+                    DjvuError::InvalidOperation("Spilled page without a spill file".to_string())
+                })?;
+                Some(Arc::new(file.read(*offset, *len)?))
+            }
+        })
+    }
+
     pub fn len(&self) -> usize {
         self.total_pages
     }
@@ -49,23 +194,33 @@ impl PageCollection {
         self.total_pages == 0
     }
 
-    pub fn insert_page(&self, page_num: usize, page: EncodedPage) -> Result<()> {
+    /// Rejects page numbers outside `0..total_pages`
+    fn check_page_num(&self, page_num: usize) -> Result<()> {
         if page_num >= self.total_pages {
-            return Err(DjvuError::InvalidOperation(format!(
-                "Page number {} exceeds total pages {}",
+            return Err(DjvuError::InvalidArg(format!(
+                "Page number {} is out of range for {} pages",
                 page_num, self.total_pages
             )));
         }
+        Ok(())
+    }
+
+    /// Stores the encoded page in slot `page_num`
+    ///
+    /// Fails with [`DjvuError::InvalidArg`] if `page_num` is not below the
+    /// page count or the slot already holds a page.
+    pub fn insert_page(&self, page_num: usize, page: EncodedPage) -> Result<()> {
+        self.check_page_num(page_num)?;
 
         {
             let mut slot = self.slots[page_num].write().unwrap();
-            if matches!(*slot, PageSlot::Ready(_)) {
-                return Err(DjvuError::InvalidOperation(format!(
-                    "Page {} already exists",
+            if slot.is_ready() {
+                return Err(DjvuError::InvalidArg(format!(
+                    "Page {} has already been added",
                     page_num
                 )));
             }
-            *slot = PageSlot::Ready(Arc::clone(&page.data));
+            *slot = self.store(Arc::clone(&page.data))?;
         }
 
         {
@@ -74,55 +229,160 @@ impl PageCollection {
                 width: page.width,
                 height: page.height,
                 id: meta.as_ref().and_then(|m| m.id.clone()),
+                title: meta.as_ref().and_then(|m| m.title.clone()),
             });
         }
 
         Ok(())
     }
 
+    /// Appends `other`'s slots after this collection's, keeping their pages,
+    /// ids and titles. Page `i` of `other` becomes page `len() + i`.
+    pub fn append(&mut self, other: PageCollection) -> Result<()> {
+        for slot in &other.slots {
+            let slot = slot.read().unwrap();
+            let data = other.load(&slot)?;
+            self.slots.push(RwLock::new(match data {
+                Some(data) => self.store(data)?,
+                None => PageSlot::Pending,
+            }));
+        }
+        self.metadata.extend(other.metadata);
+        self.total_pages += other.total_pages;
+        Ok(())
+    }
+
+    /// Moves the page at index `order[i]` to index `i`, with its id and
+    /// title. `order` must mention every current index exactly once.
+    pub fn reorder(&mut self, order: &[usize]) -> Result<()> {
+        if order.len() != self.total_pages {
+            return Err(DjvuError::InvalidArg(format!(
+                "Page order lists {} pages, document has {}",
+                order.len(),
+                self.total_pages
+            )));
+        }
+        let mut seen = vec![false; self.total_pages];
+        for &i in order {
+            match seen.get_mut(i) {
+                Some(s) if !*s => *s = true,
+                Some(_) => {
+                    return Err(DjvuError::InvalidArg(format!(
+                        "Page {} appears twice in the page order",
+                        i
+                    )));
+                }
+                None => {
+                    return Err(DjvuError::InvalidArg(format!(
+                        "Page {} exceeds total pages {}",
+                        i, self.total_pages
+                    )));
+                }
+            }
+        }
+
+        let mut slots: Vec<_> = std::mem::take(&mut self.slots)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut metadata: Vec<_> = std::mem::take(&mut self.metadata)
+            .into_iter()
+            .map(Some)
+            .collect();
+        for &i in order {
+            self.slots.push(slots[i].take().unwrap());
+            self.metadata.push(metadata[i].take().unwrap());
+        }
+        Ok(())
+    }
+
+    /// Replaces the data of every ready page for which `f` returns a new blob.
+    pub fn rewrite_pages<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Option<Vec<u8>>,
+    {
+        for i in 0..self.slots.len() {
+            let slot = std::mem::replace(self.slots[i].get_mut().unwrap(), PageSlot::Pending);
+            let Some(data) = self.load(&slot)? else {
+                continue;
+            };
+            let Some(new_data) = f(&data) else {
+                *self.slots[i].get_mut().unwrap() = slot;
+                continue;
+            };
+            if let PageSlot::Ready(old) = &slot {
+                self.release(old.len());
+            }
+            *self.slots[i].get_mut().unwrap() = self.store(Arc::new(new_data))?;
+        }
+        Ok(())
+    }
+
     pub fn is_page_ready(&self, page_num: usize) -> bool {
         if page_num >= self.total_pages {
             return false;
         }
-        let slot = self.slots[page_num].read().unwrap();
-        matches!(*slot, PageSlot::Ready(_))
+        self.slots[page_num].read().unwrap().is_ready()
     }
 
     pub fn is_complete(&self) -> bool {
-        self.slots
-            .iter()
-            .all(|s| matches!(*s.read().unwrap(), PageSlot::Ready(_)))
+        self.slots.iter().all(|s| s.read().unwrap().is_ready())
     }
 
     pub fn ready_count(&self) -> usize {
         self.slots
             .iter()
-            .filter(|s| matches!(*s.read().unwrap(), PageSlot::Ready(_)))
+            .filter(|s| s.read().unwrap().is_ready())
             .count()
     }
 
-    pub fn get_page(&self, page_num: usize) -> Option<Arc<Vec<u8>>> {
-        if page_num >= self.total_pages {
-            return None;
+    /// Returns a page's data, reading it back from the spill file if needed.
+    /// `None` for pending or out-of-range pages; fails if the spill file
+    /// can't be read.
+    pub fn get_page(&self, page_num: usize) -> Result<Option<Arc<Vec<u8>>>> {
+        match self.slots.get(page_num) {
+            Some(slot) => self.load(&slot.read().unwrap()),
+            None => Ok(None),
         }
-        let slot = self.slots[page_num].read().unwrap();
-        match &*slot {
-            PageSlot::Ready(data) => Some(Arc::clone(data)),
+    }
+
+    /// Size in bytes of a ready page, without reading it
+    pub fn page_len(&self, page_num: usize) -> Option<usize> {
+        match &*self.slots.get(page_num)?.read().unwrap() {
             PageSlot::Pending => None,
+            PageSlot::Ready(data) => Some(data.len()),
+            PageSlot::Spilled { len, .. } => Some(*len),
         }
     }
 
-    /// Collect all pages as `Arc` references (non-destructive).
-    pub fn collect_all(&self) -> Option<Vec<Arc<Vec<u8>>>> {
+    /// Collect all pages as `Arc` references (non-destructive), or `None`
+    /// if any is still pending.
+    pub fn collect_all(&self) -> Result<Option<Vec<Arc<Vec<u8>>>>> {
         let mut pages = Vec::with_capacity(self.total_pages);
-        for slot_lock in &self.slots {
-            let slot = slot_lock.read().unwrap();
-            match &*slot {
-                PageSlot::Ready(data) => pages.push(Arc::clone(data)),
-                PageSlot::Pending => return None,
+        for i in 0..self.total_pages {
+            match self.get_page(i)? {
+                Some(page) => pages.push(page),
+                None => return Ok(None),
             }
         }
-        Some(pages)
+        Ok(Some(pages))
+    }
+
+    /// Takes one page out of the collection, leaving its slot pending.
+    pub fn take_page(&self, page_num: usize) -> Result<Vec<u8>> {
+        let missing = || DjvuError::InvalidOperation(format!("Page {} is not ready", page_num));
+        let mut slot = self
+            .slots
+            .get(page_num)
+            .ok_or_else(missing)?
+            .write()
+            .unwrap();
+        // Read before swapping so a failed read leaves the page in place
+        let data = self.load(&slot)?.ok_or_else(missing)?;
+        if let PageSlot::Ready(_) = std::mem::replace(&mut *slot, PageSlot::Pending) {
+            self.release(data.len());
+        }
+        Ok(Arc::try_unwrap(data).unwrap_or_else(|a| (*a).clone()))
     }
 
     /// Take all pages out of the collection, consuming the internal references.
@@ -130,22 +390,30 @@ impl PageCollection {
     /// Each slot is swapped to `Pending`, dropping the collection's `Arc`
     /// reference. This guarantees `Arc::try_unwrap` succeeds on the returned
     /// values, avoiding deep clones during finalization.
-    pub fn take_all(&self) -> Option<Vec<Vec<u8>>> {
-        // Quick check: all slots must be Ready before we start swapping.
-        for slot_lock in &self.slots {
-            if !matches!(*slot_lock.read().unwrap(), PageSlot::Ready(_)) {
-                return None;
+    ///
+    /// Returns `None` if any page is pending. Every page is read before any
+    /// slot is emptied, so on `None` or a failed read all pages stay in place.
+    pub fn take_all(&self) -> Result<Option<Vec<Vec<u8>>>> {
+        let mut slots: Vec<_> = self.slots.iter().map(|s| s.write().unwrap()).collect();
+        let mut pages = Vec::with_capacity(slots.len());
+        for slot in &slots {
+            match self.load(slot)? {
+                Some(data) => pages.push(data),
+                None => return Ok(None),
             }
         }
-
-        let mut pages = Vec::with_capacity(self.total_pages);
-        for slot_lock in &self.slots {
-            let mut slot = slot_lock.write().unwrap();
-            if let PageSlot::Ready(data) = std::mem::replace(&mut *slot, PageSlot::Pending) {
-                pages.push(Arc::try_unwrap(data).unwrap_or_else(|a| (*a).clone()));
+        for (slot, data) in slots.iter_mut().zip(&pages) {
+            if let PageSlot::Ready(_) = std::mem::replace(&mut **slot, PageSlot::Pending) {
+                self.release(data.len());
             }
         }
-        Some(pages)
+        drop(slots);
+        Ok(Some(
+            pages
+                .into_iter()
+                .map(|data| Arc::try_unwrap(data).unwrap_or_else(|a| (*a).clone()))
+                .collect(),
+        ))
     }
 
     pub fn get_metadata(&self, page_num: usize) -> Option<(u32, u32)> {
@@ -157,12 +425,7 @@ impl PageCollection {
     }
 
     pub fn set_page_id(&self, page_num: usize, id: String) -> Result<()> {
-        if page_num >= self.total_pages {
-            return Err(DjvuError::InvalidOperation(format!(
-                "Page number {} exceeds total pages {}",
-                page_num, self.total_pages
-            )));
-        }
+        self.check_page_num(page_num)?;
 
         let mut meta = self.metadata[page_num].write().unwrap();
         match meta.as_mut() {
@@ -172,12 +435,36 @@ impl PageCollection {
                     width: 0,
                     height: 0,
                     id: Some(id),
+                    title: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the title stored for `page_num` in the document directory
+    pub fn set_page_title(&self, page_num: usize, title: String) -> Result<()> {
+        self.check_page_num(page_num)?;
+
+        let mut meta = self.metadata[page_num].write().unwrap();
+        match meta.as_mut() {
+            Some(m) => m.title = Some(title),
+            None => {
+                *meta = Some(PageMetadata {
+                    width: 0,
+                    height: 0,
+                    id: None,
+                    title: Some(title),
                 });
             }
         }
         Ok(())
     }
 
+    pub fn page_title(&self, page_num: usize) -> Option<String> {
+        self.metadata_for(page_num).and_then(|m| m.title)
+    }
+
     pub fn metadata_for(&self, page_num: usize) -> Option<PageMetadata> {
         if page_num >= self.total_pages {
             return None;
@@ -190,7 +477,6 @@ impl PageCollection {
 pub struct DocumentBuilder {
     pages: Arc<PageCollection>,
     params: PageEncodeParams,
-    dpi: u32,
     gamma: Option<f32>,
     nav: Option<DjVmNav>,
     metadata: HashMap<String, String>,
@@ -202,7 +488,6 @@ impl DocumentBuilder {
         Self {
             pages: Arc::new(PageCollection::new(total_pages)),
             params,
-            dpi: 300,
             gamma: Some(2.2),
             nav: None,
             metadata: HashMap::new(),
@@ -210,13 +495,11 @@ impl DocumentBuilder {
     }
 
     pub fn with_params(mut self, params: PageEncodeParams) -> Self {
-        self.dpi = params.dpi;
         self.params = params;
         self
     }
 
     pub fn with_dpi(mut self, dpi: u32) -> Self {
-        self.dpi = dpi;
         self.params.dpi = dpi;
         self
     }
@@ -231,8 +514,7 @@ impl DocumentBuilder {
     }
 
     pub fn encode_and_insert(&self, page_num: usize, components: PageComponents) -> Result<()> {
-        let encoded =
-            EncodedPage::from_components(page_num, components, &self.params, self.dpi, self.gamma)?;
+        let encoded = EncodedPage::from_components(page_num, components, &self.params, self.gamma)?;
         self.pages.insert_page(page_num, encoded)
     }
 
@@ -268,7 +550,7 @@ impl DocumentBuilder {
             .collect();
 
         let page_data = pages
-            .take_all()
+            .take_all()?
             .ok_or_else(|| DjvuError::InvalidOperation("Not all pages ready".to_string()))?;
 
         Ok((page_data, identifiers))
@@ -291,3 +573,55 @@ impl DocumentStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page_num: usize) -> EncodedPage {
+        EncodedPage::new(page_num, b"AT&TFORM\0\0\0\x04DJVU".to_vec(), 10, 10)
+    }
+
+    #[test]
+    fn test_insert_out_of_range_page() {
+        let collection = PageCollection::new(2);
+        let err = collection.insert_page(2, page(2)).unwrap_err();
+        assert!(matches!(err, DjvuError::InvalidArg(_)), "{err}");
+        assert_eq!(collection.ready_count(), 0);
+        assert!(matches!(
+            collection.set_page_title(5, "Late".to_string()),
+            Err(DjvuError::InvalidArg(_))
+        ));
+    }
+
+    #[test]
+    fn test_insert_same_page_twice() {
+        let collection = PageCollection::new(2);
+        collection.insert_page(1, page(1)).unwrap();
+        let err = collection.insert_page(1, page(1)).unwrap_err();
+        assert!(matches!(err, DjvuError::InvalidArg(_)), "{err}");
+        assert_eq!(collection.ready_count(), 1);
+    }
+
+    #[test]
+    fn test_failed_spill_read_keeps_every_page() {
+        let mut collection = PageCollection::new(2);
+        collection.set_memory_limit(page(0).data.len());
+        collection.insert_page(0, page(0)).unwrap();
+        collection.insert_page(1, page(1)).unwrap();
+        assert!(matches!(
+            *collection.slots[1].read().unwrap(),
+            PageSlot::Spilled { .. }
+        ));
+
+        let spill = collection.spill.lock().unwrap();
+        spill.as_ref().unwrap().file.set_len(0).unwrap();
+        drop(spill);
+
+        assert!(collection.get_page(1).is_err());
+        assert!(collection.take_all().is_err());
+        assert!(collection.is_complete());
+        assert!(collection.get_page(0).unwrap().is_some());
+        assert_eq!(collection.page_len(1), Some(page(1).data.len()));
+    }
+}
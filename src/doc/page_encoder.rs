@@ -3,16 +3,22 @@
 use crate::annotations::{Annotations, hidden_text::HiddenText};
 use crate::encode::{
     iw44::encoder::{EncoderParams as IW44EncoderParams, IWEncoder},
-    jb2::encoder::JB2Encoder,
+    jb2::{ReadingOrder, encoder::JB2Encoder},
     symbol_dict::BitImage,
 };
-use crate::iff::{bs_byte_stream::bzz_compress, iff::IffWriter};
-use crate::image::image_formats::{Bitmap, GrayPixel, Pixel, Pixmap};
-use crate::{DjvuError, Result};
+use crate::iff::{
+    bs_byte_stream::bzz_compress,
+    iff::{IffWriter, IffWriterExt},
+};
+use crate::image::image_formats::{Bitmap, GrayPixel, Pixel, Pixmap, resample_area};
+use crate::utils::color_checker::{GRAY_CHROMA_TOLERANCE, is_grayscale_rgb};
+use crate::{DjvuError, EncodeStage, Result};
 use byteorder::{BigEndian, WriteBytesExt};
-use log::debug;
+use log::{debug, warn};
+use std::fmt;
 use std::io::{self, Write};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 fn blit_bit_image(dst: &mut BitImage, src: &BitImage, x0: u32, y0: u32) {
     let x0 = x0 as usize;
@@ -33,6 +39,169 @@ fn blit_bit_image(dst: &mut BitImage, src: &BitImage, x0: u32, y0: u32) {
     }
 }
 
+/// Whether the black pixels of `image` form one blob whose rows are single
+/// runs, each overlapping (or diagonally touching) the run above.
+fn is_single_blob(image: &BitImage) -> bool {
+    let mut prev: Option<(usize, usize)> = None;
+    let mut ended = false;
+    for y in 0..image.height {
+        let mut runs = (0..image.width)
+            .filter(|&x| image.get_pixel_unchecked(x, y))
+            .peekable();
+        let Some(start) = runs.next() else {
+            ended |= prev.is_some();
+            continue;
+        };
+        let mut end = start;
+        for x in runs {
+            if x != end + 1 {
+                return false;
+            }
+            end = x;
+        }
+        if ended || prev.is_some_and(|(s, e)| start > e + 1 || end + 1 < s) {
+            return false;
+        }
+        prev = Some((start, end));
+    }
+    prev.is_some()
+}
+
+/// Number of color changes along the rows and columns of `image`, counting
+/// the white outside its border.
+fn edge_count(image: &BitImage) -> usize {
+    let (w, h) = (image.width, image.height);
+    let pixel = |x: usize, y: usize| x < w && y < h && image.get_pixel_unchecked(x, y);
+    let mut edges = 0;
+    for y in 0..h {
+        for x in 0..w {
+            let on = pixel(x, y);
+            let left = x > 0 && pixel(x - 1, y);
+            let above = y > 0 && pixel(x, y - 1);
+            edges += (on != left) as usize + (on != above) as usize;
+        }
+        edges += pixel(w - 1, y) as usize;
+    }
+    edges + (0..w).filter(|&x| pixel(x, h - 1)).count()
+}
+
+/// Slices in a TH44 thumbnail, as DjVuLibre uses when generating them.
+const THUMBNAIL_SLICES: usize = 97;
+
+/// Size of a thumbnail of a `w`x`h` page whose longer side is at most
+/// `size` pixels. Pages that already fit keep their size.
+pub(crate) fn thumbnail_dimensions((w, h): (u32, u32), size: u32) -> Result<(u32, u32)> {
+    if size == 0 || w == 0 || h == 0 {
+        return Err(DjvuError::InvalidArg(format!(
+            "Cannot make a {size}-pixel thumbnail of a {w}x{h} page"
+        )));
+    }
+    let longest = w.max(h);
+    let scale = |n: u32| ((n as u64 * size as u64 / longest as u64) as u32).clamp(1, n);
+    Ok(if longest <= size {
+        (w, h)
+    } else {
+        (scale(w), scale(h))
+    })
+}
+
+/// Encodes a rendered thumbnail as the data of a TH44 chunk: a single
+/// color IW44 chunk.
+pub(crate) fn encode_th44(thumbnail: &Pixmap) -> Result<Vec<u8>> {
+    let params = IW44EncoderParams {
+        slices: Some(THUMBNAIL_SLICES),
+        crcb_mode: crate::encode::iw44::encoder::CrcbMode::Normal,
+        ..IW44EncoderParams::default()
+    };
+    let mut encoder = IWEncoder::from_rgb(thumbnail, None, params)
+        .map_err(|e| DjvuError::encoding(EncodeStage::Iw44, e))?;
+    let (chunk, _) = encoder
+        .encode_chunk(THUMBNAIL_SLICES)
+        .map_err(|e| DjvuError::encoding(EncodeStage::Iw44, e))?;
+    Ok(chunk)
+}
+
+/// Slices in an FG44 foreground, as DjVuLibre's `csepdjvu` uses.
+const FOREGROUND_SLICES: usize = 100;
+
+/// Largest reduction of an FG44 foreground relative to the page.
+const MAX_FOREGROUND_REDUCTION: u32 = 12;
+
+/// Chunk ids with a meaning in DjVu or IFF, which custom chunks may not use.
+const RESERVED_CHUNK_IDS: [&[u8; 4]; 26] = [
+    b"FORM", b"LIST", b"PROP", b"CAT ", b"INFO", b"INCL", b"BG44", b"FG44", b"BGjp", b"FGjp",
+    b"BG2k", b"FG2k", b"Sjbz", b"Smmr", b"Djbz", b"FGbz", b"TXTa", b"TXTz", b"ANTa", b"ANTz",
+    b"TH44", b"CIDa", b"DIRM", b"NAVM", b"NDIR", b"WMRM",
+];
+
+/// Largest page width or height representable in the INFO chunk.
+pub const MAX_PAGE_DIMENSION: u32 = u16::MAX as u32;
+
+/// Rejects page sizes that don't fit the INFO chunk's 16-bit width/height.
+fn check_dimension_limit(width: u32, height: u32) -> Result<()> {
+    if width > MAX_PAGE_DIMENSION || height > MAX_PAGE_DIMENSION {
+        return Err(DjvuError::InvalidArg(format!(
+            "Page size {width}x{height} exceeds the DjVu limit of {MAX_PAGE_DIMENSION} pixels \
+             per side (INFO stores width and height as 16-bit values)"
+        )));
+    }
+    Ok(())
+}
+
+/// Sets up the IW44 encoder for an FG44 foreground.
+fn foreground_encoder(fg: &Pixmap) -> Result<IWEncoder> {
+    let params = IW44EncoderParams {
+        slices: Some(FOREGROUND_SLICES),
+        crcb_mode: crate::encode::iw44::encoder::CrcbMode::Normal,
+        ..IW44EncoderParams::default()
+    };
+    IWEncoder::from_rgb(fg, None, params).map_err(|e| DjvuError::encoding(EncodeStage::Iw44, e))
+}
+
+/// Encodes an FG44 foreground as a single IW44 chunk.
+fn encode_iw44_foreground(fg: &Pixmap) -> Result<Vec<u8>> {
+    let (chunk, _) = foreground_encoder(fg)?
+        .encode_chunk(FOREGROUND_SLICES)
+        .map_err(|e| DjvuError::encoding(EncodeStage::Iw44, e))?;
+    Ok(chunk)
+}
+
+/// Reads the frame size from a JPEG's SOF marker, if the data looks like a JPEG.
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        // Fill bytes and standalone markers carry no length field
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        // SOF0..SOF15, except DHT (C4), JPG (C8) and DAC (CC)
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let sof = data.get(pos + 4..pos + 9)?;
+            let height = u16::from_be_bytes([sof[1], sof[2]]) as u32;
+            let width = u16::from_be_bytes([sof[3], sof[4]]) as u32;
+            return Some((width, height));
+        }
+        if marker == 0xDA || len < 2 {
+            return None;
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rect {
     pub x: u32,
@@ -68,12 +237,35 @@ pub enum PageLayer {
     JB2Mask { image: BitImage, rect: Rect },
 }
 
+/// A non-fatal observation made while encoding a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeDiagnostic {
+    /// The background was forced to color (`ColorMode::Color`) but has
+    /// negligible chroma; `ColorMode::Gray` or `Auto` would be smaller.
+    GrayInColorMode { page_num: usize },
+}
+
+impl fmt::Display for EncodeDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeDiagnostic::GrayInColorMode { page_num } => write!(
+                f,
+                "page {} is encoded in color but is effectively grayscale; \
+                 ColorMode::Gray or ColorMode::Auto would be smaller",
+                page_num + 1
+            ),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct EncodedPage {
     pub page_num: usize,
     pub data: Arc<Vec<u8>>,
     pub width: u32,
     pub height: u32,
+    /// Observations made while encoding (empty for raw pages)
+    pub diagnostics: Vec<EncodeDiagnostic>,
 }
 
 impl EncodedPage {
@@ -83,6 +275,7 @@ impl EncodedPage {
             data: Arc::new(data),
             width,
             height,
+            diagnostics: Vec::new(),
         }
     }
 
@@ -90,36 +283,221 @@ impl EncodedPage {
         page_num: usize,
         components: PageComponents,
         params: &PageEncodeParams,
-        dpi: u32,
         gamma: Option<f32>,
     ) -> Result<Self> {
         let (width, height) = components.dimensions();
-        let dpm = (dpi * 100 / 254) as u32;
+        let dpm = params.dpi * 100 / 254;
         let rotation = if width >= height { 1 } else { 1 };
         let data = components.encode(params, (page_num + 1) as u32, dpm, rotation, gamma)?;
+        let diagnostics = components.diagnose(page_num, params);
+        for diagnostic in &diagnostics {
+            warn!("{}", diagnostic);
+        }
         Ok(Self {
             page_num,
             data: Arc::new(data),
             width,
             height,
+            diagnostics,
         })
     }
 }
 
+/// How the IW44 background color space is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ColorMode {
+    /// Always encode luminance and chrominance
+    #[default]
+    Color,
+    /// Always encode luminance only
+    Gray,
+    /// Analyze each page and drop chrominance when it is effectively gray
+    Auto,
+}
+
+/// Codec for the bilevel stencil extracted from the mask or foreground.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum MaskCodec {
+    /// JB2 symbol coding in an `Sjbz` chunk
+    #[default]
+    Jb2,
+    /// ITU-T G4 (MMR) in an `Smmr` chunk, for pipelines that only handle
+    /// fax-style data. Usually larger than JB2, and it cannot color shapes
+    /// individually, so a page whose foreground colors the shapes of a
+    /// different mask is rejected. Manual `jb2_shapes` are still written as
+    /// JB2.
+    Mmr,
+}
+
+/// How a JB2 stencil extracted from the mask or foreground is coded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Jb2Coding {
+    /// Code the stencil directly when it is a single connected blob, where
+    /// symbol extraction would only split it into tiles; otherwise extract
+    /// symbols
+    #[default]
+    Auto,
+    /// Always run connected-component analysis and symbol clustering
+    Symbols,
+    /// Always code the whole stencil as one bitmap, skipping the analysis.
+    /// Shapes cannot be colored individually, so pages with both a mask and
+    /// a foreground fall back to symbols.
+    Direct,
+}
+
+/// Morphological cleanup applied to a bilevel layer before connected-component
+/// analysis. The value is the radius of the square structuring element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum MaskMorphology {
+    /// Grow black regions
+    Dilate(usize),
+    /// Shrink black regions
+    Erode(usize),
+    /// Erode then dilate: drop specks, keep larger shapes
+    Open(usize),
+    /// Dilate then erode: bridge small gaps and holes
+    Close(usize),
+}
+
+impl MaskMorphology {
+    /// Returns the cleaned-up copy of `image`.
+    pub fn apply(self, image: &BitImage) -> BitImage {
+        match self {
+            MaskMorphology::Dilate(r) => image.dilate(r),
+            MaskMorphology::Erode(r) => image.erode(r),
+            MaskMorphology::Open(r) => image.open(r),
+            MaskMorphology::Close(r) => image.close(r),
+        }
+    }
+}
+
+/// BZZ block size, in KB, for each kind of compressed chunk
+///
+/// A larger block lets the Burrows-Wheeler pass find repeats further apart,
+/// which pays off for big inputs such as the directory of a document with
+/// thousands of pages, at the cost of memory and encoding time. Inputs
+/// smaller than a block compress the same either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct BzzLevel {
+    /// Document directory, `DIRM` (default: 50)
+    pub dirm: usize,
+    /// Bookmarks, `NAVM` (default: 100)
+    pub navm: usize,
+    /// Hidden text, `TXTz` (default: 100)
+    pub text: usize,
+    /// Annotations, `ANTz` (default: 100)
+    pub annotations: usize,
+    /// Foreground color indices, `FGbz` (default: 50)
+    pub palette: usize,
+}
+
+impl Default for BzzLevel {
+    fn default() -> Self {
+        Self {
+            dirm: 50,
+            navm: 100,
+            text: 100,
+            annotations: 100,
+            palette: 50,
+        }
+    }
+}
+
+impl BzzLevel {
+    /// Smallest block size BZZ supports, in KB
+    pub const MIN: usize = 10;
+    /// Largest block size BZZ supports, in KB
+    pub const MAX: usize = 4096;
+
+    /// Uses the same block size for every chunk
+    pub fn uniform(block_size_k: usize) -> Self {
+        Self {
+            dirm: block_size_k,
+            navm: block_size_k,
+            text: block_size_k,
+            annotations: block_size_k,
+            palette: block_size_k,
+        }
+    }
+
+    /// Checks that every block size lies in `MIN..=MAX`
+    pub fn validate(&self) -> Result<()> {
+        let sizes = [
+            ("DIRM", self.dirm),
+            ("NAVM", self.navm),
+            ("TXTz", self.text),
+            ("ANTz", self.annotations),
+            ("FGbz", self.palette),
+        ];
+        for (chunk, size) in sizes {
+            if !(Self::MIN..=Self::MAX).contains(&size) {
+                return Err(DjvuError::InvalidArg(format!(
+                    "BZZ block size for {chunk} is {size} KB, expected {} to {}",
+                    Self::MIN,
+                    Self::MAX
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Configuration for page encoding
+///
+/// With the `serde` feature it can be read from config files. Missing
+/// fields take their default, `cancel` is never (de)serialized, and
+/// qualities, decibels and BZZ block sizes out of range are rejected.
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct PageEncodeParams {
     /// Dots per inch (default: 300)
     pub dpi: u32,
     /// Background quality (0-100, higher is better quality)
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "serde_checks::quality"))]
     pub bg_quality: u8,
     /// Foreground quality (0-100, higher is better quality)
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "serde_checks::quality"))]
     pub fg_quality: u8,
     /// Whether to use IW44 for background (true) or JB2 (false)
     pub use_iw44: bool,
-    /// Whether to encode in color (true) or grayscale (false)
-    pub color: bool,
-    /// Target SNR in dB for IW44 encoding (overrides bg_quality if set)
+    /// Whether to encode the background in color, grayscale, or decide per page
+    pub color: ColorMode,
+    /// Largest channel spread (`max(r,g,b) - min(r,g,b)`) that
+    /// [`ColorMode::Auto`] still counts as gray (default: 8). Lower it so a
+    /// faint colored stamp or highlight makes the page color.
+    pub chroma_threshold: u8,
+    /// Target SNR in dB for IW44 encoding (overrides bg_quality if set);
+    /// must lie between 20 and 120
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "serde_checks::decibels"))]
     pub decibels: Option<f32>,
     /// Maximum slices per chunk (default: 74, like C44)
     pub slices: Option<usize>,
@@ -133,6 +511,73 @@ pub struct PageEncodeParams {
     /// Lower = more coefficients = better quality but larger files
     /// Higher = fewer coefficients = smaller files but lower quality
     pub quant_multiplier: Option<f32>,
+    /// DjVu file format version written to the INFO chunk as `(major, minor)`
+    /// (default: `(0, 26)`). Only major version 0 and minor versions up to 26
+    /// are supported.
+    pub info_version: (u8, u8),
+    /// Whether pages with only bilevel (JB2) content get a blank white BG44
+    /// (default: true). When false such pages are written as pure `Sjbz`
+    /// bitonal pages, which are smaller and faster to decode.
+    pub emit_bg_for_bilevel: bool,
+    /// Cleanup applied to the foreground or mask before JB2 symbol
+    /// extraction (default: None). Useful for noisy scans.
+    pub mask_morphology: Option<MaskMorphology>,
+    /// Codec for the bilevel stencil (default: JB2)
+    pub mask_codec: MaskCodec,
+    /// Whether a JB2 stencil is split into symbols or coded as one bitmap
+    /// (default: decide per page)
+    pub jb2_coding: Jb2Coding,
+    /// Whether an extracted JB2 shape that closely matches an earlier one is
+    /// coded as a lossless refinement of it (default: false). Shrinks pages
+    /// of near-identical glyphs at some extra encoding time.
+    pub jb2_refinement: bool,
+    /// Flag polled between chunks (default: None). Once it is set, encoding
+    /// stops with [`DjvuError::Cancelled`] and the partial page is dropped.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// BZZ block sizes for the compressed chunks (default: see [`BzzLevel`])
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "serde_checks::bzz"))]
+    pub bzz: BzzLevel,
+    /// Direction the page's text is read in, which sets the order of the JB2
+    /// dictionary; blits are coded in rows either way (default: left to
+    /// right, top down)
+    pub reading_order: ReadingOrder,
+}
+
+/// Range checks applied while deserializing [`PageEncodeParams`].
+#[cfg(feature = "serde")]
+mod serde_checks {
+    use super::BzzLevel;
+    use crate::encode::iw44::encoder::DECIBELS_RANGE;
+    use serde::de::{Deserialize, Deserializer, Error};
+
+    pub fn quality<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+        let quality = u8::deserialize(deserializer)?;
+        if quality > 100 {
+            return Err(D::Error::custom(format!(
+                "quality is {quality}, expected 0 to 100"
+            )));
+        }
+        Ok(quality)
+    }
+
+    pub fn decibels<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+        let decibels = Option::<f32>::deserialize(deserializer)?;
+        if let Some(db) = decibels.filter(|db| !DECIBELS_RANGE.contains(db)) {
+            return Err(D::Error::custom(format!(
+                "decibels is {db}, expected {} to {}",
+                DECIBELS_RANGE.start(),
+                DECIBELS_RANGE.end()
+            )));
+        }
+        Ok(decibels)
+    }
+
+    pub fn bzz<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BzzLevel, D::Error> {
+        let level = BzzLevel::deserialize(deserializer)?;
+        level.validate().map_err(D::Error::custom)?;
+        Ok(level)
+    }
 }
 
 impl Default for PageEncodeParams {
@@ -142,13 +587,86 @@ impl Default for PageEncodeParams {
             bg_quality: 90,
             fg_quality: 90,
             use_iw44: true, // Default to IW44 for background
-            color: true,    // Default to color encoding
+            color: ColorMode::Color,
+            chroma_threshold: GRAY_CHROMA_TOLERANCE,
             decibels: None,
             slices: Some(74), // C44 default
             bytes: None,
             db_frac: 0.35,
             lossless: false,
             quant_multiplier: None, // Use C++ default
+            info_version: (0, 26),
+            emit_bg_for_bilevel: true,
+            mask_morphology: None,
+            mask_codec: MaskCodec::Jb2,
+            jb2_coding: Jb2Coding::Auto,
+            jb2_refinement: false,
+            cancel: None,
+            bzz: BzzLevel::default(),
+            reading_order: ReadingOrder::default(),
+        }
+    }
+}
+
+impl PageEncodeParams {
+    /// Preset for photographs and other continuous-tone color images with no
+    /// text layer: a high-fidelity color IW44 background (48 dB target, up to
+    /// 100 slices) and no JB2 content expected.
+    pub fn photo() -> Self {
+        Self {
+            bg_quality: 95,
+            color: ColorMode::Color,
+            decibels: Some(48.0),
+            slices: Some(100),
+            ..Self::default()
+        }
+    }
+
+    /// Preset for scanned book or magazine pages split into the usual three
+    /// layers: crisp JB2 text over a moderate-quality IW44 background that is
+    /// encoded in grayscale when the scan has no real color.
+    pub fn scanned_document() -> Self {
+        Self {
+            bg_quality: 75,
+            fg_quality: 90,
+            color: ColorMode::Auto,
+            decibels: None,
+            slices: Some(74),
+            ..Self::default()
+        }
+    }
+
+    /// Preset for black-and-white scans (fax, line art, text-only pages):
+    /// JB2 only, with no IW44 background at all, not even a blank one.
+    pub fn bitonal() -> Self {
+        Self {
+            fg_quality: 100,
+            use_iw44: false,
+            color: ColorMode::Gray,
+            emit_bg_for_bilevel: false,
+            ..Self::default()
+        }
+    }
+
+    /// Preset for bulk archiving where size matters more than fidelity:
+    /// a coarse, short IW44 background with stronger quantization.
+    pub fn compressed_archive() -> Self {
+        Self {
+            bg_quality: 50,
+            fg_quality: 75,
+            color: ColorMode::Auto,
+            decibels: None,
+            slices: Some(52),
+            quant_multiplier: Some(1.5),
+            ..Self::default()
+        }
+    }
+
+    /// Returns [`DjvuError::Cancelled`] once the `cancel` flag is set.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(flag) if flag.load(Ordering::Relaxed) => Err(DjvuError::Cancelled),
+            _ => Ok(()),
         }
     }
 }
@@ -158,6 +676,21 @@ impl Default for PageEncodeParams {
 /// Use `PageComponents::new()` to create an empty page, then add components
 /// like background, foreground, and mask using the `with_*` methods.
 /// The dimensions of the first image added will set the dimensions for the page.
+///
+/// The layers follow the DjVu three-layer model:
+/// - `background` is the continuous-tone image (BG44) seen where the
+///   stencil is clear.
+/// - `mask` is the bilevel stencil (Sjbz): its black pixels show the
+///   foreground color instead of the background.
+/// - `foreground` colors the stencil (FGbz): stencil shapes touching its
+///   black pixels are drawn in `foreground_color`, the others in black.
+///
+/// With only one of `mask` or `foreground`, that bitmap is the stencil and
+/// everything is drawn in black.
+///
+/// An `iw44_foreground` (FG44) replaces those stencil colors with a
+/// low-resolution color image: each stencil pixel takes the color of the
+/// foreground pixel covering it, and everything else shows the background.
 pub struct PageComponents {
     /// Page width in pixels
     width: u32,
@@ -169,6 +702,9 @@ pub struct PageComponents {
     pub foreground: Option<BitImage>,
     /// Optional mask data (bitonal)
     pub mask: Option<BitImage>,
+    /// Color of stencil shapes selected by `foreground` when a `mask` is
+    /// also present (default: black)
+    pub foreground_color: Pixel,
     /// JB2 shape dictionary (bitonal symbol images)
     /// Used for manual JB2 encoding without connected component analysis
     pub jb2_shapes: Option<Vec<BitImage>>,
@@ -182,8 +718,21 @@ pub struct PageComponents {
     pub text_layer: Option<HiddenText>,
     /// Optional hyperlink/annotation layer (ANTa/ANTz)
     pub annotations: Option<Annotations>,
+    /// Annotations shared with other pages, merged under `annotations` when
+    /// the page's ANTz chunk is written
+    pub shared_annotations: Option<std::sync::Arc<Annotations>>,
     /// Optional shared JB2 dictionary for cross-page symbol sharing
     pub shared_dict: Option<std::sync::Arc<crate::encode::jb2::symbol_dict::SharedDict>>,
+    /// Ids of `FORM:DJVI` include files referenced through INCL chunks
+    pub includes: Vec<String>,
+    /// Optional pre-compressed JPEG background, written as-is in a BGjp chunk
+    pub jpeg_background: Option<Vec<u8>>,
+    /// Longest side of an embedded TH44 thumbnail, if one is written
+    pub thumbnail_size: Option<u32>,
+    /// Optional color foreground (FG44), the page reduced by 1 to 12
+    pub iw44_foreground: Option<Pixmap>,
+    /// Non-standard chunks written after all the others, in order
+    pub custom_chunks: Vec<([u8; 4], Vec<u8>)>,
 }
 
 impl Default for PageComponents {
@@ -194,13 +743,20 @@ impl Default for PageComponents {
             background: None,
             foreground: None,
             mask: None,
+            foreground_color: Pixel::black(),
             text: None,
             layers: Vec::new(),
             text_layer: None,
             annotations: None,
+            shared_annotations: None,
             shared_dict: None,
             jb2_shapes: None,
             jb2_blits: None,
+            includes: Vec::new(),
+            jpeg_background: None,
+            thumbnail_size: None,
+            iw44_foreground: None,
+            custom_chunks: Vec::new(),
         }
     }
 }
@@ -218,13 +774,20 @@ impl PageComponents {
             background: None,
             foreground: None,
             mask: None,
+            foreground_color: Pixel::black(),
             text: None,
             layers: Vec::new(),
             text_layer: None,
             annotations: None,
+            shared_annotations: None,
             shared_dict: None,
             jb2_shapes: None,
             jb2_blits: None,
+            includes: Vec::new(),
+            jpeg_background: None,
+            thumbnail_size: None,
+            iw44_foreground: None,
+            custom_chunks: Vec::new(),
         }
     }
 
@@ -241,6 +804,17 @@ impl PageComponents {
         self
     }
 
+    /// References a shared `FORM:DJVI` include file by id.
+    ///
+    /// The page emits an INCL chunk instead of carrying the data itself, so a
+    /// background shared by many pages (e.g. a letterhead) is stored once.
+    /// When a page has includes, no blank BG44 is synthesized for JB2-only
+    /// content; the include is expected to provide the background.
+    pub fn with_include(mut self, id: impl Into<String>) -> Self {
+        self.includes.push(id.into());
+        self
+    }
+
     /// Returns the dimensions of the page.
     pub fn dimensions(&self) -> (u32, u32) {
         (self.width, self.height)
@@ -249,6 +823,7 @@ impl PageComponents {
     /// Checks and sets the page dimensions if they are not already set.
     /// Returns an error if the new dimensions conflict with existing ones.
     fn check_and_set_dimensions(&mut self, new_dims: (u32, u32)) -> Result<()> {
+        check_dimension_limit(new_dims.0, new_dims.1)?;
         if self.width == 0 && self.height == 0 {
             self.width = new_dims.0;
             self.height = new_dims.1;
@@ -261,9 +836,30 @@ impl PageComponents {
         Ok(())
     }
 
-    pub fn add_iw44_background(mut self, image: Pixmap, rect: Rect) -> Result<Self> {
-        let new_dims = (rect.x + rect.width, rect.y + rect.height);
-        self.check_and_set_dimensions(new_dims)?;
+    /// Checks that a layer at `rect` lies on the page. A page without a size
+    /// yet takes the layer's extent as its size.
+    fn check_layer_rect(&mut self, rect: &Rect) -> Result<()> {
+        let extent = (rect.x + rect.width, rect.y + rect.height);
+        if self.width == 0 && self.height == 0 {
+            return self.check_and_set_dimensions(extent);
+        }
+        if extent.0 > self.width || extent.1 > self.height {
+            return Err(DjvuError::InvalidOperation(format!(
+                "{}x{} layer at ({}, {}) extends past the {}x{} page",
+                rect.width, rect.height, rect.x, rect.y, self.width, self.height
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn add_iw44_background(self, image: Pixmap, rect: Rect) -> Result<Self> {
+        self.place_iw44_background(image, rect, Pixel::white())
+    }
+
+    /// Draws `image` into the background at `rect`; the rest of a new
+    /// background canvas is `fill`.
+    fn place_iw44_background(mut self, image: Pixmap, rect: Rect, fill: Pixel) -> Result<Self> {
+        self.check_layer_rect(&rect)?;
         if image.width() != rect.width || image.height() != rect.height {
             return Err(DjvuError::InvalidOperation(
                 "Background layer dimensions do not match rect".to_string(),
@@ -276,7 +872,7 @@ impl PageComponents {
             let mut canvas = self
                 .background
                 .take()
-                .unwrap_or_else(|| Pixmap::from_pixel(self.width, self.height, Pixel::white()));
+                .unwrap_or_else(|| Pixmap::from_pixel(self.width, self.height, fill));
             for y in 0..rect.height {
                 for x in 0..rect.width {
                     let px = image.get_pixel(x, y);
@@ -291,8 +887,7 @@ impl PageComponents {
     }
 
     pub fn add_jb2_foreground(mut self, image: BitImage, rect: Rect) -> Result<Self> {
-        let new_dims = (rect.x + rect.width, rect.y + rect.height);
-        self.check_and_set_dimensions(new_dims)?;
+        self.check_layer_rect(&rect)?;
         if image.width as u32 != rect.width || image.height as u32 != rect.height {
             return Err(DjvuError::InvalidOperation(
                 "Foreground layer dimensions do not match rect".to_string(),
@@ -312,8 +907,7 @@ impl PageComponents {
     }
 
     pub fn add_jb2_mask(mut self, image: BitImage, rect: Rect) -> Result<Self> {
-        let new_dims = (rect.x + rect.width, rect.y + rect.height);
-        self.check_and_set_dimensions(new_dims)?;
+        self.check_layer_rect(&rect)?;
         if image.width as u32 != rect.width || image.height as u32 != rect.height {
             return Err(DjvuError::InvalidOperation(
                 "Mask layer dimensions do not match rect".to_string(),
@@ -333,23 +927,134 @@ impl PageComponents {
     }
 
     /// Adds a background image to the page.
-    pub fn with_background(self, image: Pixmap) -> Result<Self> {
+    pub fn with_background(mut self, image: Pixmap) -> Result<Self> {
+        self.check_and_set_dimensions(image.dimensions())?;
         let rect = Rect::from_dimensions(image.width(), image.height());
         self.add_iw44_background(image, rect)
     }
 
+    /// Places a background image smaller than the page with its top-left
+    /// corner at (`x`, `y`); the rest of the background is white.
+    ///
+    /// The page must already have its size (see [`Self::new_with_dimensions`]).
+    pub fn with_background_rect(self, image: Pixmap, x: u32, y: u32) -> Result<Self> {
+        self.with_background_rect_filled(image, x, y, Pixel::white())
+    }
+
+    /// Like [`Self::with_background_rect`], with `fill` around the image
+    /// instead of white. The fill only applies when this is the first
+    /// background placed on the page.
+    pub fn with_background_rect_filled(
+        self,
+        image: Pixmap,
+        x: u32,
+        y: u32,
+        fill: Pixel,
+    ) -> Result<Self> {
+        if self.width == 0 && self.height == 0 {
+            return Err(DjvuError::InvalidOperation(
+                "Page size must be set before placing a background at an offset".to_string(),
+            ));
+        }
+        let rect = Rect::new(x, y, image.width(), image.height());
+        self.place_iw44_background(image, rect, fill)
+    }
+
+    /// Uses an already-compressed JPEG as the page background.
+    ///
+    /// The data is written unchanged in a `BGjp` chunk instead of an IW44
+    /// `BG44`, and takes precedence over any pixmap background. Only the
+    /// header is checked: the SOF marker must declare `width`x`height`.
+    ///
+    /// Note that not every DjVu viewer supports `BGjp`; DjVuLibre decodes it
+    /// only when built with JPEG support.
+    pub fn with_jpeg_background(
+        mut self,
+        jpeg_bytes: Vec<u8>,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        let (jpeg_w, jpeg_h) = jpeg_dimensions(&jpeg_bytes).ok_or_else(|| {
+            DjvuError::InvalidArg("JPEG background has no readable SOF header".to_string())
+        })?;
+        if (jpeg_w, jpeg_h) != (width, height) {
+            return Err(DjvuError::InvalidArg(format!(
+                "JPEG background is {jpeg_w}x{jpeg_h}, expected {width}x{height}"
+            )));
+        }
+        self.check_and_set_dimensions((width, height))?;
+        self.jpeg_background = Some(jpeg_bytes);
+        Ok(self)
+    }
+
     /// Adds a foreground image to the page.
-    pub fn with_foreground(self, image: BitImage) -> Result<Self> {
+    pub fn with_foreground(mut self, image: BitImage) -> Result<Self> {
+        self.check_and_set_dimensions((image.width as u32, image.height as u32))?;
         let rect = Rect::from_dimensions(image.width as u32, image.height as u32);
         self.add_jb2_foreground(image, rect)
     }
 
     /// Adds a mask to the page.
-    pub fn with_mask(self, image: BitImage) -> Result<Self> {
+    pub fn with_mask(mut self, image: BitImage) -> Result<Self> {
+        self.check_and_set_dimensions((image.width as u32, image.height as u32))?;
         let rect = Rect::from_dimensions(image.width as u32, image.height as u32);
         self.add_jb2_mask(image, rect)
     }
 
+    /// Colors the stencil with a low-resolution image, encoded as IW44 in an
+    /// FG44 chunk.
+    ///
+    /// `image` is the page reduced by a factor `r` between 1 and 12, i.e.
+    /// `ceil(width / r)` by `ceil(height / r)` pixels, which is how viewers
+    /// match it to the page; 12 is typical, since stencil colors rarely
+    /// change within a glyph. Each black pixel of the mask (or foreground
+    /// bitmap) is drawn in the color of the foreground pixel covering it,
+    /// and the background shows everywhere else. It takes the place of the
+    /// FGbz palette, so [`Self::with_foreground_color`] no longer applies,
+    /// and encoding fails without a stencil to color.
+    pub fn with_iw44_foreground(mut self, image: Pixmap) -> Result<Self> {
+        if self.width != 0 || self.height != 0 {
+            self.foreground_reduction(&image)?;
+        }
+        self.iw44_foreground = Some(image);
+        Ok(self)
+    }
+
+    /// Fails when the page has no size yet, as for a text-only page built
+    /// with [`Self::new`]: every chunk, including a blank BG44, is sized
+    /// from it.
+    fn check_page_size(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(DjvuError::InvalidOperation(format!(
+                "Page size is {}x{}; create the page with new_with_dimensions or add a \
+                 background, foreground or mask layer before encoding",
+                self.width, self.height
+            )));
+        }
+        Ok(())
+    }
+
+    /// The factor the page is reduced by in the FG44 image `fg`.
+    fn foreground_reduction(&self, fg: &Pixmap) -> Result<u32> {
+        let (w, h) = (self.width, self.height);
+        (1..=MAX_FOREGROUND_REDUCTION)
+            .find(|&r| w.div_ceil(r) == fg.width() && h.div_ceil(r) == fg.height())
+            .ok_or_else(|| {
+                DjvuError::InvalidArg(format!(
+                    "FG44 foreground is {}x{}, not the {w}x{h} page reduced by 1 to {}",
+                    fg.width(),
+                    fg.height(),
+                    MAX_FOREGROUND_REDUCTION
+                ))
+            })
+    }
+
+    /// Sets the color of the stencil shapes selected by the foreground.
+    pub fn with_foreground_color(mut self, color: Pixel) -> Self {
+        self.foreground_color = color;
+        self
+    }
+
     /// Adds text/annotations to the page.
     pub fn with_text(mut self, text: String) -> Self {
         self.text = Some(text);
@@ -415,19 +1120,174 @@ impl PageComponents {
         let shapes = cc_image.extract_shapes();
 
         // Convert to encoder format
-        let (bitmaps, _parents, blits) = shapes_to_encoder_format(shapes, image.height as i32);
+        let (bitmaps, _parents, blits) = shapes_to_encoder_format(shapes, image.height as i32)
+            .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
 
         self.jb2_shapes = Some(bitmaps);
         self.jb2_blits = Some(blits);
         Ok(self)
     }
 
+    /// Embeds a TH44 thumbnail whose longer side is at most `size` pixels.
+    ///
+    /// The thumbnail is an IW44 image rendered from the background and the
+    /// mask (or foreground) at encode time, and is written inside the page's
+    /// own `FORM:DJVU` so the page carries it when used standalone. Pages
+    /// smaller than `size` are not upscaled.
+    pub fn with_thumbnail(mut self, size: u32) -> Self {
+        self.thumbnail_size = Some(size);
+        self
+    }
+
+    /// Attaches a non-standard chunk, e.g. layout JSON or OCR confidences.
+    ///
+    /// The chunk is written inside the page's `FORM:DJVU` after all the
+    /// standard chunks; viewers skip ids they don't know. Fails with
+    /// `InvalidArg` unless `id` is four printable ASCII characters other
+    /// than `:` (which IFF reserves for composite ids) that no DjVu or IFF
+    /// chunk already uses.
+    pub fn with_custom_chunk(mut self, id: [u8; 4], data: Vec<u8>) -> Result<Self> {
+        let name = String::from_utf8_lossy(&id);
+        if !id.iter().all(|b| (b' '..=b'~').contains(b)) {
+            return Err(DjvuError::InvalidArg(format!(
+                "chunk id {name:?} is not four printable ASCII characters"
+            )));
+        }
+        if id.contains(&b':') {
+            return Err(DjvuError::InvalidArg(format!(
+                "chunk id {name:?} contains ':', which marks composite ids"
+            )));
+        }
+        if RESERVED_CHUNK_IDS.contains(&&id) {
+            return Err(DjvuError::InvalidArg(format!(
+                "chunk id {name:?} is reserved for standard chunks"
+            )));
+        }
+        self.custom_chunks.push((id, data));
+        Ok(self)
+    }
+
     /// Adds hyperlink/annotation data.
     pub fn with_annotations(mut self, annotations: Annotations) -> Self {
         self.annotations = Some(annotations);
         self
     }
 
+    /// Sets annotations shared with other pages (e.g. a common zoom or
+    /// footer link).
+    ///
+    /// They are combined with the page's own annotations via
+    /// [`Annotations::merge`] into a single ANTz chunk, so page-level values
+    /// win on conflict.
+    pub fn with_shared_annotations(mut self, annotations: std::sync::Arc<Annotations>) -> Self {
+        self.shared_annotations = Some(annotations);
+        self
+    }
+
+    /// The page's annotations merged over the shared ones, if there are any.
+    fn merged_annotations(&self) -> Option<Annotations> {
+        match (&self.annotations, &self.shared_annotations) {
+            (Some(page), Some(shared)) => {
+                let mut merged = page.clone();
+                merged.merge(shared);
+                Some(merged)
+            }
+            (page, shared) => page.clone().or_else(|| shared.as_deref().cloned()),
+        }
+    }
+
+    /// Checks the page for settings likely to waste space under `params`.
+    pub fn diagnose(&self, page_num: usize, params: &PageEncodeParams) -> Vec<EncodeDiagnostic> {
+        let mut diagnostics = Vec::new();
+        let gray_in_color = params.use_iw44
+            && params.color == ColorMode::Color
+            && self.jpeg_background.is_none()
+            && self
+                .background
+                .as_ref()
+                .is_some_and(|bg| is_grayscale_rgb(bg.as_raw(), params.chroma_threshold));
+        if gray_in_color {
+            diagnostics.push(EncodeDiagnostic::GrayInColorMode { page_num });
+        }
+        diagnostics
+    }
+
+    /// Estimates the encoded size of the page in bytes without running the
+    /// entropy coders.
+    ///
+    /// The background is judged from its wavelet coefficient energy and
+    /// the JB2 layer from the outlines of its shapes. This is a heuristic,
+    /// typically within a factor of two of what [`encode`](Self::encode)
+    /// produces with the same `params` (speckled scans come out larger than
+    /// estimated); decibel and byte limits, MMR masks, hidden text and
+    /// annotations are not modelled. Useful for picking parameters before committing to a full
+    /// encode.
+    pub fn estimated_size(&self, params: &PageEncodeParams) -> Result<usize> {
+        self.check_page_size()?;
+        // AT&T magic, FORM:DJVU header and the INFO chunk
+        let mut size = 4 + 12 + 18;
+        for id in &self.includes {
+            size += 8 + id.len();
+        }
+        for (_, data) in &self.custom_chunks {
+            size += 8 + data.len().next_multiple_of(2);
+        }
+
+        let has_jb2 = self.foreground.is_some() || self.mask.is_some() || self.jb2_shapes.is_some();
+        let slices = params.slices.unwrap_or(74);
+        let mut has_bg = true;
+        if let Some(jpeg) = &self.jpeg_background {
+            size += 8 + jpeg.len();
+        } else if let (Some(bg), true) = (&self.background, params.use_iw44) {
+            size += 8 + self.background_encoder(bg, params)?.estimated_size(slices);
+        } else if has_jb2 && params.emit_bg_for_bilevel && self.includes.is_empty() {
+            let white = Pixmap::from_pixel(self.width, self.height, Pixel::white());
+            size += 8 + self
+                .background_encoder(&white, params)?
+                .estimated_size(slices);
+        } else {
+            has_bg = false;
+        }
+
+        let (shapes, blits, edges) =
+            if let (Some(shapes), Some(blits)) = (&self.jb2_shapes, &self.jb2_blits) {
+                let edges: usize = shapes.iter().map(edge_count).sum();
+                (shapes.len(), blits.len(), edges)
+            } else if let Some(stencil) = self.mask.as_ref().or(self.foreground.as_ref()) {
+                use crate::encode::jb2::{analyze_page_in_order, shapes_to_encoder_format};
+                let cleaned = params.mask_morphology.map(|m| m.apply(stencil));
+                let cc_image = analyze_page_in_order(
+                    cleaned.as_ref().unwrap_or(stencil),
+                    params.dpi as i32,
+                    1,
+                    params.reading_order,
+                );
+                let (dictionary, _, blits) =
+                    shapes_to_encoder_format(cc_image.extract_shapes(), self.height as i32)
+                        .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
+                let edges: usize = dictionary.iter().map(edge_count).sum();
+                (dictionary.len(), blits.len(), edges)
+            } else {
+                (0, 0, 0)
+            };
+        if shapes > 0 || blits > 0 {
+            // Shape bitmaps dominate and cost about what their outlines do:
+            // from half a bit per edge for straight strokes to one for
+            // curves, more for noisy scans. Blit and shape headers are lost
+            // in that spread.
+            let bits = edges as f64 * 0.65;
+            // Sjbz header and start-of-image record
+            size += 8 + 20 + (bits / 8.0) as usize;
+            if let Some(fg) = &self.iw44_foreground {
+                size += 8 + foreground_encoder(fg)?.estimated_size(FOREGROUND_SLICES);
+            } else if has_bg {
+                // FGbz palette and BZZ-packed blit indices
+                size += 8 + 32;
+            }
+        }
+        Ok(size)
+    }
+
     /// Encodes the page to a byte vector using the given parameters
     pub fn encode(
         &self,
@@ -437,6 +1297,9 @@ impl PageComponents {
         rotation: u8,       // 1=0°, 6=90°CCW, 2=180°, 5=90°CW
         gamma: Option<f32>, // If None, use 2.2
     ) -> Result<Vec<u8>> {
+        params.check_cancelled()?;
+        params.bzz.validate()?;
+        self.check_page_size()?;
         let mut output = Vec::new();
         {
             let mut cursor = io::Cursor::new(&mut output);
@@ -449,30 +1312,37 @@ impl PageComponents {
             writer.put_chunk("FORM:DJVU")?;
 
             // Write INFO chunk (required for all pages)
-            self.write_info_chunk(
-                &mut writer,
-                params.dpi as u16,
-                page_num,
-                dpm,
-                rotation,
-                gamma,
-            )?;
+            self.write_info_chunk(&mut writer, params, page_num, dpm, rotation, gamma)?;
+
+            // --- INCL: references to shared include files ---
+            for id in &self.includes {
+                writer.put_chunk("INCL")?;
+                writer.write_all(id.as_bytes())?;
+                writer.close_chunk()?;
+            }
 
             // --- BG44: Always emit a blank background for bitonal/JB2 pages ---
             let mut wrote_bg44 = false;
-            if let Some(bg_img) = &self.background {
+            if let Some(jpeg) = &self.jpeg_background {
+                writer.put_chunk("BGjp")?;
+                writer.write_all(jpeg)?;
+                writer.close_chunk()?;
+                wrote_bg44 = true;
+            } else if let Some(bg_img) = &self.background {
                 if params.use_iw44 {
                     self.encode_iw44_background(bg_img, &mut writer, params)?;
                     wrote_bg44 = true;
                 } else {
-                    return Err(DjvuError::InvalidOperation(
-                        "JB2 background encoding requires a bitonal image. Use foreground instead."
+                    return Err(DjvuError::Unsupported(
+                        "JB2 encoding of a color background; use IW44 or supply the layer as foreground"
                             .to_string(),
                     ));
                 }
             }
             // If no background but JB2 content exists, emit an all-white BG44
             if !wrote_bg44
+                && params.emit_bg_for_bilevel
+                && self.includes.is_empty()
                 && (self.foreground.is_some() || self.mask.is_some() || self.jb2_shapes.is_some())
             {
                 let (w, h) = (self.width, self.height);
@@ -481,6 +1351,7 @@ impl PageComponents {
             }
 
             // --- Djbz + Sjbz: JB2 encoding ---
+            params.check_cancelled()?;
             let mut num_blits = 0;
             let mut encoded_sjbz: Option<Vec<u8>> = None;
 
@@ -508,7 +1379,7 @@ impl PageComponents {
                             0,
                             None,
                         )
-                        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+                        .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
 
                     encoded_sjbz = Some(sjbz_raw);
                     true
@@ -516,56 +1387,69 @@ impl PageComponents {
                     false
                 };
 
-            // Auto-extraction fallback (only if manual JB2 wasn't used)
-            if !_jb2_encoded {
-                if let Some(fg_img) = &self.foreground {
-                    // Auto-extract from foreground (requires symboldict feature)
-                    use crate::encode::jb2::{
-                        analyze_page, encoder::JB2Encoder, shapes_to_encoder_format,
-                    };
+            // Auto-extraction fallback (only if manual JB2 wasn't used). The
+            // mask is the stencil when present; a foreground next to it only
+            // picks the color of each shape. A stencil without a single set
+            // pixel has nothing to code, so the page gets no Sjbz (nor the
+            // colors that would go with it) rather than an empty JB2 image.
+            let mut blit_colors = None;
+            let stencil = if _jb2_encoded {
+                None
+            } else {
+                self.mask.as_ref().or(self.foreground.as_ref())
+            };
+            let blank_stencil = stencil.is_some_and(|s| s.count_set_bits() == 0);
+            let stencil = stencil.filter(|_| !blank_stencil);
+            let mut mask_chunk = "Sjbz";
+            if let (Some(stencil), MaskCodec::Mmr) = (stencil, params.mask_codec) {
+                if self.colors_shapes() {
+                    return Err(DjvuError::Unsupported(
+                        "per-shape foreground colors need a JB2 mask; use MaskCodec::Jb2"
+                            .to_string(),
+                    ));
+                }
+                let cleaned = params.mask_morphology.map(|m| m.apply(stencil));
+                let smmr = crate::encode::mmr::encode_smmr(cleaned.as_ref().unwrap_or(stencil))
+                    .map_err(|e| DjvuError::encoding(EncodeStage::Mmr, e))?;
+                encoded_sjbz = Some(smmr);
+                mask_chunk = "Smmr";
+            } else if let Some(stencil) = stencil {
+                use crate::encode::jb2::{
+                    analyze_page_in_order, find_refinement_parents, shapes_to_encoder_format,
+                };
 
-                    let mut page_encoder = JB2Encoder::new(Vec::new());
+                let mut page_encoder = JB2Encoder::new(Vec::new());
+                let cleaned = params.mask_morphology.map(|m| m.apply(stencil));
+                let stencil = cleaned.as_ref().unwrap_or(stencil);
 
-                    // Run connected component analysis
-                    let dpi = 300;
-                    let losslevel = 1;
-                    let cc_image = analyze_page(fg_img, dpi, losslevel);
-                    let shapes = cc_image.extract_shapes();
-                    let (dictionary, parents, blits) =
-                        shapes_to_encoder_format(shapes, self.height as i32);
-                    num_blits = blits.len();
-
-                    // --- Sjbz ---
+                if self.direct_jb2(stencil, params.jb2_coding) {
+                    // One bitmap record, no symbol extraction or clustering.
+                    // Decoders count its non-mark data as a blit.
+                    num_blits = 1;
                     let sjbz_raw = page_encoder
-                        .encode_page_with_shapes(
-                            self.width,
-                            self.height,
-                            &dictionary,
-                            &parents,
-                            &blits,
-                            0,
-                            None,
-                        )
-                        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
-
+                        .encode_single_page(stencil)
+                        .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
                     encoded_sjbz = Some(sjbz_raw);
-                } else if let Some(mask_img) = &self.mask {
-                    // Auto-extract from mask (requires symboldict feature)
-                    use crate::encode::jb2::{
-                        analyze_page, encoder::JB2Encoder, shapes_to_encoder_format,
-                    };
-
-                    let mut page_encoder = JB2Encoder::new(Vec::new());
-
+                } else {
                     // Run connected component analysis
-                    let dpi = 300;
+                    let dpi = params.dpi as i32;
                     let losslevel = 1;
-                    let cc_image = analyze_page(mask_img, dpi, losslevel);
+                    let order = params.reading_order;
+                    let cc_image = analyze_page_in_order(stencil, dpi, losslevel, order);
                     let shapes = cc_image.extract_shapes();
-                    let (dictionary, parents, blits) =
-                        shapes_to_encoder_format(shapes, self.height as i32);
+                    let (dictionary, mut parents, blits) =
+                        shapes_to_encoder_format(shapes, self.height as i32)
+                            .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
+                    if params.jb2_refinement {
+                        parents = find_refinement_parents(&dictionary, &blits);
+                    }
                     num_blits = blits.len();
 
+                    if let Some(fg_img) = self.foreground.as_ref().filter(|_| self.colors_shapes())
+                    {
+                        blit_colors = Some(self.blit_colors(&dictionary, &blits, fg_img));
+                    }
+
                     // --- Sjbz ---
                     let sjbz_raw = page_encoder
                         .encode_page_with_shapes(
@@ -577,7 +1461,7 @@ impl PageComponents {
                             0,
                             None,
                         )
-                        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+                        .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
 
                     encoded_sjbz = Some(sjbz_raw);
                 }
@@ -586,20 +1470,40 @@ impl PageComponents {
             // --- FGbz: Foreground colors for compound images ---
             // Must be written BEFORE Sjbz to inform viewer of colors?
             // Spec says no strict order, but standard is BG44 -> FGbz -> Sjbz.
+            // An FG44 color layer takes the place of FGbz.
 
             let has_jb2 = encoded_sjbz.is_some();
-            if wrote_bg44 && has_jb2 {
+            if let Some(fg) = self.iw44_foreground.as_ref().filter(|_| !blank_stencil) {
+                if !has_jb2 {
+                    return Err(DjvuError::InvalidOperation(
+                        "An IW44 foreground needs a mask or foreground bitmap to color".to_string(),
+                    ));
+                }
+                self.foreground_reduction(fg)?;
+                params.check_cancelled()?;
+                let fg44 = encode_iw44_foreground(fg)?;
+                writer.put_chunk("FG44")?;
+                writer.write_all(&fg44)?;
+                writer.close_chunk()?;
+            } else if wrote_bg44 && has_jb2 {
                 // Determine if we have blits to color
                 if num_blits > 0 {
+                    // Palette: black, plus the foreground color for shapes it selects
+                    let (colors, indices) = match blit_colors {
+                        Some(indices) => (vec![Pixel::black(), self.foreground_color], indices),
+                        None => (vec![Pixel::black()], vec![0; num_blits]),
+                    };
+
                     // Write FGbz with correspondence (Version 0x80 | 0)
                     writer.put_chunk("FGbz")?;
 
                     // Version 0 with correspondence bit (0x80)
                     writer.write_u8(0x80)?;
 
-                    // Palette size: 1 (black)
-                    writer.write_u16::<BigEndian>(1)?;
-                    writer.write_all(&[0x00, 0x00, 0x00])?; // Black BGR
+                    writer.write_u16::<BigEndian>(colors.len() as u16)?;
+                    for color in &colors {
+                        writer.write_all(&[color.b, color.g, color.r])?; // BGR
+                    }
 
                     // Correspondence Data (per DjVuPalette.cpp)
                     // nDataSize: INT24 = number of blits (NOT compressed size)
@@ -608,17 +1512,12 @@ impl PageComponents {
                     writer.write_u8(((n >> 8) & 0xFF) as u8)?;
                     writer.write_u8((n & 0xFF) as u8)?;
 
-                    // Indices: BZZ encoded stream of INT16 indices (big-endian)
-                    // Since we have only 1 color (index 0), all blits get index 0.
-                    // Each index is written as a 16-bit big-endian integer.
-                    let mut index_bytes = Vec::with_capacity(num_blits * 2);
-                    for _ in 0..num_blits {
-                        index_bytes.push(0u8); // High byte of index 0
-                        index_bytes.push(0u8); // Low byte of index 0
-                    }
-                    let compressed_indices = bzz_compress(&index_bytes, 50).map_err(|e| {
-                        DjvuError::EncodingError(format!("FGbz compression failed: {e}"))
-                    })?;
+                    // Indices: BZZ encoded stream of INT16 indices (big-endian),
+                    // one per blit in coding order.
+                    let index_bytes: Vec<u8> =
+                        indices.iter().flat_map(|i| i.to_be_bytes()).collect();
+                    let compressed_indices = bzz_compress(&index_bytes, params.bzz.palette)
+                        .map_err(|e| DjvuError::encoding(EncodeStage::Palette, e))?;
                     writer.write_all(&compressed_indices)?;
 
                     writer.close_chunk()?;
@@ -636,14 +1535,16 @@ impl PageComponents {
                 }
             }
 
-            // --- Write Delayed Sjbz ---
+            // --- Write Delayed Sjbz (or Smmr) ---
             if let Some(sjbz_data) = encoded_sjbz {
-                // Write raw JB2 stream (already ZP-compressed, no BZZ needed)
-                writer.put_chunk("Sjbz")?;
+                // Write raw JB2 or G4 stream (already compressed, no BZZ needed)
+                writer.put_chunk(mask_chunk)?;
                 writer.write_all(&sjbz_data)?;
                 writer.close_chunk()?;
             }
 
+            params.check_cancelled()?;
+
             // --- TXTa/TXTz: Hidden text layer ---
             // NOTE: Text layer encoding is NON-FATAL. If it fails, we skip the TXTz chunk
             // rather than failing the entire page. This prevents OCR coordinate issues
@@ -652,22 +1553,19 @@ impl PageComponents {
                 let mut txt_buf = Vec::new();
                 let tl = text_layer;
                 match tl.encode(&mut txt_buf) {
-                    Ok(()) => {
-                        // Use BZZ compression for DJVU spec compliance (100KB blocks)
-                        match bzz_compress(&txt_buf, 100) {
-                            Ok(data) => {
-                                writer.put_chunk("TXTz")?;
-                                writer.write_all(&data)?;
-                                writer.close_chunk()?;
-                            }
-                            Err(_e) => {
-                                #[cfg(feature = "debug-logging")]
-                                eprintln!(
-                                    "[page_encoder] Warning: BZZ compression for TXTz failed: {e}. Skipping text layer."
-                                );
-                            }
+                    Ok(()) => match bzz_compress(&txt_buf, params.bzz.text) {
+                        Ok(data) => {
+                            writer.put_chunk("TXTz")?;
+                            writer.write_all(&data)?;
+                            writer.close_chunk()?;
                         }
-                    }
+                        Err(_e) => {
+                            #[cfg(feature = "debug-logging")]
+                            eprintln!(
+                                "[page_encoder] Warning: BZZ compression for TXTz failed: {e}. Skipping text layer."
+                            );
+                        }
+                    },
                     Err(_e) => {
                         // Log but don't fail - page will still be viewable without searchable text
                         #[cfg(feature = "debug-logging")]
@@ -679,15 +1577,13 @@ impl PageComponents {
             }
 
             // --- ANTa/ANTz: Hyperlink/annotation layer ---
-            if let Some(annotations) = &self.annotations {
+            if let Some(annotations) = self.merged_annotations() {
                 let mut ann_buf = Vec::new();
-                annotations.encode(&mut ann_buf).map_err(|e| {
-                    DjvuError::InvalidOperation(format!("Failed to encode annotations: {e}"))
-                })?;
-                // Use BZZ compression for DJVU spec compliance (100KB blocks)
-                let data = bzz_compress(&ann_buf, 100).map_err(|e| {
-                    DjvuError::EncodingError(format!("BZZ compression failed: {e}"))
-                })?;
+                annotations
+                    .encode(&mut ann_buf, self.height)
+                    .map_err(|e| DjvuError::encoding(EncodeStage::Annotations, e))?;
+                let data = bzz_compress(&ann_buf, params.bzz.annotations)
+                    .map_err(|e| DjvuError::encoding(EncodeStage::Annotations, e))?;
                 writer.put_chunk("ANTz")?;
                 writer.write_all(&data)?;
                 writer.close_chunk()?;
@@ -698,18 +1594,83 @@ impl PageComponents {
                 self.write_text_chunk(text, &mut writer)?;
             }
 
+            // --- TH44: Embedded page thumbnail ---
+            if let Some(size) = self.thumbnail_size {
+                params.check_cancelled()?;
+                let thumbnail = self.encode_thumbnail(size)?;
+                writer.put_chunk("TH44")?;
+                writer.write_all(&thumbnail)?;
+                writer.close_chunk()?;
+            }
+
+            // --- Custom chunks, after everything viewers understand ---
+            for (id, data) in &self.custom_chunks {
+                writer.write_chunk(*id, data)?;
+            }
+
             // Close the FORM:DJVU chunk
             writer.close_chunk()?;
         }
         Ok(output)
     }
 
+    /// Palette index of each blit: 1 if the shape covers any black pixel of
+    /// `fg`, else 0.
+    fn blit_colors(
+        &self,
+        dictionary: &[BitImage],
+        blits: &[(i32, i32, usize)],
+        fg: &BitImage,
+    ) -> Vec<u16> {
+        blits
+            .iter()
+            .map(|&(left, bottom, idx)| {
+                let shape = &dictionary[idx];
+                // Blits are bottom-up; bitmaps are top-down
+                let top = self.height as i32 - bottom - shape.height as i32;
+                let touches = (0..shape.height).any(|sy| {
+                    (0..shape.width).any(|sx| {
+                        let (x, y) = (left + sx as i32, top + sy as i32);
+                        shape.get_pixel_unchecked(sx, sy)
+                            && x >= 0
+                            && y >= 0
+                            && (x as usize) < fg.width
+                            && (y as usize) < fg.height
+                            && fg.get_pixel_unchecked(x as usize, y as usize)
+                    })
+                });
+                u16::from(touches)
+            })
+            .collect()
+    }
+
+    /// Encodes this page's background alone as a `FORM:DJVI` include file.
+    ///
+    /// Register the result with [`crate::DjvuDocument::add_include`] and
+    /// reference it from pages with [`PageComponents::with_include`].
+    pub fn encode_background_include(&self, params: &PageEncodeParams) -> Result<Vec<u8>> {
+        let background = self.background.as_ref().ok_or_else(|| {
+            DjvuError::InvalidOperation("No background to encode as an include".to_string())
+        })?;
+
+        let mut output = Vec::new();
+        {
+            let mut cursor = io::Cursor::new(&mut output);
+            let mut writer = IffWriter::new(&mut cursor);
+            writer.write_magic_bytes()?;
+            writer.put_chunk("FORM:DJVI")?;
+            self.encode_iw44_background(background, &mut writer, params)?;
+            writer.close_chunk()?;
+        }
+        Ok(output)
+    }
+
     /// Writes the INFO chunk as per DjVu spec (10 bytes)
     /// Format: width(2,BE) height(2,BE) minor_ver(1) major_ver(1) dpi(2,LE) gamma(1) flags(1)
     fn write_info_chunk(
         &self,
         writer: &mut IffWriter,
-        dpi: u16,
+        params: &PageEncodeParams,
         _page_num: u32,
         _dpm: u32,
         rotation: u8,       // 1=0°, 6=90°CCW, 2=180°, 5=90°CW
@@ -717,23 +1678,45 @@ impl PageComponents {
     ) -> Result<()> {
         use byteorder::LittleEndian;
 
+        check_dimension_limit(self.width, self.height)?;
+
+        let (major, minor) = params.info_version;
+        if major != 0 {
+            return Err(DjvuError::InvalidArg(format!(
+                "Unsupported DjVu major version {major} (only 0 is supported)"
+            )));
+        }
+        if minor > 26 {
+            return Err(DjvuError::InvalidArg(format!(
+                "Unsupported DjVu minor version {minor} (maximum is 26)"
+            )));
+        }
+
+        // Pixels are stored as given, so only the default 2.2 describes them
+        let gamma_val = gamma.map_or(22, |g| (g * 10.0 + 0.5) as u8); // Default gamma = 2.2
+        if gamma_val != 22 {
+            return Err(DjvuError::Unsupported(format!(
+                "gamma {} (pixels are not gamma-corrected; only 2.2 is supported)",
+                gamma.unwrap_or_default()
+            )));
+        }
+
         writer.put_chunk("INFO")?;
 
         // Width and height (2 bytes each, big-endian)
         writer.write_u16::<BigEndian>(self.width as u16)?;
         writer.write_u16::<BigEndian>(self.height as u16)?;
 
-        // Minor version (1 byte, currently 24 per C44 reference)
-        writer.write_u8(24)?;
+        // Minor version (1 byte)
+        writer.write_u8(minor)?;
 
         // Major version (1 byte, currently 0 per spec)
-        writer.write_u8(0)?;
+        writer.write_u8(major)?;
 
         // DPI (2 bytes, little-endian per spec)
-        writer.write_u16::<LittleEndian>(dpi)?;
+        writer.write_u16::<LittleEndian>(params.dpi as u16)?;
 
         // Gamma (1 byte, gamma * 10)
-        let gamma_val = gamma.map_or(22, |g| (g * 10.0 + 0.5) as u8); // Default gamma = 2.2
         writer.write_u8(gamma_val)?;
 
         // Flags (1 byte: bits 0-2 = rotation, bits 3-7 = reserved)
@@ -744,14 +1727,15 @@ impl PageComponents {
         Ok(())
     }
 
-    /// Encodes the background using IW44 (wavelet)
-    fn encode_iw44_background(
-        &self,
-        img: &Pixmap,
-        writer: &mut IffWriter,
-        params: &PageEncodeParams,
-    ) -> Result<()> {
-        let crcb_mode = if params.color {
+    /// Sets up the IW44 encoder for `img` the way the page's BG44 chunks are
+    /// written: color mode, chroma delay and the mask all follow `params`.
+    fn background_encoder(&self, img: &Pixmap, params: &PageEncodeParams) -> Result<IWEncoder> {
+        let is_color = match params.color {
+            ColorMode::Color => true,
+            ColorMode::Gray => false,
+            ColorMode::Auto => !is_grayscale_rgb(img.as_raw(), params.chroma_threshold),
+        };
+        let crcb_mode = if is_color {
             // C++ c44.exe uses CRCBnormal by default, not CRCBfull
             crate::encode::iw44::encoder::CrcbMode::Normal
         } else {
@@ -787,6 +1771,7 @@ impl PageComponents {
             db_frac: params.db_frac,
             lossless: params.lossless,
             quant_multiplier: params.quant_multiplier.unwrap_or(1.0),
+            ..IW44EncoderParams::default()
         };
 
         // If a mask is present, convert it to Bitmap and pass to IWEncoder for mask-aware encoding
@@ -813,23 +1798,28 @@ impl PageComponents {
             debug!("Using mask-aware IW44 encoding for background");
         }
 
-        let mut encoder = if params.color {
+        if is_color {
             IWEncoder::from_rgb(img, mask_gray.as_ref(), iw44_params)
         } else {
             let gray = img.to_bitmap();
             IWEncoder::from_gray(&gray, mask_gray.as_ref(), iw44_params)
         }
-        .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+        .map_err(|e| DjvuError::encoding(EncodeStage::Iw44, e))
+    }
 
-        // Choose the correct chunk type for IW44 background images:
-        // - BG44 for background layer (the main use case for IW44 in DjVu pages)
-        // - FG44 for foreground layer (has mask)
+    /// Encodes the background using IW44 (wavelet)
+    fn encode_iw44_background(
+        &self,
+        img: &Pixmap,
+        writer: &mut IffWriter,
+        params: &PageEncodeParams,
+    ) -> Result<()> {
+        let mut encoder = self.background_encoder(img, params)?;
+
+        // The background is always BG44, masked or not: the mask is the JB2
+        // stencil over it, not a foreground color layer (FG44).
         // Note: PM44/BM44 are for standalone IW44 files, not DjVu page backgrounds
-        let iw_chunk_id = if self.mask.is_some() {
-            "FG44"
-        } else {
-            "BG44" // Use BG44 for background images in DjVu pages
-        };
+        let iw_chunk_id = "BG44";
 
         // Encode and write IW44 data - use consistent slice limit for all chunks
         let mut chunk_count = 0;
@@ -838,6 +1828,8 @@ impl PageComponents {
         let total_slices_target = slices_per_chunk; // For now, match first chunk limit
 
         loop {
+            params.check_cancelled()?;
+
             // Check if we've reached total slice target
             if total_slices_encoded >= total_slices_target {
                 debug!(
@@ -850,7 +1842,7 @@ impl PageComponents {
             // Use consistent slice limit for all chunks
             let (iw44_stream, more) = encoder
                 .encode_chunk(slices_per_chunk)
-                .map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+                .map_err(|e| DjvuError::encoding(EncodeStage::Iw44, e))?;
 
             if iw44_stream.is_empty() {
                 break;
@@ -875,6 +1867,73 @@ impl PageComponents {
         Ok(())
     }
 
+    /// Whether stencil shapes take colors from the foreground: it sits next
+    /// to a mask it differs from, or colors shapes other than black. A
+    /// foreground equal to the mask would draw every shape black anyway.
+    fn colors_shapes(&self) -> bool {
+        match (&self.mask, &self.foreground) {
+            (Some(mask), Some(fg)) => mask != fg || self.foreground_color != Pixel::black(),
+            _ => false,
+        }
+    }
+
+    /// Whether `stencil` is coded as a single JB2 bitmap under `coding`.
+    ///
+    /// Never for stencils that don't cover the page or whose shapes take
+    /// colors from a foreground. In [`Jb2Coding::Auto`] mode only for a
+    /// single blob: one run per row over a contiguous band of rows, each run
+    /// touching the one above. Connected-component analysis would find that
+    /// same blob and only split it up again.
+    fn direct_jb2(&self, stencil: &BitImage, coding: Jb2Coding) -> bool {
+        if (stencil.width, stencil.height) != (self.width as usize, self.height as usize)
+            || self.colors_shapes()
+        {
+            return false;
+        }
+        match coding {
+            Jb2Coding::Symbols => false,
+            Jb2Coding::Direct => true,
+            Jb2Coding::Auto => is_single_blob(stencil),
+        }
+    }
+
+    /// Renders the page at most `size` pixels on its longer side and encodes
+    /// it as a single IW44 chunk.
+    ///
+    /// Each thumbnail pixel averages the block of page pixels it covers,
+    /// taking stencil pixels as the foreground color and the rest from the
+    /// background (white when there is none).
+    fn encode_thumbnail(&self, size: u32) -> Result<Vec<u8>> {
+        let (w, h) = (self.width, self.height);
+        let (tw, th) = thumbnail_dimensions((w, h), size)?;
+
+        let stencil = self.mask.as_ref().or(self.foreground.as_ref());
+        let fg44 = match &self.iw44_foreground {
+            Some(fg) => Some((fg, self.foreground_reduction(fg)?)),
+            None => None,
+        };
+        let ink = |x: u32, y: u32| match fg44 {
+            Some((fg, r)) => fg.get_pixel(x / r, y / r),
+            None if self.mask.is_some() && self.foreground.is_some() => self.foreground_color,
+            None => Pixel::black(),
+        };
+        // Box-filter the page as it would be drawn
+        let thumbnail = resample_area((w, h), (tw, th), |x, y| {
+            let inked = stencil.is_some_and(|s| {
+                (x as usize) < s.width
+                    && (y as usize) < s.height
+                    && s.get_pixel_unchecked(x as usize, y as usize)
+            });
+            match &self.background {
+                _ if inked => ink(x, y),
+                Some(bg) if x < bg.width() && y < bg.height() => bg.get_pixel(x, y),
+                _ => Pixel::white(),
+            }
+        });
+
+        encode_th44(&thumbnail)
+    }
+
     /// Encodes the foreground using JB2
     fn _encode_jb2_foreground(
         &self,
@@ -884,11 +1943,13 @@ impl PageComponents {
     ) -> Result<()> {
         // Create JB2 encoder and encode as single page (non-symbol data)
         let mut jb2_encoder = JB2Encoder::new(Vec::new());
-        let jb2_raw = jb2_encoder.encode_single_page(img)?;
+        let jb2_raw = jb2_encoder
+            .encode_single_page(img)
+            .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
 
         // BZZ-compress the JB2 data as required by DjVu spec (§3.2.5)
         let sjbz_payload =
-            bzz_compress(&jb2_raw, 256).map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+            bzz_compress(&jb2_raw, 256).map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
 
         // Write Sjbz chunk for JB2 bitmap data (shapes and positions)
         // Note: FGbz is for JB2 colors, Sjbz is for the actual bitmap content
@@ -903,11 +1964,13 @@ impl PageComponents {
     fn _encode_jb2_mask(&self, img: &BitImage, writer: &mut IffWriter) -> Result<()> {
         // Create JB2 encoder and encode as single page (non-symbol data)
         let mut jb2_encoder = JB2Encoder::new(Vec::new());
-        let jb2_raw = jb2_encoder.encode_single_page(img)?;
+        let jb2_raw = jb2_encoder
+            .encode_single_page(img)
+            .map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
 
         // BZZ-compress the JB2 data as required by DjVu spec
         let sjbz_payload =
-            bzz_compress(&jb2_raw, 256).map_err(|e| DjvuError::EncodingError(e.to_string()))?;
+            bzz_compress(&jb2_raw, 256).map_err(|e| DjvuError::encoding(EncodeStage::Jb2, e))?;
 
         // Write Sjbz chunk
         writer.put_chunk("Sjbz")?;
@@ -964,6 +2027,795 @@ mod tests {
         assert!(encoded.windows(4).any(|w| w == b"TXTa"));
     }
 
+    #[test]
+    fn test_info_version_bytes() {
+        let page = PageComponents::new()
+            .with_background(Pixmap::from_pixel(16, 16, Pixel::white()))
+            .unwrap();
+
+        let params = PageEncodeParams {
+            info_version: (0, 24),
+            ..PageEncodeParams::default()
+        };
+        let encoded = page.encode(&params, 1, 300, 1, None).unwrap();
+
+        // INFO payload: width(2) height(2) minor(1) major(1) ...
+        let info = encoded.windows(4).position(|w| w == b"INFO").unwrap();
+        let payload = &encoded[info + 8..];
+        assert_eq!(payload[4], 24);
+        assert_eq!(payload[5], 0);
+
+        let bad = PageEncodeParams {
+            info_version: (1, 0),
+            ..PageEncodeParams::default()
+        };
+        assert!(matches!(
+            page.encode(&bad, 1, 300, 1, None),
+            Err(DjvuError::InvalidArg(_))
+        ));
+    }
+
+    #[test]
+    fn test_include_emits_incl_chunk() {
+        let mut fg = BitImage::new(32, 32).unwrap();
+        fg.set_usize(4, 4, true);
+        let page = PageComponents::new()
+            .with_foreground(fg)
+            .unwrap()
+            .with_include("letterhead.djvi");
+        let encoded = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+
+        let pos = encoded.windows(4).position(|w| w == b"INCL").unwrap();
+        let len = u32::from_be_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        assert_eq!(&encoded[pos + 8..pos + 8 + len], b"letterhead.djvi");
+        // The include provides the background, so no blank BG44 is added
+        assert!(!encoded.windows(4).any(|w| w == b"BG44"));
+    }
+
+    #[test]
+    fn test_custom_chunk_is_written_last() {
+        let mut fg = BitImage::new(32, 32).unwrap();
+        fg.set_usize(4, 4, true);
+        let json = br#"{"columns":1}"#.to_vec();
+        let page = PageComponents::new()
+            .with_foreground(fg)
+            .unwrap()
+            .with_custom_chunk(*b"META", json.clone())
+            .unwrap();
+        let encoded = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+
+        // Odd-sized, so it ends with a pad byte closing the page
+        let pos = encoded.windows(4).position(|w| w == b"META").unwrap();
+        assert_eq!(
+            &encoded[pos + 4..pos + 8],
+            &(json.len() as u32).to_be_bytes()
+        );
+        assert_eq!(&encoded[pos + 8..pos + 8 + json.len()], &json[..]);
+        assert_eq!(encoded.len(), pos + 8 + json.len() + 1);
+        assert_eq!(crate::validate::validate_page(&encoded), Vec::new());
+
+        for id in [*b"ANTz", *b"FORM", *b"ME\0A", *b"M\xC9TA", *b"AB:C"] {
+            assert!(matches!(
+                PageComponents::new().with_custom_chunk(id, Vec::new()),
+                Err(DjvuError::InvalidArg(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_bilevel_page_without_background() {
+        let mut mask = BitImage::new(600, 800).unwrap();
+        for i in 0..20 {
+            for y in 100..130 {
+                for x in (40 + i * 25)..(55 + i * 25) {
+                    mask.set_usize(x, y, true);
+                }
+            }
+        }
+        let page = PageComponents::new().with_foreground(mask).unwrap();
+
+        let with_bg = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+        let params = PageEncodeParams {
+            emit_bg_for_bilevel: false,
+            ..PageEncodeParams::default()
+        };
+        let bitonal = page.encode(&params, 1, 300, 1, None).unwrap();
+
+        assert!(with_bg.windows(4).any(|w| w == b"BG44"));
+        assert!(!bitonal.windows(4).any(|w| w == b"BG44"));
+        assert!(bitonal.windows(4).any(|w| w == b"INFO"));
+        assert!(bitonal.windows(4).any(|w| w == b"Sjbz"));
+        assert!(bitonal.len() * 2 < with_bg.len());
+    }
+
+    #[test]
+    fn test_mmr_mask_codec() {
+        let mut mask = BitImage::new(120, 40).unwrap();
+        for y in 10..30 {
+            for x in (5..115).filter(|x| x % 12 < 7) {
+                mask.set_usize(x, y, true);
+            }
+        }
+        let params = PageEncodeParams {
+            mask_codec: MaskCodec::Mmr,
+            ..PageEncodeParams::bitonal()
+        };
+        let page = PageComponents::new().with_foreground(mask.clone()).unwrap();
+        let data = page.encode(&params, 1, 300, 1, None).unwrap();
+
+        assert!(!data.windows(4).any(|w| w == b"Sjbz"));
+        let at = data.windows(4).position(|w| w == b"Smmr").unwrap();
+        let len = u32::from_be_bytes(data[at + 4..at + 8].try_into().unwrap()) as usize;
+        assert_eq!(
+            &data[at + 8..at + 8 + len],
+            &crate::encode::mmr::encode_smmr(&mask).unwrap()[..]
+        );
+
+        // A foreground equal to the mask draws every shape black
+        let same = PageComponents::new()
+            .with_mask(mask.clone())
+            .unwrap()
+            .with_foreground(mask.clone())
+            .unwrap();
+        let data = same.encode(&params, 1, 300, 1, None).unwrap();
+        assert!(data.windows(4).any(|w| w == b"Smmr"));
+
+        // Shapes colored by a different foreground need JB2
+        let mut some = BitImage::new(120, 40).unwrap();
+        some.set_usize(5, 10, true);
+        let both = PageComponents::new()
+            .with_mask(mask)
+            .unwrap()
+            .with_foreground(some)
+            .unwrap();
+        assert!(matches!(
+            both.encode(&params, 1, 300, 1, None),
+            Err(DjvuError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_estimated_size_tracks_content() {
+        let params = PageEncodeParams::default();
+        let blank = PageComponents::new()
+            .with_background(Pixmap::from_pixel(400, 300, Pixel::white()))
+            .unwrap();
+        let blank_estimate = blank.estimated_size(&params).unwrap();
+        assert!(
+            blank_estimate < 200,
+            "blank page estimated at {blank_estimate}"
+        );
+
+        let mut seed = 1u32;
+        let busy = Pixmap::from_fn(400, 300, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let noise = (seed >> 24) as u8 / 2;
+            Pixel::new(
+                (x as u8).wrapping_add(noise),
+                (y as u8) ^ noise,
+                noise.wrapping_mul(3),
+            )
+        });
+        let photo = PageComponents::new().with_background(busy).unwrap();
+        let photo_estimate = photo.estimated_size(&params).unwrap();
+        assert!(photo_estimate > 20 * blank_estimate);
+
+        let within_twice = |page: &PageComponents, params: &PageEncodeParams| {
+            let estimate = page.estimated_size(params).unwrap() as f64;
+            let actual = page.encode(params, 1, 300, 1, None).unwrap().len() as f64;
+            assert!(
+                (actual / 2.0..=actual * 2.0).contains(&estimate),
+                "estimated {estimate} bytes, encoded {actual}"
+            );
+        };
+        within_twice(&photo, &params);
+
+        // Rows of glyphs made of strokes and rings
+        let mut text = BitImage::new(600, 400).unwrap();
+        for (i, (x0, y0)) in (0..14)
+            .flat_map(|row| (0..30).map(move |col| (10 + col * 19, 10 + row * 27)))
+            .enumerate()
+        {
+            for (x, y) in (0..12).flat_map(|x| (0..20).map(move |y| (x, y))) {
+                let on = match i * 7 % 5 {
+                    0 => x < 2 || y < 2,
+                    1 => ((x as i32 - 6).pow(2) + (y as i32 - 10).pow(2) - 24).abs() < 10,
+                    2 => x == y / 2 || x == 11 - y / 2,
+                    3 => y > 17 || x < 3,
+                    _ => x > 8 || y % 9 < 2,
+                };
+                text.set_usize(x0 + x, y0 + y, on);
+            }
+        }
+        let bilevel = PageComponents::new().with_foreground(text).unwrap();
+        within_twice(&bilevel, &PageEncodeParams::bitonal());
+    }
+
+    #[test]
+    fn test_presets() {
+        let photo = PageEncodeParams::photo();
+        assert!(photo.use_iw44);
+        assert_eq!(photo.color, ColorMode::Color);
+        assert_eq!(photo.decibels, Some(48.0));
+        assert_eq!(photo.slices, Some(100));
+
+        let scanned = PageEncodeParams::scanned_document();
+        assert!(scanned.use_iw44);
+        assert_eq!(scanned.color, ColorMode::Auto);
+        assert_eq!((scanned.bg_quality, scanned.fg_quality), (75, 90));
+        assert!(scanned.emit_bg_for_bilevel);
+
+        let bitonal = PageEncodeParams::bitonal();
+        assert!(!bitonal.use_iw44);
+        assert!(!bitonal.emit_bg_for_bilevel);
+
+        let archive = PageEncodeParams::compressed_archive();
+        assert_eq!(archive.slices, Some(52));
+        assert_eq!(archive.quant_multiplier, Some(1.5));
+        assert!(archive.bg_quality < scanned.bg_quality);
+    }
+
+    #[test]
+    fn test_bitonal_preset_skips_iw44() {
+        let mut mask = BitImage::new(200, 100).unwrap();
+        for y in 20..60 {
+            for x in 30..90 {
+                mask.set_usize(x, y, true);
+            }
+        }
+        let page = PageComponents::new().with_foreground(mask).unwrap();
+        let data = page
+            .encode(&PageEncodeParams::bitonal(), 1, 300, 1, None)
+            .unwrap();
+
+        assert!(data.windows(4).any(|w| w == b"Sjbz"));
+        assert!(!data.windows(4).any(|w| w == b"BG44"));
+        assert!(!data.windows(4).any(|w| w == b"FG44"));
+    }
+
+    #[test]
+    fn test_solid_mask_is_coded_directly() {
+        let chunk = |encoded: &[u8], id: &[u8]| {
+            let pos = encoded.windows(4).position(|w| w == id).unwrap();
+            let len = u32::from_be_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            encoded[pos + 8..pos + 8 + len].to_vec()
+        };
+        let sjbz = |mask: &BitImage, coding: Jb2Coding| {
+            let params = PageEncodeParams {
+                jb2_coding: coding,
+                emit_bg_for_bilevel: false,
+                ..PageEncodeParams::default()
+            };
+            let encoded = PageComponents::new()
+                .with_mask(mask.clone())
+                .unwrap()
+                .encode(&params, 1, 300, 1, None)
+                .unwrap();
+            chunk(&encoded, b"Sjbz")
+        };
+
+        let mut solid = BitImage::new(400, 300).unwrap();
+        for y in 50..250 {
+            for x in 30..370 {
+                solid.set_usize(x, y, true);
+            }
+        }
+        assert!(is_single_blob(&solid));
+        // The page stream is exactly the single-bitmap coding of the mask,
+        // so no symbols were extracted or clustered
+        let direct = JB2Encoder::new(Vec::new())
+            .encode_single_page(&solid)
+            .unwrap();
+        assert_eq!(sjbz(&solid, Jb2Coding::Auto), direct);
+        assert_ne!(sjbz(&solid, Jb2Coding::Symbols), direct);
+        let decoded = crate::encode::jb2::decoder::decode(&direct, &[]).unwrap();
+        assert_eq!(decoded.blits.len(), 1);
+        assert_eq!(decoded.render().unwrap(), solid);
+
+        // Over a background, the one blit gets its palette index
+        let encoded = PageComponents::new()
+            .with_background(Pixmap::from_pixel(400, 300, Pixel::white()))
+            .unwrap()
+            .with_mask(solid.clone())
+            .unwrap()
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+        assert_eq!(chunk(&encoded, b"Sjbz"), direct);
+        let fgbz = chunk(&encoded, b"FGbz");
+        assert_eq!(fgbz[0], 0x80);
+        assert_eq!(fgbz[6..9], [0, 0, 1]);
+        assert_eq!(crate::validate::validate_page(&encoded), Vec::new());
+
+        // Two separate blobs still go through symbol extraction
+        let mut two = solid.clone();
+        for x in 30..370 {
+            two.set_usize(x, 150, false);
+        }
+        assert!(!is_single_blob(&two));
+        let direct = JB2Encoder::new(Vec::new())
+            .encode_single_page(&two)
+            .unwrap();
+        assert_ne!(sjbz(&two, Jb2Coding::Auto), direct);
+        assert_eq!(sjbz(&two, Jb2Coding::Direct), direct);
+        let decoded = crate::encode::jb2::decoder::decode(&direct, &[]).unwrap();
+        assert_eq!(decoded.render().unwrap(), two);
+    }
+
+    #[test]
+    fn test_thumbnail_chunk() {
+        let bg = Pixmap::from_fn(300, 200, |x, y| Pixel::new(x as u8, y as u8, 128));
+        let mut mask = BitImage::new(300, 200).unwrap();
+        for x in 20..280 {
+            mask.set_usize(x, 100, true);
+        }
+        let page = PageComponents::new()
+            .with_background(bg)
+            .unwrap()
+            .with_mask(mask)
+            .unwrap()
+            .with_thumbnail(64);
+        let encoded = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+
+        // TH44 sits inside the page FORM, after INFO
+        let form_len = u32::from_be_bytes(encoded[8..12].try_into().unwrap()) as usize;
+        assert_eq!(&encoded[12..16], b"DJVU");
+        let pos = encoded.windows(4).position(|w| w == b"TH44").unwrap();
+        assert!(pos > 16 && pos < 12 + form_len);
+        let len = u32::from_be_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        assert!(pos + 8 + len <= 12 + form_len);
+
+        // IW44 primary header: serial, slices, major, minor, width, height
+        let th44 = &encoded[pos + 8..pos + 8 + len];
+        assert_eq!(th44[0], 0);
+        let width = u16::from_be_bytes([th44[4], th44[5]]);
+        let height = u16::from_be_bytes([th44[6], th44[7]]);
+        assert_eq!((width, height), (64, 42));
+
+        // Pages already smaller than the requested size keep their size
+        let small = PageComponents::new()
+            .with_background(Pixmap::from_pixel(40, 30, Pixel::white()))
+            .unwrap()
+            .with_thumbnail(64)
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+        let pos = small.windows(4).position(|w| w == b"TH44").unwrap();
+        assert_eq!(&small[pos + 12..pos + 16], &[0, 40, 0, 30]);
+    }
+
+    #[test]
+    fn test_iw44_foreground_chunks() {
+        let bg = Pixmap::from_fn(300, 200, |x, y| Pixel::new(255, x as u8, y as u8));
+        let mut mask = BitImage::new(300, 200).unwrap();
+        for y in 80..120 {
+            for x in 20..280 {
+                mask.set_usize(x, y, true);
+            }
+        }
+        // Reduced by 12: 300x200 becomes 25x17
+        let fg = Pixmap::from_fn(25, 17, |x, _| Pixel::new(0, 10 * x as u8, 200));
+        let page = PageComponents::new()
+            .with_background(bg)
+            .unwrap()
+            .with_mask(mask.clone())
+            .unwrap()
+            .with_iw44_foreground(fg.clone())
+            .unwrap();
+        let encoded = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+
+        let mut ids = Vec::new();
+        let mut pos = 16;
+        while pos + 8 <= encoded.len() {
+            let len = u32::from_be_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            ids.push(String::from_utf8_lossy(&encoded[pos..pos + 4]).into_owned());
+            if &encoded[pos..pos + 4] == b"FG44" {
+                // Primary IW44 header: a single color chunk at the reduced size
+                let fg44 = &encoded[pos + 8..pos + 8 + len];
+                assert_eq!(fg44[0], 0);
+                assert_eq!(&fg44[4..8], &[0, 25, 0, 17]);
+            }
+            pos += 8 + len + len % 2;
+        }
+        for id in ["Sjbz", "FG44", "BG44"] {
+            assert!(ids.iter().any(|i| i == id), "{id} missing from {ids:?}");
+        }
+        assert!(!ids.iter().any(|i| i == "FGbz"), "{ids:?}");
+
+        // Sizes that no reduction produces are rejected, as is a foreground
+        // without a stencil to color
+        let page = PageComponents::new().with_mask(mask).unwrap();
+        assert!(
+            page.with_iw44_foreground(Pixmap::from_pixel(26, 17, Pixel::black()))
+                .is_err()
+        );
+        let result = PageComponents::new()
+            .with_iw44_foreground(fg)
+            .unwrap()
+            .with_background(Pixmap::from_pixel(300, 200, Pixel::white()))
+            .unwrap()
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None);
+        assert!(matches!(result, Err(DjvuError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn test_jpeg_background_chunk() {
+        let rgb =
+            image::RgbImage::from_fn(40, 24, |x, y| image::Rgb([x as u8 * 6, y as u8 * 10, 90]));
+        let mut jpeg = Vec::new();
+        image::DynamicImage::ImageRgb8(rgb)
+            .write_to(&mut io::Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+
+        assert!(matches!(
+            PageComponents::new().with_jpeg_background(jpeg.clone(), 24, 40),
+            Err(DjvuError::InvalidArg(_))
+        ));
+        assert!(
+            PageComponents::new()
+                .with_jpeg_background(vec![0; 16], 40, 24)
+                .is_err()
+        );
+
+        let page = PageComponents::new()
+            .with_jpeg_background(jpeg.clone(), 40, 24)
+            .unwrap();
+        assert_eq!(page.dimensions(), (40, 24));
+        let encoded = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+
+        let pos = encoded.windows(4).position(|w| w == b"BGjp").unwrap();
+        let len = u32::from_be_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        assert_eq!(&encoded[pos + 8..pos + 8 + len], &jpeg[..]);
+        assert!(!encoded.windows(4).any(|w| w == b"BG44"));
+    }
+
+    /// Returns the IW44 major-version byte of the first BG44 chunk.
+    fn first_bg44_major(encoded: &[u8]) -> u8 {
+        let pos = encoded.windows(4).position(|w| w == b"BG44").unwrap();
+        // header: serial(1) slices(1) major(1) ...
+        encoded[pos + 8 + 2]
+    }
+
+    #[test]
+    fn test_auto_color_mode_picks_gray() {
+        let near_gray = Pixmap::from_fn(64, 64, |x, y| {
+            let v = ((x * 3 + y * 2) % 256) as u8;
+            Pixel::new(v, v.saturating_add(2), v)
+        });
+        let tinted = Pixmap::from_fn(64, 64, |x, _| Pixel::new((x * 4) as u8, 40, 200));
+
+        let params = PageEncodeParams {
+            color: ColorMode::Auto,
+            ..PageEncodeParams::default()
+        };
+
+        let page = PageComponents::new().with_background(near_gray).unwrap();
+        let encoded = page.encode(&params, 1, 300, 1, None).unwrap();
+        assert_ne!(
+            first_bg44_major(&encoded) & 0x80,
+            0,
+            "expected grayscale BG44"
+        );
+
+        let page = PageComponents::new().with_background(tinted).unwrap();
+        let encoded = page.encode(&params, 1, 300, 1, None).unwrap();
+        assert_eq!(first_bg44_major(&encoded) & 0x80, 0, "expected color BG44");
+    }
+
+    #[test]
+    fn test_lower_chroma_threshold_catches_faint_stamp() {
+        // A light gray page with a pale pink stamp (channel spread 6)
+        let page = PageComponents::new()
+            .with_background(Pixmap::from_fn(64, 64, |x, y| {
+                if (40..56).contains(&x) && (8..20).contains(&y) {
+                    Pixel::new(236, 230, 232)
+                } else {
+                    Pixel::new(230, 230, 230)
+                }
+            }))
+            .unwrap();
+
+        let mut params = PageEncodeParams {
+            color: ColorMode::Auto,
+            ..PageEncodeParams::default()
+        };
+        let encoded = page.encode(&params, 1, 300, 1, None).unwrap();
+        assert_ne!(
+            first_bg44_major(&encoded) & 0x80,
+            0,
+            "expected grayscale BG44"
+        );
+
+        params.chroma_threshold = 4;
+        let encoded = page.encode(&params, 1, 300, 1, None).unwrap();
+        assert_eq!(first_bg44_major(&encoded) & 0x80, 0, "expected color BG44");
+    }
+
+    #[test]
+    fn test_background_rect_is_placed_at_offset() {
+        let photo = Pixmap::from_fn(50, 50, |x, y| Pixel::new(x as u8 * 4, y as u8 * 4, 60));
+        let page = PageComponents::new_with_dimensions(100, 100)
+            .with_background_rect(photo.clone(), 10, 10)
+            .unwrap();
+
+        let bg = page.background.as_ref().unwrap();
+        assert_eq!(bg.dimensions(), (100, 100));
+        assert_eq!(bg.get_pixel(10, 10), photo.get_pixel(0, 0));
+        assert_eq!(bg.get_pixel(59, 59), photo.get_pixel(49, 49));
+        assert_eq!(bg.get_pixel(9, 30), Pixel::white());
+        assert_eq!(bg.get_pixel(60, 60), Pixel::white());
+
+        let encoded = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+        assert_eq!(crate::validate::validate_page(&encoded), Vec::new());
+        let (_, chunks) = crate::doc::encoder::DocumentEncoder::form_chunks(&encoded).unwrap();
+        let bg44 = chunks
+            .iter()
+            .filter(|(id, _)| id == b"BG44")
+            .map(|(_, data)| *data);
+        let decoded = crate::encode::iw44::Iw44Image::decode(bg44)
+            .unwrap()
+            .to_pixmap()
+            .unwrap();
+        assert_eq!(decoded.dimensions(), (100, 100));
+        let close = |a: Pixel, b: Pixel| {
+            let d = |x: u8, y: u8| x.abs_diff(y) <= 24;
+            d(a.r, b.r) && d(a.g, b.g) && d(a.b, b.b)
+        };
+        for (x, y) in [(20, 20), (35, 45), (50, 25)] {
+            let (got, want) = (decoded.get_pixel(x, y), photo.get_pixel(x - 10, y - 10));
+            assert!(close(got, want), "({x}, {y}): {got:?} vs {want:?}");
+        }
+        for (x, y) in [(3, 3), (90, 20), (30, 90), (85, 85)] {
+            let got = decoded.get_pixel(x, y);
+            assert!(close(got, Pixel::white()), "({x}, {y}): {got:?}");
+        }
+
+        let off_page = PageComponents::new_with_dimensions(100, 100).with_background_rect(
+            photo.clone(),
+            60,
+            10,
+        );
+        assert!(off_page.is_err());
+        assert!(
+            PageComponents::new()
+                .with_background_rect(photo, 10, 10)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_oversized_page_rejected() {
+        let result = PageComponents::new().with_background(Pixmap::new(70000, 10));
+        match result {
+            Err(DjvuError::InvalidArg(msg)) => {
+                assert!(msg.contains("70000x10"));
+                assert!(msg.contains("65535"));
+            }
+            _ => panic!("expected InvalidArg for a 70000px wide page"),
+        }
+
+        // Pages sized up front are caught at encode time instead
+        let page = PageComponents::new_with_dimensions(70000, 10);
+        let result = page.encode(&PageEncodeParams::default(), 1, 300, 1, None);
+        assert!(matches!(result, Err(DjvuError::InvalidArg(_))));
+    }
+
+    #[test]
+    fn test_text_only_page_needs_dimensions() {
+        let page = PageComponents::new().with_text("Hello".to_string());
+        let params = PageEncodeParams::default();
+        match page.encode(&params, 1, 300, 1, None) {
+            Err(DjvuError::InvalidOperation(msg)) => {
+                assert!(msg.contains("0x0"));
+                assert!(msg.contains("new_with_dimensions"));
+            }
+            other => panic!("expected InvalidOperation for an unsized page, got {other:?}"),
+        }
+        assert!(page.estimated_size(&params).is_err());
+
+        let sized = PageComponents::new_with_dimensions(40, 30).with_text("Hello".to_string());
+        assert!(sized.encode(&params, 1, 300, 1, None).is_ok());
+    }
+
+    #[test]
+    fn test_blank_mask_writes_no_jb2() {
+        let bg = Pixmap::from_fn(64, 48, |x, y| Pixel::new(x as u8 * 4, y as u8 * 5, 90));
+        let blank = BitImage::new(64, 48).unwrap();
+        let params = PageEncodeParams::default();
+        let page = PageComponents::new()
+            .with_background(bg)
+            .unwrap()
+            .with_mask(blank.clone())
+            .unwrap()
+            .encode(&params, 1, 300, 1, None)
+            .unwrap();
+        for id in [b"Sjbz", b"Djbz", b"FGbz"] {
+            assert!(!page.windows(4).any(|w| w == id));
+        }
+        assert!(page.windows(4).any(|w| w == b"BG44"));
+        assert_eq!(crate::validate::validate_page(&page), Vec::new());
+
+        // Without a background the page is still a valid, blank white page
+        let page = PageComponents::new_with_dimensions(64, 48)
+            .with_foreground(blank)
+            .unwrap()
+            .encode(&params, 1, 300, 1, None)
+            .unwrap();
+        assert!(!page.windows(4).any(|w| w == b"Sjbz"));
+        assert_eq!(crate::validate::validate_page(&page), Vec::new());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_params_from_config() {
+        let config = r#"{
+            "dpi": 600,
+            "bg_quality": 70,
+            "color": "auto",
+            "decibels": 45.0,
+            "mask_morphology": { "open": 1 },
+            "reading_order": "rtl_top_down",
+            "bzz": { "dirm": 1024 }
+        }"#;
+        let params: PageEncodeParams = serde_json::from_str(config).unwrap();
+        assert_eq!(params.dpi, 600);
+        assert_eq!((params.bg_quality, params.fg_quality), (70, 90));
+        assert_eq!(params.color, ColorMode::Auto);
+        assert_eq!(params.decibels, Some(45.0));
+        assert_eq!(params.mask_morphology, Some(MaskMorphology::Open(1)));
+        assert_eq!(params.reading_order, ReadingOrder::RtlTopDown);
+        assert_eq!(params.bzz.dirm, 1024);
+        assert_eq!(params.bzz.text, BzzLevel::default().text);
+
+        for bad in [
+            r#"{ "bg_quality": 150 }"#,
+            r#"{ "decibels": 200.0 }"#,
+            r#"{ "bzz": { "navm": 5 } }"#,
+        ] {
+            assert!(
+                serde_json::from_str::<PageEncodeParams>(bad).is_err(),
+                "{bad}"
+            );
+        }
+        let err = serde_json::from_str::<PageEncodeParams>(r#"{ "fg_quality": 150 }"#).unwrap_err();
+        assert!(err.to_string().contains("150"));
+
+        // Presets survive a round trip
+        let photo = serde_json::to_string(&PageEncodeParams::photo()).unwrap();
+        let back: PageEncodeParams = serde_json::from_str(&photo).unwrap();
+        assert_eq!(serde_json::to_string(&back).unwrap(), photo);
+    }
+
+    #[test]
+    fn test_jb2_failure_reports_stage() {
+        let shape = BitImage::new(4, 4).unwrap();
+        // Blit references shape 3, but only one shape exists
+        let page = PageComponents::new_with_dimensions(32, 32)
+            .with_jb2_manual(vec![shape], vec![(0, 0, 3)]);
+        let err = page
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            DjvuError::Encoding {
+                stage: EncodeStage::Jb2,
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("JB2"));
+    }
+
+    #[test]
+    fn test_unsupported_features_are_reported() {
+        let page = PageComponents::new()
+            .with_background(Pixmap::new(40, 30))
+            .unwrap();
+
+        let jb2_background = PageEncodeParams {
+            use_iw44: false,
+            ..PageEncodeParams::default()
+        };
+        let result = page.encode(&jb2_background, 1, 300, 1, None);
+        assert!(matches!(result, Err(DjvuError::Unsupported(_))));
+
+        let result = page.encode(&PageEncodeParams::default(), 1, 300, 1, Some(1.8));
+        assert!(matches!(result, Err(DjvuError::Unsupported(_))));
+        assert!(
+            page.encode(&PageEncodeParams::default(), 1, 300, 1, Some(2.2))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_mask_morphology_cleans_foreground() {
+        let block = |x0: usize, x1: usize| {
+            let mut img = BitImage::new(64, 48).unwrap();
+            for y in 10..30 {
+                for x in x0..x1 {
+                    img.set_usize(x, y, true);
+                }
+            }
+            img
+        };
+        // A scan-line dropout splits one block into two components
+        let mut broken = block(8, 40);
+        for y in 10..30 {
+            broken.set_usize(20, y, false);
+        }
+
+        let params = PageEncodeParams {
+            mask_morphology: Some(MaskMorphology::Close(1)),
+            ..PageEncodeParams::bitonal()
+        };
+        let encode = |img: BitImage, params: &PageEncodeParams| {
+            PageComponents::new()
+                .with_foreground(img)
+                .unwrap()
+                .encode(params, 1, 300, 1, None)
+                .unwrap()
+        };
+        assert_ne!(
+            encode(broken.clone(), &PageEncodeParams::bitonal()),
+            encode(block(8, 40), &PageEncodeParams::bitonal())
+        );
+        assert_eq!(
+            encode(broken, &params),
+            encode(block(8, 40), &PageEncodeParams::bitonal())
+        );
+    }
+
+    #[test]
+    fn test_mask_and_foreground_are_separate_layers() {
+        let blocks = |ranges: &[(usize, usize)]| {
+            let mut img = BitImage::new(64, 48).unwrap();
+            for &(x0, x1) in ranges {
+                for y in 10..30 {
+                    for x in x0..x1 {
+                        img.set_usize(x, y, true);
+                    }
+                }
+            }
+            img
+        };
+        let red = Pixel::new(200, 0, 0);
+        let data = PageComponents::new()
+            .with_background(Pixmap::from_pixel(64, 48, Pixel::white()))
+            .unwrap()
+            .with_mask(blocks(&[(4, 20), (36, 52)]))
+            .unwrap()
+            .with_foreground(blocks(&[(40, 44)]))
+            .unwrap()
+            .with_foreground_color(red)
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap();
+        assert!(data.windows(4).any(|w| w == b"Sjbz"));
+        assert!(data.windows(4).any(|w| w == b"BG44"));
+
+        // FGbz: version, palette size, BGR colors, blit count, BZZ indices
+        let pos = data.windows(4).position(|w| w == b"FGbz").unwrap() + 4;
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let fgbz = &data[pos + 4..pos + 4 + len];
+        assert_eq!(fgbz[..3], [0x80, 0, 2]);
+        assert_eq!(fgbz[3..9], [0, 0, 0, red.b, red.g, red.r]);
+        assert_eq!(fgbz[9..12], [0, 0, 2]);
+        let indices = crate::iff::bs_byte_stream::bzz_decompress(&fgbz[12..]).unwrap();
+        // Blits run left to right; only the right block touches the foreground
+        assert_eq!(indices, [0, 0, 0, 1]);
+    }
+
     #[test]
     fn test_dimension_mismatch() {
         let bg_image = Pixmap::new(100, 200);
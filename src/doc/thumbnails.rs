@@ -0,0 +1,235 @@
+//! Thumbnails of finished pages, rendered back from their encoded chunks
+//!
+//! Pages are drawn the way a viewer composes them: the BG44 background
+//! (white without one), with the Sjbz mask on top in the FG44 or FGbz
+//! colors (black without either). Chunks of included components count as
+//! the page's own, so shared dictionaries and backgrounds are found.
+
+use crate::doc::encoder::{Chunk, Component, DocumentEncoder};
+use crate::doc::page_encoder::{encode_th44, thumbnail_dimensions};
+use crate::encode::iw44::Iw44Image;
+use crate::encode::jb2::decoder as jb2;
+use crate::iff::bs_byte_stream::bzz_decompress;
+use crate::iff::iff::{IffWriter, IffWriterExt};
+use crate::image::image_formats::{Pixel, Pixmap, resample_area};
+use crate::{DjvuError, Result};
+use std::io::Cursor;
+
+/// Nesting of INCL chunks followed before giving up on a cycle
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Largest reduction of a BG44 or FG44 layer relative to the page, as in
+/// DjVuLibre
+const MAX_REDUCTION: u32 = 12;
+
+/// Renders `page`, a `FORM:DJVU` with or without the `AT&T` magic, at most
+/// `size` pixels on its longer side and encodes it as TH44 chunk data.
+///
+/// `includes` resolves the page's INCL chunks. Layers this crate cannot
+/// decode (JPEG or JPEG 2000 images, MMR masks) are reported as
+/// [`DjvuError::Unsupported`].
+pub(crate) fn page_thumbnail(page: &[u8], includes: &[Component], size: u32) -> Result<Vec<u8>> {
+    let mut chunks = Vec::new();
+    collect_chunks(page, includes, 0, &mut chunks)?;
+    if let Some((id, _)) = chunks
+        .iter()
+        .find(|(id, _)| matches!(id, b"Smmr" | b"BGjp" | b"BG2k" | b"FGjp" | b"FG2k"))
+    {
+        return Err(DjvuError::Unsupported(format!(
+            "Cannot render {} chunks",
+            String::from_utf8_lossy(id)
+        )));
+    }
+    let find = |id: &[u8; 4]| chunks.iter().find(|(c, _)| c == id).map(|(_, data)| *data);
+
+    let info = find(b"INFO")
+        .filter(|info| info.len() >= 4)
+        .ok_or_else(|| invalid("the page has no INFO chunk".to_string()))?;
+    let w = u16::from_be_bytes([info[0], info[1]]) as u32;
+    let h = u16::from_be_bytes([info[2], info[3]]) as u32;
+    let (tw, th) = thumbnail_dimensions((w, h), size)?;
+
+    let background = layer(&chunks, b"BG44", (w, h))?;
+    let foreground = layer(&chunks, b"FG44", (w, h))?;
+    let mask = match find(b"Sjbz") {
+        Some(sjbz) => Some(Mask::decode(sjbz, find(b"Djbz"), find(b"FGbz"))?),
+        None => None,
+    };
+
+    let thumbnail = resample_area((w, h), (tw, th), |x, y| {
+        let sample = |(image, r): &(Pixmap, u32)| {
+            let x = (x / r).min(image.width() - 1);
+            let y = (y / r).min(image.height() - 1);
+            image.get_pixel(x, y)
+        };
+        match mask.as_ref().and_then(|m| m.color(x, y)) {
+            Some(ink) => foreground.as_ref().map_or(ink, sample),
+            None => background.as_ref().map_or(Pixel::white(), sample),
+        }
+    });
+    encode_th44(&thumbnail)
+}
+
+/// A `FORM:THUM` component, magic included, holding `thumbnails` as TH44
+/// chunks in page order.
+pub(crate) fn thumbnails_form(thumbnails: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut iff = IffWriter::new(Cursor::new(&mut out));
+    iff.write_magic_bytes()?;
+    iff.put_chunk("FORM:THUM")?;
+    for thumbnail in thumbnails {
+        iff.write_chunk(*b"TH44", thumbnail)?;
+    }
+    iff.close_chunk()?;
+    drop(iff);
+    Ok(out)
+}
+
+fn invalid(reason: String) -> DjvuError {
+    DjvuError::InvalidOperation(format!("Cannot render the page: {reason}"))
+}
+
+/// Appends the chunks of `component`, replacing each INCL chunk with the
+/// chunks of the include it names.
+fn collect_chunks<'a>(
+    component: &'a [u8],
+    includes: &'a [Component],
+    depth: usize,
+    out: &mut Vec<Chunk<'a>>,
+) -> Result<()> {
+    let (_, chunks) = DocumentEncoder::form_chunks(component)
+        .ok_or_else(|| invalid("a component does not parse".to_string()))?;
+    for (id, data) in chunks {
+        if id != *b"INCL" {
+            out.push((id, data));
+            continue;
+        }
+        let name = String::from_utf8_lossy(data);
+        let (_, include) = includes
+            .iter()
+            .find(|(i, _)| *i == name)
+            .ok_or_else(|| DjvuError::NotFound(format!("Include '{name}' is not registered")))?;
+        if depth == MAX_INCLUDE_DEPTH {
+            return Err(invalid(format!("includes nest deeper than {depth}")));
+        }
+        collect_chunks(include, includes, depth + 1, out)?;
+    }
+    Ok(())
+}
+
+/// Decodes the `id` chunks (BG44 or FG44) into an image and its reduction
+/// relative to the `w`x`h` page, if there are any.
+fn layer(chunks: &[Chunk], id: &[u8; 4], (w, h): (u32, u32)) -> Result<Option<(Pixmap, u32)>> {
+    let data = chunks
+        .iter()
+        .filter(|(c, _)| c == id)
+        .map(|(_, data)| *data);
+    if data.clone().next().is_none() {
+        return Ok(None);
+    }
+    let name = String::from_utf8_lossy(id);
+    let image = Iw44Image::decode(data)
+        .and_then(|image| image.to_pixmap())
+        .map_err(|e| invalid(format!("{name} does not decode: {e}")))?;
+    // A layer of another size is drawn unscaled, clipped to the page
+    let r = (1..=MAX_REDUCTION)
+        .find(|&r| w.div_ceil(r) == image.width() && h.div_ceil(r) == image.height())
+        .unwrap_or(1);
+    Ok(Some((image, r)))
+}
+
+/// The decoded Sjbz mask, with the FGbz color of each pixel
+struct Mask {
+    width: usize,
+    height: usize,
+    /// Per page pixel: 0 where no blit is, else 1 + the palette index of
+    /// the last blit drawn there
+    ink: Vec<u16>,
+    palette: Vec<Pixel>,
+}
+
+impl Mask {
+    fn decode(sjbz: &[u8], djbz: Option<&[u8]>, fgbz: Option<&[u8]>) -> Result<Self> {
+        let failed =
+            |what: &str, e: &dyn std::fmt::Display| invalid(format!("{what} does not decode: {e}"));
+        let dictionary = match djbz {
+            Some(djbz) => jb2::decode(djbz, &[])
+                .map_err(|e| failed("Djbz", &e))?
+                .dictionary(),
+            None => Vec::new(),
+        };
+        let image = jb2::decode(sjbz, &dictionary).map_err(|e| failed("Sjbz", &e))?;
+        let (palette, colors) = match fgbz {
+            Some(fgbz) => Self::palette(fgbz).map_err(|e| failed("FGbz", &e))?,
+            None => (vec![Pixel::black()], Vec::new()),
+        };
+
+        let (width, height) = (image.width as usize, image.height as usize);
+        let mut ink = vec![0u16; width * height];
+        let mut bad_index = None;
+        image.for_each_blit_pixel(|x, y, blitno| {
+            let color = colors.get(blitno).copied().unwrap_or(0);
+            if color as usize >= palette.len() {
+                bad_index = Some(color);
+            }
+            ink[y * width + x] = color.wrapping_add(1);
+        });
+        if let Some(index) = bad_index {
+            return Err(failed(
+                "FGbz",
+                &format!("color {index} of {} colors", palette.len()),
+            ));
+        }
+        Ok(Mask {
+            width,
+            height,
+            ink,
+            palette,
+        })
+    }
+
+    /// Colors and blit color indices of an FGbz chunk. Without indices,
+    /// every blit takes the first color.
+    fn palette(fgbz: &[u8]) -> Result<(Vec<Pixel>, Vec<u16>)> {
+        let short = || DjvuError::Stream("FGbz chunk is truncated".to_string());
+        let [version, n_hi, n_lo, rest @ ..] = fgbz else {
+            return Err(short());
+        };
+        let count = u16::from_be_bytes([*n_hi, *n_lo]) as usize;
+        let colors = rest.get(..3 * count).ok_or_else(short)?;
+        // Colors are stored blue first
+        let palette = colors
+            .chunks_exact(3)
+            .map(|bgr| Pixel::new(bgr[2], bgr[1], bgr[0]))
+            .collect();
+        if version & 0x80 == 0 {
+            return Ok((palette, Vec::new()));
+        }
+        let rest = &rest[3 * count..];
+        let [b0, b1, b2, packed @ ..] = rest else {
+            return Err(short());
+        };
+        let blits = u32::from_be_bytes([0, *b0, *b1, *b2]) as usize;
+        let indices = bzz_decompress(packed)?;
+        let indices = indices.get(..2 * blits).ok_or_else(short)?;
+        Ok((
+            palette,
+            indices
+                .chunks_exact(2)
+                .map(|i| u16::from_be_bytes([i[0], i[1]]))
+                .collect(),
+        ))
+    }
+
+    /// Color of the ink at page pixel `(x, y)`, if there is any
+    fn color(&self, x: u32, y: u32) -> Option<Pixel> {
+        let (x, y) = (x as usize, y as usize);
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        match self.ink[y * self.width + x] {
+            0 => None,
+            i => Some(self.palette[i as usize - 1]),
+        }
+    }
+}
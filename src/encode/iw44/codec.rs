@@ -19,6 +19,17 @@ fn words_for_coeffs(n: usize) -> usize {
     (n + WORD_BITS - 1) / WORD_BITS
 }
 
+/// What one wavelet band has cost so far, for quality tuning.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandStat {
+    /// Compressed bits attributed to the band. The ZP coder emits whole
+    /// bytes a little after the decisions that produce them, so the split
+    /// between bands is approximate while the total is exact.
+    pub bits: u64,
+    /// Coefficients of the band that have become significant
+    pub active_coeffs: usize,
+}
+
 /// Represents the IW44 codec for encoding wavelet coefficients.
 /// Each codec instance owns its own slice state (curbit, curband) as per djvulibre design.
 pub struct Codec {
@@ -37,6 +48,7 @@ pub struct Codec {
     pub curbit: i32,    // Current bitplane (starts at 1, goes to -1 when done)
     pub curband: i32,   // Current band (0-9)
     pub lossless: bool, // True if encoding in lossless mode (thresholds stay >= 1)
+    band_stats: [BandStat; BAND_BUCKETS.len()],
 }
 
 impl Codec {
@@ -136,14 +148,44 @@ impl Codec {
             curbit: 1,  // Start at bitplane 1
             curband: 0, // Start at band 0
             lossless: params.lossless,
+            band_stats: [BandStat::default(); BAND_BUCKETS.len()],
         }
     }
 
+    /// Bits spent and coefficients activated per band, band 0 (DC) first
+    pub fn band_stats(&self) -> [BandStat; BAND_BUCKETS.len()] {
+        self.band_stats
+    }
+
     /// Returns a reference to the coefficient map.
     pub fn map(&self) -> &CoeffMap {
         &self.map
     }
 
+    /// Stops refining the coefficients `keep` rejects, given their index
+    /// (`block * 1024 + bucket * 16 + i`): their targets become what the
+    /// decoder already holds, zero where nothing was coded yet, so later
+    /// slices leave them as they are and spend next to nothing on them.
+    pub fn freeze(&mut self, keep: impl Fn(usize) -> bool) {
+        for blockno in 0..self.map.num_blocks {
+            for bucket in 0..64u8 {
+                let Some(&src) = self.map.blocks[blockno].get_bucket(bucket) else {
+                    continue;
+                };
+                // emap holds magnitudes; the sign is the source's
+                let coded = self.emap.blocks[blockno].get_bucket_raw(bucket);
+                let base = blockno * 1024 + bucket as usize * 16;
+                let mut frozen = src;
+                for i in (0..16).filter(|&i| !keep(base + i)) {
+                    frozen[i] = if src[i] < 0 { -coded[i] } else { coded[i] };
+                }
+                if frozen != src {
+                    self.map.blocks[blockno].set_bucket(bucket, frozen);
+                }
+            }
+        }
+    }
+
     #[inline]
     fn is_signif(&self, idx: usize) -> bool {
         (self.signif[idx / WORD_BITS] >> (idx % WORD_BITS)) & 1 != 0
@@ -154,6 +196,30 @@ impl Codec {
         self.signif[idx / WORD_BITS] |= 1 << (idx % WORD_BITS);
     }
 
+    /// Threshold of coefficient `i` of `band` for the current slice.
+    /// Thresholds are absolute (already decayed by `finish_slice`), never bit-plane shifted.
+    #[inline]
+    fn slice_threshold(&self, band: usize, i: usize) -> i32 {
+        if band == 0 {
+            self.quant_lo[i]
+        } else {
+            self.quant_hi[band]
+        }
+    }
+
+    /// A threshold can only activate coefficients while it lies in `1..0x8000`:
+    /// coefficients are `i16`, and zero means the band is exhausted.
+    #[inline]
+    fn is_live_threshold(thres: i32) -> bool {
+        thres > 0 && thres < 0x8000
+    }
+
+    /// The single significance test shared by null-slice detection and bucket preparation.
+    #[inline]
+    fn crosses_threshold(coeff: i16, thres: i32) -> bool {
+        Self::is_live_threshold(thres) && (coeff as i32).abs() >= thres
+    }
+
     /// Quickly scans if there is any work to be done for a given (bit, band) slice.
     /// Returns true if at least one coefficient is either NEW or ACTIVE.
     /// This is much faster than the full two-pass approach as it returns immediately
@@ -176,12 +242,7 @@ impl Codec {
 
                 let coeffs = self.map.blocks[blockno].get_bucket_raw(bucket_idx as u8);
                 for i in 0..16 {
-                    let step = if band == 0 {
-                        self.quant_lo[i]
-                    } else {
-                        self.quant_hi[band]
-                    };
-                    if (coeffs[i] as i32).abs() >= step {
+                    if Self::crosses_threshold(coeffs[i], self.slice_threshold(band, i)) {
                         return true;
                     }
                 }
@@ -240,11 +301,11 @@ impl Codec {
 
             if band != 0 {
                 // Band other than zero: derive state from pcoeff/epcoeff like DjVuLibre
-                let thres = self.quant_hi[band as usize];
+                let thres = self.slice_threshold(band as usize, 0);
                 for i in 0..16 {
                     let cstate = if ep16[i] != 0 {
                         ACTIVE
-                    } else if (src16[i] as i32).abs() >= thres {
+                    } else if Self::crosses_threshold(src16[i], thres) {
                         NEW | UNK
                     } else {
                         UNK
@@ -257,7 +318,7 @@ impl Codec {
                 // CRITICAL: Must read existing cstate[i] value first (C++ does this)
                 for i in 0..16 {
                     let gidx = coeff_idx0 + i;
-                    let thres = self.quant_lo[i];
+                    let thres = self.slice_threshold(0, i);
                     let mut cstatetmp = self.coeff_state[gidx];
 
                     #[cfg(debug_assertions)]
@@ -276,7 +337,7 @@ impl Codec {
                     if cstatetmp != ZERO {
                         cstatetmp = if ep16[i] != 0 {
                             ACTIVE
-                        } else if (src16[i] as i32).abs() >= thres {
+                        } else if Self::crosses_threshold(src16[i], thres) {
                             NEW | UNK
                         } else {
                             UNK
@@ -304,10 +365,10 @@ impl Codec {
             for blockno in 0..self.map.num_blocks {
                 let base_idx = blockno * 64 * 16; // Start of this block's coefficients
                 for i in 0..16 {
-                    let threshold = self.quant_lo[i];
+                    let threshold = self.slice_threshold(0, i);
                     // Reset state to ZERO
                     self.coeff_state[base_idx + i] = ZERO;
-                    if threshold > 0 && threshold < 0x8000 {
+                    if Self::is_live_threshold(threshold) {
                         // Mark as UNK (unknown) if threshold is active
                         self.coeff_state[base_idx + i] = UNK;
                         is_null = false;
@@ -317,8 +378,8 @@ impl Codec {
             is_null
         } else {
             // For other bands, just check the threshold (no state update needed)
-            let threshold = self.quant_hi[band as usize];
-            !(threshold > 0 && threshold < 0x8000)
+            let threshold = self.slice_threshold(band as usize, 0);
+            !Self::is_live_threshold(threshold)
         }
     }
 
@@ -381,6 +442,7 @@ impl Codec {
         fbucket: usize,
         nbucket: usize,
     ) -> Result<(), super::EncoderError> {
+        let start_bytes = zp.tell_bytes();
         // Prepare the state for this block
         let bbstate = self.encode_prepare(band, fbucket, nbucket, blockno, bit);
 
@@ -574,12 +636,14 @@ impl Codec {
                         if (self.coeff_state[gidx] & NEW) != 0 {
                             self.mark_signif(gidx);
                             self.coeff_state[gidx] = ACTIVE;
+                            self.band_stats[band as usize].active_coeffs += 1;
                         }
                     }
                 }
             }
         }
 
+        self.band_stats[band as usize].bits += 8 * (zp.tell_bytes() - start_bytes) as u64;
         Ok(())
     }
 
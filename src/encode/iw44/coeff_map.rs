@@ -1,6 +1,8 @@
+use super::constants::{DB_PER_PLANE, IW_LEVELS, IW_SHIFT};
 use super::masking;
 use super::transform::Encode;
 use super::zigzag::ZIGZAG_LOC;
+use crate::image::geom::Rect;
 use crate::image::image_formats::Bitmap;
 
 /// Replaces `IW44Image::Block`, storing coefficients for a 32x32 image block.
@@ -106,6 +108,20 @@ impl Block {
     }
 }
 
+/// Rows kept above and below each strip by [`CoeffMap::create_tiled`]. The
+/// five lifting levels reach at most 186 rows, so this covers their support;
+/// it must be a multiple of 32 to keep strips block-aligned.
+pub const STRIP_OVERLAP: usize = 192;
+
+/// Most wavelet levels [`forward_wavelet`](super::transform::forward_wavelet)
+/// accepts for a `width`x`height` image: five, the IW44 block limit, or
+/// fewer once the coarsest band would be a single pixel. IW44 streams do
+/// not use it: DjVuLibre transforms scales 1 to 32 (see [`IW_LEVELS`])
+/// whatever the image size, and decodes them the same way.
+pub(crate) fn transform_levels(width: usize, height: usize) -> usize {
+    ((width.min(height) as f32).log2() as usize).min(5)
+}
+
 /// Replaces `IW44Image::Map`. Owns all the coefficient blocks for one image component (Y, Cb, or Cr).
 #[derive(Debug, Clone)]
 pub struct CoeffMap {
@@ -178,13 +194,14 @@ impl CoeffMap {
 
         transform_fn(&mut data16, map.iw, map.ih, map.bw);
 
-        let levels = ((map.iw.min(map.ih) as f32).log2() as usize).min(5);
-        Encode::forward(&mut data16, map.iw, map.ih, map.bw, levels);
-
         if let Some(mask_img) = mask {
+            // Masked pixels are interpolated from the visible ones, and the
+            // masked transform replaces the plain one
             let mask8 = masking::image_to_mask8(mask_img, map.bw, map.ih);
             masking::interpolate_mask(&mut data16, map.iw, map.ih, map.bw, &mask8, map.bw);
             masking::forward_mask(&mut data16, map.iw, map.ih, map.bw, 1, 32, &mask8, map.bw);
+        } else {
+            Encode::forward(&mut data16, map.iw, map.ih, map.bw, IW_LEVELS);
         }
 
         let blocks_w = map.bw / 32;
@@ -200,6 +217,122 @@ impl CoeffMap {
         map
     }
 
+    /// Builds the map strip by strip instead of transforming the whole image
+    /// at once.
+    ///
+    /// `fill_row(y, row)` must write source row `y` (top-down, `width`
+    /// values) of one signed channel into `row`. Each strip of `strip_rows`
+    /// rows (rounded up to a multiple of 32) is transformed together with
+    /// [`STRIP_OVERLAP`] rows of context on either side, and only the strip's
+    /// own blocks are kept. The coefficient map itself is full-size; what
+    /// stays bounded is the source channel and the transform buffer, which
+    /// hold one extended strip instead of the whole image, at the cost of
+    /// transforming the overlap rows more than once. Without a mask the
+    /// coefficients equal those of the whole-image transform; mask
+    /// interpolation reaches further, so masked images may differ slightly
+    /// near strip seams.
+    pub fn create_tiled<F>(
+        width: usize,
+        height: usize,
+        mask: Option<&Bitmap>,
+        strip_rows: usize,
+        mut fill_row: F,
+    ) -> Self
+    where
+        F: FnMut(usize, &mut [i8]),
+    {
+        let [map] = Self::create_tiled_channels(width, height, mask, strip_rows, |y, rows| {
+            fill_row(y, &mut rows[0])
+        });
+        map
+    }
+
+    /// Like [`CoeffMap::create_tiled`], for `N` channels of the same size
+    /// built in one pass.
+    ///
+    /// `fill_rows(y, rows)` writes source row `y` of every channel at once,
+    /// so a conversion shared by the channels (such as RGB to YCbCr) runs
+    /// once per row of each strip instead of once per channel.
+    pub fn create_tiled_channels<const N: usize, F>(
+        width: usize,
+        height: usize,
+        mask: Option<&Bitmap>,
+        strip_rows: usize,
+        mut fill_rows: F,
+    ) -> [Self; N]
+    where
+        F: FnMut(usize, &mut [Vec<i8>; N]),
+    {
+        let mut maps: [Self; N] = std::array::from_fn(|_| Self::new(width, height));
+        let (iw, ih, bw, bh) = (width, height, maps[0].bw, maps[0].bh);
+        let strip_rows = (strip_rows.max(32) + 31) & !31;
+        let blocks_w = bw / 32;
+        let mut rows: [Vec<i8>; N] = std::array::from_fn(|_| vec![0i8; width]);
+
+        // Buffer rows are bottom-up (see `Encode::from_i8_channel_with_stride`),
+        // so buffer row `r` holds source row `ih - 1 - r`.
+        for start in (0..bh).step_by(strip_rows) {
+            let end = (start + strip_rows).min(bh);
+            let ext_start = start.saturating_sub(STRIP_OVERLAP);
+            let ext_end = (end + STRIP_OVERLAP).min(bh);
+            let image_rows = ext_end.min(ih) - ext_start;
+
+            let mut data: [Vec<i16>; N] =
+                std::array::from_fn(|_| vec![0i16; bw * (ext_end - ext_start)]);
+            for r in 0..image_rows {
+                fill_rows(ih - 1 - (ext_start + r), &mut rows);
+                for (data16, row) in data.iter_mut().zip(&rows) {
+                    let dst = &mut data16[r * bw..r * bw + width];
+                    for (d, &v) in dst.iter_mut().zip(row) {
+                        *d = ((v as i32) << IW_SHIFT) as i16;
+                    }
+                }
+            }
+
+            // Same bottom-up row convention as `image_to_mask8`
+            let mask8 = mask.map(|mask_img| {
+                let mut mask8 = vec![0i8; bw * image_rows];
+                let mask_w = (mask_img.width() as usize).min(bw);
+                for r in 0..image_rows {
+                    let src_y = ih - 1 - (ext_start + r);
+                    if src_y >= mask_img.height() as usize {
+                        continue;
+                    }
+                    for x in 0..mask_w {
+                        let m = mask_img.get_pixel(x as u32, src_y as u32).y;
+                        mask8[r * bw + x] = if m > 0 { 1 } else { 0 };
+                    }
+                }
+                mask8
+            });
+
+            for (map, data16) in maps.iter_mut().zip(data.iter_mut()) {
+                if let Some(mask8) = &mask8 {
+                    masking::interpolate_mask(data16, iw, image_rows, bw, mask8, bw);
+                    masking::forward_mask(data16, iw, image_rows, bw, 1, 32, mask8, bw);
+                } else {
+                    Encode::forward(data16, iw, image_rows, bw, IW_LEVELS);
+                }
+
+                for block_y in start / 32..end / 32 {
+                    for block_x in 0..blocks_w {
+                        let mut liftblock = [0i16; 1024];
+                        Self::copy_block_data(
+                            &mut liftblock,
+                            data16,
+                            bw,
+                            block_x,
+                            block_y - ext_start / 32,
+                        );
+                        map.blocks[block_y * blocks_w + block_x].read_liftblock(&liftblock);
+                    }
+                }
+            }
+        }
+
+        maps
+    }
+
     /// Create coefficients from an image. Corresponds to `Map::Encode::create`.
     pub fn create_from_image(img: &Bitmap, mask: Option<&Bitmap>) -> Self {
         let (w, h) = img.dimensions();
@@ -264,6 +397,108 @@ impl CoeffMap {
             }
         }
     }
+
+    /// Extra bit planes each coefficient gets from `regions`, in codec order
+    /// (`block * 1024 + bucket * 16 + i`).
+    ///
+    /// `regions` are `(rect, boost)` pairs in top-down image coordinates;
+    /// `subsample` divides them for reduced-resolution chroma maps. Each
+    /// coefficient takes the largest boost of the rects its wavelet support
+    /// touches, at one plane (a halving of every threshold, about 6 dB) per
+    /// [`DB_PER_PLANE`] dB, rounded. Coefficients away from every region get
+    /// none. See [`IWEncoder`](super::IWEncoder) for how the planes are coded.
+    pub fn region_planes(&self, regions: &[(Rect, f32)], subsample: usize) -> Vec<u8> {
+        let planes = |boost: f32| (boost / DB_PER_PLANE).round().clamp(0.0, u8::MAX as f32) as u8;
+
+        // (x0, x1, r0, r1, planes) in buffer coordinates, whose rows are bottom-up
+        let sub = subsample.max(1) as i64;
+        let ih = self.ih as i64;
+        let bounds: Vec<_> = regions
+            .iter()
+            .filter(|&&(rect, boost)| !rect.is_empty() && planes(boost) > 0)
+            .map(|&(rect, boost)| {
+                let y0 = (rect.y as i64).div_euclid(sub);
+                let y1 = (rect.y_max() as i64 + sub - 1).div_euclid(sub);
+                (
+                    (rect.x as i64).div_euclid(sub),
+                    (rect.x_max() as i64 + sub - 1).div_euclid(sub),
+                    ih - y1,
+                    ih - y0,
+                    planes(boost),
+                )
+            })
+            .collect();
+
+        let mut out = vec![0u8; self.num_blocks * 1024];
+        if bounds.is_empty() {
+            return out;
+        }
+        let blocks_w = self.bw / 32;
+        for (idx, block_planes) in out.chunks_exact_mut(1024).enumerate() {
+            let bx = (idx % blocks_w * 32) as i64;
+            let by = (idx / blocks_w * 32) as i64;
+            for (n, &k) in ZIGZAG_LOC.iter().enumerate() {
+                let (i, j) = (k / 32, k % 32);
+                // Lifting at scale s uses taps up to 3s away
+                let reach = 3i64 << (i | j | 32).trailing_zeros();
+                let (x, r) = (bx + j as i64, by + i as i64);
+                block_planes[n] = bounds
+                    .iter()
+                    .filter(|&&(x0, x1, r0, r1, _)| {
+                        x + reach >= x0 && x - reach < x1 && r + reach >= r0 && r - reach < r1
+                    })
+                    .map(|b| b.4)
+                    .fold(0, u8::max);
+            }
+        }
+        out
+    }
+
+    /// Requantizes flat blocks more coarsely than detailed ones, by up to
+    /// `strength` dB.
+    ///
+    /// A block's activity is the mean square of its detail coefficients,
+    /// which tracks the variance of the 32x32 area it covers. On a log scale
+    /// between the flattest and the busiest block, each block's deficit runs
+    /// from `strength` down to zero, and its detail coefficients are
+    /// truncated to multiples of `10^(deficit / 20) - 1` grey levels. The
+    /// busiest blocks and every DC coefficient are untouched, so flat areas
+    /// keep their tone while a byte or decibel budget goes to the detail.
+    pub fn apply_adaptive_quant(&mut self, strength: f32) {
+        if strength <= 0.0 || self.blocks.is_empty() {
+            return;
+        }
+        let mut liftblock = [0i16; 1024];
+        let activity: Vec<f32> = self
+            .blocks
+            .iter()
+            .map(|block| {
+                block.write_liftblock(&mut liftblock);
+                let energy: f64 = liftblock[1..].iter().map(|&c| (c as f64).powi(2)).sum();
+                (energy / 1023.0).ln_1p() as f32
+            })
+            .collect();
+        let lo = activity.iter().copied().fold(f32::INFINITY, f32::min);
+        let hi = activity.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if hi - lo <= f32::EPSILON {
+            return;
+        }
+
+        for (block, a) in self.blocks.iter_mut().zip(activity) {
+            let deficit = strength * (hi - a) / (hi - lo);
+            // Zero deficit means a step of zero, so the ramp starts smoothly
+            let step = ((10f32.powf(deficit / 20.0) - 1.0) * (1 << IW_SHIFT) as f32) as i32;
+            if step <= 1 {
+                continue;
+            }
+            block.write_liftblock(&mut liftblock);
+            for c in liftblock[1..].iter_mut() {
+                *c = (*c as i32 / step * step) as i16;
+            }
+            *block = Block::default();
+            block.read_liftblock(&liftblock);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -8,11 +8,19 @@ pub const IW_QUANT: [i32; 16] = [
 ];
 
 pub const IW_SHIFT: i32 = 6;
+
+// Wavelet levels of every IW44 image: DjVuLibre's forward and backward
+// transforms run over scales 1 to 32 whatever the image size, so the depth
+// is not configurable; a stream coded with another one decodes wrong
+pub const IW_LEVELS: usize = 5;
 pub const IW_ROUND: i32 = 1 << (IW_SHIFT - 1); // = 32
 
 // From IW44EncodeCodec.cpp - DECIBEL_PRUNE constant
 pub const DECIBEL_PRUNE: f32 = 5.0;
 
+// Gain of one more bit plane: every threshold halves, 20 * log10(2) dB
+pub const DB_PER_PLANE: f32 = 6.0206;
+
 // From IW44EncodeCodec.cpp - iw_norm array for wavelet norms
 pub const IW_NORM: [f32; 16] = [
     2.627989e+03,
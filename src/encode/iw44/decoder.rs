@@ -0,0 +1,552 @@
+//! IW44 decoder, the inverse of [`IWEncoder`](super::IWEncoder).
+//!
+//! Follows the decoding path of DjVuLibre's `IW44Image`: chunks are fed in
+//! order to one [`Iw44Image`], each continuing the bit planes where the
+//! previous one stopped, and the image can be reconstructed after any of
+//! them. The crate uses it to render pages it has already encoded, e.g. to
+//! regenerate thumbnails.
+
+use super::constants::{BAND_BUCKETS, IW_LEVELS, IW_QUANT, IW_SHIFT, ZIGZAG_LOC};
+use super::encoder::EncoderError;
+use super::transform::Decode;
+use crate::encode::zc::{BitContext, ZDecoder};
+use crate::image::image_formats::{Pixel, Pixmap};
+
+// Coefficient and bucket states, as in the encoder's `Codec`
+const ZERO: u8 = 0x00;
+const UNK: u8 = 0x01;
+const NEW: u8 = 0x02;
+const ACTIVE: u8 = 0x04;
+
+/// A (partially) decoded IW44 image, BM44/PM44 or the BG44, FG44 and TH44
+/// chunks of a page.
+#[derive(Default)]
+pub struct Iw44Image {
+    y: Option<Plane>,
+    cb: Option<Plane>,
+    cr: Option<Plane>,
+    crcb_delay: usize,
+    /// Serial number the next chunk must carry
+    serial: u8,
+    /// Slices decoded so far, over all chunks
+    slices: usize,
+}
+
+impl Iw44Image {
+    /// An image with no chunk decoded yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes an image from all of its chunks.
+    pub fn decode<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Result<Self, EncoderError> {
+        let mut image = Self::new();
+        for chunk in chunks {
+            image.decode_chunk(chunk)?;
+        }
+        Ok(image)
+    }
+
+    /// Size of the image, known once the first chunk is decoded.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.y.as_ref().map(|y| (y.width as u32, y.height as u32))
+    }
+
+    /// True if the image has chrominance.
+    pub fn is_color(&self) -> bool {
+        self.cb.is_some()
+    }
+
+    /// Decodes the next chunk: its header, then its slices.
+    ///
+    /// Chunks must come in order, starting with serial 0, which carries
+    /// the image size and color layout.
+    pub fn decode_chunk(&mut self, data: &[u8]) -> Result<(), EncoderError> {
+        let invalid = |what: String| EncoderError::InvalidData(what);
+        let [serial, slices, rest @ ..] = data else {
+            return Err(invalid("chunk is shorter than its header".to_string()));
+        };
+        if *serial != self.serial {
+            return Err(invalid(format!(
+                "chunk {serial} found where chunk {} was expected",
+                self.serial
+            )));
+        }
+        let payload = if *serial == 0 {
+            let [major, minor, wh, wl, hh, hl, rest @ ..] = rest else {
+                return Err(invalid(
+                    "first chunk is shorter than its header".to_string(),
+                ));
+            };
+            if major & 0x7f != 1 {
+                return Err(invalid(format!(
+                    "unsupported codec version {}",
+                    major & 0x7f
+                )));
+            }
+            let width = u16::from_be_bytes([*wh, *wl]) as usize;
+            let height = u16::from_be_bytes([*hh, *hl]) as usize;
+            if width == 0 || height == 0 {
+                return Err(EncoderError::EmptyObject);
+            }
+            // Version 1.2 adds the chrominance delay byte
+            let (delay, rest) = match rest {
+                [delay, rest @ ..] if *minor >= 2 => (*delay, rest),
+                _ => (0, rest),
+            };
+            self.y = Some(Plane::new(width, height));
+            if major & 0x80 == 0 {
+                self.cb = Some(Plane::new(width, height));
+                self.cr = Some(Plane::new(width, height));
+            }
+            self.crcb_delay = (delay & 0x7f) as usize;
+            rest
+        } else {
+            rest
+        };
+        let Some(y) = &mut self.y else {
+            return Err(invalid("first chunk is missing".to_string()));
+        };
+
+        let mut zp = ZDecoder::new(payload, true)?;
+        for _ in 0..*slices {
+            y.decode_slice(&mut zp)?;
+            if let (Some(cb), Some(cr)) = (&mut self.cb, &mut self.cr)
+                && self.slices >= self.crcb_delay
+            {
+                cb.decode_slice(&mut zp)?;
+                cr.decode_slice(&mut zp)?;
+            }
+            self.slices += 1;
+        }
+        self.serial = self.serial.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Reconstructs the image from the coefficients decoded so far.
+    ///
+    /// Grayscale images come out with equal red, green and blue.
+    pub fn to_pixmap(&self) -> Result<Pixmap, EncoderError> {
+        let y = self.y.as_ref().ok_or(EncoderError::EmptyObject)?;
+        let (w, h) = (y.width, y.height);
+        let luma = y.samples();
+        let chroma = match (&self.cb, &self.cr) {
+            (Some(cb), Some(cr)) => Some((cb.samples(), cr.samples())),
+            _ => None,
+        };
+        Ok(Pixmap::from_fn(w as u32, h as u32, |x, row| {
+            let i = row as usize * w + x as usize;
+            let y = luma[i];
+            match &chroma {
+                Some((cb, cr)) => ycbcr_to_rgb(y, cb[i], cr[i]),
+                None => {
+                    let v = (y + 128) as u8;
+                    Pixel::new(v, v, v)
+                }
+            }
+        }))
+    }
+}
+
+/// DjVuLibre's YCbCr to RGB conversion, inverting the encoder's.
+fn ycbcr_to_rgb(y: i32, cb: i32, cr: i32) -> Pixel {
+    let t2 = cr + (cr >> 1);
+    let t3 = y + 128 - (cb >> 2);
+    let channel = |v: i32| v.clamp(0, 255) as u8;
+    Pixel::new(
+        channel(y + 128 + t2),
+        channel(t3 - (t2 >> 1)),
+        channel(t3 + (cb << 1)),
+    )
+}
+
+/// Decoding state of one component (Y, Cb or Cr), the counterpart of the
+/// encoder's [`Codec`](super::Codec).
+struct Plane {
+    width: usize,
+    height: usize,
+    /// Coefficients of each 32x32 block in bucket order: coefficient `i`
+    /// of bucket `b` of block `n` is at `n * 1024 + b * 16 + i`
+    coeffs: Vec<i16>,
+    /// States of the buckets of the current band in the current block
+    coeff_state: [u8; 256],
+    bucket_state: [u8; 16],
+    quant_hi: [i32; 10],
+    quant_lo: [i32; 16],
+    ctx_root: BitContext,
+    ctx_bucket: [[BitContext; 8]; 10],
+    ctx_start: [BitContext; 16],
+    ctx_mant: BitContext,
+    curbit: i32,
+    curband: usize,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        let blocks = width.div_ceil(32) * height.div_ceil(32);
+        // Initial thresholds as in DjVuLibre's IW44Image: the first four
+        // band-0 coefficients get their own, the other twelve share three
+        let mut quant_lo = [0; 16];
+        quant_lo[..4].copy_from_slice(&IW_QUANT[..4]);
+        for (i, q) in quant_lo[4..].iter_mut().enumerate() {
+            *q = IW_QUANT[4 + i / 4];
+        }
+        let mut quant_hi = [0; 10];
+        quant_hi[1..].copy_from_slice(&IW_QUANT[7..]);
+        Plane {
+            width,
+            height,
+            coeffs: vec![0; blocks * 1024],
+            coeff_state: [ZERO; 256],
+            bucket_state: [ZERO; 16],
+            quant_hi,
+            quant_lo,
+            ctx_root: 0,
+            ctx_bucket: [[0; 8]; 10],
+            ctx_start: [0; 16],
+            ctx_mant: 0,
+            curbit: 1,
+            curband: 0,
+        }
+    }
+
+    /// Decodes the current slice and moves on to the next band, like
+    /// `Codec::code_slice`. Does nothing once all bit planes are done.
+    fn decode_slice(&mut self, zp: &mut ZDecoder) -> Result<(), EncoderError> {
+        if self.curbit < 0 {
+            return Ok(());
+        }
+        if !self.is_null_slice() {
+            let band = BAND_BUCKETS[self.curband];
+            for blockno in 0..self.coeffs.len() / 1024 {
+                self.decode_buckets(zp, blockno, band.start, band.size)?;
+            }
+        }
+
+        self.quant_hi[self.curband] >>= 1;
+        if self.curband == 0 {
+            for q in &mut self.quant_lo {
+                *q >>= 1;
+            }
+        }
+        self.curband += 1;
+        if self.curband == BAND_BUCKETS.len() {
+            self.curband = 0;
+            self.curbit += 1;
+            if self.quant_hi[BAND_BUCKETS.len() - 1] == 0 {
+                self.curbit = -1;
+            }
+        }
+        Ok(())
+    }
+
+    /// True if no threshold of the current band can activate a
+    /// coefficient. For band 0 this also marks the coefficients whose
+    /// threshold is exhausted as `ZERO`.
+    fn is_null_slice(&mut self) -> bool {
+        let live = |thres: i32| thres > 0 && thres < 0x8000;
+        if self.curband == 0 {
+            for (state, &thres) in self.coeff_state.iter_mut().zip(&self.quant_lo) {
+                *state = if live(thres) { UNK } else { ZERO };
+            }
+            !self.quant_lo.iter().any(|&thres| live(thres))
+        } else {
+            !live(self.quant_hi[self.curband])
+        }
+    }
+
+    /// Decodes the buckets `fbucket..fbucket + nbucket` of one block, the
+    /// mirror image of `Codec::encode_buckets`.
+    fn decode_buckets(
+        &mut self,
+        zp: &mut ZDecoder,
+        blockno: usize,
+        fbucket: usize,
+        nbucket: usize,
+    ) -> Result<(), EncoderError> {
+        let band = self.curband;
+        let block = &mut self.coeffs[blockno * 1024..(blockno + 1) * 1024];
+        let thres = |quant_lo: &[i32; 16], quant_hi: i32, i: usize| match band {
+            0 => quant_lo[i],
+            _ => quant_hi,
+        };
+
+        // Coefficients decoded so far are active; band 0 keeps the ones
+        // `is_null_slice` ruled out
+        let mut bbstate = 0;
+        for buckno in 0..nbucket {
+            let mut bstate = 0;
+            for i in 0..16 {
+                let state = &mut self.coeff_state[buckno * 16 + i];
+                if band != 0 || *state != ZERO {
+                    *state = if block[(fbucket + buckno) * 16 + i] != 0 {
+                        ACTIVE
+                    } else {
+                        UNK
+                    };
+                }
+                bstate |= *state;
+            }
+            self.bucket_state[buckno] = bstate;
+            bbstate |= bstate;
+        }
+
+        // Root bit: does the block have new coefficients at all?
+        if nbucket < 16
+            || bbstate & ACTIVE != 0
+            || (bbstate & UNK != 0 && zp.decode(&mut self.ctx_root)?)
+        {
+            bbstate |= NEW;
+        }
+
+        if bbstate & NEW != 0 {
+            // Bucket bits, in the context of the parent coefficients
+            for buckno in 0..nbucket {
+                if self.bucket_state[buckno] & UNK == 0 {
+                    continue;
+                }
+                let mut ctx = 0;
+                if band > 0 {
+                    let k = (fbucket + buckno) << 2;
+                    let parents = &block[k..k + 4];
+                    ctx = parents[..3].iter().filter(|&&c| c != 0).count();
+                    if ctx < 3 && parents[3] != 0 {
+                        ctx += 1;
+                    }
+                }
+                if bbstate & ACTIVE != 0 {
+                    ctx |= 4;
+                }
+                if zp.decode(&mut self.ctx_bucket[band][ctx])? {
+                    self.bucket_state[buckno] |= NEW;
+                }
+            }
+
+            // New coefficients and their signs
+            for buckno in 0..nbucket {
+                if self.bucket_state[buckno] & NEW == 0 {
+                    continue;
+                }
+                let states = &mut self.coeff_state[buckno * 16..(buckno + 1) * 16];
+                let active = if self.bucket_state[buckno] & ACTIVE != 0 {
+                    8
+                } else {
+                    0
+                };
+                let mut gotcha = states.iter().filter(|&&s| s & UNK != 0).count();
+                for i in 0..16 {
+                    if states[i] & UNK == 0 {
+                        continue;
+                    }
+                    if zp.decode(&mut self.ctx_start[gotcha.min(7) | active])? {
+                        states[i] |= NEW;
+                        let thres = thres(&self.quant_lo, self.quant_hi[band], i);
+                        // Small coefficients are the likelier ones, so the
+                        // first guess sits an eighth step below the middle
+                        // of the interval; the first refinement adds it back
+                        let magnitude = (thres + (thres >> 1) - (thres >> 3)) as i16;
+                        block[(fbucket + buckno) * 16 + i] = if zp.decode_raw()? {
+                            -magnitude
+                        } else {
+                            magnitude
+                        };
+                        gotcha = 0;
+                    } else {
+                        gotcha = gotcha.saturating_sub(1);
+                    }
+                }
+            }
+        }
+
+        // Refinement of the coefficients that were already active
+        if bbstate & ACTIVE != 0 {
+            for buckno in 0..nbucket {
+                if self.bucket_state[buckno] & ACTIVE == 0 {
+                    continue;
+                }
+                for i in 0..16 {
+                    if self.coeff_state[buckno * 16 + i] & ACTIVE == 0 {
+                        continue;
+                    }
+                    let thres = thres(&self.quant_lo, self.quant_hi[band], i);
+                    let coeff = &mut block[(fbucket + buckno) * 16 + i];
+                    let mut magnitude = (*coeff as i32).abs();
+                    let upper = if magnitude <= 3 * thres {
+                        // Undo the bias of the first guess
+                        magnitude += thres >> 2;
+                        zp.decode(&mut self.ctx_mant)?
+                    } else {
+                        zp.decode_raw()?
+                    };
+                    magnitude += (thres >> 1) - if upper { 0 } else { thres };
+                    let magnitude = magnitude.min(i16::MAX as i32) as i16;
+                    *coeff = if *coeff > 0 { magnitude } else { -magnitude };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Inverse transform of the coefficients over the fixed scales 32 to 1,
+    /// as in DjVuLibre: one signed sample per pixel, top row first, in the
+    /// range the encoder's channels use.
+    fn samples(&self) -> Vec<i32> {
+        let (w, h) = (self.width, self.height);
+        let (bw, bh) = (w.div_ceil(32) * 32, h.div_ceil(32) * 32);
+        let mut data = vec![0i16; bw * bh];
+        for (blockno, block) in self.coeffs.chunks_exact(1024).enumerate() {
+            let (bx, by) = (blockno % (bw / 32) * 32, blockno / (bw / 32) * 32);
+            for (&coeff, &loc) in block.iter().zip(&ZIGZAG_LOC) {
+                data[(by + loc / 32) * bw + bx + loc % 32] = coeff;
+            }
+        }
+        Decode::backward(&mut data, w, h, bw, 1 << IW_LEVELS, 1);
+
+        // The transform works bottom-up
+        let round = 1 << (IW_SHIFT - 1);
+        (0..h)
+            .rev()
+            .flat_map(|row| &data[row * bw..row * bw + w])
+            .map(|&s| ((s as i32 + round) >> IW_SHIFT).clamp(-128, 127))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::iw44::{CrcbMode, EncoderParams, IWEncoder};
+    use crate::image::image_formats::{Bitmap, GrayPixel};
+
+    fn psnr(a: &Pixmap, b: &Pixmap) -> f64 {
+        let (x, y) = (a.as_raw(), b.as_raw());
+        let mse = x
+            .iter()
+            .zip(y)
+            .map(|(&p, &q)| (p as f64 - q as f64).powi(2))
+            .sum::<f64>()
+            / x.len() as f64;
+        10.0 * (255.0 * 255.0 / mse.max(1e-9)).log10()
+    }
+
+    fn chunks(encoder: &mut IWEncoder, slices: usize) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        loop {
+            let (chunk, more) = encoder.encode_chunk(slices).unwrap();
+            if !chunk.is_empty() {
+                chunks.push(chunk);
+            }
+            if !more {
+                return chunks;
+            }
+        }
+    }
+
+    #[test]
+    fn test_color_image_round_trip_over_several_chunks() {
+        let img = Pixmap::from_fn(75, 50, |x, y| {
+            Pixel::new((x * 3) as u8, (y * 5) as u8, ((x + y) * 2) as u8)
+        });
+        let params = EncoderParams {
+            crcb_mode: CrcbMode::Normal,
+            ..EncoderParams::default()
+        };
+        let mut encoder = IWEncoder::from_rgb(&img, None, params).unwrap();
+        let chunks = chunks(&mut encoder, 30);
+        assert!(chunks.len() > 2);
+
+        let mut image = Iw44Image::new();
+        let mut quality = Vec::new();
+        for chunk in &chunks {
+            image.decode_chunk(chunk).unwrap();
+            quality.push(psnr(&img, &image.to_pixmap().unwrap()));
+        }
+        assert_eq!(image.dimensions(), Some((75, 50)));
+        assert!(image.is_color());
+        // Early chunks give a rough image; all of them leave only the
+        // rounding of the color conversion
+        assert!(quality[0] < 30.0, "{quality:?}");
+        assert!(*quality.last().unwrap() > 45.0, "{quality:?}");
+    }
+
+    #[test]
+    fn test_gray_image_decodes_exactly() {
+        let gray = Bitmap::from_vec(
+            40,
+            33,
+            (0..40 * 33)
+                .map(|i| GrayPixel::new((i * 7 % 256) as u8))
+                .collect(),
+        );
+        let params = EncoderParams {
+            slices: Some(100),
+            ..EncoderParams::default()
+        };
+        let mut encoder = IWEncoder::from_gray(&gray, None, params).unwrap();
+        let image = Iw44Image::decode(chunks(&mut encoder, 100).iter().map(Vec::as_slice)).unwrap();
+        assert!(!image.is_color());
+
+        // Once every bit plane is in, the coefficients are exact
+        let expected = Pixmap::from_fn(40, 33, |x, y| {
+            let v = gray.get_pixel(x, y).y;
+            Pixel::new(v, v, v)
+        });
+        assert_eq!(image.to_pixmap().unwrap().as_raw(), expected.as_raw());
+    }
+
+    /// Images under 32 pixels still go through all five levels: the fully
+    /// decoded image matches the encoder's coefficients inverted over the
+    /// fixed scales 32 to 1, as DjVuLibre does, and so the source.
+    #[test]
+    fn test_small_image_uses_fixed_scales() {
+        use crate::encode::iw44::coeff_map::CoeffMap;
+
+        let (w, h) = (16usize, 12usize);
+        let gray = Bitmap::from_vec(
+            w as u32,
+            h as u32,
+            (0..w * h)
+                .map(|i| GrayPixel::new((i * 37 % 256) as u8))
+                .collect(),
+        );
+        let params = EncoderParams {
+            slices: Some(100),
+            ..EncoderParams::default()
+        };
+        let mut encoder = IWEncoder::from_gray(&gray, None, params).unwrap();
+        let image = Iw44Image::decode(chunks(&mut encoder, 100).iter().map(Vec::as_slice)).unwrap();
+        let decoded = image.to_pixmap().unwrap();
+
+        let map = CoeffMap::create_from_image(&gray, None);
+        let mut data = vec![0i16; 32 * 32];
+        map.blocks[0].write_liftblock((&mut data[..]).try_into().unwrap());
+        Decode::backward(&mut data, w, h, 32, 32, 1);
+        for (x, y) in (0..h).flat_map(|y| (0..w).map(move |x| (x, y))) {
+            // Buffer rows are bottom-up
+            let s = (data[(h - 1 - y) * 32 + x] as i32 + 32) >> IW_SHIFT;
+            let reference = (s.clamp(-128, 127) + 128) as u8;
+            let pixel = decoded.get_pixel(x as u32, y as u32);
+            assert_eq!(pixel.r, reference, "({x}, {y})");
+            assert_eq!(pixel.r, gray.get_pixel(x as u32, y as u32).y, "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn test_chunks_out_of_order_are_rejected() {
+        let img = Pixmap::from_fn(16, 16, |x, y| Pixel::new(x as u8 * 9, y as u8 * 9, 0));
+        let params = EncoderParams {
+            slices: Some(10),
+            ..EncoderParams::default()
+        };
+        let mut encoder = IWEncoder::from_rgb(&img, None, params).unwrap();
+        let chunks = chunks(&mut encoder, 10);
+        assert!(matches!(
+            Iw44Image::new().decode_chunk(&chunks[1]),
+            Err(EncoderError::InvalidData(_))
+        ));
+        assert!(matches!(
+            Iw44Image::new().decode_chunk(&chunks[0][..5]),
+            Err(EncoderError::InvalidData(_))
+        ));
+    }
+}
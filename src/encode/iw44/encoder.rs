@@ -1,8 +1,10 @@
 // src/encode/iw44/encoder.rs
 
-use super::codec::Codec;
+use super::codec::{BandStat, Codec};
 use super::coeff_map::CoeffMap;
+use super::constants::BAND_BUCKETS;
 use crate::encode::zc::ZpEncoderCursor;
+use crate::image::geom::Rect;
 use crate::image::image_formats::{Bitmap, Pixmap};
 use bytemuck;
 use log::{debug, info};
@@ -18,11 +20,43 @@ pub enum EncoderError {
     EmptyObject,
     #[error("ZP codec error: {0}")]
     ZCodec(#[from] crate::encode::zc::ZCodecError),
+    #[error("{levels} wavelet levels requested for a {width}x{height} image (allowed: 1..={max})")]
+    InvalidLevels {
+        levels: usize,
+        width: usize,
+        height: usize,
+        max: usize,
+    },
+    #[error(
+        "A {len}-sample buffer with rows {stride} samples apart cannot hold a \
+         {width}x{height} image"
+    )]
+    InvalidBuffer {
+        len: usize,
+        width: usize,
+        height: usize,
+        stride: usize,
+    },
+    #[error("{name} is {value}, expected a finite value {expected}")]
+    InvalidValue {
+        name: &'static str,
+        value: f32,
+        expected: &'static str,
+    },
+    #[error("IW44 images are limited to 256 chunks (serial numbers 0-255)")]
+    TooManyChunks,
+    #[error("Invalid IW44 data: {0}")]
+    InvalidData(String),
     #[error("General error: {0}")]
     General(#[from] crate::utils::error::DjvuError),
 }
 
 #[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum CrcbMode {
     #[default]
     None,
@@ -31,8 +65,11 @@ pub enum CrcbMode {
     Full,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct EncoderParams {
+    /// Quality target; encoding stops once the estimated PSNR reaches it.
+    /// Must lie in [`DECIBELS_RANGE`], or construction fails with
+    /// [`EncoderError::InvalidValue`].
     pub decibels: Option<f32>,
     pub slices: Option<usize>, // Max slices per chunk (C44 default: 74 for first chunk)
     pub bytes: Option<usize>,  // Max bytes per chunk
@@ -44,6 +81,25 @@ pub struct EncoderParams {
     /// Lower values = less aggressive filtering = larger files, potentially higher quality
     /// Range: 0.5 to 2.0 recommended
     pub quant_multiplier: f32,
+    /// Regions to encode at higher quality, as `(rect, db_boost)` pairs in
+    /// top-down image coordinates (e.g. a signature or stamp). When the
+    /// first chunk reaches its slice, byte or decibel limit, the rest of
+    /// the image is frozen as coded so far and coding goes on in the
+    /// coefficients whose support touches a region, one more round of bands
+    /// per 6 dB of `db_boost` (see [`CoeffMap::region_planes`]). Each round
+    /// halves the regions' quantization step, worth up to 6 dB once most of
+    /// their coefficients are coded, while the surroundings stay as they
+    /// were. The extra slices come on top of that chunk's limits; later
+    /// chunks go on refining the regions with the largest boost, the rest
+    /// keeping what the first chunk gave it. Empty by default.
+    pub region_boosts: Vec<(Rect, f32)>,
+    /// Variance-adaptive quantization strength in dB, or `None` to quantize
+    /// every block alike (the default). Flat 32x32 blocks are quantized up
+    /// to this many dB more coarsely than the busiest ones, which saves
+    /// bits where errors are least visible so a byte budget buys more
+    /// detail elsewhere; see [`CoeffMap::apply_adaptive_quant`]. Must be
+    /// finite and above zero.
+    pub adaptive_quant: Option<f32>,
 }
 
 impl Default for EncoderParams {
@@ -56,6 +112,8 @@ impl Default for EncoderParams {
             db_frac: 0.35,
             lossless: false,
             quant_multiplier: 1.0, // Start with C++ default behavior
+            region_boosts: Vec::new(),
+            adaptive_quant: None,
         }
     }
 }
@@ -143,6 +201,40 @@ pub fn ycbcr_from_rgb(img: &Pixmap) -> (Vec<i8>, Vec<i8>, Vec<i8>) {
     (y_buf, cb_buf, cr_buf)
 }
 
+/// Decibel targets accepted in [`EncoderParams::decibels`]. Below 20 dB
+/// nothing recognizable is left, and 120 dB is beyond lossless.
+pub const DECIBELS_RANGE: std::ops::RangeInclusive<f32> = 20.0..=120.0;
+
+/// Checks that no float setting in `params` is NaN, infinite or out of
+/// range, which would silently corrupt quantization.
+fn check_params(params: &EncoderParams) -> Result<(), EncoderError> {
+    let invalid = |name, value, expected| EncoderError::InvalidValue {
+        name,
+        value,
+        expected,
+    };
+    if let Some(db) = params.decibels.filter(|db| !DECIBELS_RANGE.contains(db)) {
+        return Err(invalid("decibels", db, "between 20 and 120"));
+    }
+    if !(params.quant_multiplier.is_finite() && params.quant_multiplier > 0.0) {
+        return Err(invalid(
+            "quant_multiplier",
+            params.quant_multiplier,
+            "above zero",
+        ));
+    }
+    if let Some(&(_, boost)) = params.region_boosts.iter().find(|(_, b)| !b.is_finite()) {
+        return Err(invalid("region boost", boost, "in decibels"));
+    }
+    if let Some(db) = params
+        .adaptive_quant
+        .filter(|db| !(db.is_finite() && *db > 0.0))
+    {
+        return Err(invalid("adaptive_quant", db, "above zero"));
+    }
+    Ok(())
+}
+
 pub fn make_ycbcr_codecs(
     y_buf: &[i8],
     cb_buf: &[i8],
@@ -341,29 +433,91 @@ pub fn encoder_from_rgb_with_helpers(
     params: EncoderParams,
 ) -> Result<IWEncoder, EncoderError> {
     let (w, h) = img.dimensions();
+    check_params(&params)?;
     let (y_buf, cb_buf, cr_buf) = ycbcr_from_rgb(img);
     let (y_codec, cb_codec, cr_codec) =
         make_ycbcr_codecs(&y_buf, &cb_buf, &cr_buf, w, h, mask, &params);
 
-    Ok(IWEncoder {
-        y_codec,
-        cb_codec,
-        cr_codec,
-        params,
-        total_slices: 0,
-        serial: 0,
-        crcb_delay: match params.crcb_mode {
-            CrcbMode::None => -1,
-            CrcbMode::Half => 10,
-            CrcbMode::Normal => 10,
-            CrcbMode::Full => 0,
-        },
-        crcb_half: match params.crcb_mode {
-            CrcbMode::Half => true,
-            _ => false,
-        },
-        // Note: curbit/curband state is now owned by each codec (initialized in Codec::new)
-    })
+    Ok(IWEncoder::from_ycbcr_codecs(
+        y_codec, cb_codec, cr_codec, params,
+    ))
+}
+
+/// Converts row `y` of `img` into its Y, Cb and Cr rows.
+fn ycbcr_rows(img: &Pixmap, y: usize, out: &mut [Vec<i8>; 3]) {
+    let w = img.width() as usize;
+    let raw = &img.as_raw()[y * w * 3..(y + 1) * w * 3];
+    let [py, pcb, pcr] = out;
+    rgb_to_ycbcr_planes(raw, py, pcb, pcr);
+}
+
+/// Row `hy` of both chroma channels at half resolution, averaged over 2x2
+/// pixels like the whole-image `CrcbMode::Half` path.
+fn half_chroma_rows(img: &Pixmap, hy: usize, out: &mut [Vec<i8>; 2]) {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let mut rows = vec![[vec![0i8; w], vec![0i8; w], vec![0i8; w]]];
+    ycbcr_rows(img, hy * 2, &mut rows[0]);
+    if hy * 2 + 1 < h {
+        let mut below = [vec![0i8; w], vec![0i8; w], vec![0i8; w]];
+        ycbcr_rows(img, hy * 2 + 1, &mut below);
+        rows.push(below);
+    }
+    for (c, plane) in out.iter_mut().enumerate() {
+        for (hx, dst) in plane.iter_mut().enumerate() {
+            let mut sum = 0i32;
+            let mut count = 0;
+            for row in &rows {
+                for &v in &row[c + 1][hx * 2..(hx * 2 + 2).min(w)] {
+                    sum += v as i32;
+                    count += 1;
+                }
+            }
+            *dst = (sum / count) as i8;
+        }
+    }
+}
+
+pub fn encoder_from_rgb_tiled(
+    img: &Pixmap,
+    mask: Option<&Bitmap>,
+    params: EncoderParams,
+    strip_rows: usize,
+) -> Result<IWEncoder, EncoderError> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    check_params(&params)?;
+    let codec = |map: CoeffMap| Codec::new(map, &params);
+
+    let (y_codec, cb_codec, cr_codec) = match params.crcb_mode {
+        CrcbMode::Normal | CrcbMode::Full => {
+            let [y, cb, cr] = CoeffMap::create_tiled_channels(w, h, mask, strip_rows, |y, rows| {
+                ycbcr_rows(img, y, rows)
+            });
+            (codec(y), Some(codec(cb)), Some(codec(cr)))
+        }
+        CrcbMode::None | CrcbMode::Half => {
+            let mut ycbcr = [vec![0i8; w], vec![0i8; w], vec![0i8; w]];
+            let [y] = CoeffMap::create_tiled_channels(w, h, mask, strip_rows, |y, rows| {
+                ycbcr_rows(img, y, &mut ycbcr);
+                rows[0].copy_from_slice(&ycbcr[0]);
+            });
+            if matches!(params.crcb_mode, CrcbMode::Half) {
+                let [cb, cr] = CoeffMap::create_tiled_channels(
+                    w.div_ceil(2),
+                    h.div_ceil(2),
+                    None,
+                    strip_rows,
+                    |y, rows| half_chroma_rows(img, y, rows),
+                );
+                (codec(y), Some(codec(cb)), Some(codec(cr)))
+            } else {
+                (codec(y), None, None)
+            }
+        }
+    };
+
+    Ok(IWEncoder::from_ycbcr_codecs(
+        y_codec, cb_codec, cr_codec, params,
+    ))
 }
 
 pub fn encoder_from_gray_with_helpers(
@@ -371,7 +525,12 @@ pub fn encoder_from_gray_with_helpers(
     mask: Option<&Bitmap>,
     params: EncoderParams,
 ) -> Result<IWEncoder, EncoderError> {
-    let ymap = CoeffMap::create_from_image(img, mask);
+    check_params(&params)?;
+    let mut ymap = CoeffMap::create_from_image(img, mask);
+    let region_planes = region_planes(&params, [Some(&ymap)], ymap.iw);
+    if let Some(strength) = params.adaptive_quant {
+        ymap.apply_adaptive_quant(strength);
+    }
     let y_codec = Codec::new(ymap, &params);
 
     Ok(IWEncoder {
@@ -383,7 +542,8 @@ pub fn encoder_from_gray_with_helpers(
         serial: 0,
         crcb_delay: -1,
         crcb_half: false, // Grayscale has no chroma
-                          // Note: curbit/curband state is now owned by each codec (initialized in Codec::new)
+        region_planes,
+        // Note: curbit/curband state is now owned by each codec (initialized in Codec::new)
     })
 }
 
@@ -393,10 +553,39 @@ pub struct IWEncoder {
     cr_codec: Option<Codec>,
     params: EncoderParams,
     total_slices: usize,
-    serial: u8,
+    serial: u16,
     crcb_delay: i32,
     crcb_half: bool, // Added to match C++ behavior
-                     // Note: curbit/curband state is now owned by each codec independently
+    // Note: curbit/curband state is now owned by each codec independently
+    /// Extra bit planes per coefficient of each codec (Y, then Cb and Cr),
+    /// from [`EncoderParams::region_boosts`]; empty without boosts and once
+    /// the first chunk has coded them
+    region_planes: Vec<Vec<u8>>,
+}
+
+/// [`CoeffMap::region_planes`] of `maps` for the boosts in `params`, chroma
+/// maps narrower than `full_width` being half-size; empty without boosts.
+fn region_planes<'a>(
+    params: &EncoderParams,
+    maps: impl IntoIterator<Item = Option<&'a CoeffMap>>,
+    full_width: usize,
+) -> Vec<Vec<u8>> {
+    let regions = &params.region_boosts;
+    if regions.is_empty() {
+        return Vec::new();
+    }
+    let planes: Vec<Vec<u8>> = maps
+        .into_iter()
+        .flatten()
+        .map(|map| {
+            let subsample = if map.iw < full_width { 2 } else { 1 };
+            map.region_planes(regions, subsample)
+        })
+        .collect();
+    if planes.iter().flatten().all(|&p| p == 0) {
+        return Vec::new();
+    }
+    planes
 }
 
 impl IWEncoder {
@@ -421,6 +610,173 @@ impl IWEncoder {
         encoder_from_rgb_with_helpers(img, mask, params)
     }
 
+    /// Like [`IWEncoder::from_rgb`], but builds the coefficient maps in
+    /// horizontal strips of `strip_rows` rows.
+    ///
+    /// The full-size Y/Cb/Cr planes and transform buffers are never
+    /// materialized; only the coefficient maps are full-size. Each row of a
+    /// strip is converted to YCbCr once for all channels. The result is
+    /// still a single BG44 stream, identical to [`IWEncoder::from_rgb`]
+    /// without a mask. Overlap rows are transformed once per strip they
+    /// border, and with a mask the coefficients near strip seams may differ
+    /// slightly from a whole-image transform (see
+    /// [`CoeffMap::create_tiled`]). Strips are rounded up to a multiple of
+    /// 32 rows.
+    pub fn from_rgb_tiled(
+        img: &Pixmap,
+        mask: Option<&Bitmap>,
+        params: EncoderParams,
+        strip_rows: usize,
+    ) -> Result<Self, EncoderError> {
+        info!(
+            "IWEncoder::from_rgb_tiled called with image {}x{}, {} rows per strip",
+            img.width(),
+            img.height(),
+            strip_rows
+        );
+        encoder_from_rgb_tiled(img, mask, params, strip_rows)
+    }
+
+    /// Like [`IWEncoder::from_rgb`], but takes Y, Cb and Cr planes the
+    /// caller already has, e.g. from a JPEG decoder, skipping the RGB round
+    /// trip.
+    ///
+    /// Each plane holds `width * height` samples in row-major order, in the
+    /// signed layout [`rgb_to_ycbcr_planes`] produces: luma offset by -128
+    /// and chroma centered on zero. Cb and Cr are still read with
+    /// [`CrcbMode::None`], so all three must have that length.
+    pub fn from_ycbcr_planes(
+        y: &[i8],
+        cb: &[i8],
+        cr: &[i8],
+        width: u32,
+        height: u32,
+        mask: Option<&Bitmap>,
+        params: EncoderParams,
+    ) -> Result<Self, EncoderError> {
+        let (w, h) = (width as usize, height as usize);
+        if w == 0 || h == 0 {
+            return Err(EncoderError::EmptyObject);
+        }
+        if let Some(plane) = [y, cb, cr].into_iter().find(|p| p.len() != w * h) {
+            return Err(EncoderError::InvalidBuffer {
+                len: plane.len(),
+                width: w,
+                height: h,
+                stride: w,
+            });
+        }
+        check_params(&params)?;
+        let (y_codec, cb_codec, cr_codec) =
+            make_ycbcr_codecs(y, cb, cr, width, height, mask, &params);
+
+        Ok(IWEncoder::from_ycbcr_codecs(
+            y_codec, cb_codec, cr_codec, params,
+        ))
+    }
+
+    fn from_ycbcr_codecs(
+        mut y_codec: Codec,
+        mut cb_codec: Option<Codec>,
+        mut cr_codec: Option<Codec>,
+        params: EncoderParams,
+    ) -> Self {
+        let region_planes = region_planes(
+            &params,
+            [Some(&y_codec), cb_codec.as_ref(), cr_codec.as_ref()].map(|c| c.map(|c| &c.map)),
+            y_codec.map.iw,
+        );
+        if let Some(strength) = params.adaptive_quant {
+            for codec in [Some(&mut y_codec), cb_codec.as_mut(), cr_codec.as_mut()]
+                .into_iter()
+                .flatten()
+            {
+                codec.map.apply_adaptive_quant(strength);
+            }
+        }
+
+        IWEncoder {
+            y_codec,
+            cb_codec,
+            cr_codec,
+            total_slices: 0,
+            serial: 0,
+            crcb_delay: match params.crcb_mode {
+                CrcbMode::None => -1,
+                CrcbMode::Half => 10,
+                CrcbMode::Normal => 10,
+                CrcbMode::Full => 0,
+            },
+            crcb_half: matches!(params.crcb_mode, CrcbMode::Half),
+            params,
+            region_planes,
+            // Note: curbit/curband state is now owned by each codec (initialized in Codec::new)
+        }
+    }
+
+    /// Rough size in bytes of the stream that `slices` slices would produce,
+    /// judged from the coefficient magnitudes without running the coder.
+    ///
+    /// A heuristic: a band's threshold halves each time a slice visits it,
+    /// so every coefficient above the band's final threshold costs a few
+    /// bits plus one per refinement, and coefficients just under the
+    /// threshold cost about a bit. Chroma gets the slices left after its delay.
+    /// Decibel and byte limits are not modelled, and the estimate is only
+    /// meaningful before the first chunk has been encoded.
+    pub fn estimated_size(&self, slices: usize) -> usize {
+        let chroma_slices = if self.crcb_delay < 0 {
+            0
+        } else {
+            slices.saturating_sub(self.crcb_delay as usize)
+        };
+        let mut bits = Self::estimated_bits(&self.y_codec, slices);
+        for codec in self.cb_codec.iter().chain(&self.cr_codec) {
+            bits += Self::estimated_bits(codec, chroma_slices);
+        }
+        // Chunk header (serial, slices, then the first chunk's format bytes)
+        9 + (bits / 8.0) as usize
+    }
+
+    fn estimated_bits(codec: &Codec, slices: usize) -> f64 {
+        // Bits for a coefficient that becomes significant, before refinements
+        const SIGNIFICANT_BITS: f64 = 3.0;
+        // Bits for a coefficient within a factor of two below the threshold;
+        // smaller ones are cheap for the adaptive coder and are ignored
+        const INSIGNIFICANT_BITS: f64 = 1.0;
+
+        let mut bits = 0.0;
+        for (band, buckets) in BAND_BUCKETS.iter().enumerate() {
+            let passes = slices / 10 + usize::from(band < slices % 10);
+            if passes == 0 {
+                continue;
+            }
+            for block in &codec.map.blocks {
+                for bucket in buckets.start..buckets.start + buckets.size {
+                    for (i, &c) in block.get_bucket_raw(bucket as u8).iter().enumerate() {
+                        let initial = if band == 0 {
+                            codec.quant_lo[i]
+                        } else {
+                            codec.quant_hi[band]
+                        };
+                        let threshold = (initial >> (passes - 1).min(31)).max(1);
+                        if threshold >= 0x8000 {
+                            // The coder skips bands this coarse entirely
+                            continue;
+                        }
+                        let threshold = threshold as f64;
+                        let c = (c as i32).unsigned_abs() as f64;
+                        if c >= threshold {
+                            bits += SIGNIFICANT_BITS + (c / threshold).log2();
+                        } else if 2.0 * c >= threshold {
+                            bits += INSIGNIFICANT_BITS;
+                        }
+                    }
+                }
+            }
+        }
+        bits
+    }
+
     pub fn encode_chunk(&mut self, max_slices: usize) -> Result<(Vec<u8>, bool), EncoderError> {
         info!("encode_chunk called with max_slices={}", max_slices);
 
@@ -438,11 +794,15 @@ impl IWEncoder {
             return Err(EncoderError::NeedStopCondition);
         }
 
-        // Check if encoding is finished (check Y codec state)
-        if self.y_codec.curbit < 0 {
+        // Check if encoding is finished
+        if !self.has_pending_slices() {
             return Ok((Vec::new(), false));
         }
 
+        // The chunk header stores the serial in one byte; a wrapped serial of 0
+        // would be read back as a fresh first chunk.
+        let serial = u8::try_from(self.serial).map_err(|_| EncoderError::TooManyChunks)?;
+
         let mut chunk_data = Vec::new();
         // Create the ZP encoder for IW44 only. When the `asm_zp` feature is enabled,
         // use the assembly-backed encoder; otherwise, use the Rust implementation.
@@ -452,33 +812,17 @@ impl IWEncoder {
         let mut zp_impl = crate::encode::zc::zcodec::ZEncoder::new(Cursor::new(Vec::new()), true)?;
         let mut slices_encoded = 0;
         let mut estdb = -1.0;
+        let mut reached_decibels = false;
 
         // IMPORTANT: Do NOT reset contexts between progressive chunks of the same image
         // Contexts should only be reset when creating a new encoder for a different image
         // The ZP encoder's adaptive state must persist across progressive chunks
 
-        let _more = self.y_codec.curbit >= 0;
         while slices_encoded < max_slices && self.y_codec.curbit >= 0 {
-            // Encode one slice using codec-controlled scheduling (mirrors DjVuLibre)
-            // Each codec manages its own curbit/curband state independently
-            let should_continue = self.y_codec.code_slice(&mut zp_impl)?;
-
-            if let Some(ref mut cb) = self.cb_codec {
-                if self.total_slices as i32 >= self.crcb_delay {
-                    debug!("Encoding Cb slice {}", self.total_slices);
-                    cb.code_slice(&mut zp_impl)?;
-                }
-            }
-            if let Some(ref mut cr) = self.cr_codec {
-                if self.total_slices as i32 >= self.crcb_delay {
-                    debug!("Encoding Cr slice {}", self.total_slices);
-                    cr.code_slice(&mut zp_impl)?;
-                }
-            }
+            let should_continue = self.code_slice(&mut zp_impl)?;
 
             // A slice is always processed, so we always increment
             slices_encoded += 1;
-            self.total_slices += 1;
 
             // Check slice limit only if not overridden by max_slices parameter
             // When max_slices is usize::MAX, we encode all remaining slices
@@ -518,7 +862,7 @@ impl IWEncoder {
                     {
                         estdb = self.y_codec.estimate_decibel(self.params.db_frac);
                         if estdb >= db_target {
-                            self.y_codec.curbit = -1;
+                            reached_decibels = true;
                             break;
                         }
                     }
@@ -526,6 +870,27 @@ impl IWEncoder {
             }
         }
 
+        // Boosted regions are refined past the first chunk's limits, unless
+        // the image is already coded in full
+        if !self.region_planes.is_empty() && self.y_codec.curbit >= 0 {
+            slices_encoded = self.refine_regions(&mut zp_impl, slices_encoded)?;
+        }
+        if reached_decibels {
+            self.y_codec.curbit = -1;
+        }
+
+        // The Y codec drives the slice schedule (as in DjVuLibre): decoders stop
+        // reading chroma slices once luminance is done, so chroma codecs that
+        // lag behind because of the CrCb delay are finished along with it.
+        if self.y_codec.curbit < 0 {
+            for codec in [&mut self.cb_codec, &mut self.cr_codec]
+                .into_iter()
+                .flatten()
+            {
+                codec.curbit = -1;
+            }
+        }
+
         // Finish on the concrete implementation
         let zp_data = zp_impl.finish()?.into_inner();
 
@@ -545,11 +910,11 @@ impl IWEncoder {
         }
 
         // Write IW44 chunk header
-        chunk_data.push(self.serial);
+        chunk_data.push(serial);
         chunk_data.push(slices_encoded as u8);
 
         // Full secondary header only for the first chunk (serial == 0)
-        if self.serial == 0 {
+        if serial == 0 {
             let is_color = self.cb_codec.is_some() && self.cr_codec.is_some();
             // Major version: bit 7 set (0x80) indicates grayscale/BM44, clear indicates color/PM44
             // C++ uses: major = 1 | 0x80 for grayscale, major = 1 for color
@@ -580,12 +945,86 @@ impl IWEncoder {
         // Append ZP payload
         chunk_data.extend_from_slice(&zp_data);
 
-        // Determine if more chunks are needed
-        let more = self.y_codec.curbit >= 0;
+        // Determine if more chunks are needed, regardless of why this chunk stopped
+        let more = self.has_pending_slices();
 
         // Increment serial for next chunk
-        self.serial = self.serial.wrapping_add(1);
+        self.serial += 1;
 
         Ok((chunk_data, more))
     }
+
+    /// Codes one slice of luminance, and of chrominance once the CrCb delay
+    /// has passed. Returns false when luminance is done.
+    fn code_slice<Z: ZpEncoderCursor>(&mut self, zp: &mut Z) -> Result<bool, EncoderError> {
+        // Each codec manages its own curbit/curband state (mirrors DjVuLibre)
+        let should_continue = self.y_codec.code_slice(zp)?;
+        if self.total_slices as i32 >= self.crcb_delay {
+            for (name, codec) in [("Cb", &mut self.cb_codec), ("Cr", &mut self.cr_codec)] {
+                if let Some(codec) = codec {
+                    debug!("Encoding {} slice {}", name, self.total_slices);
+                    codec.code_slice(zp)?;
+                }
+            }
+        }
+        self.total_slices += 1;
+        Ok(should_continue)
+    }
+
+    /// Codes the extra bit planes of [`EncoderParams::region_boosts`]: a
+    /// round of all bands per plane, each coefficient frozen once it has
+    /// had its planes. Takes and returns the chunk's slice count, which
+    /// stops at 255 so that it still fits the chunk header.
+    fn refine_regions<Z: ZpEncoderCursor>(
+        &mut self,
+        zp: &mut Z,
+        mut slices_encoded: usize,
+    ) -> Result<usize, EncoderError> {
+        let planes = std::mem::take(&mut self.region_planes);
+        let rounds = planes.iter().flatten().copied().max().unwrap_or(0);
+        'rounds: for round in 0..rounds {
+            let codecs = [
+                Some(&mut self.y_codec),
+                self.cb_codec.as_mut(),
+                self.cr_codec.as_mut(),
+            ];
+            for (codec, planes) in codecs.into_iter().flatten().zip(&planes) {
+                codec.freeze(|i| planes[i] > round);
+            }
+            for _ in 0..BAND_BUCKETS.len() {
+                if slices_encoded == u8::MAX as usize {
+                    break 'rounds;
+                }
+                let more = self.code_slice(zp)?;
+                slices_encoded += 1;
+                if !more {
+                    break 'rounds;
+                }
+            }
+        }
+        Ok(slices_encoded)
+    }
+
+    /// Per-band cost summed over the luminance and chrominance codecs,
+    /// band 0 (DC) first. See [`Codec::band_stats`].
+    pub fn band_stats(&self) -> [BandStat; BAND_BUCKETS.len()] {
+        let mut total = self.y_codec.band_stats();
+        for codec in [&self.cb_codec, &self.cr_codec].into_iter().flatten() {
+            for (sum, stat) in total.iter_mut().zip(codec.band_stats()) {
+                sum.bits += stat.bits;
+                sum.active_coeffs += stat.active_coeffs;
+            }
+        }
+        total
+    }
+
+    /// Returns true while any codec still has slices left to encode, i.e.
+    /// while another `encode_chunk` call would produce a non-empty chunk.
+    pub fn has_pending_slices(&self) -> bool {
+        self.y_codec.curbit >= 0
+            || [&self.cb_codec, &self.cr_codec]
+                .into_iter()
+                .flatten()
+                .any(|codec| codec.curbit >= 0)
+    }
 }
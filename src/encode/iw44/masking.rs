@@ -1,15 +1,22 @@
 // src/iw44/masking.rs
 
-use crate::encode::iw44::transform::Encode;
+use crate::encode::iw44::transform::{Decode, Encode};
 use crate::image::image_formats::Bitmap;
 
-/// Convert Bitmap mask to i8 mask buffer
+/// Convert Bitmap mask to i8 mask buffer.
+///
+/// The mask is flipped vertically like the image data (see
+/// `Encode::from_u8_image`), so row `y` of the result is mask row `ih - 1 - y`.
 pub fn image_to_mask8(mask_img: &Bitmap, bw: usize, ih: usize) -> Vec<i8> {
     let mut mask8 = vec![0i8; bw * ih];
     for y in 0..ih {
+        let src_y = ih - 1 - y;
+        if src_y >= mask_img.height() as usize {
+            continue;
+        }
         for x in 0..(mask_img.width() as usize).min(bw) {
             // Non-zero mask pixels indicate masked-out regions
-            let mask_val = mask_img.get_pixel(x as u32, y as u32).y;
+            let mask_val = mask_img.get_pixel(x as u32, src_y as u32).y;
             mask8[y * bw + x] = if mask_val > 0 { 1 } else { 0 };
         }
     }
@@ -55,30 +62,20 @@ pub fn interpolate_mask(
         for i in (0..h).step_by(scale) {
             for j in (0..w).step_by(scale) {
                 // compute weighted average over the square [i..i+scale)×[j..j+scale)
-                let istart = if i + split > h {
-                    i.saturating_sub(scale)
-                } else {
-                    i
-                };
-                let jstart = if j + split > w {
-                    j.saturating_sub(scale)
-                } else {
-                    j
-                };
                 let mut gray_sum = 0i32;
                 let mut total_w = 0i32;
                 let mut saw_zero = false;
                 let iend = (i + scale).min(h);
                 let jend = (j + scale).min(w);
-                let mut ii = istart;
+                let mut ii = i;
                 while ii < iend {
-                    let mut jj = jstart;
+                    let mut jj = j;
                     while jj < jend {
                         let wght = count[ii * w + jj];
                         if wght > 0 {
                             total_w += wght;
                             gray_sum += wght * scratch[ii * w + jj];
-                        } else if ii >= i && jj >= j {
+                        } else {
                             saw_zero = true;
                         }
                         jj += split;
@@ -116,10 +113,13 @@ pub fn interpolate_mask(
 }
 
 /// Performs the "forward_mask" multiscale masked wavelet decomposition
-/// from IW44EncodeCodec.cpp. Updated to work with i32 data for consistency
-/// with transform.rs and eliminate unnecessary type conversions.
-/// At each scale it zeroes out wavelet coefficients under the mask,
-/// then reconstructs and re-decomposes to freeze those regions.
+/// from IW44EncodeCodec.cpp, covering scales `begin` up to `end` in place of
+/// `Encode::forward`.
+///
+/// At each scale the detail coefficients under the mask are zeroed, the
+/// scale is reconstructed with the inverse transform, the visible pixels are
+/// restored and the scale is decomposed again. Masked pixels thus take
+/// whatever values cost nothing to code instead of their literal values.
 pub fn forward_mask(
     data: &mut [i16],
     w: usize,
@@ -130,94 +130,74 @@ pub fn forward_mask(
     mask: &[i8],
     mskrowsize: usize,
 ) {
-    // 1) copy mask into an aligned 1-per-pixel array
+    // Copy mask into a buffer with one entry per pixel
     let mut smask = vec![0i8; w * h];
     for y in 0..h {
-        for x in 0..w {
-            smask[y * w + x] = mask[y * mskrowsize + x];
-        }
+        smask[y * w..y * w + w].copy_from_slice(&mask[y * mskrowsize..y * mskrowsize + w]);
     }
-    // 2) scratch buffer for single-level decomposition (now i32)
-    let mut scratch = vec![0i32; w * h];
-    let mut scratch_i16 = vec![0i16; w * h];
+    let mut sdata = vec![0i16; w * h];
 
-    let mut scale = begin.next_power_of_two();
+    let mut scale = begin;
     while scale < end {
-        // copy every scale-th sample into scratch (convert i16 to i32)
+        // Copy every scale-th sample into sdata
         for y in (0..h).step_by(scale) {
             for x in (0..w).step_by(scale) {
-                scratch[y * w + x] = data[y * rowsize + x] as i32;
+                sdata[y * w + x] = data[y * rowsize + x];
             }
         }
-        // full-band forward transform - use new API
-        let levels = ((scale * 2).trailing_zeros() as usize)
-            .saturating_sub(1)
-            .min(5);
-        for i in 0..scratch.len() {
-            scratch_i16[i] = scratch[i] as i16;
-        }
-        Encode::forward(&mut scratch_i16, w, h, w, levels);
-        for i in 0..scratch.len() {
-            scratch[i] = scratch_i16[i] as i32;
-        }
 
-        // zero out masked detail coefficients
+        // Decompose one level
+        Encode::forward_scales(&mut sdata, w, h, w, scale, scale + scale);
+
+        // Cancel masked coefficients
         for y in (0..h).step_by(scale * 2) {
-            // horizontal band
             for x in (scale..w).step_by(scale * 2) {
                 if smask[y * w + x] != 0 {
-                    scratch[y * w + x] = 0;
+                    sdata[y * w + x] = 0;
                 }
             }
-            // vertical band
             if y + scale < h {
+                let row = (y + scale) * w;
                 for x in (0..w).step_by(scale) {
-                    if smask[(y + scale) * w + x] != 0 {
-                        scratch[(y + scale) * w + x] = 0;
+                    if smask[row + x] != 0 {
+                        sdata[row + x] = 0;
                     }
                 }
             }
         }
 
-        // reconstruct back to pixel domain (inverse IW44 transform would be called here in reference impl, but is not implemented in encoder)
-        // restore visible pixels so they remain exact (convert i16 to i32)
+        // Reconstruct, and put the visible pixels back
+        Decode::backward(&mut sdata, w, h, w, scale + scale, scale);
         for y in (0..h).step_by(scale) {
             for x in (0..w).step_by(scale) {
                 if smask[y * w + x] == 0 {
-                    scratch[y * w + x] = data[y * rowsize + x] as i32;
+                    sdata[y * w + x] = data[y * rowsize + x];
                 }
             }
         }
 
-        // re-decompose to freeze the mask out
-        for i in 0..scratch.len() {
-            scratch_i16[i] = scratch[i] as i16;
-        }
-        Encode::forward(&mut scratch_i16, w, h, w, levels);
-        for i in 0..scratch.len() {
-            scratch[i] = scratch_i16[i] as i32;
-        }
-
-        // copy the frozen coefficients back into data (convert i32 to i16)
+        // Decompose again and copy the coefficients back into data
+        Encode::forward_scales(&mut sdata, w, h, w, scale, scale + scale);
         for y in (0..h).step_by(scale) {
             for x in (0..w).step_by(scale) {
-                data[y * rowsize + x] = scratch[y * w + x] as i16;
+                data[y * rowsize + x] = sdata[y * w + x];
             }
         }
 
-        // update the mask for the next coarser scale
+        // A coarser sample stays masked only if its neighbours were masked
+        let mut m0 = 0;
         for y in (0..h).step_by(scale * 2) {
+            let m = y * w;
+            let m1 = if y + scale < h { m + scale * w } else { m0 };
             for x in (0..w).step_by(scale * 2) {
-                let m00 = smask[y * w + x] != 0;
-                let m10 = if y + scale < h {
-                    smask[(y + scale) * w + x] != 0
-                } else {
-                    false
-                };
-                let left = x >= scale && smask[y * w + x - scale] != 0;
-                let right = x + scale < w && smask[y * w + x + scale] != 0;
-                smask[y * w + x] = if m00 && m10 && left && right { 1 } else { 0 };
+                let masked = smask[m + x] != 0
+                    && smask[m0 + x] != 0
+                    && smask[m1 + x] != 0
+                    && (x == 0 || smask[m + x - scale] != 0)
+                    && (x + scale >= w || smask[m + x + scale] != 0);
+                smask[m + x] = masked as i8;
             }
+            m0 = m1;
         }
 
         scale <<= 1;
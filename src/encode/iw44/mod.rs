@@ -8,6 +8,7 @@
 pub mod codec;
 pub mod coeff_map;
 pub mod constants;
+pub mod decoder;
 pub mod encoder;
 pub mod masking;
 #[cfg(test)]
@@ -19,6 +20,7 @@ pub mod zigzag;
 pub use codec::*;
 pub use coeff_map::*;
 pub use constants::*;
+pub use decoder::Iw44Image;
 pub use encoder::*;
 pub use masking::*;
 pub use zigzag::{ZIGZAG_LOC, get_zigzag_loc, get_zigzag_loc_checked};
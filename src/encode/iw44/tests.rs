@@ -1,6 +1,22 @@
 #[cfg(test)]
 mod tests {
-    use crate::encode::iw44::encoder::{CrcbMode, EncoderParams, rgb_to_ycbcr_planes};
+    use crate::encode::iw44::encoder::{
+        CrcbMode, EncoderParams, IWEncoder, rgb_to_ycbcr_planes,
+    };
+    use crate::image::image_formats::{Pixel, Pixmap};
+
+    fn gradient_encoder(crcb_mode: CrcbMode, decibels: Option<f32>) -> IWEncoder {
+        let img = Pixmap::from_fn(48, 40, |x, y| {
+            Pixel::new((x * 5) as u8, (y * 6) as u8, ((x + y) * 3) as u8)
+        });
+        let params = EncoderParams {
+            slices: None,
+            decibels,
+            crcb_mode,
+            ..EncoderParams::default()
+        };
+        IWEncoder::from_rgb(&img, None, params).unwrap()
+    }
 
     /// Test color conversion with known values
     #[test]
@@ -169,5 +185,565 @@ mod tests {
         let default_mode = CrcbMode::default();
         assert!(matches!(default_mode, CrcbMode::None));
     }
+
+    /// Driving the encoder by the `more` flag must produce exactly the chunks
+    /// of the "call until an empty chunk comes back" loop, and then stop.
+    #[test]
+    fn test_more_flag_matches_empty_chunk_loop() {
+        for (mode, decibels) in [
+            (CrcbMode::Full, None),
+            (CrcbMode::Normal, None),
+            (CrcbMode::Normal, Some(45.0)),
+        ] {
+            let mut by_empty = Vec::new();
+            let mut encoder = gradient_encoder(mode, decibels);
+            loop {
+                let (chunk, _) = encoder.encode_chunk(70).unwrap();
+                if chunk.is_empty() {
+                    break;
+                }
+                by_empty.push(chunk);
+            }
+
+            let mut by_more = Vec::new();
+            let mut encoder = gradient_encoder(mode, decibels);
+            loop {
+                let (chunk, more) = encoder.encode_chunk(70).unwrap();
+                assert!(!chunk.is_empty(), "more was set but no data remained");
+                by_more.push(chunk);
+                assert_eq!(more, encoder.has_pending_slices());
+                if !more {
+                    break;
+                }
+            }
+
+            assert!(by_more.len() > 1);
+            assert_eq!(by_more, by_empty);
+            assert_eq!(encoder.encode_chunk(7).unwrap(), (Vec::new(), false));
+        }
+    }
+
+    /// Without a mask, tiling must not change a single coefficient, so the
+    /// tiled and whole-image encodes are byte-identical.
+    #[test]
+    fn test_tiled_encode_matches_whole_image() {
+        use crate::encode::iw44::coeff_map::CoeffMap;
+        use crate::encode::iw44::encoder::ycbcr_from_rgb;
+
+        let (w, h) = (200u32, 700u32);
+        let img = Pixmap::from_fn(w, h, |x, y| {
+            let ring = (((x as i32 - 100).pow(2) + (y as i32 - 350).pow(2)) / 400) as u8;
+            Pixel::new(ring.wrapping_mul(7), (y / 3) as u8, (x + y / 5) as u8)
+        });
+
+        let (y_buf, _, _) = ycbcr_from_rgb(&img);
+        let whole = CoeffMap::create_from_signed_channel(&y_buf, w, h, None, "Y");
+        let tiled = CoeffMap::create_tiled(w as usize, h as usize, None, 64, |y, row| {
+            row.copy_from_slice(&y_buf[y * w as usize..(y + 1) * w as usize])
+        });
+        for (a, b) in whole.blocks.iter().zip(&tiled.blocks) {
+            for i in 0..1024 {
+                assert_eq!(
+                    a.get_coeff_at_zigzag_index(i),
+                    b.get_coeff_at_zigzag_index(i)
+                );
+            }
+        }
+
+        for crcb_mode in [CrcbMode::Normal, CrcbMode::Half, CrcbMode::None] {
+            let params = EncoderParams {
+                slices: Some(74),
+                decibels: None,
+                crcb_mode,
+                ..EncoderParams::default()
+            };
+            let mut whole_enc = IWEncoder::from_rgb(&img, None, params.clone()).unwrap();
+            let mut tiled_enc = IWEncoder::from_rgb_tiled(&img, None, params, 64).unwrap();
+            assert_eq!(
+                whole_enc.encode_chunk(74).unwrap(),
+                tiled_enc.encode_chunk(74).unwrap()
+            );
+        }
+    }
+
+    /// A boosted rect decodes closer to the source than without the boost,
+    /// and the rest of the image no worse: the boost refines the rect
+    /// rather than coarsening its surroundings. Later chunks go on
+    /// refining the rect.
+    #[test]
+    fn test_region_boost_raises_local_fidelity() {
+        use crate::encode::iw44::Iw44Image;
+        use crate::image::geom::Rect;
+        use crate::image::image_formats::{Bitmap, GrayPixel};
+
+        let (w, h) = (192u32, 192u32);
+        let pixels = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| GrayPixel {
+                y: ((x + y) / 2 + ((x * 7919 + y * 104_729) ^ (x * y)) % 48) as u8,
+            })
+            .collect();
+        let img = Bitmap::from_vec(w, h, pixels);
+        let rect = Rect::new(64, 64, 64, 64);
+        // (inside, outside) pixel PSNR of the image decoded so far
+        let psnr = |image: &Iw44Image| {
+            let decoded = image.to_pixmap().unwrap();
+            let (mut inside, mut outside) = ((0f64, 0f64), (0f64, 0f64));
+            for (x, y) in (0..h).flat_map(|y| (0..w).map(move |x| (x, y))) {
+                let d = decoded.get_pixel(x, y).r as f64 - img.get_pixel(x, y).y as f64;
+                let acc = if rect.contains(x as i32, y as i32) {
+                    &mut inside
+                } else {
+                    &mut outside
+                };
+                acc.0 += d * d;
+                acc.1 += 1.0;
+            }
+            let db = |(sq_err, n): (f64, f64)| 10.0 * (255.0 * 255.0 * n / sq_err.max(1.0)).log10();
+            (db(inside), db(outside))
+        };
+        // The encoder and the image decoded from its first chunk
+        let first_chunk = |region_boosts| {
+            let params = EncoderParams {
+                region_boosts,
+                ..EncoderParams::default()
+            };
+            let mut encoder = IWEncoder::from_gray(&img, None, params).unwrap();
+            let (chunk, more) = encoder.encode_chunk(70).unwrap();
+            assert!(more);
+            let mut image = Iw44Image::new();
+            image.decode_chunk(&chunk).unwrap();
+            (encoder, image)
+        };
+
+        let (_, plain) = first_chunk(Vec::new());
+        let (plain_in, plain_out) = psnr(&plain);
+        let (mut encoder, mut boosted) = first_chunk(vec![(rect, 18.0)]);
+        let (boosted_in, boosted_out) = psnr(&boosted);
+        assert!(
+            boosted_in > plain_in + 6.0,
+            "inside {plain_in:.1} dB, boosted {boosted_in:.1} dB"
+        );
+        assert!(
+            boosted_out > plain_out - 0.5,
+            "outside {plain_out:.1} dB, boosted {boosted_out:.1} dB"
+        );
+
+        // The stream goes on, refining the rect and leaving the rest as is
+        let (chunk, _) = encoder.encode_chunk(20).unwrap();
+        assert!(!chunk.is_empty());
+        boosted.decode_chunk(&chunk).unwrap();
+        let (later_in, later_out) = psnr(&boosted);
+        assert!(
+            later_in > boosted_in + 1.0,
+            "inside {boosted_in:.1} dB, then {later_in:.1} dB"
+        );
+        assert!(
+            (later_out - boosted_out).abs() < 0.5,
+            "outside {boosted_out:.1} dB, then {later_out:.1} dB"
+        );
+    }
+
+    /// The inverse transform undoes every level of the forward one, at
+    /// sizes that exercise all the edge cases of both filters.
+    #[test]
+    fn test_backward_inverts_forward() {
+        use crate::encode::iw44::transform::{Decode, Encode};
+
+        for (w, h) in [(1, 1), (2, 3), (5, 4), (7, 9), (33, 17), (64, 64), (100, 37)] {
+            let stride = w + 3;
+            let original: Vec<i16> = (0..stride * h)
+                .map(|i| {
+                    let (x, y) = (i % stride, i / stride);
+                    if x < w {
+                        ((x * 37 + y * 91 + x * y) % 256) as i16 * 64 - 8192
+                    } else {
+                        0
+                    }
+                })
+                .collect();
+            let mut data = original.clone();
+            Encode::forward(&mut data, w, h, stride, 5);
+            if w * h > 4 {
+                assert_ne!(data, original);
+            }
+            Decode::backward(&mut data, w, h, stride, 32, 1);
+            assert_eq!(data, original, "{w}x{h}");
+        }
+    }
+
+    /// A mask hides the dark box from the transform: the masked pixels are
+    /// interpolated from the background, so the blocks under the box carry
+    /// far less detail energy than when the box is coded literally.
+    #[test]
+    fn test_mask_reduces_energy_under_masked_region() {
+        use crate::encode::iw44::coeff_map::CoeffMap;
+        use crate::image::image_formats::{Bitmap, GrayPixel};
+
+        let (w, h) = (96u32, 96u32);
+        let in_box = |x: u32, y: u32| (30..60).contains(&x) && (10..30).contains(&y);
+        let mut img = Bitmap::new(w, h);
+        let mut mask = Bitmap::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let gray = if in_box(x, y) { 0 } else { 180 + ((x + y) / 8) as u8 };
+                img.put_pixel(x, y, GrayPixel::new(gray));
+                if in_box(x, y) {
+                    mask.put_pixel(x, y, GrayPixel::new(255));
+                }
+            }
+        }
+
+        // Detail energy of the blocks covering the box, which sits in block
+        // row 2 counting bottom-up like the transform buffer
+        let energy = |map: &CoeffMap| {
+            let blocks_w = map.bw / 32;
+            let mut total = 0i64;
+            for block_x in 0..2 {
+                let mut liftblock = [0i16; 1024];
+                map.blocks[2 * blocks_w + block_x].write_liftblock(&mut liftblock);
+                total += liftblock[1..].iter().map(|&c| (c as i64).pow(2)).sum::<i64>();
+            }
+            total
+        };
+        let plain = energy(&CoeffMap::create_from_image(&img, None));
+        let masked = energy(&CoeffMap::create_from_image(&img, Some(&mask)));
+        assert!(
+            masked * 10 < plain,
+            "masked energy {masked}, unmasked energy {plain}"
+        );
+
+        let params = EncoderParams {
+            slices: None,
+            decibels: None,
+            ..EncoderParams::default()
+        };
+        let mut encoder = IWEncoder::from_gray(&img, Some(&mask), params).unwrap();
+        assert!(!encoder.encode_chunk(20).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_non_finite_decibels() {
+        use crate::encode::iw44::encoder::EncoderError;
+
+        let img = Pixmap::from_pixel(16, 16, Pixel::white());
+        for db in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 1000.0, 5.0] {
+            let params = EncoderParams {
+                decibels: Some(db),
+                ..EncoderParams::default()
+            };
+            match IWEncoder::from_rgb(&img, None, params) {
+                Err(EncoderError::InvalidValue {
+                    name: "decibels", ..
+                }) => {}
+                other => panic!("{db} dB: {:?}", other.map(|_| ())),
+            }
+        }
+
+        let params = EncoderParams {
+            quant_multiplier: f32::NAN,
+            ..EncoderParams::default()
+        };
+        assert!(IWEncoder::from_gray(&img.to_bitmap(), None, params).is_err());
+
+        let params = EncoderParams {
+            decibels: Some(120.0),
+            ..EncoderParams::default()
+        };
+        assert!(IWEncoder::from_rgb(&img, None, params).is_ok());
+    }
+
+    #[test]
+    fn test_wavelet_round_trip_with_stride() {
+        use crate::encode::iw44::transform::{forward_wavelet, inverse_wavelet};
+
+        let (w, h, stride) = (37, 23, 40);
+        let original: Vec<i16> = (0..stride * h)
+            .map(|i| {
+                let (x, y) = (i % stride, i / stride);
+                if x < w {
+                    ((x * 29 + y * 53) % 256) as i16 * 64 - 8192
+                } else {
+                    7
+                }
+            })
+            .collect();
+        let mut data = original.clone();
+        forward_wavelet(&mut data, w, h, stride, 4).unwrap();
+        assert_ne!(data, original);
+        // Padding between rows is untouched
+        assert!((0..h).all(|y| data[y * stride + w..(y + 1) * stride].iter().all(|&v| v == 7)));
+        inverse_wavelet(&mut data, w, h, stride, 4).unwrap();
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn test_wavelet_rejects_bad_arguments() {
+        use crate::encode::iw44::encoder::EncoderError;
+        use crate::encode::iw44::transform::forward_wavelet;
+
+        let mut data = vec![0i16; 64 * 64];
+        assert!(matches!(
+            forward_wavelet(&mut data, 64, 64, 32, 3),
+            Err(EncoderError::InvalidBuffer { stride: 32, .. })
+        ));
+        assert!(matches!(
+            forward_wavelet(&mut data, 64, 65, 64, 3),
+            Err(EncoderError::InvalidBuffer { height: 65, .. })
+        ));
+        assert!(matches!(
+            forward_wavelet(&mut data, 64, 64, 64, 0),
+            Err(EncoderError::InvalidLevels { levels: 0, .. })
+        ));
+        assert!(matches!(
+            forward_wavelet(&mut data, 64, 64, 64, 6),
+            Err(EncoderError::InvalidLevels { max: 5, .. })
+        ));
+        assert!(matches!(
+            forward_wavelet(&mut data, 8, 64, 64, 4),
+            Err(EncoderError::InvalidLevels { max: 3, .. })
+        ));
+        assert!(matches!(
+            forward_wavelet(&mut data, 0, 64, 64, 1),
+            Err(EncoderError::EmptyObject)
+        ));
+        assert!(data.iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn test_chunk_serial_does_not_wrap() {
+        use crate::encode::iw44::encoder::EncoderError;
+        use crate::image::image_formats::{Bitmap, GrayPixel};
+
+        let mut img = Bitmap::new(64, 64);
+        for y in 0..64 {
+            for x in 0..64 {
+                img.put_pixel(x, y, GrayPixel::new(((x * 37 + y * 11) % 256) as u8));
+            }
+        }
+        let params = EncoderParams {
+            slices: None,
+            lossless: true,
+            ..EncoderParams::default()
+        };
+        let mut encoder = IWEncoder::from_gray(&img, None, params).unwrap();
+
+        let mut serials = Vec::new();
+        let result = loop {
+            match encoder.encode_chunk(1) {
+                Ok((chunk, more)) => {
+                    if !chunk.is_empty() {
+                        serials.push(chunk[0]);
+                    }
+                    if !more || serials.len() > 300 {
+                        break None;
+                    }
+                }
+                Err(e) => break Some(e),
+            }
+        };
+
+        assert!(serials.len() <= 256);
+        assert_eq!(serials.iter().filter(|&&s| s == 0).count(), 1);
+        if serials.len() == 256 {
+            assert!(matches!(result, Some(EncoderError::TooManyChunks)));
+        } else {
+            assert!(result.is_none());
+        }
+    }
+
+    #[test]
+    fn test_band_stats_count_dc_bits() {
+        let mut encoder = gradient_encoder(CrcbMode::Full, None);
+        assert!(encoder.band_stats().iter().all(|stat| stat.bits == 0));
+        for _ in 0..5 {
+            encoder.encode_chunk(70).unwrap();
+        }
+
+        let stats = encoder.band_stats();
+        assert!(stats[0].bits > 0, "{stats:?}");
+        assert!(stats[0].active_coeffs > 0, "{stats:?}");
+        assert!(stats.iter().map(|stat| stat.bits).sum::<u64>() > stats[0].bits);
+    }
+
+    #[test]
+    fn test_ycbcr_planes_match_rgb() {
+        use crate::encode::iw44::encoder::{EncoderError, ycbcr_from_rgb};
+
+        let img = Pixmap::from_fn(48, 40, |x, y| {
+            Pixel::new((x * 5) as u8, (y * 6) as u8, ((x + y) * 3) as u8)
+        });
+        let (y, cb, cr) = ycbcr_from_rgb(&img);
+        for crcb_mode in [CrcbMode::Full, CrcbMode::Half] {
+            let params = EncoderParams {
+                crcb_mode,
+                ..EncoderParams::default()
+            };
+            let mut from_rgb = IWEncoder::from_rgb(&img, None, params.clone()).unwrap();
+            let mut from_planes =
+                IWEncoder::from_ycbcr_planes(&y, &cb, &cr, 48, 40, None, params).unwrap();
+            for _ in 0..3 {
+                assert_eq!(
+                    from_planes.encode_chunk(20).unwrap(),
+                    from_rgb.encode_chunk(20).unwrap()
+                );
+            }
+        }
+
+        let short = IWEncoder::from_ycbcr_planes(
+            &y,
+            &cb[1..],
+            &cr,
+            48,
+            40,
+            None,
+            EncoderParams::default(),
+        );
+        assert!(matches!(
+            short,
+            Err(EncoderError::InvalidBuffer { len: 1919, .. })
+        ));
+    }
+
+    /// With a byte budget, coarser steps in the flat half of a photo leave
+    /// more bits for the textured half, which then decodes more faithfully
+    /// than under flat quantization, while the whole image holds up.
+    #[test]
+    fn test_adaptive_quant_favours_detail_at_equal_bytes() {
+        use crate::encode::iw44::Iw44Image;
+
+        // Left half: a gentle ramp with light grain; right half: texture
+        let (w, h) = (256u32, 128u32);
+        let img = Pixmap::from_fn(w, h, |x, y| {
+            let hash = (x * 7919 + y * 104_729) ^ (x * y);
+            let v = if x < w / 2 {
+                100 + y / 8 + hash % 16
+            } else {
+                60 + (x + y) % 32 * 3 + hash % 64
+            };
+            Pixel::new(v as u8, v as u8, v as u8)
+        });
+
+        // (textured half, whole image) pixel PSNR after each one-slice chunk
+        let psnr = |decoded: &Pixmap| {
+            let (mut detail, mut whole) = ((0f64, 0f64), (0f64, 0f64));
+            for (x, y) in (0..h).flat_map(|y| (0..w).map(move |x| (x, y))) {
+                let d = decoded.get_pixel(x, y).r as f64 - img.get_pixel(x, y).r as f64;
+                for acc in [&mut whole].into_iter().chain((x >= w / 2).then_some(&mut detail)) {
+                    acc.0 += d * d;
+                    acc.1 += 1.0;
+                }
+            }
+            let db = |(sq_err, n): (f64, f64)| 10.0 * (255.0 * 255.0 * n / sq_err.max(1.0)).log10();
+            (db(detail), db(whole))
+        };
+        // Slices stop at arbitrary sizes, so the best prefix of chunks that
+        // fits `budget` bytes stands for the image at that size
+        let budget = 8_000;
+        let within_budget = |adaptive_quant: Option<f32>| {
+            let params = EncoderParams {
+                crcb_mode: CrcbMode::None,
+                adaptive_quant,
+                ..EncoderParams::default()
+            };
+            let mut encoder = IWEncoder::from_rgb(&img, None, params).unwrap();
+            let mut decoder = Iw44Image::new();
+            let (mut bytes, mut best) = (0, (0.0, 0.0));
+            loop {
+                let (chunk, more) = encoder.encode_chunk(1).unwrap();
+                bytes += chunk.len();
+                if bytes > budget {
+                    break;
+                }
+                decoder.decode_chunk(&chunk).unwrap();
+                best = psnr(&decoder.to_pixmap().unwrap());
+                if !more {
+                    break;
+                }
+            }
+            best
+        };
+
+        let (flat_detail, flat_whole) = within_budget(None);
+        let (adaptive_detail, adaptive_whole) = within_budget(Some(18.0));
+        assert!(
+            adaptive_detail > flat_detail + 1.0,
+            "detail: adaptive {adaptive_detail:.2} dB, flat {flat_detail:.2} dB"
+        );
+        assert!(
+            adaptive_whole > flat_whole - 1.0,
+            "whole: adaptive {adaptive_whole:.2} dB, flat {flat_whole:.2} dB"
+        );
+
+        let bad = EncoderParams {
+            adaptive_quant: Some(f32::NAN),
+            ..EncoderParams::default()
+        };
+        assert!(IWEncoder::from_rgb(&img, None, bad).is_err());
+    }
+
+    /// A lone DC coefficient must become significant on the first band-0 slice whose
+    /// threshold it reaches, and the null-slice scan must agree with bucket preparation.
+    #[test]
+    fn test_single_coefficient_significance_bitplane() {
+        use crate::encode::iw44::codec::Codec;
+        use crate::encode::iw44::coeff_map::CoeffMap;
+        use crate::encode::zc::zcodec::ZEncoder;
+        use std::io::Cursor;
+
+        let magnitude = 1000i16;
+        let mut map = CoeffMap::new(32, 32);
+        let mut bucket = [0i16; 16];
+        bucket[0] = -magnitude;
+        map.blocks[0].set_bucket(0, bucket);
+
+        let params = EncoderParams::default();
+        let mut codec = Codec::new(map, &params);
+        let mut zp = ZEncoder::new(Cursor::new(Vec::new()), true).unwrap();
+
+        // Thresholds halve once per bit-plane, so the expected plane follows from the start value
+        let start = codec.quant_lo[0];
+        let expected_plane = (0..).find(|&k| start >> k <= magnitude as i32).unwrap();
+
+        let mut plane = 0;
+        let mut activated_at = None;
+        loop {
+            if codec.curband == 0 && activated_at.is_none() {
+                let thres = codec.quant_lo[0];
+                assert_eq!(thres, start >> plane);
+                assert_eq!(
+                    codec.has_data_for_slice(codec.curbit, 0),
+                    thres <= magnitude as i32,
+                    "plane {plane}: threshold {thres}"
+                );
+            }
+            let band = codec.curband;
+            let more = codec.code_slice(&mut zp).unwrap();
+            if band == 0 {
+                if activated_at.is_none() && codec.emap.blocks[0].get_bucket_raw(0)[0] != 0 {
+                    activated_at = Some(plane);
+                }
+                plane += 1;
+            }
+            if !more || activated_at.is_some() {
+                break;
+            }
+        }
+
+        assert_eq!(activated_at, Some(expected_plane));
+    }
+
+    /// A mask shorter than the image covers the image's top rows, which are
+    /// the last rows of the bottom-up buffer.
+    #[test]
+    fn test_short_mask_keeps_top_rows() {
+        use crate::encode::iw44::masking::image_to_mask8;
+        use crate::image::image_formats::{Bitmap, GrayPixel};
+
+        let mask = Bitmap::from_pixel(4, 3, GrayPixel::new(255));
+        let mask8 = image_to_mask8(&mask, 4, 5);
+        let rows: Vec<bool> = mask8.chunks(4).map(|r| r.iter().all(|&m| m == 1)).collect();
+        assert_eq!(rows, [false, false, true, true, true]);
+    }
 }
 
@@ -1,5 +1,7 @@
 // Removed SIMD dependencies for stable Rust compatibility
 
+use crate::encode::iw44::coeff_map::transform_levels;
+use crate::encode::iw44::encoder::EncoderError;
 use crate::image::image_formats::Bitmap;
 
 /// Saturating conversion from i32 to i16 to prevent overflow
@@ -155,8 +157,22 @@ impl Encode {
     /// Forward wavelet transform using the streaming algorithm from DjVuLibre.
     /// Now operates on i16 throughout, matching C++'s short* buffer behavior.
     pub fn forward(buf: &mut [i16], w: usize, h: usize, rowsize: usize, levels: usize) {
-        let mut scale = 1;
-        for _ in 0..levels {
+        Self::forward_scales(buf, w, h, rowsize, 1, 1 << levels);
+    }
+
+    /// Runs the decomposition steps for scales `begin`, `2 * begin`, ...
+    /// below `end`, like `Transform::Encode::forward(p, w, h, rowsize, begin, end)`
+    /// in DjVuLibre. `begin` must be a power of two.
+    pub fn forward_scales(
+        buf: &mut [i16],
+        w: usize,
+        h: usize,
+        rowsize: usize,
+        begin: usize,
+        end: usize,
+    ) {
+        let mut scale = begin;
+        while scale < end {
             filter_fh(buf, w, h, rowsize, scale);
             filter_fv(buf, w, h, rowsize, scale);
             scale <<= 1;
@@ -184,6 +200,208 @@ impl Encode {
     }
 }
 
+/// Inverse wavelet transform, used by the encoder to reconstruct pixels
+/// while freezing masked coefficients.
+pub struct Decode;
+
+impl Decode {
+    /// Undoes [`Encode::forward_scales`] for scales `begin / 2` down to
+    /// `end`, like `Transform::Decode::backward(p, w, h, rowsize, begin, end)`
+    /// in DjVuLibre. `Decode::backward(buf, w, h, rowsize, 1 << levels, 1)`
+    /// inverts `Encode::forward(buf, w, h, rowsize, levels)` exactly.
+    pub fn backward(buf: &mut [i16], w: usize, h: usize, rowsize: usize, begin: usize, end: usize) {
+        let end = end.max(1);
+        let mut scale = begin >> 1;
+        while scale >= end {
+            filter_bv(buf, w, h, rowsize, scale);
+            filter_bh(buf, w, h, rowsize, scale);
+            scale >>= 1;
+        }
+    }
+}
+
+/// Applies `levels` levels of the IW44 wavelet transform in place
+///
+/// `data` holds a `width`x`height` image of signed samples (IW44 centers
+/// pixels around zero, so 8-bit values are usually shifted by -128 and
+/// scaled by 64). Row `y` starts at `data[y * stride]`; samples between
+/// `width` and `stride` are left alone. Each level halves the resolution
+/// of the low-pass band, and `levels` must lie between 1 and what the image
+/// allows: five at most, fewer once the coarsest band would be a single
+/// pixel. IW44 images themselves always use five. Coefficients stay in
+/// the pixel layout: the coarsest band ends up on the grid of multiples of
+/// `1 << levels`.
+pub fn forward_wavelet(
+    data: &mut [i16],
+    width: usize,
+    height: usize,
+    stride: usize,
+    levels: usize,
+) -> Result<(), EncoderError> {
+    check_wavelet_args(data.len(), width, height, stride, levels)?;
+    Encode::forward(data, width, height, stride, levels);
+    Ok(())
+}
+
+/// Undoes [`forward_wavelet`] with the same arguments, restoring the
+/// original samples exactly
+pub fn inverse_wavelet(
+    data: &mut [i16],
+    width: usize,
+    height: usize,
+    stride: usize,
+    levels: usize,
+) -> Result<(), EncoderError> {
+    check_wavelet_args(data.len(), width, height, stride, levels)?;
+    Decode::backward(data, width, height, stride, 1 << levels, 1);
+    Ok(())
+}
+
+/// Checks that a `len`-sample buffer holds the image and that `levels`
+/// fits it.
+fn check_wavelet_args(
+    len: usize,
+    width: usize,
+    height: usize,
+    stride: usize,
+    levels: usize,
+) -> Result<(), EncoderError> {
+    if width == 0 || height == 0 {
+        return Err(EncoderError::EmptyObject);
+    }
+    let needed = (height - 1)
+        .checked_mul(stride)
+        .and_then(|n| n.checked_add(width));
+    if stride < width || needed.is_none_or(|n| n > len) {
+        return Err(EncoderError::InvalidBuffer {
+            len,
+            width,
+            height,
+            stride,
+        });
+    }
+    let max = transform_levels(width, height);
+    if levels == 0 || levels > max {
+        return Err(EncoderError::InvalidLevels {
+            levels,
+            width,
+            height,
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// Inverse of `filter_fv`. The forward filter predicts odd rows from the
+/// original even rows and then updates even rows from the odd details, so
+/// undoing the updates first and the predictions second restores the input
+/// bit for bit (DjVuLibre's decoder filter differs at the borders).
+fn filter_bv(buf: &mut [i16], w: usize, h: usize, rowsize: usize, scale: usize) {
+    let s = scale * rowsize;
+    let h = if h > 0 { ((h - 1) / scale) + 1 } else { 0 };
+    let row = |buf: &[i16], y: usize, x: usize| {
+        if y < h { buf[y * s + x] as i32 } else { 0 }
+    };
+
+    // 1-Undo the update of even row y - 3 from the odd rows around it
+    let mut y = 3usize;
+    while y - 3 < h {
+        let p = (y - 3) * s;
+        for x in (0..w).step_by(scale) {
+            let a = if y >= 4 { row(buf, y - 4, x) } else { 0 } + row(buf, y - 2, x);
+            let b = if y >= 6 { row(buf, y - 6, x) } else { 0 } + row(buf, y, x);
+            buf[p + x] = (buf[p + x] as i32 - (((a << 3) + a - b + 16) >> 5)) as i16;
+        }
+        y += 2;
+    }
+
+    // 2-Undo the prediction of odd row y from the restored even rows
+    let mut y = 1usize;
+    while y < h {
+        let p = y * s;
+        for x in (0..w).step_by(scale) {
+            let above = if y + 1 < h {
+                row(buf, y + 1, x)
+            } else {
+                row(buf, y - 1, x)
+            };
+            let a = row(buf, y - 1, x) + above;
+            let prediction = if y >= 3 && y + 3 < h {
+                let b = row(buf, y - 3, x) + row(buf, y + 3, x);
+                ((a << 3) + a - b + 8) >> 4
+            } else {
+                (a + 1) >> 1
+            };
+            buf[p + x] = (buf[p + x] as i32 + prediction) as i16;
+        }
+        y += 2;
+    }
+}
+
+/// Inverse of `filter_fh`, undoing the updates and then the predictions of
+/// each row like `filter_bv` does for columns.
+fn filter_bh(buf: &mut [i16], w: usize, h: usize, rowsize: usize, scale: usize) {
+    let s = scale;
+    let s3 = s + s + s;
+    let rowsize = rowsize * scale;
+    let mut y = 0usize;
+    let mut p = 0usize;
+
+    while y < h {
+        let e = p + w;
+
+        // 1-Undo the update of even sample q - s3
+        let mut q = p + s;
+        let (mut b1, mut b2, mut b3) = (0i32, 0i32, 0i32);
+        while q < e + s3 {
+            let b0 = b1;
+            b1 = b2;
+            b2 = b3;
+            b3 = if q < e { buf[q] as i32 } else { 0 };
+            if q >= p + s3 && q - s3 < e {
+                let update = (((b1 + b2) << 3) + (b1 + b2) - b0 - b3 + 16) >> 5;
+                buf[q - s3] = (buf[q - s3] as i32 - update) as i16;
+            }
+            q += s + s;
+        }
+
+        // 2-Undo the prediction of odd sample q
+        let mut q = p + s;
+        let (mut a1, mut a2, mut a3) = (0i32, 0i32, 0i32);
+        if q < e {
+            a1 = buf[q - s] as i32;
+            a2 = a1;
+            a3 = a1;
+            if q + s < e {
+                a2 = buf[q + s] as i32;
+            }
+            if q + s3 < e {
+                a3 = buf[q + s3] as i32;
+            }
+            buf[q] = (buf[q] as i32 + ((a1 + a2 + 1) >> 1)) as i16;
+            q += s + s;
+        }
+        while q + s3 < e {
+            let a0 = a1;
+            a1 = a2;
+            a2 = a3;
+            a3 = buf[q + s3] as i32;
+            let prediction = (((a1 + a2) << 3) + (a1 + a2) - a0 - a3 + 8) >> 4;
+            buf[q] = (buf[q] as i32 + prediction) as i16;
+            q += s + s;
+        }
+        while q < e {
+            a1 = a2;
+            a2 = a3;
+            buf[q] = (buf[q] as i32 + ((a1 + a2 + 1) >> 1)) as i16;
+            q += s + s;
+        }
+
+        y += scale;
+        p += rowsize;
+    }
+}
+
 /// Streaming horizontal filter - operates on i16 like C++ (port of filter_fh from IW44EncodeCodec.cpp:514)
 fn filter_fh(buf: &mut [i16], w: usize, h: usize, mut rowsize: usize, scale: usize) {
     let s = scale;
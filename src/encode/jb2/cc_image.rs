@@ -62,7 +62,34 @@
 //! preserves the algorithmic structure but is a clean-room reimplementation
 //! of the public API and data flow described in the DjVu specification.
 
-use crate::encode::jb2::symbol_dict::BitImage;
+use crate::doc::page_encoder::MAX_PAGE_DIMENSION;
+use crate::encode::jb2::error::Jb2Error;
+use crate::encode::jb2::symbol_dict::{BitImage, Comparator};
+use std::collections::HashMap;
+
+/// Pixel coordinate shared by the JB2 geometry types: [`Run`], [`BBox`],
+/// [`CCImage`] and the blits of [`EncoderShapes`].
+///
+/// Coordinates lie in `0..=MAX_COORD`, so sums and differences of two of
+/// them always fit. Pixel counts can exceed `i32` on a full-size page and
+/// are kept as `i64` instead.
+pub type Coord = i32;
+
+/// Largest coordinate: the DjVu page size limit.
+pub const MAX_COORD: Coord = MAX_PAGE_DIMENSION as Coord;
+
+/// Converts a `BitImage` index or size to a [`Coord`].
+///
+/// Every `BitImage` constructor rejects sides above [`MAX_PAGE_DIMENSION`],
+/// so a value past [`MAX_COORD`] means the image's public `width` or
+/// `height` was changed by hand, and the analysis panics rather than
+/// produce wrapped coordinates.
+fn coord(value: usize) -> Coord {
+    match Coord::try_from(value) {
+        Ok(c) if c <= MAX_COORD => c,
+        _ => panic!("coordinate {value} exceeds the DjVu page limit of {MAX_COORD}"),
+    }
+}
 
 // ─── Run ────────────────────────────────────────────────────────────────────
 
@@ -72,18 +99,18 @@ pub struct Run {
     /// Vertical coordinate (row).  y = 0 is the **top** of the image in our
     /// coordinate system; cjb2.cpp uses bottom-up, but we canonicalize to
     /// top-down since `BitImage` is top-down.  The algorithm is symmetric.
-    pub y: i32,
+    pub y: Coord,
     /// First (leftmost) horizontal coordinate of the run, inclusive.
-    pub x1: i32,
+    pub x1: Coord,
     /// Last (rightmost) horizontal coordinate of the run, inclusive.
-    pub x2: i32,
+    pub x2: Coord,
     /// Connected-component id assigned during analysis.
     pub ccid: i32,
 }
 
 impl Run {
     /// Ordering used when sorting: primary by y ascending, secondary by x1.
-    fn sort_key(&self) -> (i32, i32) {
+    fn sort_key(&self) -> (Coord, Coord) {
         (self.y, self.x1)
     }
 }
@@ -94,19 +121,19 @@ impl Run {
 /// matching DjVuLibre's `GRect` convention.
 #[derive(Clone, Copy, Debug, Default)]
 pub struct BBox {
-    pub xmin: i32,
-    pub ymin: i32,
+    pub xmin: Coord,
+    pub ymin: Coord,
     /// Exclusive right edge.
-    pub xmax: i32,
+    pub xmax: Coord,
     /// Exclusive bottom edge.
-    pub ymax: i32,
+    pub ymax: Coord,
 }
 
 impl BBox {
-    pub fn width(&self) -> i32 {
+    pub fn width(&self) -> Coord {
         self.xmax - self.xmin
     }
-    pub fn height(&self) -> i32 {
+    pub fn height(&self) -> Coord {
         self.ymax - self.ymin
     }
 }
@@ -117,7 +144,7 @@ pub struct CC {
     /// Bounding box (xmin/ymin inclusive, xmax/ymax exclusive).
     pub bb: BBox,
     /// Total number of foreground pixels in this CC.
-    pub npix: i32,
+    pub npix: i64,
     /// Number of runs belonging to this CC.
     pub nrun: i32,
     /// Index of the first run in the sorted runs array.
@@ -130,8 +157,8 @@ pub struct CC {
 /// cleaning, merging/splitting, and reading-order sort — matching the full
 /// pipeline of `cjb2.cpp`'s `CCImage` class.
 pub struct CCImage {
-    pub width: i32,
-    pub height: i32,
+    pub width: Coord,
+    pub height: Coord,
     pub runs: Vec<Run>,
     pub ccs: Vec<CC>,
     /// Number of "regular" CCs (text-sized).  CCs at indices ≥ nregularccs
@@ -157,7 +184,7 @@ impl CCImage {
     /// smallsize = max(2, dpi / 150)
     /// tinysize  = max(0, dpi² / 20000 − 1)
     /// ```
-    pub fn new(width: i32, height: i32, dpi: i32) -> Self {
+    pub fn new(width: Coord, height: Coord, dpi: i32) -> Self {
         let dpi = dpi.max(200).min(900);
         Self {
             width,
@@ -174,7 +201,7 @@ impl CCImage {
     // ── Run extraction ──────────────────────────────────────────────────
 
     /// Add a single run.
-    pub fn add_single_run(&mut self, y: i32, x1: i32, x2: i32) {
+    pub fn add_single_run(&mut self, y: Coord, x1: Coord, x2: Coord) {
         self.runs.push(Run { y, x1, x2, ccid: 0 });
     }
 
@@ -197,7 +224,7 @@ impl CCImage {
                     while x < bm.width && bm.get_pixel_unchecked(x, y) {
                         x += 1;
                     }
-                    self.add_single_run(y as i32, x1 as i32, (x - 1) as i32);
+                    self.add_single_run(coord(y), coord(x1), coord(x - 1));
                 }
             }
         }
@@ -426,18 +453,18 @@ impl CCImage {
             self.runs[start..end].sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
 
             // Compute bounds and pixel count
-            let mut npix = 0i32;
-            let mut xmin = i32::MAX;
-            let mut xmax = i32::MIN;
-            let mut ymin = i32::MAX;
-            let mut ymax = i32::MIN;
+            let mut npix = 0i64;
+            let mut xmin = Coord::MAX;
+            let mut xmax = Coord::MIN;
+            let mut ymin = Coord::MAX;
+            let mut ymax = Coord::MIN;
 
             for run in &self.runs[start..end] {
                 xmin = xmin.min(run.x1);
                 xmax = xmax.max(run.x2);
                 ymin = ymin.min(run.y);
                 ymax = ymax.max(run.y);
-                npix += run.x2 - run.x1 + 1;
+                npix += (run.x2 - run.x1 + 1) as i64;
             }
 
             let cc = &mut self.ccs[i];
@@ -460,7 +487,7 @@ impl CCImage {
     /// regions should be exempted, but neither cjb2 nor we do that.)
     pub fn erase_tiny_ccs(&mut self) {
         for i in 0..self.ccs.len() {
-            if self.ccs[i].npix <= self.tinysize {
+            if self.ccs[i].npix <= self.tinysize as i64 {
                 let frun = self.ccs[i].frun as usize;
                 let nrun = self.ccs[i].nrun as usize;
                 self.ccs[i].nrun = 0;
@@ -625,6 +652,14 @@ impl CCImage {
     ///
     /// Direct port of `CCImage::sort_in_reading_order()`.
     pub fn sort_in_reading_order(&mut self) {
+        self.sort_in_order(ReadingOrder::LtrTopDown);
+    }
+
+    /// Sort CCs into text lines (or columns) following `order`.
+    ///
+    /// Lines are grouped along the block axis with the same tolerance as
+    /// [`Self::sort_in_reading_order`], then sorted along the inline axis.
+    pub fn sort_in_order(&mut self, order: ReadingOrder) {
         let n = self.nregularccs;
         if n < 2 {
             return;
@@ -637,38 +672,51 @@ impl CCImage {
             .map(|(i, cc)| (i, cc.clone()))
             .collect();
 
-        // Sort by top edge ascending (lowest ymin first) for Top-Down coordinates.
-        // This ensures Top-to-Bottom reading order.
+        // Position of a CC across lines (growing from the first line to the
+        // last) and along its line (growing in reading direction). Vertical
+        // columns run right to left, so their block position is -xmax.
+        let block = |cc: &CC| match order {
+            ReadingOrder::LtrTopDown | ReadingOrder::RtlTopDown => cc.bb.ymin,
+            ReadingOrder::VerticalRtl => -cc.bb.xmax,
+        };
+        let inline = |cc: &CC| match order {
+            ReadingOrder::LtrTopDown => cc.bb.xmin,
+            ReadingOrder::RtlTopDown => -cc.bb.xmax,
+            ReadingOrder::VerticalRtl => cc.bb.ymin,
+        };
+
+        // Sort by the leading edge of the line axis first; for left-to-right
+        // text that is the top edge ascending (lowest ymin first).
         cc_arr.sort_by(|a, b| {
-            a.1.bb
-                .ymin
-                .cmp(&b.1.bb.ymin)
-                .then(a.1.bb.xmin.cmp(&b.1.bb.xmin))
+            block(&a.1)
+                .cmp(&block(&b.1))
+                .then(inline(&a.1).cmp(&inline(&b.1)))
                 .then(a.1.frun.cmp(&b.1.frun))
         });
 
-        // Determine max vertical deviation for line grouping
-        let maxtopchange = (self.width / 40).max(32);
+        // Determine max deviation across the line axis for line grouping
+        let maxtopchange = match order {
+            ReadingOrder::VerticalRtl => (self.height / 40).max(32),
+            _ => (self.width / 40).max(32),
+        };
 
         // Group into text lines and sort within each line
         let mut ccno = 0usize;
         while ccno < n {
-            let line_start_ymin = cc_arr[ccno].1.bb.ymin;
-            // Scan for the end of this line (items that are vertically close)
+            let line_start = block(&cc_arr[ccno].1);
+            // Scan for the end of this line (items that are close across it)
 
             let mut nccno = ccno + 1;
             while nccno < n {
-                let curr_ymin = cc_arr[nccno].1.bb.ymin;
-
-                // If the next items top edge is significantly below the line start, it's a new line
-                if curr_ymin > line_start_ymin + maxtopchange {
+                // If the next item starts significantly further along, it's a new line
+                if block(&cc_arr[nccno].1) > line_start + maxtopchange {
                     break;
                 }
                 nccno += 1;
             }
 
-            // Sort this line left-to-right (by xmin)
-            cc_arr[ccno..nccno].sort_by(|a, b| a.1.bb.xmin.cmp(&b.1.bb.xmin));
+            // Sort this line in reading direction
+            cc_arr[ccno..nccno].sort_by_key(|a| inline(&a.1));
 
             // Move to next line
             ccno = nccno;
@@ -716,7 +764,7 @@ impl CCImage {
             return None;
         }
 
-        let mut bm = BitImage::new(w as u32, h as u32).ok()?;
+        let mut bm = BitImage::new(w.try_into().ok()?, h.try_into().ok()?).ok()?;
         let frun = cc.frun as usize;
         let nrun = cc.nrun as usize;
 
@@ -748,6 +796,11 @@ impl CCImage {
     /// After this, iterate `0..self.ccs.len()` and call
     /// `get_bitmap_for_cc(i)` to extract symbol bitmaps.
     pub fn analyze(&mut self, losslevel: i32) {
+        self.analyze_in_order(losslevel, ReadingOrder::LtrTopDown);
+    }
+
+    /// Like [`Self::analyze`], sorting the CCs with [`Self::sort_in_order`].
+    pub fn analyze_in_order(&mut self, losslevel: i32, order: ReadingOrder) {
         self.make_ccids_by_analysis();
         self.make_ccs_from_ccids();
 
@@ -756,7 +809,7 @@ impl CCImage {
         }
 
         self.merge_and_split_ccs();
-        self.sort_in_reading_order();
+        self.sort_in_order(order);
     }
 
     /// Convert the analyzed CCs into (bitmap, bounding_box) pairs ready
@@ -775,6 +828,36 @@ impl CCImage {
     }
 }
 
+// ─── Reading order ──────────────────────────────────────────────────────────
+
+/// Direction text is read in, which decides the order CCs are numbered in
+/// and so the order of the JB2 dictionary.
+///
+/// Blits are coded left to right in rows whatever the order: JB2 starts a
+/// new row whenever a blit lies left of the previous one, so blits coded
+/// right to left or by column would each open a row of their own and make
+/// the page larger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ReadingOrder {
+    /// Horizontal lines from the top, each read left to right (Latin, Cyrillic)
+    #[default]
+    LtrTopDown,
+    /// Horizontal lines from the top, each read right to left (Arabic, Hebrew)
+    RtlTopDown,
+    /// Vertical columns from the right, each read top to bottom (traditional
+    /// Chinese, Japanese)
+    VerticalRtl,
+}
+
+/// Dictionary, refinement parents and `(left, bottom, shapeno)` blits, as
+/// taken by `JB2Encoder::encode_page_with_shapes()`.
+pub type EncoderShapes = (Vec<BitImage>, Vec<i32>, Vec<(Coord, Coord, usize)>);
+
 // ─── Convenience entry point ────────────────────────────────────────────────
 
 /// Perform connected-component analysis on a `BitImage` and return the
@@ -792,33 +875,56 @@ impl CCImage {
 /// A `CCImage` with the full analysis complete.  Call `extract_shapes()`
 /// to get `(BitImage, BBox)` pairs.
 pub fn analyze_page(image: &BitImage, dpi: i32, losslevel: i32) -> CCImage {
-    let mut ccimg = CCImage::new(image.width as i32, image.height as i32, dpi);
+    analyze_page_in_order(image, dpi, losslevel, ReadingOrder::LtrTopDown)
+}
+
+/// Like [`analyze_page`], for text laid out in `order`.
+pub fn analyze_page_in_order(
+    image: &BitImage,
+    dpi: i32,
+    losslevel: i32,
+    order: ReadingOrder,
+) -> CCImage {
+    let mut ccimg = CCImage::new(coord(image.width), coord(image.height), dpi);
     ccimg.add_bitmap_runs(image);
-    ccimg.analyze(losslevel);
+    ccimg.analyze_in_order(losslevel, order);
     ccimg
 }
 
 /// Convert CC analysis results into the format expected by JB2Encoder::encode_page_with_shapes().
 ///
+/// `page_height` must be the height of the analyzed image: every bbox then
+/// ends at or above it, so `bottom = page_height - bbox.ymax` is never
+/// negative. A shape that starts above or left of the page, or ends below
+/// it, is returned as [`Jb2Error::InvalidNumber`]; the encoder itself
+/// accepts blits partly off the page, as user-supplied shapes may need.
+///
 /// Returns:
 /// - shapes: Vec<BitImage> - the symbol bitmaps
 /// - parents: Vec<i32> - parent indices for refinement (-1 for no parent)
 /// - blits: Vec<(i32, i32, usize)> - (left, bottom, shapeno) for each symbol instance
 ///
-/// Note: Currently returns no parents (-1 for all shapes) and one blit per shape.
-/// For production use with symbol matching and refinement, you'd need to:
-/// 1. Compare shapes to find duplicates/similar symbols
-/// 2. Build parent relationships for refinement
-/// 3. Map multiple blits to the same shape index
+/// Shapes keep their order, so the dictionary follows the reading order of
+/// the analysis, while blits are always sorted into rows read left to right
+/// (see [`ReadingOrder`]).
+///
+/// Note: Returns no parents (-1 for all shapes) and one blit per shape. Use
+/// [`find_refinement_parents`] to code near-duplicate shapes as refinements.
 pub fn shapes_to_encoder_format(
     shapes: Vec<(BitImage, BBox)>,
-    page_height: i32,
-) -> (Vec<BitImage>, Vec<i32>, Vec<(i32, i32, usize)>) {
+    page_height: Coord,
+) -> Result<EncoderShapes, Jb2Error> {
     let mut bitmaps = Vec::with_capacity(shapes.len());
     let mut parents = Vec::with_capacity(shapes.len());
     let mut blits = Vec::with_capacity(shapes.len());
 
     for (idx, (bitmap, bbox)) in shapes.into_iter().enumerate() {
+        if bbox.xmin < 0 || bbox.ymin < 0 || bbox.ymax > page_height {
+            return Err(Jb2Error::InvalidNumber(format!(
+                "shape {} spanning rows {}..{} from column {} lies outside a page {} rows high",
+                idx, bbox.ymin, bbox.ymax, bbox.xmin, page_height
+            )));
+        }
         bitmaps.push(bitmap);
         parents.push(-1); // No parent (no refinement)
 
@@ -837,7 +943,69 @@ pub fn shapes_to_encoder_format(
             .then(a.0.cmp(&b.0))
     });
 
-    (bitmaps, parents, blits)
+    Ok((bitmaps, parents, blits))
+}
+
+/// Largest fraction of a shape's pixels that may differ from its parent for
+/// the shape to be coded as a refinement.
+const REFINE_MAX_ERROR: f32 = 0.08;
+
+/// Earlier shapes of each size compared against a new one; bounds the search
+/// on pages with thousands of glyphs.
+const REFINE_CANDIDATES: usize = 32;
+
+/// Picks a refinement parent for each shape of `dictionary`, in the form
+/// taken by `JB2Encoder::encode_page_with_shapes()`.
+///
+/// Shapes are visited in the order `blits` first use them. A shape whose
+/// size is within two pixels of an earlier one and which differs from it in
+/// at most 8% of its pixels is refined from the closest such shape; its
+/// bitmap is then coded against the parent's instead of from scratch. The
+/// refinement is still lossless. Shapes that are never blitted, or that
+/// have no close match, keep `-1`.
+pub fn find_refinement_parents(
+    dictionary: &[BitImage],
+    blits: &[(Coord, Coord, usize)],
+) -> Vec<i32> {
+    let mut parents = vec![-1; dictionary.len()];
+    let mut seen = vec![false; dictionary.len()];
+    let mut by_size: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+    let mut comparator = Comparator::default();
+
+    for &(_, _, shapeno) in blits {
+        if shapeno >= dictionary.len() || seen[shapeno] {
+            continue;
+        }
+        seen[shapeno] = true;
+        let shape = &dictionary[shapeno];
+        let max_err = (shape.width * shape.height) as f32 * REFINE_MAX_ERROR;
+
+        let mut best: Option<(u32, usize)> = None;
+        for w in shape.width.saturating_sub(2)..=shape.width + 2 {
+            for h in shape.height.saturating_sub(2)..=shape.height + 2 {
+                let Some(candidates) = by_size.get(&(w, h)) else {
+                    continue;
+                };
+                for &other in candidates.iter().rev().take(REFINE_CANDIDATES) {
+                    let limit = best.map_or(max_err as u32, |(err, _)| err.saturating_sub(1));
+                    if let Some((err, _, _)) = comparator.distance(shape, &dictionary[other], limit)
+                    {
+                        best = Some((err, other));
+                    }
+                }
+            }
+        }
+        if let Some((_, parent)) = best {
+            parents[shapeno] = parent as i32;
+        }
+        if shape.count_set_bits() > 0 {
+            by_size
+                .entry((shape.width, shape.height))
+                .or_default()
+                .push(shapeno);
+        }
+    }
+    parents
 }
 
 #[cfg(test)]
@@ -884,6 +1052,65 @@ mod tests {
         assert_eq!(ccimg.ccs[1].npix, 25);
     }
 
+    /// Three glyphs on one line and one on a second line below them.
+    fn make_line_image() -> BitImage {
+        let mut bm = BitImage::new(160, 120).unwrap();
+        for (x0, y0) in [(10, 10), (60, 10), (110, 10), (35, 80)] {
+            for y in y0..y0 + 8 {
+                for x in x0..x0 + 6 {
+                    bm.set_usize(x, y, true);
+                }
+            }
+        }
+        bm
+    }
+
+    #[test]
+    fn test_rtl_dictionary_runs_right_to_left() {
+        let bm = make_line_image();
+        let order = ReadingOrder::RtlTopDown;
+        let ccimg = analyze_page_in_order(&bm, 300, 0, order);
+        let xmins: Vec<i32> = ccimg.ccs.iter().map(|cc| cc.bb.xmin).collect();
+        assert_eq!(xmins, vec![110, 60, 10, 35]);
+
+        // Shapes are numbered right to left, but still blitted left to right
+        let (rtl_dict, rtl_parents, rtl_blits) =
+            shapes_to_encoder_format(ccimg.extract_shapes(), 120).unwrap();
+        let blits: Vec<(i32, usize)> = rtl_blits.iter().map(|b| (b.0, b.2)).collect();
+        assert_eq!(blits, vec![(10, 2), (60, 1), (110, 0), (35, 3)]);
+
+        // The default order reads the same line left to right
+        let ccimg = analyze_page(&bm, 300, 0);
+        let xmins: Vec<i32> = ccimg.ccs.iter().map(|cc| cc.bb.xmin).collect();
+        assert_eq!(xmins, vec![10, 60, 110, 35]);
+
+        // Either way the page codes to the same size
+        let (ltr_dict, ltr_parents, ltr_blits) =
+            shapes_to_encoder_format(ccimg.extract_shapes(), 120).unwrap();
+        let encode = |dict: &[BitImage], parents: &[i32], blits: &[(i32, i32, usize)]| {
+            crate::encode::jb2::JB2Encoder::new(Vec::new())
+                .encode_page_with_shapes(160, 120, dict, parents, blits, 0, None)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(
+            encode(&rtl_dict, &rtl_parents, &rtl_blits),
+            encode(&ltr_dict, &ltr_parents, &ltr_blits)
+        );
+    }
+
+    #[test]
+    fn test_vertical_columns_run_right_to_left() {
+        let bm = make_line_image();
+        let ccimg = analyze_page_in_order(&bm, 300, 0, ReadingOrder::VerticalRtl);
+        let starts: Vec<(i32, i32)> = ccimg
+            .ccs
+            .iter()
+            .map(|cc| (cc.bb.xmin, cc.bb.ymin))
+            .collect();
+        assert_eq!(starts, vec![(110, 10), (60, 10), (35, 80), (10, 10)]);
+    }
+
     #[test]
     fn test_full_pipeline() {
         let bm = make_test_image();
@@ -918,4 +1145,70 @@ mod tests {
         assert_eq!(shapes.len(), 1);
         assert_eq!(shapes[0].0.width, 5);
     }
+
+    #[test]
+    fn test_max_size_page_geometry() {
+        let max = MAX_PAGE_DIMENSION as usize;
+
+        // A rule across the full width of a maximum-width page
+        let mut wide = BitImage::new(MAX_PAGE_DIMENSION, 4).unwrap();
+        for x in 0..max {
+            wide.set_usize(x, 1, true);
+        }
+        let mut ccimg = CCImage::new(MAX_COORD, 4, 300);
+        ccimg.add_bitmap_runs(&wide);
+        assert_eq!(ccimg.runs.len(), 1);
+        assert_eq!((ccimg.runs[0].x1, ccimg.runs[0].x2), (0, MAX_COORD - 1));
+        ccimg.make_ccids_by_analysis();
+        ccimg.make_ccs_from_ccids();
+        assert_eq!(ccimg.ccs[0].bb.width(), MAX_COORD);
+        assert_eq!(ccimg.ccs[0].npix, max as i64);
+
+        // A pixel in the far corner of a maximum-height page
+        let mut tall = BitImage::new(3, MAX_PAGE_DIMENSION).unwrap();
+        tall.set_usize(2, max - 1, true);
+        tall.set_usize(1, max - 1, true);
+        let ccimg = analyze_page(&tall, 300, 0);
+        let shapes = ccimg.extract_shapes();
+        assert_eq!(shapes.len(), 1);
+        let bb = shapes[0].1;
+        assert_eq!(
+            (bb.xmin, bb.ymin, bb.xmax, bb.ymax),
+            (1, MAX_COORD - 1, 3, MAX_COORD)
+        );
+        let (_, _, blits) = shapes_to_encoder_format(shapes, MAX_COORD).unwrap();
+        assert_eq!(blits, vec![(1, 0, 0)]);
+
+        // Pages past the limit can't reach the analysis at all
+        assert!(BitImage::from_bytes(max + 1, 1, &[0; 8193]).is_err());
+        assert!(BitImage::from_scanlines(max as u32 + 1, 1, [[0u8; 8193]]).is_err());
+    }
+
+    #[test]
+    fn test_shape_on_bottom_edge_has_bottom_zero() {
+        let mut bm = BitImage::new(30, 12).unwrap();
+        for y in 8..12 {
+            for x in 5..9 {
+                bm.set_usize(x, y, true);
+            }
+        }
+        let shapes = analyze_page(&bm, 300, 0).extract_shapes();
+        assert_eq!(shapes[0].1.ymax, 12);
+        let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, 12).unwrap();
+        assert_eq!(blits, vec![(5, 0, 0)]);
+
+        let mut encoder = crate::encode::jb2::JB2Encoder::new(Vec::new());
+        assert!(
+            encoder
+                .encode_page_with_shapes(30, 12, &bitmaps, &parents, &blits, 0, None)
+                .is_ok()
+        );
+        // Converted against a page that is too short, the shape would fall
+        // below it
+        let shapes = analyze_page(&bm, 300, 0).extract_shapes();
+        assert!(matches!(
+            shapes_to_encoder_format(shapes, 10),
+            Err(Jb2Error::InvalidNumber(_))
+        ));
+    }
 }
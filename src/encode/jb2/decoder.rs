@@ -0,0 +1,508 @@
+//! JB2 decoder, the inverse of [`JB2Encoder`](super::JB2Encoder).
+//!
+//! Reads every record type of Appendix 2 of the DjVu specification, for
+//! both page (Sjbz) and dictionary (Djbz) streams. The eventual image
+//! refinement flag must be clear, as DjVuLibre does not implement it
+//! either. Like [`ZDecoder`], it exists so the crate can check its own
+//! output (validation and round-trip tests) and render pages it encoded.
+
+use crate::encode::jb2::encoder::lib_rect;
+use crate::encode::jb2::error::Jb2Error;
+use crate::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder, NumContext};
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::encode::zc::ZDecoder;
+
+// Record types as per DjVu specification Table 6
+const START_OF_DATA: i32 = 0;
+const NEW_MARK: i32 = 1;
+const NEW_MARK_LIBRARY_ONLY: i32 = 2;
+const NEW_MARK_IMAGE_ONLY: i32 = 3;
+const MATCHED_REFINE: i32 = 4;
+const MATCHED_REFINE_LIBRARY_ONLY: i32 = 5;
+const MATCHED_REFINE_IMAGE_ONLY: i32 = 6;
+const MATCHED_COPY: i32 = 7;
+const NON_MARK_DATA: i32 = 8;
+const REQUIRED_DICT_OR_RESET: i32 = 9;
+const PRESERVED_COMMENT: i32 = 10;
+const END_OF_DATA: i32 = 11;
+
+/// The content of a decoded JB2 stream.
+#[derive(Debug, Clone)]
+pub struct Jb2Image {
+    /// Image size from the start record; 0x0 for a dictionary
+    pub width: u32,
+    pub height: u32,
+    /// Every shape of the stream: the inherited dictionary first, then
+    /// each decoded shape in stream order, including image-only ones
+    pub shapes: Vec<BitImage>,
+    /// `(left, bottom, shapeno)` of each blit, in DjVu's bottom-up page
+    /// coordinates, like the blits [`JB2Encoder`](super::JB2Encoder) takes
+    pub blits: Vec<(i32, i32, usize)>,
+    /// Indices into `shapes` of the library, in match-index order
+    library: Vec<usize>,
+}
+
+impl Jb2Image {
+    /// The library at the end of the stream, inherited shapes first. For a
+    /// Djbz stream this is the dictionary pages inherit.
+    pub fn dictionary(&self) -> Vec<BitImage> {
+        self.library
+            .iter()
+            .map(|&i| self.shapes[i].clone())
+            .collect()
+    }
+
+    /// Renders the blits onto a page of the stream's size. Parts of shapes
+    /// that fall off the page are clipped.
+    pub fn render(&self) -> Result<BitImage, Jb2Error> {
+        let mut page = BitImage::new(self.width, self.height)
+            .map_err(|e| Jb2Error::InvalidData(format!("cannot render: {e}")))?;
+        self.for_each_blit_pixel(|x, y, _| page.set_usize(x, y, true));
+        Ok(page)
+    }
+
+    /// Calls `plot(x, y, blitno)` for every black pixel of every blit that
+    /// lands on the page, in blit order. `(x, y)` counts from the top-left
+    /// corner, like [`Self::render`]'s bitmap.
+    pub fn for_each_blit_pixel(&self, mut plot: impl FnMut(usize, usize, usize)) {
+        let (width, height) = (self.width as i32, self.height as i32);
+        for (blitno, &(left, bottom, shapeno)) in self.blits.iter().enumerate() {
+            let shape = &self.shapes[shapeno];
+            let top = bottom + shape.height as i32 - 1;
+            for y in 0..shape.height {
+                let page_y = height - 1 - (top - y as i32);
+                if !(0..height).contains(&page_y) {
+                    continue;
+                }
+                for x in 0..shape.width {
+                    let page_x = left + x as i32;
+                    if (0..width).contains(&page_x) && shape.get_pixel_unchecked(x, y) {
+                        plot(page_x as usize, page_y as usize, blitno);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a JB2 stream.
+///
+/// `dictionary` holds the shapes of the inherited dictionary (the decoded
+/// Djbz a page includes) and may be empty when the stream requires none.
+/// A stream that requires more shapes than given is an error.
+pub fn decode(data: &[u8], dictionary: &[BitImage]) -> Result<Jb2Image, Jb2Error> {
+    Decoder::new(data)?.run(dictionary)
+}
+
+/// Pixel `(x, y)` of `bitmap` in DjVu bottom-up coordinates, 0 outside it.
+fn pixel(bitmap: &BitImage, x: i32, y: i32) -> usize {
+    let (w, h) = (bitmap.width as i32, bitmap.height as i32);
+    if x < 0 || y < 0 || x >= w || y >= h {
+        0
+    } else {
+        bitmap.get_pixel_unchecked(x as usize, (h - 1 - y) as usize) as usize
+    }
+}
+
+struct Decoder<'a> {
+    zd: ZDecoder<'a>,
+    num_coder: NumCoder,
+    record_type: NumContext,
+    match_index: NumContext,
+    abs_loc_x: NumContext,
+    abs_loc_y: NumContext,
+    abs_size_x: NumContext,
+    abs_size_y: NumContext,
+    image_size: NumContext,
+    inherited_shape_count: NumContext,
+    rel_size_x: NumContext,
+    rel_size_y: NumContext,
+    rel_loc_x_last: NumContext,
+    rel_loc_y_last: NumContext,
+    rel_loc_x_current: NumContext,
+    rel_loc_y_current: NumContext,
+    comment_length: NumContext,
+    comment_byte: NumContext,
+    offset_type: u8,
+    refinement_flag: u8,
+    bitdist: [u8; 1024],
+    cbitdist: [u8; 2048],
+    // Relative location state, as in `JB2Encoder`
+    last_left: i32,
+    last_right: i32,
+    last_bottom: i32,
+    last_row_left: i32,
+    last_row_bottom: i32,
+    short_list: [i32; 3],
+    short_list_pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, Jb2Error> {
+        Ok(Self {
+            zd: ZDecoder::new(data, true)?,
+            num_coder: NumCoder::new(),
+            record_type: 0,
+            match_index: 0,
+            abs_loc_x: 0,
+            abs_loc_y: 0,
+            abs_size_x: 0,
+            abs_size_y: 0,
+            image_size: 0,
+            inherited_shape_count: 0,
+            rel_size_x: 0,
+            rel_size_y: 0,
+            rel_loc_x_last: 0,
+            rel_loc_y_last: 0,
+            rel_loc_x_current: 0,
+            rel_loc_y_current: 0,
+            comment_length: 0,
+            comment_byte: 0,
+            offset_type: 0,
+            refinement_flag: 0,
+            bitdist: [0; 1024],
+            cbitdist: [0; 2048],
+            last_left: 0,
+            last_right: 0,
+            last_bottom: 0,
+            last_row_left: 0,
+            last_row_bottom: 0,
+            short_list: [0; 3],
+            short_list_pos: 0,
+        })
+    }
+
+    fn num(
+        &mut self,
+        ctx: fn(&mut Self) -> &mut NumContext,
+        low: i32,
+        high: i32,
+    ) -> Result<i32, Jb2Error> {
+        let mut c = *ctx(self);
+        let value = self.num_coder.decode_num(&mut self.zd, &mut c, low, high);
+        *ctx(self) = c;
+        value
+    }
+
+    /// Resets the number contexts, as REQUIRED_DICT_OR_RESET does after
+    /// the start record (DjVuLibre's `reset_numcoder`).
+    fn reset_numcoder(&mut self) {
+        self.num_coder.reset();
+        self.record_type = 0;
+        self.match_index = 0;
+        self.abs_loc_x = 0;
+        self.abs_loc_y = 0;
+        self.abs_size_x = 0;
+        self.abs_size_y = 0;
+        self.image_size = 0;
+        self.inherited_shape_count = 0;
+        self.rel_size_x = 0;
+        self.rel_size_y = 0;
+        self.rel_loc_x_last = 0;
+        self.rel_loc_y_last = 0;
+        self.rel_loc_x_current = 0;
+        self.rel_loc_y_current = 0;
+        self.comment_length = 0;
+        self.comment_byte = 0;
+    }
+
+    fn run(mut self, dictionary: &[BitImage]) -> Result<Jb2Image, Jb2Error> {
+        let mut image = Jb2Image {
+            width: 0,
+            height: 0,
+            shapes: Vec::new(),
+            blits: Vec::new(),
+            library: Vec::new(),
+        };
+
+        let mut rtype = self.num(|d| &mut d.record_type, START_OF_DATA, END_OF_DATA)?;
+        if rtype == REQUIRED_DICT_OR_RESET {
+            let count = self.num(|d| &mut d.inherited_shape_count, 0, BIG_POSITIVE)? as usize;
+            if count > dictionary.len() {
+                return Err(Jb2Error::InvalidData(format!(
+                    "stream needs a dictionary of {} shapes, {} given",
+                    count,
+                    dictionary.len()
+                )));
+            }
+            image.shapes.extend_from_slice(&dictionary[..count]);
+            image.library.extend(0..count);
+            rtype = self.num(|d| &mut d.record_type, START_OF_DATA, END_OF_DATA)?;
+        }
+        if rtype != START_OF_DATA {
+            return Err(Jb2Error::InvalidData(format!(
+                "stream starts with record type {rtype} instead of a start record"
+            )));
+        }
+        image.width = self.num(|d| &mut d.image_size, 0, BIG_POSITIVE)? as u32;
+        image.height = self.num(|d| &mut d.image_size, 0, BIG_POSITIVE)? as u32;
+        if self.zd.decode(&mut self.refinement_flag)? {
+            return Err(Jb2Error::InvalidData(
+                "eventual image refinement is not supported".to_string(),
+            ));
+        }
+        let dict = image.width == 0 && image.height == 0;
+        self.last_left = if dict { 1 } else { 1 + image.width as i32 };
+        self.last_row_left = 0;
+        self.last_row_bottom = if dict { 0 } else { image.height as i32 };
+        self.last_right = 0;
+        self.last_bottom = 0;
+        self.fill_short_list(self.last_row_bottom);
+
+        loop {
+            let rtype = self.num(|d| &mut d.record_type, START_OF_DATA, END_OF_DATA)?;
+            let blits = matches!(
+                rtype,
+                NEW_MARK
+                    | NEW_MARK_IMAGE_ONLY
+                    | MATCHED_REFINE
+                    | MATCHED_REFINE_IMAGE_ONLY
+                    | MATCHED_COPY
+                    | NON_MARK_DATA
+            );
+            if dict && blits {
+                return Err(Jb2Error::InvalidData(format!(
+                    "record type {rtype} places a shape in a dictionary"
+                )));
+            }
+
+            let shape = match rtype {
+                NEW_MARK | NEW_MARK_LIBRARY_ONLY | NEW_MARK_IMAGE_ONLY | NON_MARK_DATA => {
+                    let w = self.num(|d| &mut d.abs_size_x, 0, BIG_POSITIVE)?;
+                    let h = self.num(|d| &mut d.abs_size_y, 0, BIG_POSITIVE)?;
+                    self.decode_direct(w, h)?
+                }
+                MATCHED_REFINE | MATCHED_REFINE_LIBRARY_ONLY | MATCHED_REFINE_IMAGE_ONLY => {
+                    let parent = self.match_index(&image)?;
+                    let parent = &image.shapes[image.library[parent]];
+                    let (x0, y0, x1, y1) = lib_rect(parent);
+                    let w = x1 - x0
+                        + 1
+                        + self.num(|d| &mut d.rel_size_x, -BIG_POSITIVE, BIG_POSITIVE)?;
+                    let h = y1 - y0
+                        + 1
+                        + self.num(|d| &mut d.rel_size_y, -BIG_POSITIVE, BIG_POSITIVE)?;
+                    let parent = parent.clone();
+                    self.decode_refined(w, h, &parent)?
+                }
+                MATCHED_COPY => {
+                    let index = self.match_index(&image)?;
+                    let shapeno = image.library[index];
+                    let shape = &image.shapes[shapeno];
+                    let (rows, columns) = (shape.height as i32, shape.width as i32);
+                    let (left, bottom) = self.relative_location(rows, columns)?;
+                    image.blits.push((left, bottom, shapeno));
+                    continue;
+                }
+                REQUIRED_DICT_OR_RESET => {
+                    self.reset_numcoder();
+                    continue;
+                }
+                PRESERVED_COMMENT => {
+                    let length = self.num(|d| &mut d.comment_length, 0, BIG_POSITIVE)?;
+                    for _ in 0..length {
+                        self.num(|d| &mut d.comment_byte, 0, 255)?;
+                    }
+                    continue;
+                }
+                END_OF_DATA => return Ok(image),
+                _ => {
+                    return Err(Jb2Error::InvalidData(format!(
+                        "unexpected record type {rtype} after the start record"
+                    )));
+                }
+            };
+
+            let (rows, columns) = (shape.height as i32, shape.width as i32);
+            let shapeno = image.shapes.len();
+            image.shapes.push(shape);
+            if matches!(
+                rtype,
+                NEW_MARK | NEW_MARK_LIBRARY_ONLY | MATCHED_REFINE | MATCHED_REFINE_LIBRARY_ONLY
+            ) {
+                image.library.push(shapeno);
+            }
+            if rtype == NON_MARK_DATA {
+                let left = self.num(|d| &mut d.abs_loc_x, 1, image.width as i32)? - 1;
+                let top = self.num(|d| &mut d.abs_loc_y, 1, image.height as i32)? - 1;
+                image.blits.push((left, top - rows + 1, shapeno));
+            } else if blits {
+                let (left, bottom) = self.relative_location(rows, columns)?;
+                image.blits.push((left, bottom, shapeno));
+            }
+        }
+    }
+
+    /// Decodes a library position for a matched record.
+    fn match_index(&mut self, image: &Jb2Image) -> Result<usize, Jb2Error> {
+        if image.library.is_empty() {
+            return Err(Jb2Error::InvalidData(
+                "matched record with an empty library".to_string(),
+            ));
+        }
+        let high = image.library.len() as i32 - 1;
+        Ok(self.num(|d| &mut d.match_index, 0, high)? as usize)
+    }
+
+    fn new_bitmap(w: i32, h: i32) -> Result<BitImage, Jb2Error> {
+        BitImage::new(w.max(0) as u32, h.max(0) as u32)
+            .map_err(|e| Jb2Error::InvalidData(format!("shape of {w}x{h}: {e}")))
+    }
+
+    /// Inverse of `JB2Encoder::encode_bitmap_directly`.
+    fn decode_direct(&mut self, w: i32, h: i32) -> Result<BitImage, Jb2Error> {
+        let mut bm = Self::new_bitmap(w, h)?;
+        for y in (0..h).rev() {
+            let mut context = (pixel(&bm, -1, y + 2) << 9)
+                | (pixel(&bm, 0, y + 2) << 8)
+                | (pixel(&bm, 1, y + 2) << 7)
+                | (pixel(&bm, -2, y + 1) << 6)
+                | (pixel(&bm, -1, y + 1) << 5)
+                | (pixel(&bm, 0, y + 1) << 4)
+                | (pixel(&bm, 1, y + 1) << 3)
+                | (pixel(&bm, 2, y + 1) << 2);
+            for x in 0..w {
+                let bit = self.zd.decode(&mut self.bitdist[context])?;
+                bm.set_usize(x as usize, (h - 1 - y) as usize, bit);
+                context = ((context << 1) & 0x37a)
+                    | (pixel(&bm, x + 3, y + 1) << 2)
+                    | (pixel(&bm, x + 2, y + 2) << 7)
+                    | bit as usize;
+            }
+        }
+        Ok(bm)
+    }
+
+    /// Inverse of `JB2Encoder::encode_bitmap_by_cross_coding`.
+    fn decode_refined(&mut self, w: i32, h: i32, parent: &BitImage) -> Result<BitImage, Jb2Error> {
+        let mut bm = Self::new_bitmap(w, h)?;
+        let (left, bottom, right, top) = lib_rect(parent);
+        let xd2c = (w / 2 - w + 1) - ((right - left + 1) / 2 - right);
+        let yd2c = (h / 2 - h + 1) - ((top - bottom + 1) / 2 - top);
+        for y in (0..h).rev() {
+            let ry = y + yd2c;
+            for x in 0..w {
+                let rx = x + xd2c;
+                let context = (pixel(&bm, x - 1, y + 1) << 10)
+                    | (pixel(&bm, x, y + 1) << 9)
+                    | (pixel(&bm, x + 1, y + 1) << 8)
+                    | (pixel(&bm, x - 1, y) << 7)
+                    | (pixel(parent, rx, ry + 1) << 6)
+                    | (pixel(parent, rx - 1, ry) << 5)
+                    | (pixel(parent, rx, ry) << 4)
+                    | (pixel(parent, rx + 1, ry) << 3)
+                    | (pixel(parent, rx - 1, ry - 1) << 2)
+                    | (pixel(parent, rx, ry - 1) << 1)
+                    | pixel(parent, rx + 1, ry - 1);
+                let bit = self.zd.decode(&mut self.cbitdist[context])?;
+                bm.set_usize(x as usize, (h - 1 - y) as usize, bit);
+            }
+        }
+        Ok(bm)
+    }
+
+    fn fill_short_list(&mut self, v: i32) {
+        self.short_list = [v; 3];
+        self.short_list_pos = 0;
+    }
+
+    /// Inverse of `JB2Encoder::encode_relative_location`; returns
+    /// `(left, bottom)`.
+    fn relative_location(&mut self, rows: i32, columns: i32) -> Result<(i32, i32), Jb2Error> {
+        let (left, bottom) = if self.zd.decode(&mut self.offset_type)? {
+            let left = self.last_row_left
+                + self.num(|d| &mut d.rel_loc_x_last, -BIG_POSITIVE, BIG_POSITIVE)?;
+            let top = self.last_row_bottom
+                + self.num(|d| &mut d.rel_loc_y_last, -BIG_POSITIVE, BIG_POSITIVE)?;
+            let bottom = top - rows + 1;
+            self.last_row_left = left;
+            self.last_row_bottom = bottom;
+            self.last_bottom = bottom;
+            self.fill_short_list(bottom);
+            (left, bottom)
+        } else {
+            let left = self.last_right
+                + self.num(|d| &mut d.rel_loc_x_current, -BIG_POSITIVE, BIG_POSITIVE)?;
+            let bottom = self.last_bottom
+                + self.num(|d| &mut d.rel_loc_y_current, -BIG_POSITIVE, BIG_POSITIVE)?;
+            self.short_list_pos = (self.short_list_pos + 1) % 3;
+            self.short_list[self.short_list_pos] = bottom;
+            let mut sorted = self.short_list;
+            sorted.sort_unstable();
+            self.last_bottom = sorted[1];
+            (left, bottom)
+        };
+        self.last_left = left;
+        self.last_right = left + columns - 1;
+        Ok((left, bottom))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::jb2::JB2Encoder;
+
+    fn glyph(w: u32, h: u32, seed: usize) -> BitImage {
+        let mut bm = BitImage::new(w, h).unwrap();
+        for y in 0..h as usize {
+            for x in 0..w as usize {
+                bm.set_usize(x, y, (x * 3 + y * 5 + seed) % 7 < 3);
+            }
+        }
+        bm
+    }
+
+    #[test]
+    fn test_non_mark_data_round_trip() {
+        let image = glyph(37, 23, 1);
+        let data = JB2Encoder::new(Vec::new())
+            .encode_single_page(&image)
+            .unwrap();
+        let decoded = decode(&data, &[]).unwrap();
+        assert_eq!((decoded.width, decoded.height), (37, 23));
+        assert!(decoded.dictionary().is_empty());
+        assert_eq!(decoded.render().unwrap(), image);
+    }
+
+    #[test]
+    fn test_dictionary_and_inheriting_page_round_trip() {
+        let mut refined = glyph(9, 12, 2);
+        refined.set_usize(4, 6, !refined.get_pixel_unchecked(4, 6));
+        let shapes = vec![glyph(9, 12, 2), refined, glyph(6, 8, 4)];
+        let dict = JB2Encoder::new(Vec::new())
+            .encode_dictionary(&shapes, &[-1, 0, -1], 0)
+            .unwrap();
+        let decoded = decode(&dict, &[]).unwrap();
+        assert_eq!((decoded.width, decoded.height), (0, 0));
+        assert!(decoded.blits.is_empty());
+        assert_eq!(decoded.dictionary(), shapes);
+
+        let blits = [(3, 40, 0), (15, 40, 1), (27, 41, 2), (3, 10, 1)];
+        let page = JB2Encoder::new(Vec::new())
+            .encode_page_with_shapes(60, 60, &[], &[], &blits, 3, Some(&shapes))
+            .unwrap();
+        let decoded = decode(&page, &shapes).unwrap();
+        assert_eq!(decoded.blits, blits);
+
+        let mut expected = BitImage::new(60, 60).unwrap();
+        for &(left, bottom, shapeno) in &blits {
+            let shape = &shapes[shapeno];
+            let top = 59 - (bottom as usize + shape.height - 1);
+            for y in 0..shape.height {
+                for x in 0..shape.width {
+                    if shape.get_pixel_unchecked(x, y) {
+                        expected.set_usize(left as usize + x, top + y, true);
+                    }
+                }
+            }
+        }
+        assert_eq!(decoded.render().unwrap(), expected);
+
+        // Without its dictionary the page cannot be decoded
+        assert!(matches!(
+            decode(&page, &shapes[..2]),
+            Err(Jb2Error::InvalidData(_))
+        ));
+    }
+}
@@ -4,7 +4,7 @@
 //! producing a single Sjbz chunk with arithmetically encoded records.
 
 use crate::encode::jb2::error::Jb2Error;
-use crate::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder, NumContext};
+use crate::encode::jb2::num_coder::{BIG_POSITIVE, MAX_COORDINATE, NumCoder, NumContext};
 use crate::encode::jb2::symbol_dict::BitImage;
 use crate::encode::zc::ZEncoder;
 use std::io::Write;
@@ -23,6 +23,26 @@ const END_OF_DATA: i32 = 11;
 // Constants from DjVuLibre
 const CELLCHUNK: usize = 20000;
 
+/// `(left, bottom, right, top)` of the set pixels of a library shape, in
+/// DjVu's bottom-up coordinates, or of the whole bitmap if it is blank.
+/// Refinements are sized and centered against this box, as DjVuLibre's
+/// `LibRect` does.
+pub(crate) fn lib_rect(bitmap: &BitImage) -> (i32, i32, i32, i32) {
+    let (w, h) = (bitmap.width, bitmap.height);
+    let mut rect: Option<(usize, usize, usize, usize)> = None;
+    for y in 0..h {
+        for x in 0..w {
+            if bitmap.get_pixel_unchecked(x, y) {
+                let (l, t, r, b) = rect.unwrap_or((x, y, x, y));
+                rect = Some((l.min(x), t.min(y), r.max(x), b.max(y)));
+            }
+        }
+    }
+    // BitImage rows run top-down, so its first inked row is the top
+    let (l, t, r, b) = rect.unwrap_or((0, 0, w.saturating_sub(1), h.saturating_sub(1)));
+    (l as i32, (h - 1 - b) as i32, r as i32, (h - 1 - t) as i32)
+}
+
 /// Blit information for page encoding
 #[derive(Clone, Debug)]
 pub struct Jb2BlitInfo {
@@ -38,6 +58,21 @@ pub struct Jb2ShapeInfo<'a> {
     pub parent: i32, // -1 for no parent, -2 for non-mark data
 }
 
+/// Snapshot of a [`JB2Encoder`]'s adaptive arithmetic-coding statistics.
+///
+/// Captures the number-coder tree, its context roots, and the direct and
+/// refinement bitmap contexts. Taken with [`JB2Encoder::contexts`] and
+/// installed with [`JB2Encoder::with_initial_contexts`].
+#[derive(Clone)]
+pub struct JB2Context {
+    num_coder: NumCoder,
+    num_contexts: [NumContext; 14],
+    offset_type_dist: u8,
+    bitdist: [u8; 1024],
+    cbitdist: [u8; 2048],
+    dist_refinement_flag: u8,
+}
+
 /// DjVu-compatible JB2 encoder matching DjVuLibre's exact algorithm.
 pub struct JB2Encoder<W: Write> {
     _writer: W,
@@ -81,6 +116,8 @@ pub struct JB2Encoder<W: Write> {
     gotstartrecordp: bool,
     // Track number of cells used for REQUIRED_DICT_OR_RESET
     cur_ncell: usize,
+    // Statistics to start the next stream from instead of fresh contexts
+    initial_contexts: Option<JB2Context>,
 }
 
 impl<W: Write> JB2Encoder<W> {
@@ -121,6 +158,99 @@ impl<W: Write> JB2Encoder<W> {
             dist_refinement_flag: 0,
             gotstartrecordp: false,
             cur_ncell: 1, // Start at 1 like DjVuLibre
+            initial_contexts: None,
+        }
+    }
+
+    /// Seeds the next encoded stream with previously learned statistics.
+    ///
+    /// Adaptive contexts start at zero probability knowledge, so the first
+    /// few hundred glyphs of every page pay a "learning" cost. Pages set in
+    /// the same typeface produce near-identical statistics, and starting page
+    /// N from the state left by page N-1 (or by the shared dictionary) skips
+    /// most of that cost. The seed applies to the next
+    /// `encode_page_with_shapes` or `encode_dictionary` call only.
+    ///
+    /// **Compatibility:** DjVu decoders begin every JB2 stream with fresh
+    /// contexts, so a stream encoded from seeded contexts decodes correctly
+    /// only with a decoder seeded identically. The page encoder never seeds
+    /// contexts; use this for experiments, size estimation, or closed
+    /// pipelines that control both ends.
+    pub fn with_initial_contexts(mut self, contexts: &JB2Context) -> Self {
+        self.initial_contexts = Some(contexts.clone());
+        self
+    }
+
+    /// Returns a snapshot of the statistics learned so far.
+    pub fn contexts(&self) -> JB2Context {
+        JB2Context {
+            num_coder: self.num_coder.clone(),
+            num_contexts: [
+                self.dist_record_type,
+                self.dist_match_index,
+                self.abs_loc_x,
+                self.abs_loc_y,
+                self.abs_size_x,
+                self.abs_size_y,
+                self.image_size_dist,
+                self.inherited_shape_count_dist,
+                self.rel_size_x,
+                self.rel_size_y,
+                self.rel_loc_x_last,
+                self.rel_loc_y_last,
+                self.rel_loc_x_current,
+                self.rel_loc_y_current,
+            ],
+            offset_type_dist: self.offset_type_dist,
+            bitdist: self.bitdist,
+            cbitdist: self.cbitdist,
+            dist_refinement_flag: self.dist_refinement_flag,
+        }
+    }
+
+    /// Discards any seed and all learned statistics, so the next stream is
+    /// encoded from fresh contexts exactly as a standard decoder expects.
+    pub fn reset_contexts(&mut self) {
+        self.initial_contexts = None;
+        self.num_coder.reset();
+        self.reset_numcoder();
+        self.bitdist = [0; 1024];
+        self.cbitdist = [0; 2048];
+        self.dist_refinement_flag = 0;
+    }
+
+    /// Prepares contexts at the start of a new stream: either the pending
+    /// seed from `with_initial_contexts`, or fresh number-coder contexts.
+    fn begin_stream(&mut self) {
+        match self.initial_contexts.take() {
+            Some(ctx) => {
+                self.num_coder = ctx.num_coder;
+                [
+                    self.dist_record_type,
+                    self.dist_match_index,
+                    self.abs_loc_x,
+                    self.abs_loc_y,
+                    self.abs_size_x,
+                    self.abs_size_y,
+                    self.image_size_dist,
+                    self.inherited_shape_count_dist,
+                    self.rel_size_x,
+                    self.rel_size_y,
+                    self.rel_loc_x_last,
+                    self.rel_loc_y_last,
+                    self.rel_loc_x_current,
+                    self.rel_loc_y_current,
+                ] = ctx.num_contexts;
+                self.offset_type_dist = ctx.offset_type_dist;
+                self.bitdist = ctx.bitdist;
+                self.cbitdist = ctx.cbitdist;
+                self.dist_refinement_flag = ctx.dist_refinement_flag;
+                self.reset_location_state();
+            }
+            None => {
+                self.num_coder.reset();
+                self.reset_numcoder();
+            }
         }
     }
 
@@ -142,7 +272,11 @@ impl<W: Write> JB2Encoder<W> {
         self.rel_loc_y_last = 0;
         self.rel_loc_x_current = 0;
         self.rel_loc_y_current = 0;
-        // Reset relative location state
+        self.reset_location_state();
+    }
+
+    /// Reset the relative-location state tracked between blits
+    fn reset_location_state(&mut self) {
         self.last_left = 0;
         self.last_right = 0;
         self.last_bottom = 0;
@@ -610,19 +744,19 @@ impl<W: Write> JB2Encoder<W> {
         // Reference image pixels
         let rx = x + xd2c;
 
-        // Bits 0-3: current image causal neighborhood
-        // Bits 4-10: reference image 3x3 centered neighborhood
+        // Bits 7-10: current image causal neighborhood
+        // Bits 0-6: reference pixel above, then the 3x3 rows at and below
         ((get_current(x - 1, up1_y) as usize) << 10)
             | ((get_current(x, up1_y) as usize) << 9)
             | ((get_current(x + 1, up1_y) as usize) << 8)
             | ((get_current(x - 1, y) as usize) << 7)
-            | ((get_ref(rx - 1, y + 1) as usize) << 6)
-            | ((get_ref(rx, y + 1) as usize) << 5)
-            | ((get_ref(rx + 1, y + 1) as usize) << 4)
-            | ((get_ref(rx - 1, y) as usize) << 3)
-            | ((get_ref(rx, y) as usize) << 2)
-            | ((get_ref(rx + 1, y) as usize) << 1)
-            | ((get_ref(rx, y - 1) as usize) << 0)
+            | ((get_ref(rx, y + 1) as usize) << 6)
+            | ((get_ref(rx - 1, y) as usize) << 5)
+            | ((get_ref(rx, y) as usize) << 4)
+            | ((get_ref(rx + 1, y) as usize) << 3)
+            | ((get_ref(rx - 1, y - 1) as usize) << 2)
+            | ((get_ref(rx, y - 1) as usize) << 1)
+            | (get_ref(rx + 1, y - 1) as usize)
     }
 
     /// Encode bitmap by cross-coding against a reference bitmap.
@@ -638,9 +772,10 @@ impl<W: Write> JB2Encoder<W> {
         let cw = ref_bitmap.width as i32;
         let ch = ref_bitmap.height as i32;
 
-        // Calculate centering offset (matching DjVuLibre)
-        let xd2c = (dw / 2 - dw + 1) - (cw / 2 - cw + 1);
-        let yd2c = (dh / 2 - dh + 1) - (ch / 2 - ch + 1);
+        // Center on the reference's inked area (matching DjVuLibre)
+        let (left, bottom, right, top) = lib_rect(ref_bitmap);
+        let xd2c = (dw / 2 - dw + 1) - ((right - left + 1) / 2 - right);
+        let yd2c = (dh / 2 - dh + 1) - ((top - bottom + 1) / 2 - top);
 
         // Get pixel accessor for current bitmap (with Y flip)
         let get_current = |x: i32, y: i32| -> u8 {
@@ -706,13 +841,14 @@ impl<W: Write> JB2Encoder<W> {
         // Encode match index
         self.encode_match_index(zc, parent_index, lib_size - 1)?;
 
-        // Encode relative size
+        // Encode size relative to the parent's inked area
+        let (x0, y0, x1, y1) = lib_rect(parent_bitmap);
         self.encode_relative_mark_size(
             zc,
             bitmap.width as i32,
             bitmap.height as i32,
-            parent_bitmap.width as i32,
-            parent_bitmap.height as i32,
+            x1 - x0 + 1,
+            y1 - y0 + 1,
         )?;
 
         // Encode bitmap by cross-coding
@@ -730,8 +866,7 @@ impl<W: Write> JB2Encoder<W> {
         inherited_shape_count: usize,
     ) -> Result<Vec<u8>, Jb2Error> {
         // Reset state for a fresh dictionary stream
-        self.num_coder.reset();
-        self.reset_numcoder();
+        self.begin_stream();
         self.gotstartrecordp = false;
 
         let buffer = Vec::new();
@@ -801,6 +936,13 @@ impl<W: Write> JB2Encoder<W> {
             return Err(Jb2Error::InvalidState("No start record".to_string()));
         }
 
+        if left.abs() > MAX_COORDINATE || bottom.abs() > MAX_COORDINATE {
+            return Err(Jb2Error::InvalidNumber(format!(
+                "Blit position ({}, {}) is outside ±{}",
+                left, bottom, MAX_COORDINATE
+            )));
+        }
+
         // Calculate top and right (DjVuLibre uses 1-based coordinates internally)
         let top = bottom + rows - 1;
         let right = left + columns - 1;
@@ -962,13 +1104,14 @@ impl<W: Write> JB2Encoder<W> {
         // Encode match index
         self.encode_match_index(zc, parent_index, lib_size - 1)?;
 
-        // Encode relative size
+        // Encode size relative to the parent's inked area
+        let (x0, y0, x1, y1) = lib_rect(parent_bitmap);
         self.encode_relative_mark_size(
             zc,
             bitmap.width as i32,
             bitmap.height as i32,
-            parent_bitmap.width as i32,
-            parent_bitmap.height as i32,
+            x1 - x0 + 1,
+            y1 - y0 + 1,
         )?;
 
         // Encode bitmap by cross-coding
@@ -995,8 +1138,7 @@ impl<W: Write> JB2Encoder<W> {
         inherited_shapes: Option<&[BitImage]>, // shapes from inherited dict if available
     ) -> Result<Vec<u8>, Jb2Error> {
         // Reset state for a fresh page stream
-        self.num_coder.reset();
-        self.reset_numcoder();
+        self.begin_stream();
         self.gotstartrecordp = false;
 
         let buffer = Vec::new();
@@ -1013,14 +1155,15 @@ impl<W: Write> JB2Encoder<W> {
         // Emit START_OF_DATA with page dimensions
         self.encode_start_of_image(&mut zc)?;
 
-        // Track which shapes have been encoded to library
+        // Library position of each shape once it has been encoded. Match
+        // indices name positions, which follow blit order rather than
+        // shape numbers. Inherited shapes are already in the library.
         let total_shapes = inherited_shape_count + shapes.len();
-        let mut shape_in_lib: Vec<bool> = vec![false; total_shapes];
-
-        // Inherited shapes are already in library
-        for i in 0..inherited_shape_count {
-            shape_in_lib[i] = true;
+        let mut lib_index: Vec<Option<i32>> = vec![None; total_shapes];
+        for (i, slot) in lib_index.iter_mut().enumerate().take(inherited_shape_count) {
+            *slot = Some(i as i32);
         }
+        let mut lib_size = inherited_shape_count as i32;
 
         // Encode each blit
         for &(left, bottom, shapeno) in blits.iter() {
@@ -1032,7 +1175,7 @@ impl<W: Write> JB2Encoder<W> {
                 )));
             }
 
-            if shape_in_lib[shapeno] {
+            if let Some(index) = lib_index[shapeno] {
                 // Shape already in library - use MATCHED_COPY
                 let (shape_height, shape_width) = if shapeno < inherited_shape_count {
                     inherited_shapes
@@ -1046,12 +1189,12 @@ impl<W: Write> JB2Encoder<W> {
 
                 self.encode_matched_copy(
                     &mut zc,
-                    shapeno as i32,
+                    index,
                     left,
                     bottom,
                     shape_height,
                     shape_width,
-                    shape_in_lib.iter().filter(|&&x| x).count() as i32,
+                    lib_size,
                 )?;
             } else {
                 // Shape not in library - encode it
@@ -1059,7 +1202,15 @@ impl<W: Write> JB2Encoder<W> {
                 let bitmap = &shapes[local_idx];
                 let parent = parents.get(local_idx).copied().unwrap_or(-1);
 
-                if parent >= 0 && shape_in_lib[parent as usize] {
+                if parent >= 0 && parent as usize >= total_shapes {
+                    return Err(Jb2Error::InvalidParentShape);
+                }
+                let parent_index = if parent >= 0 {
+                    lib_index[parent as usize]
+                } else {
+                    None
+                };
+                if let Some(parent_index) = parent_index {
                     // Use MATCHED_REFINE
                     let parent_bitmap = if (parent as usize) < inherited_shape_count {
                         inherited_shapes
@@ -1074,11 +1225,11 @@ impl<W: Write> JB2Encoder<W> {
                     self.encode_matched_refine(
                         &mut zc,
                         bitmap,
-                        parent,
+                        parent_index,
                         parent_bitmap,
                         left,
                         bottom,
-                        shape_in_lib.iter().filter(|&&x| x).count() as i32,
+                        lib_size,
                     )?;
                 } else {
                     // Use NEW_MARK
@@ -1086,7 +1237,8 @@ impl<W: Write> JB2Encoder<W> {
                 }
 
                 // Mark shape as in library
-                shape_in_lib[shapeno] = true;
+                lib_index[shapeno] = Some(lib_size);
+                lib_size += 1;
             }
 
             // Check if we need to reset contexts
@@ -1127,6 +1279,50 @@ mod tests {
         println!("Encoded {} bytes for 10x10 single pixel", data.len());
     }
 
+    #[test]
+    fn test_out_of_range_blit_is_an_error() {
+        let mut glyph = BitImage::new(4, 4).unwrap();
+        glyph.set_usize(1, 1, true);
+        let shapes = [glyph];
+
+        for blit in [(100_000, 10, 0), (10, -100_000, 0), (i32::MAX, i32::MIN, 0)] {
+            let mut encoder = JB2Encoder::new(Vec::new());
+            let result =
+                encoder.encode_page_with_shapes(100, 100, &shapes, &[-1], &[blit], 0, None);
+            assert!(
+                matches!(result, Err(Jb2Error::InvalidNumber(_))),
+                "{blit:?}: {result:?}"
+            );
+        }
+
+        let mut encoder = JB2Encoder::new(Vec::new());
+        let result =
+            encoder.encode_page_with_shapes(100, 100, &shapes, &[7], &[(10, 10, 0)], 0, None);
+        assert!(matches!(result, Err(Jb2Error::InvalidParentShape)));
+    }
+
+    #[test]
+    fn test_blits_partly_off_the_page() {
+        let mut glyph = BitImage::new(4, 4).unwrap();
+        for y in 0..4 {
+            for x in 0..4 {
+                glyph.set_usize(x, y, true);
+            }
+        }
+        // Bottom-left and top-right corners, each overhanging by two pixels
+        let blits = [(-2, -2, 0), (98, 98, 0)];
+        let mut encoder = JB2Encoder::new(Vec::new());
+        let data = encoder
+            .encode_page_with_shapes(100, 100, &[glyph], &[-1], &blits, 0, None)
+            .unwrap();
+
+        let page = decode_page(&data);
+        assert_eq!(page.count_set_bits(), 8);
+        for (x, y) in [(0, 98), (1, 99), (98, 0), (99, 1)] {
+            assert!(page.get_pixel_unchecked(x, y), "({x}, {y})");
+        }
+    }
+
     #[test]
     fn test_all_black_pattern() {
         // Create a 8x8 all-black pattern
@@ -1145,6 +1341,60 @@ mod tests {
         println!("Encoded {} bytes for 8x8 all-black", data.len());
     }
 
+    /// A few letter-like glyphs repeated along text lines, shifted by `seed`.
+    fn glyph_page(seed: usize) -> (Vec<BitImage>, Vec<(i32, i32, usize)>) {
+        let mut shapes = Vec::new();
+        for g in 0..12 {
+            let mut bm = BitImage::new(12, 16).unwrap();
+            for y in 0..16 {
+                for x in 0..12 {
+                    if x == 0 || y == 15 || (x * 3 + y * (g + 1)) % 11 == 0 {
+                        bm.set_usize(x, y, true);
+                    }
+                }
+            }
+            shapes.push(bm);
+        }
+        let mut blits = Vec::new();
+        for line in 0..10 {
+            for col in 0..30 {
+                let shape = (line * 7 + col * 5 + seed) % shapes.len();
+                blits.push((10 + col as i32 * 16, 400 - line as i32 * 24, shape));
+            }
+        }
+        (shapes, blits)
+    }
+
+    #[test]
+    fn test_carried_contexts_shrink_next_page() {
+        let (shapes1, blits1) = glyph_page(0);
+        let (shapes2, blits2) = glyph_page(3);
+        let parents1 = vec![-1; shapes1.len()];
+        let parents2 = vec![-1; shapes2.len()];
+
+        let mut first = JB2Encoder::new(Vec::new());
+        first
+            .encode_page_with_shapes(520, 420, &shapes1, &parents1, &blits1, 0, None)
+            .unwrap();
+        let learned = first.contexts();
+
+        let fresh = JB2Encoder::new(Vec::new())
+            .encode_page_with_shapes(520, 420, &shapes2, &parents2, &blits2, 0, None)
+            .unwrap();
+        let mut seeded_encoder = JB2Encoder::new(Vec::new()).with_initial_contexts(&learned);
+        let seeded = seeded_encoder
+            .encode_page_with_shapes(520, 420, &shapes2, &parents2, &blits2, 0, None)
+            .unwrap();
+        assert!(seeded.len() < fresh.len());
+
+        // After a reset the same encoder reproduces the fresh stream exactly
+        seeded_encoder.reset_contexts();
+        let again = seeded_encoder
+            .encode_page_with_shapes(520, 420, &shapes2, &parents2, &blits2, 0, None)
+            .unwrap();
+        assert_eq!(again, fresh);
+    }
+
     #[test]
     fn test_checkerboard_pattern() {
         // Create a 16x16 checkerboard
@@ -1164,4 +1414,53 @@ mod tests {
         let data = result.unwrap();
         println!("Encoded {} bytes for 16x16 checkerboard", data.len());
     }
+
+    /// Decodes a page stream that needs no dictionary and renders it.
+    fn decode_page(data: &[u8]) -> BitImage {
+        crate::encode::jb2::decoder::decode(data, &[])
+            .unwrap()
+            .render()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_refinement_shrinks_near_duplicate_glyphs() {
+        use crate::encode::jb2::{analyze_page, find_refinement_parents, shapes_to_encoder_format};
+
+        // Rounded 10x14 blobs, each with a hole in a different place
+        let (width, height) = (420, 110);
+        let mut page = BitImage::new(width, height).unwrap();
+        for n in 0..75 {
+            let (left, top) = (8 + (n % 25) * 16, 8 + (n / 25) * 34);
+            let hole = (2 + n % 6, 2 + (n * 5) % 10);
+            for y in 0..14 {
+                for x in 0..10 {
+                    let corner = (x == 0 || x == 9) && (y == 0 || y == 13);
+                    if !corner && (x, y) != hole {
+                        page.set_usize(left + x, top + y, true);
+                    }
+                }
+            }
+        }
+
+        let shapes = analyze_page(&page, 300, 0).extract_shapes();
+        let (dictionary, plain, blits) = shapes_to_encoder_format(shapes, height as i32).unwrap();
+        let refined = find_refinement_parents(&dictionary, &blits);
+        assert_eq!(refined.iter().filter(|&&p| p < 0).count(), 1);
+
+        let encode = |parents: &[i32]| {
+            JB2Encoder::new(Vec::new())
+                .encode_page_with_shapes(width, height, &dictionary, parents, &blits, 0, None)
+                .unwrap()
+        };
+        let (without, with) = (encode(&plain), encode(&refined));
+        assert!(
+            with.len() < without.len(),
+            "{} vs {}",
+            with.len(),
+            without.len()
+        );
+        assert_eq!(decode_page(&without), page);
+        assert_eq!(decode_page(&with), page);
+    }
 }
@@ -12,15 +12,21 @@
 //! - `cc_image` - cjb2-based CC analysis (run-length + union-find)
 //! - `symbol_dict` - BitImage, Comparator, SharedDict
 //! - `encoder` - JB2Encoder with all 12 DjVu record types
+//! - `decoder` - JB2 stream decoder, for checking and rendering encoder output
 //! - `num_coder` - Tree-based integer coder (DjVuLibre-compatible)
 //! - `error` - Error types
 
 pub mod cc_image;
+pub mod decoder;
 pub mod encoder;
 pub mod error;
 pub mod num_coder;
 pub mod symbol_dict;
 
-pub use cc_image::{BBox, CC, CCImage, Run, analyze_page, shapes_to_encoder_format};
-pub use encoder::JB2Encoder;
+pub use cc_image::{
+    BBox, CC, CCImage, Coord, EncoderShapes, MAX_COORD, ReadingOrder, Run, analyze_page,
+    analyze_page_in_order, find_refinement_parents, shapes_to_encoder_format,
+};
+pub use decoder::Jb2Image;
+pub use encoder::{JB2Context, JB2Encoder};
 pub use symbol_dict::{BitImage, Comparator, Rect, SharedDict};
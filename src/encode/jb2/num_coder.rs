@@ -5,13 +5,25 @@
 //! left/right child pointers to navigate based on encoding decisions.
 
 use crate::encode::jb2::error::Jb2Error;
-use crate::encode::zc::ZEncoder;
+use crate::encode::zc::{ZDecoder, ZEncoder};
 use std::io::Write;
 
 /// Bounds for signed integer coding (from DjVuLibre).
+///
+/// Every range passed to [`NumCoder::code_num`] must lie within these
+/// bounds; larger ranges would overflow the tree walk. Blit coordinates are
+/// further limited to [`MAX_COORDINATE`] so that the differences the JB2
+/// encoder codes always stay inside this range.
 pub const BIG_POSITIVE: i32 = 262_142;
 pub const BIG_NEGATIVE: i32 = -262_143;
 
+/// Largest absolute blit coordinate the JB2 encoder accepts.
+///
+/// DjVu page dimensions are 16-bit, so a symbol can sit at most one page
+/// size away from the page in either direction; this keeps every coded
+/// offset within `±BIG_POSITIVE`.
+pub const MAX_COORDINATE: i32 = u16::MAX as i32;
+
 /// Chunk size for cell allocation (matches DjVuLibre CELLCHUNK).
 const CELLCHUNK: usize = 20000;
 
@@ -24,6 +36,7 @@ pub type NumContext = u32;
 /// - `bitcells[ctx]` is the bit context for node `ctx`
 /// - `leftcell[ctx]` is the left child (decision = false)
 /// - `rightcell[ctx]` is the right child (decision = true)
+#[derive(Clone)]
 pub struct NumCoder {
     /// Bit contexts for each tree node
     pub bitcells: Vec<u8>,
@@ -90,6 +103,7 @@ impl NumCoder {
         mut high: i32,
         mut v: i32,
     ) -> Result<(), Jb2Error> {
+        check_range(low, high)?;
         if v < low || v > high {
             return Err(Jb2Error::InvalidNumber(format!(
                 "Value {} outside range [{}, {}]",
@@ -143,6 +157,8 @@ impl NumCoder {
                     CtxRef::Right(idx) => self.rightcell[idx] = new_cell,
                 }
                 new_cell
+            } else if current_ctx >= self.cur_ncell {
+                return Err(unallocated(current_ctx));
             } else {
                 current_ctx
             };
@@ -215,6 +231,99 @@ impl NumCoder {
         Ok(())
     }
 
+    /// Decodes an integer written by [`code_num`](Self::code_num) with the
+    /// same root context and range, following the identical tree walk.
+    pub fn decode_num(
+        &mut self,
+        zd: &mut ZDecoder,
+        ctx: &mut NumContext,
+        mut low: i32,
+        mut high: i32,
+    ) -> Result<i32, Jb2Error> {
+        check_range(low, high)?;
+        let mut cutoff: i32 = 0;
+        let mut phase = 1;
+        let mut range: u32 = 0xffffffff;
+        let mut negative = false;
+        // `None` is the root context, otherwise (cell, went_right)
+        let mut parent: Option<(usize, bool)> = None;
+
+        while range != 1 {
+            let slot = match parent {
+                None => *ctx,
+                Some((idx, false)) => self.leftcell[idx],
+                Some((idx, true)) => self.rightcell[idx],
+            };
+            let current_ctx = if slot == 0 {
+                if self.cur_ncell as usize >= self.bitcells.len() {
+                    let new_size = self.bitcells.len() + CELLCHUNK;
+                    self.bitcells.resize(new_size, 0);
+                    self.leftcell.resize(new_size, 0);
+                    self.rightcell.resize(new_size, 0);
+                }
+                let new_cell = self.cur_ncell;
+                self.cur_ncell += 1;
+                match parent {
+                    None => *ctx = new_cell,
+                    Some((idx, false)) => self.leftcell[idx] = new_cell,
+                    Some((idx, true)) => self.rightcell[idx] = new_cell,
+                }
+                new_cell
+            } else if slot >= self.cur_ncell {
+                return Err(unallocated(slot));
+            } else {
+                slot
+            };
+
+            let decision = if low < cutoff && high >= cutoff {
+                zd.decode(&mut self.bitcells[current_ctx as usize])?
+            } else {
+                low >= cutoff
+            };
+            parent = Some((current_ctx as usize, decision));
+
+            match phase {
+                1 => {
+                    negative = !decision;
+                    if negative {
+                        let temp = -low - 1;
+                        low = -high - 1;
+                        high = temp;
+                    }
+                    phase = 2;
+                    cutoff = 1;
+                }
+                2 => {
+                    if !decision {
+                        phase = 3;
+                        range = ((cutoff + 1) / 2) as u32;
+                        if range == 1 {
+                            cutoff = 0;
+                        } else {
+                            cutoff -= (range / 2) as i32;
+                        }
+                    } else {
+                        cutoff = 2 * cutoff + 1;
+                    }
+                }
+                _ => {
+                    range /= 2;
+                    if range != 1 {
+                        if !decision {
+                            cutoff -= (range / 2) as i32;
+                        } else {
+                            cutoff += (range / 2) as i32;
+                        }
+                    } else if !decision {
+                        cutoff -= 1;
+                    }
+                }
+            }
+        }
+
+        Ok(if negative { -cutoff - 1 } else { cutoff })
+    }
+
     /// Helper function to allocate a new context and return its pointer.
     /// The context starts at 0 which will be allocated on first use.
     pub fn alloc_context(&self) -> NumContext {
@@ -222,6 +331,21 @@ impl NumCoder {
     }
 }
 
+/// Rejects ranges the tree walk cannot represent without overflowing.
+fn check_range(low: i32, high: i32) -> Result<(), Jb2Error> {
+    if low > high || low < BIG_NEGATIVE || high > BIG_POSITIVE {
+        return Err(Jb2Error::InvalidNumber(format!(
+            "Range [{}, {}] is outside [{}, {}]",
+            low, high, BIG_NEGATIVE, BIG_POSITIVE
+        )));
+    }
+    Ok(())
+}
+
+fn unallocated(ctx: NumContext) -> Jb2Error {
+    Jb2Error::InvalidState(format!("Number context {} was never allocated", ctx))
+}
+
 /// Legacy wrapper for compatibility with old API.
 /// This uses a simple approach that may not match DjVuLibre exactly.
 /// For full compatibility, use NumCoder directly.
@@ -308,6 +432,19 @@ pub fn encode_integer_simple<W: Write>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_code_num_rejects_oversized_range() {
+        let mut coder = NumCoder::new();
+        let mut zc = ZEncoder::new(Vec::new(), true).unwrap();
+        let mut ctx = 0;
+        let result = coder.code_num(&mut zc, &mut ctx, 0, i32::MAX, i32::MAX);
+        assert!(matches!(result, Err(Jb2Error::InvalidNumber(_))));
+
+        let mut bogus = 12_345;
+        let result = coder.code_num(&mut zc, &mut bogus, 0, 10, 3);
+        assert!(matches!(result, Err(Jb2Error::InvalidState(_))));
+    }
+
     #[test]
     fn test_num_coder_basic() {
         let mut coder = NumCoder::new();
@@ -343,4 +480,30 @@ mod tests {
         assert_eq!(coder.cur_ncell, 1);
         assert!(cells_before > 1);
     }
+
+    #[test]
+    fn test_decode_num_round_trip() {
+        let values = [
+            (0, 10, 5),
+            (-10, 10, -3),
+            (0, 262142, 1000),
+            (-5, -1, -5),
+            (7, 7, 7),
+        ];
+        let mut coder = NumCoder::new();
+        let mut buffer = Vec::new();
+        let mut zc = ZEncoder::new(&mut buffer, true).unwrap();
+        let mut ctx = 0;
+        for &(low, high, v) in &values {
+            coder.code_num(&mut zc, &mut ctx, low, high, v).unwrap();
+        }
+        zc.finish().unwrap();
+
+        let mut decoder = NumCoder::new();
+        let mut zd = ZDecoder::new(&buffer, true).unwrap();
+        let mut ctx = 0;
+        for &(low, high, v) in &values {
+            assert_eq!(decoder.decode_num(&mut zd, &mut ctx, low, high).unwrap(), v);
+        }
+    }
 }
@@ -6,6 +6,8 @@
 //! - `Comparator`: Symbol matching with spatial search for dictionary building
 //! - Simple shared dictionary support for multi-page encoding
 
+use crate::doc::page_encoder::MAX_PAGE_DIMENSION;
+use crate::image::image_formats::{Pixel, Pixmap};
 use bitvec::order::Msb0;
 use bitvec::prelude::*;
 use std::error::Error;
@@ -18,13 +20,38 @@ use std::sync::{Arc, OnceLock};
 pub enum BitImageError {
     /// The specified dimensions would result in a bitmap that is too large to allocate.
     TooLarge { width: u32, height: u32 },
+    /// Width or height is zero.
+    Empty { width: u32, height: u32 },
+    /// Run-length data is truncated, overflows a row, or has trailing bytes.
+    InvalidRle,
+    /// A scanline was missing or shorter than the image width.
+    InvalidScanline { row: usize },
 }
 
 impl fmt::Display for BitImageError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             BitImageError::TooLarge { width, height } => {
-                write!(f, "image dimensions ({}x{}) are too large", width, height)
+                write!(
+                    f,
+                    "image dimensions ({}x{}) are too large (at most {} pixels per side)",
+                    width, height, MAX_PAGE_DIMENSION
+                )
+            }
+            BitImageError::Empty { width, height } => {
+                write!(
+                    f,
+                    "image dimensions ({}x{}) must be non-zero",
+                    width, height
+                )
+            }
+            BitImageError::InvalidRle => write!(f, "malformed run-length data"),
+            BitImageError::InvalidScanline { row } => {
+                write!(
+                    f,
+                    "scanline {} is missing or shorter than the image width",
+                    row
+                )
             }
         }
     }
@@ -45,6 +72,11 @@ pub struct Rect {
     pub height: u32,
 }
 
+/// Runs at or above this length use the two-byte RLE form.
+const RLE_TWO_BYTE: usize = 0xC0;
+/// Longest run representable in a single two-byte RLE code.
+const RLE_MAX_RUN: usize = 0x3FFF;
+
 /// A bitmap image using MSB-first bit ordering for JB2 compatibility.
 #[derive(Clone, Debug, Eq)]
 pub struct BitImage {
@@ -52,17 +84,21 @@ pub struct BitImage {
     pub height: usize,
     bits: BitVec<u8, Msb0>,
     packed_cache: OnceLock<Vec<u32>>,
+    bytes_cache: OnceLock<Vec<u8>>,
 }
 
+/// Images are equal when they have the same dimensions and pixels. Only the
+/// `width * height` live bits take part, so whatever padding the source rows
+/// carried past the image width never matters, and no packed copy is built.
 impl PartialEq for BitImage {
     fn eq(&self, other: &Self) -> bool {
-        if self.width != other.width || self.height != other.height {
-            return false;
-        }
-        self.to_packed_words() == other.to_packed_words()
+        self.width == other.width && self.height == other.height && self.bits == other.bits
     }
 }
 
+/// Hashes the dimensions and the packed rows, whose bits past the image
+/// width are always zero, so hashing agrees with `==`. The packed words are
+/// cached, so clustering symbols in a `HashMap` packs each one once.
 impl Hash for BitImage {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.width.hash(state);
@@ -71,14 +107,40 @@ impl Hash for BitImage {
     }
 }
 
+/// Number of bits in a `width` x `height` image, if both sides are between
+/// 1 and [`MAX_PAGE_DIMENSION`] and it can be allocated.
+fn bit_count(width: usize, height: usize) -> Result<usize, BitImageError> {
+    let side = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+    let (w, h) = (side(width), side(height));
+    if width == 0 || height == 0 {
+        return Err(BitImageError::Empty {
+            width: w,
+            height: h,
+        });
+    }
+    let too_large = BitImageError::TooLarge {
+        width: w,
+        height: h,
+    };
+    if w > MAX_PAGE_DIMENSION || h > MAX_PAGE_DIMENSION {
+        return Err(too_large);
+    }
+    width
+        .checked_mul(height)
+        .filter(|&bits| bits < isize::MAX as usize)
+        .ok_or(too_large)
+}
+
 impl BitImage {
+    /// Creates an all-white image.
+    ///
+    /// Both sides must be between 1 and [`MAX_PAGE_DIMENSION`], the largest
+    /// size a DjVu page can have; anything else is rejected rather than
+    /// risking a huge or overflowing allocation.
     pub fn new(width: u32, height: u32) -> Result<Self, BitImageError> {
         let width_us = width as usize;
         let height_us = height as usize;
-        let total_bits = match width_us.checked_mul(height_us) {
-            Some(bits) if bits < (isize::MAX as usize) => bits,
-            _ => return Err(BitImageError::TooLarge { width, height }),
-        };
+        let total_bits = bit_count(width_us, height_us)?;
 
         let mut bits = BitVec::with_capacity(total_bits);
         bits.resize(total_bits, false);
@@ -87,18 +149,88 @@ impl BitImage {
             height: height_us,
             bits,
             packed_cache: OnceLock::new(),
+            bytes_cache: OnceLock::new(),
         })
     }
 
-    pub fn from_bytes(width: usize, height: usize, bytes: &[u8]) -> Self {
+    /// Builds an image from its pixels packed MSB-first with no row padding.
+    ///
+    /// Sides are checked as for [`BitImage::new`], and `bytes` must hold at
+    /// least `width * height` bits; extra bytes are ignored.
+    pub fn from_bytes(width: usize, height: usize, bytes: &[u8]) -> Result<Self, BitImageError> {
+        let total_bits = bit_count(width, height)?;
         let mut bv = BitVec::from_slice(bytes);
-        bv.truncate(width * height);
-        Self {
+        if bv.len() < total_bits {
+            return Err(BitImageError::InvalidScanline {
+                row: bv.len() / width,
+            });
+        }
+        bv.truncate(total_bits);
+        Ok(Self {
             width,
             height,
             bits: bv,
             packed_cache: OnceLock::new(),
+            bytes_cache: OnceLock::new(),
+        })
+    }
+
+    /// Builds an image row by row from packed scanlines.
+    ///
+    /// Each row holds at least `ceil(width / 8)` bytes, MSB-first, with set
+    /// bits marking black pixels (the same layout as PBM rows). Rows are
+    /// copied straight into the bit buffer, so producers such as scanners or
+    /// TIFF strip decoders never need a second full-page buffer. Only the
+    /// first `height` rows are consumed. Sides are checked as for
+    /// [`BitImage::new`].
+    pub fn from_scanlines<I, R>(width: u32, height: u32, rows: I) -> Result<Self, BitImageError>
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<[u8]>,
+    {
+        let width_us = width as usize;
+        let height_us = height as usize;
+        let total_bits = bit_count(width_us, height_us)?;
+
+        let mut bits: BitVec<u8, Msb0> = BitVec::with_capacity(total_bits);
+        let mut rows = rows.into_iter();
+        for row in 0..height_us {
+            let data = rows.next().ok_or(BitImageError::InvalidScanline { row })?;
+            let row_bits = data.as_ref().view_bits::<Msb0>();
+            if row_bits.len() < width_us {
+                return Err(BitImageError::InvalidScanline { row });
+            }
+            bits.extend_from_bitslice(&row_bits[..width_us]);
+        }
+
+        Ok(Self {
+            width: width_us,
+            height: height_us,
+            bits,
+            packed_cache: OnceLock::new(),
+            bytes_cache: OnceLock::new(),
+        })
+    }
+
+    /// Thresholds a two-color scan, such as white lines on a blueprint's
+    /// blue, into a bilevel image.
+    ///
+    /// Each pixel is set when it is closer (in RGB distance) to `fg` than to
+    /// `bg`; pixels equally far from both count as background.
+    pub fn from_rgb_two_color(img: &Pixmap, fg: Pixel, bg: Pixel) -> Result<Self, BitImageError> {
+        let distance = |a: Pixel, b: Pixel| {
+            [(a.r, b.r), (a.g, b.g), (a.b, b.b)]
+                .into_iter()
+                .map(|(x, y)| (x as i32 - y as i32).pow(2))
+                .sum::<i32>()
+        };
+        let mut image = Self::new(img.width(), img.height())?;
+        for (i, &pixel) in img.pixels().iter().enumerate() {
+            if distance(pixel, fg) < distance(pixel, bg) {
+                image.bits.set(i, true);
+            }
         }
+        Ok(image)
     }
 
     /// Gets the value of a pixel without bounds checking.
@@ -120,7 +252,210 @@ impl BitImage {
         if idx < self.bits.len() {
             self.bits.set(idx, val);
         }
-        self.packed_cache.take(); // Invalidate cache
+        self.packed_cache.take(); // Invalidate caches
+        self.bytes_cache.take();
+    }
+
+    /// Number of set (black) pixels.
+    ///
+    /// Counts a word at a time over the live `width * height` bits, so
+    /// storage bits past the last pixel never take part.
+    pub fn count_set_bits(&self) -> usize {
+        self.bits.count_ones()
+    }
+
+    /// Fraction of pixels that are set, from 0.0 (blank) to 1.0 (solid).
+    pub fn density(&self) -> f32 {
+        match self.width * self.height {
+            0 => 0.0,
+            pixels => (self.count_set_bits() as f64 / pixels as f64) as f32,
+        }
+    }
+
+    /// Serializes the image as horizontal runs, one row at a time.
+    ///
+    /// Uses the DjVu `GBitmap` RLE layout: each row is a sequence of
+    /// alternating white/black run lengths starting with white. Runs shorter
+    /// than `0xC0` take one byte, longer ones two bytes (`0xC0 | hi`, `lo`).
+    /// Runs above `0x3FFF` are split with a zero-length run of the other color.
+    ///
+    /// Text masks are mostly long white runs, so this is typically several
+    /// times smaller than the packed bitmap. Dimensions are not stored.
+    pub fn to_rle(&self) -> Vec<u8> {
+        fn put_run(out: &mut Vec<u8>, mut len: usize) {
+            while len > RLE_MAX_RUN {
+                put_run(out, RLE_MAX_RUN);
+                out.push(0);
+                len -= RLE_MAX_RUN;
+            }
+            if len < RLE_TWO_BYTE {
+                out.push(len as u8);
+            } else {
+                out.push((RLE_TWO_BYTE | (len >> 8)) as u8);
+                out.push((len & 0xff) as u8);
+            }
+        }
+
+        let mut out = Vec::new();
+        for y in 0..self.height {
+            let row = &self.bits[y * self.width..(y + 1) * self.width];
+            let mut x = 0usize;
+            let mut color = false;
+            while x < self.width {
+                let run = row[x..].iter().take_while(|b| **b == color).count();
+                put_run(&mut out, run);
+                x += run;
+                color = !color;
+            }
+        }
+        out
+    }
+
+    /// Rebuilds an image from data produced by [`BitImage::to_rle`].
+    pub fn from_rle(bytes: &[u8], width: usize, height: usize) -> Result<Self, BitImageError> {
+        bit_count(width, height)?;
+        let mut img = Self::new(width as u32, height as u32)?;
+        let mut pos = 0usize;
+        let mut next_run = || -> Result<usize, BitImageError> {
+            let b = *bytes.get(pos).ok_or(BitImageError::InvalidRle)? as usize;
+            pos += 1;
+            if b < RLE_TWO_BYTE {
+                return Ok(b);
+            }
+            let lo = *bytes.get(pos).ok_or(BitImageError::InvalidRle)? as usize;
+            pos += 1;
+            Ok(((b & !RLE_TWO_BYTE) << 8) | lo)
+        };
+
+        for y in 0..height {
+            let mut x = 0usize;
+            let mut color = false;
+            while x < width {
+                let run = next_run()?;
+                if x + run > width {
+                    return Err(BitImageError::InvalidRle);
+                }
+                if color {
+                    let start = y * width + x;
+                    img.bits[start..start + run].fill(true);
+                }
+                x += run;
+                color = !color;
+            }
+        }
+        if pos != bytes.len() {
+            return Err(BitImageError::InvalidRle);
+        }
+        Ok(img)
+    }
+
+    /// Scales the image to `new_width` x `new_height` by nearest-neighbour
+    /// sampling at each output pixel's center. Fast and keeps pixels
+    /// strictly bilevel, which suits masks. The new size is checked as in
+    /// [`Self::new`].
+    pub fn resize_nearest(&self, new_width: u32, new_height: u32) -> Result<Self, BitImageError> {
+        let mut out = BitImage::new(new_width, new_height)?;
+        let (w, h) = (self.width as u64, self.height as u64);
+        if w == 0 || h == 0 {
+            return Ok(out);
+        }
+        let sample =
+            |t: usize, len: u64, tlen: u32| ((2 * t as u64 + 1) * len / (2 * tlen as u64)) as usize;
+        let columns: Vec<usize> = (0..new_width as usize)
+            .map(|x| sample(x, w, new_width))
+            .collect();
+        for y in 0..new_height as usize {
+            let sy = sample(y, h, new_height);
+            for (x, &sx) in columns.iter().enumerate() {
+                if self.get_pixel_unchecked(sx, sy) {
+                    out.set_usize(x, y, true);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Grows black regions by `radius` pixels in every direction (a square
+    /// structuring element of side `2 * radius + 1`).
+    pub fn dilate(&self, radius: usize) -> BitImage {
+        self.morph(radius, true)
+    }
+
+    /// Shrinks black regions by `radius` pixels in every direction. Pixels
+    /// outside the image count as white, so shapes touching the border
+    /// shrink from that side too.
+    pub fn erode(&self, radius: usize) -> BitImage {
+        self.morph(radius, false)
+    }
+
+    /// Morphological closing (dilate, then erode): fills gaps and holes
+    /// narrower than `2 * radius + 1` pixels.
+    pub fn close(&self, radius: usize) -> BitImage {
+        self.dilate(radius).erode(radius)
+    }
+
+    /// Morphological opening (erode, then dilate): removes specks smaller
+    /// than `2 * radius + 1` pixels while keeping larger shapes intact.
+    pub fn open(&self, radius: usize) -> BitImage {
+        self.erode(radius).dilate(radius)
+    }
+
+    /// Separable square dilation (`grow`) or erosion on the packed rows:
+    /// a horizontal pass of word shifts, then a vertical pass over rows.
+    fn morph(&self, radius: usize, grow: bool) -> BitImage {
+        if radius == 0 || self.width == 0 || self.height == 0 {
+            return self.clone();
+        }
+        let wpr = self.width.div_ceil(32);
+        let words = self.to_packed_words();
+        // Clears the padding bits past `width` in the last word of a row
+        let tail_mask = match self.width % 32 {
+            0 => u32::MAX,
+            bits => u32::MAX << (32 - bits),
+        };
+        let combine = |acc: &mut u32, w: u32| {
+            if grow { *acc |= w } else { *acc &= w }
+        };
+
+        let mut horizontal = words.to_vec();
+        let mut shifted = vec![0u32; wpr];
+        for (row, out) in words.chunks(wpr).zip(horizontal.chunks_mut(wpr)) {
+            for s in 1..=radius as isize {
+                for dir in [s, -s] {
+                    shift_row(row, dir, &mut shifted);
+                    for (o, &w) in out.iter_mut().zip(&shifted) {
+                        combine(o, w);
+                    }
+                }
+            }
+            out[wpr - 1] &= tail_mask;
+        }
+
+        let mut result = vec![0u32; words.len()];
+        for y in 0..self.height {
+            let out = &mut result[y * wpr..(y + 1) * wpr];
+            if !grow && (y < radius || y + radius >= self.height) {
+                continue; // The window reaches white rows outside the image
+            }
+            out.copy_from_slice(&horizontal[y * wpr..(y + 1) * wpr]);
+            let lo = y.saturating_sub(radius);
+            let hi = (y + radius).min(self.height - 1);
+            for src in (lo..=hi).filter(|&src| src != y) {
+                for (o, &w) in out.iter_mut().zip(&horizontal[src * wpr..(src + 1) * wpr]) {
+                    combine(o, w);
+                }
+            }
+        }
+
+        let rows = result.chunks(wpr).map(|row| {
+            row.iter()
+                .flat_map(|w| w.to_be_bytes())
+                .collect::<Vec<u8>>()
+        });
+        let img = BitImage::from_scanlines(self.width as u32, self.height as u32, rows)
+            .expect("dimensions come from an existing image");
+        let _ = img.packed_cache.set(result);
+        img
     }
 
     pub fn to_packed_words(&self) -> &[u32] {
@@ -128,20 +463,96 @@ impl BitImage {
             let words_per_row = (self.width + 31) / 32;
             let mut out = Vec::with_capacity(words_per_row * self.height);
             for y in 0..self.height {
-                for word_x in 0..words_per_row {
-                    let mut w = 0u32;
-                    for bit in 0..32 {
-                        let x = word_x * 32 + bit;
-                        if x < self.width && self.get_pixel_unchecked(x, y) {
-                            w |= 1u32 << (31 - bit);
-                        }
-                    }
-                    out.push(w);
+                let row = &self.bits[y * self.width..(y + 1) * self.width];
+                for chunk in row.chunks(32) {
+                    // Left-align partial words so the unused low bits are zero
+                    out.push(chunk.load_be::<u32>() << (32 - chunk.len()));
                 }
             }
             out
         })
     }
+
+    /// Bytes per row in [`Self::as_bytes`] and [`Self::from_packed`]
+    /// buffers without extra padding: `ceil(width / 8)`.
+    pub fn row_stride(&self) -> usize {
+        self.width.div_ceil(8)
+    }
+
+    /// The pixels as packed rows for handing to C or other image libraries.
+    ///
+    /// Each row takes [`Self::row_stride`] bytes, top row first. Pixels are
+    /// MSB-first within each byte (x = 0 is bit 7 of the row's first byte),
+    /// a set bit is black, and bits past the image width are zero. This is
+    /// the PBM raster layout. Built on first use and cached.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes_cache.get_or_init(|| {
+            let stride = self.row_stride();
+            let mut out = vec![0u8; stride * self.height];
+            for (y, row) in out.chunks_exact_mut(stride).enumerate() {
+                let src = &self.bits[y * self.width..(y + 1) * self.width];
+                row.view_bits_mut::<Msb0>()[..self.width].copy_from_bitslice(src);
+            }
+            out
+        })
+    }
+
+    /// Builds an image from packed rows laid out as in [`Self::as_bytes`],
+    /// each starting `stride` bytes after the previous one.
+    ///
+    /// `stride` may exceed [`Self::row_stride`] for buffers with padded
+    /// rows; padding bytes and bits past the width are ignored. Fails for
+    /// sizes [`Self::new`] rejects, and with `InvalidScanline` when the
+    /// stride is narrower than a row or `bytes` ends before the last row.
+    pub fn from_packed(
+        bytes: &[u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+    ) -> Result<Self, BitImageError> {
+        if width == 0 || height == 0 {
+            return Err(BitImageError::Empty { width, height });
+        }
+        if width > MAX_PAGE_DIMENSION || height > MAX_PAGE_DIMENSION {
+            return Err(BitImageError::TooLarge { width, height });
+        }
+        if stride < (width as usize).div_ceil(8) {
+            return Err(BitImageError::InvalidScanline { row: 0 });
+        }
+        Self::from_scanlines(width, height, bytes.chunks(stride))
+    }
+}
+
+/// Writes `row` moved by `shift` pixels (positive = towards larger x) into
+/// `out`, filling vacated pixels with white.
+fn shift_row(row: &[u32], shift: isize, out: &mut [u32]) {
+    let words = (shift.unsigned_abs() / 32) as isize;
+    let bits = (shift.unsigned_abs() % 32) as u32;
+    let word = |i: isize| -> u32 {
+        if i < 0 {
+            0
+        } else {
+            row.get(i as usize).copied().unwrap_or(0)
+        }
+    };
+    for (i, o) in out.iter_mut().enumerate() {
+        let i = i as isize;
+        *o = if shift >= 0 {
+            let carry = if bits == 0 {
+                0
+            } else {
+                word(i - words - 1) << (32 - bits)
+            };
+            (word(i - words) >> bits) | carry
+        } else {
+            let carry = if bits == 0 {
+                0
+            } else {
+                word(i + words + 1) >> (32 - bits)
+            };
+            (word(i + words) << bits) | carry
+        };
+    }
 }
 
 // Lutz trait implementation removed - using homegrown connected components instead
@@ -334,6 +745,60 @@ mod tests {
         let img = img.unwrap();
         assert_eq!(img.width, 10);
         assert_eq!(img.height, 10);
+        assert_eq!(img.to_packed_words().len(), 10);
+    }
+
+    #[test]
+    fn test_bitimage_rejects_bad_dimensions() {
+        assert_eq!(
+            BitImage::new(0, 10),
+            Err(BitImageError::Empty {
+                width: 0,
+                height: 10
+            })
+        );
+        assert!(matches!(
+            BitImage::new(10, 0),
+            Err(BitImageError::Empty { .. })
+        ));
+
+        let err = BitImage::new(MAX_PAGE_DIMENSION + 1, 1).unwrap_err();
+        assert!(matches!(err, BitImageError::TooLarge { .. }));
+        assert!(err.to_string().contains("65535"), "{err}");
+        assert!(matches!(
+            BitImage::new(u32::MAX, u32::MAX),
+            Err(BitImageError::TooLarge { .. })
+        ));
+
+        let widest = BitImage::new(MAX_PAGE_DIMENSION, 1).unwrap();
+        assert_eq!(widest.to_packed_words().len(), 2048);
+
+        // Every constructor applies the same limits
+        assert!(matches!(
+            BitImage::from_bytes(0, 4, &[]),
+            Err(BitImageError::Empty { .. })
+        ));
+        assert!(matches!(
+            BitImage::from_bytes(70_000, 1, &[0; 8750]),
+            Err(BitImageError::TooLarge { .. })
+        ));
+        assert_eq!(
+            BitImage::from_bytes(8, 3, &[0xff, 0xff]),
+            Err(BitImageError::InvalidScanline { row: 2 })
+        );
+        assert!(matches!(
+            BitImage::from_scanlines(70_000, 1, [vec![0u8; 8750]]),
+            Err(BitImageError::TooLarge { .. })
+        ));
+        assert!(matches!(
+            BitImage::from_scanlines(4, 0, Vec::<Vec<u8>>::new()),
+            Err(BitImageError::Empty { .. })
+        ));
+        // Not truncated to a width of 1
+        assert!(matches!(
+            BitImage::from_rle(&[1], (1usize << 32) + 1, 1),
+            Err(BitImageError::TooLarge { .. })
+        ));
     }
 
     #[test]
@@ -354,6 +819,202 @@ mod tests {
         assert_eq!(dy, 0);
     }
 
+    #[test]
+    fn test_rle_round_trip() {
+        let w = 300;
+        let h = 40;
+        let mut patterns = vec![
+            BitImage::new(w, h).unwrap(),
+            BitImage::new(w, h).unwrap(),
+            BitImage::new(w, h).unwrap(),
+        ];
+        for y in 0..h as usize {
+            for x in 0..w as usize {
+                patterns[1].set_usize(x, y, (x + y) % 2 == 0);
+                patterns[2].set_usize(x, y, x >= 250 || y == 0);
+            }
+        }
+        // Row longer than a single two-byte run
+        let mut wide = BitImage::new(40_000, 2).unwrap();
+        wide.set_usize(39_999, 1, true);
+        patterns.push(wide);
+
+        for img in &patterns {
+            let rle = img.to_rle();
+            let back = BitImage::from_rle(&rle, img.width, img.height).unwrap();
+            assert_eq!(&back, img);
+        }
+    }
+
+    #[test]
+    fn test_rle_smaller_for_text_mask() {
+        // Sparse "glyphs" on a white page, like a scanned text mask
+        let (w, h) = (800usize, 200usize);
+        let mut img = BitImage::new(w as u32, h as u32).unwrap();
+        for line in 0..5 {
+            for glyph in 0..40 {
+                let (gx, gy) = (20 + glyph * 19, 20 + line * 35);
+                for y in gy..gy + 14 {
+                    for x in gx..gx + 10 {
+                        if x == gx || y == gy + 13 || (x + y) % 7 == 0 {
+                            img.set_usize(x, y, true);
+                        }
+                    }
+                }
+            }
+        }
+        let raw_size = w.div_ceil(8) * h;
+        assert!(img.to_rle().len() < raw_size);
+    }
+
+    #[test]
+    fn test_rle_rejects_malformed() {
+        let img = BitImage::new(10, 2).unwrap();
+        let mut rle = img.to_rle();
+        assert!(BitImage::from_rle(&rle[..1], 10, 2).is_err());
+        rle.push(0);
+        assert_eq!(
+            BitImage::from_rle(&rle, 10, 2),
+            Err(BitImageError::InvalidRle)
+        );
+        assert!(BitImage::from_rle(&[11], 10, 1).is_err());
+    }
+
+    #[test]
+    fn test_from_scanlines_checkerboard() {
+        let (w, h) = (13u32, 9u32);
+        let stride = (w as usize).div_ceil(8);
+        let rows = (0..h as usize).map(|y| {
+            let mut row = vec![0u8; stride];
+            for x in 0..w as usize {
+                if (x + y) % 2 == 0 {
+                    row[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            row
+        });
+        let streamed = BitImage::from_scanlines(w, h, rows).unwrap();
+
+        let mut direct = BitImage::new(w, h).unwrap();
+        for y in 0..h as usize {
+            for x in 0..w as usize {
+                direct.set_usize(x, y, (x + y) % 2 == 0);
+            }
+        }
+        assert_eq!(streamed, direct);
+
+        let short = BitImage::from_scanlines(w, h, vec![vec![0u8; stride]; 3]);
+        assert_eq!(short, Err(BitImageError::InvalidScanline { row: 3 }));
+        let narrow = BitImage::from_scanlines(w, 1, [[0u8; 1]]);
+        assert_eq!(narrow, Err(BitImageError::InvalidScanline { row: 0 }));
+    }
+
+    #[test]
+    fn test_eq_and_hash_ignore_row_padding() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let hash = |img: &BitImage| {
+            let mut hasher = DefaultHasher::new();
+            img.hash(&mut hasher);
+            hasher.finish()
+        };
+        // 10 pixels wide: the last 6 bits of each second byte are padding
+        let clean = BitImage::from_scanlines(10, 2, [[0b1010_0000, 0b0100_0000]; 2]).unwrap();
+        let noisy = BitImage::from_scanlines(10, 2, [[0b1010_0000, 0b0111_1111]; 2]).unwrap();
+        assert_eq!(clean, noisy);
+        assert_eq!(hash(&clean), hash(&noisy));
+        assert_eq!(clean.to_packed_words(), [0xA040_0000, 0xA040_0000]);
+
+        let mut other = clean.clone();
+        other.set_usize(8, 1, true);
+        assert_ne!(clean, other);
+        assert_ne!(hash(&clean), hash(&other));
+        // Same bits, different shape
+        let reshaped =
+            BitImage::from_bytes(5, 4, &[0b1010_0010, 0b1000_1010, 0b0000_0000]).unwrap();
+        assert_ne!(clean, reshaped);
+    }
+
+    #[test]
+    fn test_count_set_bits_and_density() {
+        for width in [8u32, 13, 32, 37] {
+            // Left half of every row set, from rows whose byte padding is set too
+            let row_bytes = (width as usize).div_ceil(8);
+            let half = width as usize / 2;
+            let mut bytes = vec![0xFF; row_bytes * 6];
+            for row in bytes.chunks_mut(row_bytes) {
+                for x in half..row_bytes * 8 {
+                    if x < width as usize {
+                        row[x / 8] &= !(0x80 >> (x % 8));
+                    }
+                }
+            }
+            let img = BitImage::from_scanlines(width, 6, bytes.chunks(row_bytes)).unwrap();
+            assert_eq!(img.count_set_bits(), half * 6, "width {width}");
+            assert_eq!(img.density(), half as f32 / width as f32, "width {width}");
+        }
+
+        let blank = BitImage::new(13, 3).unwrap();
+        assert_eq!(blank.count_set_bits(), 0);
+        assert_eq!(blank.density(), 0.0);
+        assert_eq!(filled(13, 3, &[(0, 0, 13, 3)]).density(), 1.0);
+    }
+
+    fn filled(width: u32, height: u32, rects: &[(usize, usize, usize, usize)]) -> BitImage {
+        let mut img = BitImage::new(width, height).unwrap();
+        for &(x0, y0, w, h) in rects {
+            for y in y0..y0 + h {
+                for x in x0..x0 + w {
+                    img.set_usize(x, y, true);
+                }
+            }
+        }
+        img
+    }
+
+    #[test]
+    fn test_erode_removes_specks() {
+        let img = filled(70, 30, &[(5, 5, 1, 1), (63, 20, 1, 1), (26, 8, 12, 12)]);
+        assert_eq!(img.erode(1), filled(70, 30, &[(27, 9, 10, 10)]));
+    }
+
+    #[test]
+    fn test_dilate_fills_gaps() {
+        // A line broken at x = 32, right on a word boundary
+        let img = filled(70, 20, &[(10, 10, 22, 1), (33, 10, 27, 1)]);
+        let grown = img.dilate(1);
+        assert!(grown.get_pixel_unchecked(32, 10));
+        assert_eq!(grown.close(0), grown);
+        assert_eq!(img.close(1), filled(70, 20, &[(10, 10, 50, 1)]));
+
+        // Matches a per-pixel reference on an irregular pattern
+        let mut noisy = BitImage::new(70, 25).unwrap();
+        for i in 0..70 * 25 {
+            if (i * 7919) % 13 == 0 {
+                noisy.set_usize(i % 70, i / 70, true);
+            }
+        }
+        let grown = noisy.dilate(2);
+        for y in 0..25usize {
+            for x in 0..70usize {
+                let expected = (y.saturating_sub(2)..(y + 3).min(25)).any(|sy| {
+                    (x.saturating_sub(2)..(x + 3).min(70))
+                        .any(|sx| noisy.get_pixel_unchecked(sx, sy))
+                });
+                assert_eq!(grown.get_pixel_unchecked(x, y), expected, "({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_preserves_solid_shapes() {
+        let shapes = [(26, 8, 12, 12), (50, 2, 15, 5)];
+        let mut rects = shapes.to_vec();
+        rects.extend([(3, 3, 1, 1), (40, 25, 2, 1)]);
+        let img = filled(70, 30, &rects);
+        assert_eq!(img.open(1), filled(70, 30, &shapes));
+    }
+
     #[test]
     fn test_shared_dict() {
         let shapes = vec![
@@ -366,4 +1027,92 @@ mod tests {
         assert!(dict.get_shape(1).is_some());
         assert!(dict.get_shape(2).is_none());
     }
+
+    #[test]
+    fn test_packed_bytes_round_trip() {
+        let mut image = BitImage::new(13, 5).unwrap();
+        for y in 0..5 {
+            for x in 0..13 {
+                image.set_usize(x, y, (x * 7 + y * 3) % 5 == 0 || x == 12);
+            }
+        }
+        assert_eq!(image.row_stride(), 2);
+        let bytes = image.as_bytes().to_vec();
+        assert_eq!(bytes.len(), 10);
+        // Row 0 sets x = 0, 5, 10 and 12: MSB-first, tail bits zero
+        assert_eq!(&bytes[..2], &[0b1000_0100, 0b0010_1000]);
+        assert_eq!(BitImage::from_packed(&bytes, 13, 5, 2).unwrap(), image);
+
+        // Padded rows, as a C library with 4-byte aligned strides hands out
+        let padded: Vec<u8> = bytes
+            .chunks(2)
+            .flat_map(|row| [row[0], row[1], 0xFF, 0xFF])
+            .collect();
+        let imported = BitImage::from_packed(&padded[..padded.len() - 2], 13, 5, 4).unwrap();
+        assert_eq!(imported, image);
+        assert_eq!(imported.as_bytes(), &bytes[..]);
+
+        image.set_usize(1, 0, true);
+        assert_eq!(image.as_bytes()[0], 0b1100_0100);
+
+        assert_eq!(
+            BitImage::from_packed(&bytes, 13, 5, 1),
+            Err(BitImageError::InvalidScanline { row: 0 })
+        );
+        assert_eq!(
+            BitImage::from_packed(&bytes[..9], 13, 5, 2),
+            Err(BitImageError::InvalidScanline { row: 4 })
+        );
+    }
+
+    #[test]
+    fn test_two_color_white_on_blue() {
+        let blue = Pixel::new(20, 60, 160);
+        let white = Pixel::new(255, 255, 255);
+        // A white bar on blue, with scanner noise on both colors
+        let text = |x: u32, y: u32| (4..12).contains(&x) && (3..5).contains(&y);
+        let img = Pixmap::from_fn(16, 8, |x, y| {
+            let noise = ((x * 7 + y * 3) % 20) as u8;
+            if text(x, y) {
+                Pixel::new(235 + noise, 230 + noise, 220 + noise)
+            } else {
+                Pixel::new(blue.r + noise, blue.g + noise, blue.b - noise)
+            }
+        });
+
+        let bits = BitImage::from_rgb_two_color(&img, white, blue).unwrap();
+        for y in 0..8 {
+            for x in 0..16 {
+                assert_eq!(bits.get_pixel_unchecked(x, y), text(x as u32, y as u32));
+            }
+        }
+        assert_eq!(bits.count_set_bits(), 16);
+    }
+
+    #[test]
+    fn test_resize_nearest() {
+        // A checkerboard of 2x2 cells halves to a checkerboard of pixels
+        let mut board = BitImage::new(8, 6).unwrap();
+        for y in 0..6 {
+            for x in 0..8 {
+                board.set_usize(x, y, (x / 2 + y / 2) % 2 == 0);
+            }
+        }
+        let small = board.resize_nearest(4, 3).unwrap();
+        for y in 0..3 {
+            for x in 0..4 {
+                assert_eq!(small.get_pixel_unchecked(x, y), (x + y) % 2 == 0);
+            }
+        }
+
+        // Tripling turns each pixel into a 3x3 block, keeping the pattern
+        let large = small.resize_nearest(12, 9).unwrap();
+        for y in 0..9 {
+            for x in 0..12 {
+                assert_eq!(large.get_pixel_unchecked(x, y), (x / 3 + y / 3) % 2 == 0);
+            }
+        }
+        assert_eq!(large.resize_nearest(4, 3).unwrap(), small);
+        assert!(small.resize_nearest(0, 3).is_err());
+    }
 }
@@ -0,0 +1,544 @@
+//! ITU-T T.6 (Group 4 fax, "MMR") compression for bilevel `Smmr` chunks.
+//!
+//! JB2 is the native DjVu bilevel codec and compresses text far better, but
+//! some legacy pipelines only handle G4 data. An `Smmr` chunk holds the
+//! header `MMR\0`, the width and height as big-endian `u16`, and one
+//! unstriped G4 stream coding the rows top to bottom. Rows are coded
+//! against the previous row (an all-white row for the first) with the
+//! pass, horizontal and vertical modes of T.4 two-dimensional coding. No
+//! EOFB is written; decoders stop after `height` rows.
+
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::{DjvuError, Result};
+
+/// `(code, length)` of the white terminating codes for runs 0..=63.
+const WHITE_TERMINATING: [(u16, u8); 64] = [
+    (0b00110101, 8),
+    (0b000111, 6),
+    (0b0111, 4),
+    (0b1000, 4),
+    (0b1011, 4),
+    (0b1100, 4),
+    (0b1110, 4),
+    (0b1111, 4),
+    (0b10011, 5),
+    (0b10100, 5),
+    (0b00111, 5),
+    (0b01000, 5),
+    (0b001000, 6),
+    (0b000011, 6),
+    (0b110100, 6),
+    (0b110101, 6),
+    (0b101010, 6),
+    (0b101011, 6),
+    (0b0100111, 7),
+    (0b0001100, 7),
+    (0b0001000, 7),
+    (0b0010111, 7),
+    (0b0000011, 7),
+    (0b0000100, 7),
+    (0b0101000, 7),
+    (0b0101011, 7),
+    (0b0010011, 7),
+    (0b0100100, 7),
+    (0b0011000, 7),
+    (0b00000010, 8),
+    (0b00000011, 8),
+    (0b00011010, 8),
+    (0b00011011, 8),
+    (0b00010010, 8),
+    (0b00010011, 8),
+    (0b00010100, 8),
+    (0b00010101, 8),
+    (0b00010110, 8),
+    (0b00010111, 8),
+    (0b00101000, 8),
+    (0b00101001, 8),
+    (0b00101010, 8),
+    (0b00101011, 8),
+    (0b00101100, 8),
+    (0b00101101, 8),
+    (0b00000100, 8),
+    (0b00000101, 8),
+    (0b00001010, 8),
+    (0b00001011, 8),
+    (0b01010010, 8),
+    (0b01010011, 8),
+    (0b01010100, 8),
+    (0b01010101, 8),
+    (0b00100100, 8),
+    (0b00100101, 8),
+    (0b01011000, 8),
+    (0b01011001, 8),
+    (0b01011010, 8),
+    (0b01011011, 8),
+    (0b01001010, 8),
+    (0b01001011, 8),
+    (0b00110010, 8),
+    (0b00110011, 8),
+    (0b00110100, 8),
+];
+
+/// White make-up codes for runs 64, 128, ..., 1728.
+const WHITE_MAKEUP: [(u16, u8); 27] = [
+    (0b11011, 5),
+    (0b10010, 5),
+    (0b010111, 6),
+    (0b0110111, 7),
+    (0b00110110, 8),
+    (0b00110111, 8),
+    (0b01100100, 8),
+    (0b01100101, 8),
+    (0b01101000, 8),
+    (0b01100111, 8),
+    (0b011001100, 9),
+    (0b011001101, 9),
+    (0b011010010, 9),
+    (0b011010011, 9),
+    (0b011010100, 9),
+    (0b011010101, 9),
+    (0b011010110, 9),
+    (0b011010111, 9),
+    (0b011011000, 9),
+    (0b011011001, 9),
+    (0b011011010, 9),
+    (0b011011011, 9),
+    (0b010011000, 9),
+    (0b010011001, 9),
+    (0b010011010, 9),
+    (0b011000, 6),
+    (0b010011011, 9),
+];
+
+/// Black terminating codes for runs 0..=63.
+const BLACK_TERMINATING: [(u16, u8); 64] = [
+    (0b0000110111, 10),
+    (0b010, 3),
+    (0b11, 2),
+    (0b10, 2),
+    (0b011, 3),
+    (0b0011, 4),
+    (0b0010, 4),
+    (0b00011, 5),
+    (0b000101, 6),
+    (0b000100, 6),
+    (0b0000100, 7),
+    (0b0000101, 7),
+    (0b0000111, 7),
+    (0b00000100, 8),
+    (0b00000111, 8),
+    (0b000011000, 9),
+    (0b0000010111, 10),
+    (0b0000011000, 10),
+    (0b0000001000, 10),
+    (0b00001100111, 11),
+    (0b00001101000, 11),
+    (0b00001101100, 11),
+    (0b00000110111, 11),
+    (0b00000101000, 11),
+    (0b00000010111, 11),
+    (0b00000011000, 11),
+    (0b000011001010, 12),
+    (0b000011001011, 12),
+    (0b000011001100, 12),
+    (0b000011001101, 12),
+    (0b000001101000, 12),
+    (0b000001101001, 12),
+    (0b000001101010, 12),
+    (0b000001101011, 12),
+    (0b000011010010, 12),
+    (0b000011010011, 12),
+    (0b000011010100, 12),
+    (0b000011010101, 12),
+    (0b000011010110, 12),
+    (0b000011010111, 12),
+    (0b000001101100, 12),
+    (0b000001101101, 12),
+    (0b000011011010, 12),
+    (0b000011011011, 12),
+    (0b000001010100, 12),
+    (0b000001010101, 12),
+    (0b000001010110, 12),
+    (0b000001010111, 12),
+    (0b000001100100, 12),
+    (0b000001100101, 12),
+    (0b000001010010, 12),
+    (0b000001010011, 12),
+    (0b000000100100, 12),
+    (0b000000110111, 12),
+    (0b000000111000, 12),
+    (0b000000100111, 12),
+    (0b000000101000, 12),
+    (0b000001011000, 12),
+    (0b000001011001, 12),
+    (0b000000101011, 12),
+    (0b000000101100, 12),
+    (0b000001011010, 12),
+    (0b000001100110, 12),
+    (0b000001100111, 12),
+];
+
+/// Black make-up codes for runs 64, 128, ..., 1728.
+const BLACK_MAKEUP: [(u16, u8); 27] = [
+    (0b0000001111, 10),
+    (0b000011001000, 12),
+    (0b000011001001, 12),
+    (0b000001011011, 12),
+    (0b000000110011, 12),
+    (0b000000110100, 12),
+    (0b000000110101, 12),
+    (0b0000001101100, 13),
+    (0b0000001101101, 13),
+    (0b0000001001010, 13),
+    (0b0000001001011, 13),
+    (0b0000001001100, 13),
+    (0b0000001001101, 13),
+    (0b0000001110010, 13),
+    (0b0000001110011, 13),
+    (0b0000001110100, 13),
+    (0b0000001110101, 13),
+    (0b0000001110110, 13),
+    (0b0000001110111, 13),
+    (0b0000001010010, 13),
+    (0b0000001010011, 13),
+    (0b0000001010100, 13),
+    (0b0000001010101, 13),
+    (0b0000001011010, 13),
+    (0b0000001011011, 13),
+    (0b0000001100100, 13),
+    (0b0000001100101, 13),
+];
+
+/// Make-up codes shared by both colors for runs 1792, 1856, ..., 2560.
+const EXTENDED_MAKEUP: [(u16, u8); 13] = [
+    (0b00000001000, 11),
+    (0b00000001100, 11),
+    (0b00000001101, 11),
+    (0b000000010010, 12),
+    (0b000000010011, 12),
+    (0b000000010100, 12),
+    (0b000000010101, 12),
+    (0b000000010110, 12),
+    (0b000000010111, 12),
+    (0b000000011100, 12),
+    (0b000000011101, 12),
+    (0b000000011110, 12),
+    (0b000000011111, 12),
+];
+
+const PASS: (u16, u8) = (0b0001, 4);
+const HORIZONTAL: (u16, u8) = (0b001, 3);
+/// Vertical mode codes for `a1 - b1` = -3..=3.
+const VERTICAL: [(u16, u8); 7] = [
+    (0b0000010, 7),
+    (0b000010, 6),
+    (0b010, 3),
+    (0b1, 1),
+    (0b011, 3),
+    (0b000011, 6),
+    (0b0000011, 7),
+];
+
+/// Longest run a single make-up code covers.
+const MAX_MAKEUP: usize = 2560;
+
+/// MSB-first bit packer; the last byte is padded with zeros.
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, (code, len): (u16, u8)) {
+        self.acc = (self.acc << len) | code as u32;
+        self.nbits += len as u32;
+        while self.nbits >= 8 {
+            self.nbits -= 8;
+            self.out.push((self.acc >> self.nbits) as u8);
+        }
+        self.acc &= (1 << self.nbits) - 1;
+    }
+
+    fn put_run(&mut self, mut run: usize, black: bool) {
+        let (terminating, makeup) = if black {
+            (&BLACK_TERMINATING, &BLACK_MAKEUP)
+        } else {
+            (&WHITE_TERMINATING, &WHITE_MAKEUP)
+        };
+        while run > MAX_MAKEUP {
+            self.put(EXTENDED_MAKEUP[EXTENDED_MAKEUP.len() - 1]);
+            run -= MAX_MAKEUP;
+        }
+        if run >= 64 {
+            let index = run / 64 - 1;
+            self.put(match makeup.get(index) {
+                Some(&code) => code,
+                None => EXTENDED_MAKEUP[index - makeup.len()],
+            });
+        }
+        self.put(terminating[run % 64]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.out.push((self.acc << (8 - self.nbits)) as u8);
+        }
+        self.out
+    }
+}
+
+/// Positions in row `y` where the color changes, starting from an
+/// imaginary white pixel left of the row. Even entries turn black, odd
+/// entries turn white.
+fn changing_elements(image: &BitImage, y: usize) -> Vec<usize> {
+    let mut changes = Vec::new();
+    let mut black = false;
+    for x in 0..image.width {
+        if image.get_pixel_unchecked(x, y) != black {
+            changes.push(x);
+            black = !black;
+        }
+    }
+    changes
+}
+
+/// First changing element at or after `from`, or `width` if there is none.
+fn next_change(changes: &[usize], from: usize, width: usize) -> usize {
+    let k = changes.partition_point(|&x| x < from);
+    changes.get(k).copied().unwrap_or(width)
+}
+
+/// Encodes `image` (black = set pixel, row 0 at the top) as a bare G4
+/// stream without the `Smmr` header.
+pub fn encode_g4(image: &BitImage) -> Vec<u8> {
+    let width = image.width;
+    let mut writer = BitWriter {
+        out: Vec::new(),
+        acc: 0,
+        nbits: 0,
+    };
+    let mut reference: Vec<usize> = Vec::new();
+
+    for y in 0..image.height {
+        let coding = changing_elements(image, y);
+        // a0 starts on an imaginary white pixel just left of the row
+        let mut a0: Option<usize> = None;
+        let mut black = false;
+        loop {
+            let start = a0.map_or(0, |a| a + 1);
+            let a1 = next_change(&coding, start, width);
+            // b1 is the first reference change after a0 to the color
+            // opposite a0's; changes alternate, starting with black
+            let mut k = reference.partition_point(|&x| x < start);
+            if k.is_multiple_of(2) == black {
+                k += 1;
+            }
+            let b1 = reference.get(k).copied().unwrap_or(width);
+            let b2 = reference.get(k + 1).copied().unwrap_or(width);
+
+            if b2 < a1 {
+                writer.put(PASS);
+                a0 = Some(b2);
+            } else if a1.abs_diff(b1) <= 3 {
+                writer.put(VERTICAL[(a1 as isize - b1 as isize + 3) as usize]);
+                a0 = Some(a1);
+                black = !black;
+            } else {
+                let a2 = next_change(&coding, a1 + 1, width);
+                writer.put(HORIZONTAL);
+                writer.put_run(a1 - a0.unwrap_or(0), black);
+                writer.put_run(a2 - a1, !black);
+                a0 = Some(a2);
+            }
+            if a0.is_some_and(|a| a >= width) {
+                break;
+            }
+        }
+        reference = coding;
+    }
+    writer.finish()
+}
+
+/// Encodes `image` as the payload of an `Smmr` chunk.
+///
+/// Fails with `InvalidArg` for empty images or dimensions above 65535,
+/// which the header cannot store.
+pub fn encode_smmr(image: &BitImage) -> Result<Vec<u8>> {
+    let (width, height) = (image.width, image.height);
+    if width == 0 || height == 0 || width > u16::MAX as usize || height > u16::MAX as usize {
+        return Err(DjvuError::InvalidArg(format!(
+            "MMR cannot encode a {}x{} image",
+            width, height
+        )));
+    }
+    let mut out = b"MMR\0".to_vec();
+    out.extend_from_slice(&(width as u16).to_be_bytes());
+    out.extend_from_slice(&(height as u16).to_be_bytes());
+    out.extend_from_slice(&encode_g4(image));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal T.6 decoder mirroring the encoder's rules.
+    fn decode_g4(data: &[u8], width: usize, height: usize) -> Vec<Vec<bool>> {
+        let mut pos = 0;
+        let mut bit = || {
+            let b = (data[pos / 8] >> (7 - pos % 8)) & 1;
+            pos += 1;
+            b as u16
+        };
+        let read_code = |bit: &mut dyn FnMut() -> u16, table: &[(u16, u8)]| {
+            let (mut code, mut len) = (0u16, 0u8);
+            loop {
+                code = (code << 1) | bit();
+                len += 1;
+                if let Some(i) = table.iter().position(|&c| c == (code, len)) {
+                    return i;
+                }
+                assert!(len <= 13, "bad code");
+            }
+        };
+        let read_run = |bit: &mut dyn FnMut() -> u16, black: bool| {
+            let (terminating, makeup) = if black {
+                (&BLACK_TERMINATING, &BLACK_MAKEUP)
+            } else {
+                (&WHITE_TERMINATING, &WHITE_MAKEUP)
+            };
+            let table: Vec<_> = terminating
+                .iter()
+                .chain(makeup)
+                .chain(&EXTENDED_MAKEUP)
+                .copied()
+                .collect();
+            let mut run = 0;
+            loop {
+                let i = read_code(bit, &table);
+                if i < 64 {
+                    return run + i;
+                }
+                run += (i - 63) * 64;
+            }
+        };
+        let modes: Vec<_> = [PASS, HORIZONTAL].into_iter().chain(VERTICAL).collect();
+
+        let mut rows = Vec::new();
+        let mut reference: Vec<usize> = Vec::new();
+        for _ in 0..height {
+            let mut coding = Vec::new();
+            let mut a0: Option<usize> = None;
+            let mut black = false;
+            while a0.is_none_or(|a| a < width) {
+                let start = a0.map_or(0, |a| a + 1);
+                let mut k = reference.partition_point(|&x| x < start);
+                if k.is_multiple_of(2) == black {
+                    k += 1;
+                }
+                let b1 = reference.get(k).copied().unwrap_or(width);
+                let b2 = reference.get(k + 1).copied().unwrap_or(width);
+                match read_code(&mut bit, &modes) {
+                    0 => a0 = Some(b2),
+                    1 => {
+                        let a1 = a0.unwrap_or(0) + read_run(&mut bit, black);
+                        let a2 = a1 + read_run(&mut bit, !black);
+                        coding.extend([a1, a2].into_iter().filter(|&x| x < width));
+                        a0 = Some(a2);
+                    }
+                    v => {
+                        let a1 = (b1 as isize + v as isize - 5) as usize;
+                        if a1 < width {
+                            coding.push(a1);
+                        }
+                        a0 = Some(a1);
+                        black = !black;
+                    }
+                }
+            }
+            let mut row = vec![false; width];
+            for pair in coding.chunks(2) {
+                let end = pair.get(1).copied().unwrap_or(width);
+                row[pair[0]..end].fill(true);
+            }
+            rows.push(row);
+            reference = coding;
+        }
+        rows
+    }
+
+    fn rows_of(image: &BitImage) -> Vec<Vec<bool>> {
+        (0..image.height)
+            .map(|y| {
+                (0..image.width)
+                    .map(|x| image.get_pixel_unchecked(x, y))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_code_tables_are_prefix_free() {
+        let prefix_free = |codes: Vec<(u16, u8)>| {
+            for (i, &(a, la)) in codes.iter().enumerate() {
+                for &(b, lb) in &codes[i + 1..] {
+                    let n = la.min(lb);
+                    assert_ne!(a >> (la - n), b >> (lb - n), "{a:b} vs {b:b}");
+                }
+            }
+        };
+        for (terminating, makeup) in [
+            (&WHITE_TERMINATING, &WHITE_MAKEUP),
+            (&BLACK_TERMINATING, &BLACK_MAKEUP),
+        ] {
+            prefix_free(
+                terminating
+                    .iter()
+                    .chain(makeup)
+                    .chain(&EXTENDED_MAKEUP)
+                    .copied()
+                    .collect(),
+            );
+        }
+        prefix_free([PASS, HORIZONTAL].into_iter().chain(VERTICAL).collect());
+    }
+
+    #[test]
+    fn test_reference_scanlines() {
+        // Two 8-pixel rows, each black at x = 2..5. The first row has no
+        // reference changes nearby, so it is H(white 2, black 3) then V0;
+        // the second matches the first, so it is three V0 codes:
+        // 001 0111 10 1 | 1 1 1, padded with zeros.
+        let mut image = BitImage::new(8, 2).unwrap();
+        for y in 0..2 {
+            for x in 2..5 {
+                image.set_usize(x, y, true);
+            }
+        }
+        let smmr = encode_smmr(&image).unwrap();
+        assert_eq!(smmr, b"MMR\0\x00\x08\x00\x02\x2F\x78");
+        assert_eq!(decode_g4(&smmr[8..], 8, 2), rows_of(&image));
+    }
+
+    #[test]
+    fn test_round_trip_with_long_runs() {
+        let (w, h) = (3000, 24);
+        let mut image = BitImage::new(w as u32, h as u32).unwrap();
+        for y in 0..h {
+            for x in 0..w {
+                let stripe = (100..2900).contains(&x) && y % 5 == 0;
+                let glyph = ((x / 7 + y / 3) * 31 + x * y) % 11 == 0 && x < 400;
+                image.set_usize(x, y, stripe || glyph || x == w - 1);
+            }
+        }
+        let g4 = encode_g4(&image);
+        assert_eq!(decode_g4(&g4, w, h), rows_of(&image));
+        // Mostly white rows cost a few bits each
+        assert!(g4.len() < w * h / 8 / 4);
+
+        // Images Smmr can't describe can't be built in the first place
+        assert!(BitImage::new(0, 4).is_err());
+        assert!(BitImage::new(70_000, 1).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 pub mod iw44;
 // pub mod iw44_ffi;  // FFI-based IW44 encoder - disabled for now
 pub mod jb2;
+pub mod mmr;
 pub mod zc;
 
 // Re-export commonly used encoding functionality
@@ -1,6 +1,5 @@
 use super::ZpEncoderCursor;
-use super::table::DEFAULT_ZP_TABLE;
-use super::zcodec::{BitContext, ZCodecError};
+use super::zcodec::{BitContext, ZCodecError, zp_table};
 use std::ffi::c_void;
 use std::io::{Cursor, Write};
 use std::marker::PhantomData;
@@ -116,7 +115,7 @@ pub struct ZEncoder<W: Write> {
 }
 
 impl ZEncoder<Cursor<Vec<u8>>> {
-    pub fn new(writer: Cursor<Vec<u8>>, _djvu_compat: bool) -> Result<Self, ZCodecError> {
+    pub fn new(writer: Cursor<Vec<u8>>, djvu_compat: bool) -> Result<Self, ZCodecError> {
         // Reset debug counters for each new encoder
         unsafe {
             BYTES_WRITTEN = 0;
@@ -130,17 +129,13 @@ impl ZEncoder<Cursor<Vec<u8>>> {
         let mut state: ZpAsmState = unsafe { mem::zeroed() };
         unsafe { zpcodec_einit(&mut state as *mut ZpAsmState) };
 
-        // Fill adaptation table from DEFAULT_ZP_TABLE
-        for i in 0..256 {
-            let e = if i < DEFAULT_ZP_TABLE.len() {
-                DEFAULT_ZP_TABLE[i]
-            } else {
-                DEFAULT_ZP_TABLE[DEFAULT_ZP_TABLE.len() - 1]
-            };
+        // Fill adaptation table, patched like the Rust encoder's unless
+        // `djvu_compat` is set
+        for (i, e) in zp_table(djvu_compat).iter().enumerate() {
             state.p[i] = e.p as u32;
             state.m[i] = e.m as u32;
-            state.up[i] = e.up as u8;
-            state.dn[i] = e.dn as u8;
+            state.up[i] = e.up;
+            state.dn[i] = e.dn;
         }
 
         // Hook bytestream and enable emission
@@ -2,13 +2,16 @@
 pub mod asm;
 pub mod table;
 pub mod zcodec;
+pub mod zdecoder;
 
 // Keep BitContext and errors/types from the Rust implementation for a unified API
 pub use zcodec::BitContext;
 pub use zcodec::ZCodecError;
+pub use zcodec::{ZpTable, shared_zp_table};
 
 // Always export the Rust ZEncoder by default
 pub use zcodec::ZEncoder;
+pub use zdecoder::ZDecoder;
 
 use std::io::Cursor;
 
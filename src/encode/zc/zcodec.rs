@@ -2,6 +2,7 @@ use super::ZpEncoderCursor;
 use super::table::{DEFAULT_ZP_TABLE, ZpTableEntry};
 use std::io::Cursor;
 use std::io::Write;
+use std::sync::{Arc, OnceLock};
 use thiserror::Error;
 
 /// A single byte representing the statistical context for encoding a bit.
@@ -18,6 +19,8 @@ pub enum ZCodecError {
     Io(#[from] std::io::Error),
     #[error("Attempted to encode after the stream was finished")]
     Finished,
+    #[error("Unexpected end of ZP-coded data")]
+    UnexpectedEof,
 }
 
 impl From<ZCodecError> for std::io::Error {
@@ -27,8 +30,57 @@ impl From<ZCodecError> for std::io::Error {
             ZCodecError::Finished => {
                 std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
             }
+            ZCodecError::UnexpectedEof => {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, err.to_string())
+            }
+        }
+    }
+}
+
+/// Builds the ZP-Coder state table, applying DjVuLibre's patch unless
+/// `djvu_compat` is set.
+pub(crate) fn zp_table(djvu_compat: bool) -> [ZpTableEntry; 256] {
+    // Create a 256-entry table, starting with the default 251 entries
+    let mut table = [ZpTableEntry {
+        p: 0,
+        m: 0,
+        up: 0,
+        dn: 0,
+    }; 256];
+
+    // Copy the default table entries
+    for (i, &entry) in DEFAULT_ZP_TABLE.iter().enumerate() {
+        table[i] = entry;
+    }
+
+    // Patch table when djvu_compat is false
+    if !djvu_compat {
+        for j in 0..256 {
+            let mut a = 0x10000 - table[j].p as u32;
+            while a >= 0x8000 {
+                a = (a << 1) & 0xffff;
+            }
+            if table[j].m > 0 && a + table[j].p as u32 >= 0x8000 && a >= table[j].m as u32 {
+                let x = DEFAULT_ZP_TABLE[j].dn;
+                let y = DEFAULT_ZP_TABLE[x as usize].dn;
+                table[j].dn = y;
+            }
         }
     }
+
+    table
+}
+
+/// A built ZP-Coder state table, shared between encoders by reference count.
+pub type ZpTable = Arc<[ZpTableEntry; 256]>;
+
+/// Returns the table [`zp_table`] builds for `djvu_compat`, building each
+/// variant only once per process.
+pub fn shared_zp_table(djvu_compat: bool) -> ZpTable {
+    static COMPAT: OnceLock<ZpTable> = OnceLock::new();
+    static PATCHED: OnceLock<ZpTable> = OnceLock::new();
+    let cell = if djvu_compat { &COMPAT } else { &PATCHED };
+    Arc::clone(cell.get_or_init(|| Arc::new(zp_table(djvu_compat))))
 }
 
 /// An adaptive quasi-arithmetic encoder implementing the ZP-Coder algorithm.
@@ -43,52 +95,79 @@ pub struct ZEncoder<W: Write> {
     scount: i32, // bit counter in current byte
     delay: i32,  // delay counter
     finished: bool,
-    table: [ZpTableEntry; 256], // mutable table for patching
+    table: ZpTable,
 }
 
 impl<W: Write> ZEncoder<W> {
     /// Creates a new ZP-Coder encoder that writes to the given writer.
+    ///
+    /// With `djvu_compat` the state table is used exactly as DjVu decoders
+    /// expect it; every chunk written into a DjVu file (BZZ, JB2, IW44)
+    /// needs it. Without it the table gets DjVuLibre's adaptation patch,
+    /// which only a [`ZDecoder`](super::ZDecoder) created with the same
+    /// setting can read, so it suits private streams only.
     pub fn new(writer: W, djvu_compat: bool) -> Result<Self, ZCodecError> {
-        // Create a 256-entry table, starting with the default 251 entries
-        let mut table = [ZpTableEntry {
-            p: 0,
-            m: 0,
-            up: 0,
-            dn: 0,
-        }; 256];
-
-        // Copy the default table entries
-        for (i, &entry) in DEFAULT_ZP_TABLE.iter().enumerate() {
-            table[i] = entry;
-        }
-
-        // Patch table when djvu_compat is false
-        if !djvu_compat {
-            for j in 0..256 {
-                let mut a = 0x10000 - table[j].p as u32;
-                while a >= 0x8000 {
-                    a = (a << 1) & 0xffff;
-                }
-                if table[j].m > 0 && a + table[j].p as u32 >= 0x8000 && a >= table[j].m as u32 {
-                    let x = DEFAULT_ZP_TABLE[j].dn;
-                    let y = DEFAULT_ZP_TABLE[x as usize].dn;
-                    table[j].dn = y;
-                }
-            }
-        }
+        Ok(Self::with_table(writer, shared_zp_table(djvu_compat)))
+    }
 
-        Ok(ZEncoder {
+    /// Creates an encoder using an already built state table, such as the
+    /// one returned by [`Self::table`].
+    pub fn with_table(writer: W, table: ZpTable) -> Self {
+        let mut encoder = ZEncoder {
             writer: Some(writer),
-            a: 0,             // Initialize to 0 as per DjVuLibre
-            subend: 0,        // Subinterval end starts at 0
-            buffer: 0xffffff, // 3-byte buffer initialized to all 1s
-            nrun: 0,          // Run counter starts at 0
-            byte: 0,          // Current byte starts at 0
-            scount: 0,        // Bit count starts at 0
-            delay: 25,        // Delay starts at 25
+            a: 0,
+            subend: 0,
+            buffer: 0,
+            nrun: 0,
+            byte: 0,
+            scount: 0,
+            delay: 0,
             finished: false,
             table,
-        })
+        };
+        encoder.init_registers();
+        encoder
+    }
+
+    /// Puts the coder registers in their start-of-stream state.
+    fn init_registers(&mut self) {
+        self.a = 0; // Initialize to 0 as per DjVuLibre
+        self.subend = 0; // Subinterval end starts at 0
+        self.buffer = 0xffffff; // 3-byte buffer initialized to all 1s
+        self.nrun = 0; // Run counter starts at 0
+        self.byte = 0; // Current byte starts at 0
+        self.scount = 0; // Bit count starts at 0
+        self.delay = 25; // Delay starts at 25
+        self.finished = false;
+    }
+
+    /// The state table this encoder codes with.
+    pub fn table(&self) -> &ZpTable {
+        &self.table
+    }
+
+    /// Flushes the bytes coded so far to the writer without ending the
+    /// stream. Up to a few bytes of pending code stay buffered until
+    /// [`Self::finish`] or [`Self::reset`], so the output is not yet
+    /// decodable.
+    pub fn flush(&mut self) -> Result<(), ZCodecError> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the current stream and starts a new one on `writer`, keeping
+    /// the state table. Returns the writer holding the finished stream,
+    /// exactly as [`Self::finish`] would have. Contexts belong to the
+    /// caller and must be reset separately.
+    pub fn reset(&mut self, writer: W) -> Result<W, ZCodecError> {
+        if !self.finished {
+            self.eflush()?;
+        }
+        let finished = self.writer.replace(writer).ok_or(ZCodecError::Finished)?;
+        self.init_registers();
+        Ok(finished)
     }
 
     /// Encodes a single bit using the provided statistical context.
@@ -114,6 +193,56 @@ impl<W: Write> ZEncoder<W> {
         Ok(())
     }
 
+    /// Encodes the low `nbits` of `value`, most significant bit first, all
+    /// through the same adaptive context.
+    ///
+    /// The bit order is the one used by the hand-written loops elsewhere
+    /// (`(value >> i) & 1` for `i` from `nbits - 1` down to 0). Encoding zero
+    /// bits is a no-op.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `nbits` is greater than 32.
+    pub fn encode_uint(
+        &mut self,
+        value: u32,
+        nbits: u8,
+        ctx: &mut BitContext,
+    ) -> Result<(), ZCodecError> {
+        assert!(nbits <= 32, "cannot encode {} bits from a u32", nbits);
+        for i in (0..nbits).rev() {
+            self.encode((value >> i) & 1 != 0, ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Signed counterpart of [`encode_uint`](Self::encode_uint): encodes
+    /// `value` as an `nbits`-wide two's complement integer, MSB first.
+    ///
+    /// `value` must fit in `nbits` signed bits; higher bits are discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `nbits` is greater than 32.
+    pub fn encode_int(
+        &mut self,
+        value: i32,
+        nbits: u8,
+        ctx: &mut BitContext,
+    ) -> Result<(), ZCodecError> {
+        debug_assert!(
+            match nbits {
+                0 => value == 0,
+                1..=31 => (value << (32 - nbits)) >> (32 - nbits) == value,
+                _ => true,
+            },
+            "{} does not fit in {} signed bits",
+            value,
+            nbits
+        );
+        self.encode_uint(value as u32, nbits, ctx)
+    }
+
     /// Encodes a bit without compression (pass-thru encoder).
     ///
     /// Matches DjVuLibre `ZPCodec::encoder(int bit)`:
@@ -245,11 +374,13 @@ impl<W: Write> ZEncoder<W> {
             self.byte = (self.byte << 1) | (bit & 1);
             self.scount += 1;
             if self.scount == 8 {
+                // Reset before writing so a failed write leaves the coder
+                // in a state that later calls (and Drop) can still use
+                let byte = std::mem::take(&mut self.byte);
+                self.scount = 0;
                 if let Some(ref mut writer) = self.writer {
-                    writer.write_all(&[self.byte])?;
+                    writer.write_all(&[byte])?;
                 }
-                self.scount = 0;
-                self.byte = 0;
             }
         }
         Ok(())
@@ -445,6 +576,7 @@ impl<W: Write> Drop for ZEncoder<W> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encode::zc::ZDecoder;
     use std::io::Cursor;
 
     #[test]
@@ -463,6 +595,138 @@ mod tests {
         // Update expected output after verifying against C++ output
     }
 
+    #[test]
+    fn test_encode_uint_round_trip() {
+        let unsigned: &[(u32, u8)] = &[
+            (0, 0),
+            (1, 1),
+            (0, 1),
+            (5, 3),
+            (0xab, 8),
+            (0x1234, 16),
+            (0x00ff_00ff, 24),
+            (u32::MAX, 32),
+            (0x8000_0001, 32),
+            (0, 32),
+        ];
+        let signed: &[(i32, u8)] = &[
+            (0, 0),
+            (-1, 1),
+            (-4, 3),
+            (3, 3),
+            (-300, 12),
+            (i32::MIN, 32),
+            (i32::MAX, 32),
+        ];
+
+        for djvu_compat in [false, true] {
+            let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), djvu_compat).unwrap();
+            let mut ctx = 0;
+            for &(value, nbits) in unsigned {
+                encoder.encode_uint(value, nbits, &mut ctx).unwrap();
+            }
+            for &(value, nbits) in signed {
+                encoder.encode_int(value, nbits, &mut ctx).unwrap();
+            }
+            let data = encoder.finish().unwrap().into_inner();
+
+            let mut decoder = ZDecoder::new(&data, djvu_compat).unwrap();
+            let mut ctx = 0;
+            for &(value, nbits) in unsigned {
+                assert_eq!(decoder.decode_uint(nbits, &mut ctx).unwrap(), value);
+            }
+            for &(value, nbits) in signed {
+                assert_eq!(decoder.decode_int(nbits, &mut ctx).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compat_flag_changes_bitstream() {
+        // A skewed source drives the context deep into the table, where the
+        // patched states adapt differently after an LPS
+        let bits: Vec<bool> = (0..4000u32)
+            .map(|i| i.wrapping_mul(2_654_435_761) >> 27 == 0)
+            .collect();
+        let encode = |djvu_compat| {
+            let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), djvu_compat).unwrap();
+            let mut ctx = 0;
+            for &bit in &bits {
+                encoder.encode(bit, &mut ctx).unwrap();
+            }
+            encoder.finish().unwrap().into_inner()
+        };
+        let (compat, patched) = (encode(true), encode(false));
+        assert_ne!(compat, patched);
+
+        for (data, djvu_compat) in [(&compat, true), (&patched, false)] {
+            let mut decoder = ZDecoder::new(data, djvu_compat).unwrap();
+            let mut ctx = 0;
+            for &bit in &bits {
+                assert_eq!(decoder.decode(&mut ctx).unwrap(), bit);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_uint_matches_bitwise_encoding() {
+        let mut by_bits = ZEncoder::new(Cursor::new(Vec::new()), false).unwrap();
+        let mut by_uint = ZEncoder::new(Cursor::new(Vec::new()), false).unwrap();
+        let (mut ctx_a, mut ctx_b) = (0, 0);
+        for value in [0x2du32, 0x3ff, 0x155] {
+            for i in (0..10).rev() {
+                by_bits.encode((value >> i) & 1 != 0, &mut ctx_a).unwrap();
+            }
+            by_uint.encode_uint(value, 10, &mut ctx_b).unwrap();
+        }
+        assert_eq!(ctx_a, ctx_b);
+        assert_eq!(
+            by_bits.finish().unwrap().into_inner(),
+            by_uint.finish().unwrap().into_inner()
+        );
+    }
+
+    #[test]
+    fn test_reset_encoder_matches_fresh_encoders() {
+        let streams: [Vec<bool>; 2] = [
+            (0..600u32).map(|i| i % 7 == 0).collect(),
+            (0..900u32)
+                .map(|i| i.wrapping_mul(2_654_435_761) >> 29 == 0)
+                .collect(),
+        ];
+        let code = |encoder: &mut ZEncoder<Cursor<Vec<u8>>>, bits: &[bool]| {
+            let mut ctx = 0;
+            for &bit in bits {
+                encoder.encode(bit, &mut ctx).unwrap();
+            }
+        };
+
+        for djvu_compat in [false, true] {
+            let fresh: Vec<Vec<u8>> = streams
+                .iter()
+                .map(|bits| {
+                    let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), djvu_compat).unwrap();
+                    code(&mut encoder, bits);
+                    encoder.finish().unwrap().into_inner()
+                })
+                .collect();
+
+            let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), djvu_compat).unwrap();
+            code(&mut encoder, &streams[0]);
+            encoder.flush().unwrap();
+            let first = encoder.reset(Cursor::new(Vec::new())).unwrap();
+            code(&mut encoder, &streams[1]);
+            let second = encoder.finish().unwrap();
+            assert_eq!(first.into_inner(), fresh[0]);
+            assert_eq!(second.into_inner(), fresh[1]);
+        }
+
+        // Encoders share one table per setting
+        let a = ZEncoder::new(Vec::new(), true).unwrap();
+        let b = ZEncoder::new(Vec::new(), true).unwrap();
+        assert!(Arc::ptr_eq(a.table(), b.table()));
+    }
+
     #[test]
     fn test_encode_highly_probable_sequence() {
         let mut encoder = ZEncoder::new(Cursor::new(Vec::new()), false).unwrap();
@@ -0,0 +1,141 @@
+//! ZP-Coder decoder, the inverse of [`ZEncoder`](super::ZEncoder).
+//!
+//! A port of DjVuLibre's `ZPCodec` decoding path. The encoder crate only needs
+//! it to check its own output (validation and round-trip tests).
+
+use super::table::ZpTableEntry;
+use super::zcodec::{BitContext, ZCodecError, zp_table};
+
+/// An adaptive ZP-Coder decoder reading from a byte slice.
+pub struct ZDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    table: [ZpTableEntry; 256],
+    a: u32,
+    code: u32,
+    fence: u32,
+    buffer: u32,
+    scount: i32,
+    delay: i32,
+}
+
+impl<'a> ZDecoder<'a> {
+    /// Creates a decoder over `data`; `djvu_compat` must match the encoder's.
+    pub fn new(data: &'a [u8], djvu_compat: bool) -> Result<Self, ZCodecError> {
+        let mut dec = ZDecoder {
+            data,
+            pos: 0,
+            table: zp_table(djvu_compat),
+            a: 0,
+            code: 0,
+            fence: 0,
+            buffer: 0,
+            scount: 0,
+            delay: 25,
+        };
+        dec.code = (dec.next_byte() << 8) | dec.next_byte();
+        dec.preload()?;
+        dec.fence = dec.code.min(0x7fff);
+        Ok(dec)
+    }
+
+    fn next_byte(&mut self) -> u32 {
+        // Past the end the stream reads as 0xff, like DjVuLibre
+        let byte = self.data.get(self.pos).copied().unwrap_or(0xff);
+        self.pos += 1;
+        byte as u32
+    }
+
+    fn preload(&mut self) -> Result<(), ZCodecError> {
+        while self.scount <= 24 {
+            if self.pos >= self.data.len() {
+                self.delay -= 1;
+                if self.delay < 1 {
+                    return Err(ZCodecError::UnexpectedEof);
+                }
+            }
+            self.buffer = (self.buffer << 8) | self.next_byte();
+            self.scount += 8;
+        }
+        Ok(())
+    }
+
+    fn shift_in(&mut self, shift: u32) -> Result<(), ZCodecError> {
+        self.scount -= shift as i32;
+        self.a = (self.a << shift) & 0xffff;
+        self.code =
+            ((self.code << shift) & 0xffff) | ((self.buffer >> self.scount) & ((1 << shift) - 1));
+        if self.scount < 16 {
+            self.preload()?;
+        }
+        self.fence = self.code.min(0x7fff);
+        Ok(())
+    }
+
+    /// Decodes a bit with fixed probability `z`, without a context.
+    fn decode_simple(&mut self, z: u32) -> Result<bool, ZCodecError> {
+        if z > self.code {
+            let z = 0x10000 - z;
+            self.a += z;
+            self.code += z;
+            let shift = (!(self.a as u16)).leading_zeros();
+            self.shift_in(shift)?;
+            Ok(true)
+        } else {
+            self.a = z;
+            self.shift_in(1)?;
+            Ok(false)
+        }
+    }
+
+    /// Decodes a bit using (and adapting) the statistical context `ctx`.
+    pub fn decode(&mut self, ctx: &mut BitContext) -> Result<bool, ZCodecError> {
+        let entry = self.table[*ctx as usize];
+        let mut z = self.a + entry.p as u32;
+        let mps = *ctx & 1 != 0;
+        if z <= self.fence {
+            self.a = z;
+            return Ok(mps);
+        }
+        z = z.min(0x6000 + ((z + self.a) >> 2));
+        if z > self.code {
+            z = 0x10000 - z;
+            self.a += z;
+            self.code += z;
+            *ctx = entry.dn;
+            let shift = (!(self.a as u16)).leading_zeros();
+            self.shift_in(shift)?;
+            Ok(!mps)
+        } else {
+            if self.a >= entry.m as u32 {
+                *ctx = entry.up;
+            }
+            self.a = z;
+            self.shift_in(1)?;
+            Ok(mps)
+        }
+    }
+
+    /// Decodes a pass-through bit written by [`ZEncoder::encode_raw`](super::ZEncoder::encode_raw).
+    pub fn decode_raw(&mut self) -> Result<bool, ZCodecError> {
+        self.decode_simple(0x8000 + ((self.a + self.a + self.a) >> 3))
+    }
+
+    /// Inverse of [`ZEncoder::encode_uint`](super::ZEncoder::encode_uint).
+    pub fn decode_uint(&mut self, nbits: u8, ctx: &mut BitContext) -> Result<u32, ZCodecError> {
+        let mut value = 0u32;
+        for _ in 0..nbits {
+            value = (value << 1) | self.decode(ctx)? as u32;
+        }
+        Ok(value)
+    }
+
+    /// Inverse of [`ZEncoder::encode_int`](super::ZEncoder::encode_int).
+    pub fn decode_int(&mut self, nbits: u8, ctx: &mut BitContext) -> Result<i32, ZCodecError> {
+        let raw = self.decode_uint(nbits, ctx)? as i32;
+        Ok(match nbits {
+            1..=31 => (raw << (32 - nbits)) >> (32 - nbits),
+            _ => raw,
+        })
+    }
+}
@@ -5,6 +5,7 @@
 
 use crate::encode::zc::BitContext;
 // IMPORTANT: Always use the Rust ZEncoder for BZZ to avoid FFI writer constraints
+use crate::encode::zc::ZDecoder;
 use crate::encode::zc::zcodec::ZEncoder as RustZEncoder;
 use crate::utils::error::{DjvuError, Result};
 use std::io::Write;
@@ -375,13 +376,235 @@ impl<W: Write> Drop for BsEncoder<W> {
 /// * `block_size_k` - Block size in kilobytes (clamped between 10KB and 4MB)
 ///
 /// # Returns
-/// A `Result` containing the compressed data as a `Vec<u8>`
+/// A `Result` containing the compressed data as a `Vec<u8>`. Failures are
+/// reported as [`DjvuError::Compression`] with the input length.
 pub fn bzz_compress(data: &[u8], block_size_k: usize) -> Result<Vec<u8>> {
     let mut compressed_data = Vec::new();
-    {
-        let mut encoder = BsEncoder::new(&mut compressed_data, block_size_k)?;
-        encoder.write_all(data).map_err(|e| DjvuError::Io(e))?;
-        encoder.flush().map_err(|e| DjvuError::Io(e))?;
-    }
+    bzz_compress_to(&mut compressed_data, data, block_size_k)?;
     Ok(compressed_data)
 }
+
+/// Writes the BZZ stream for `data` to `writer`; the end-of-stream marker
+/// follows when the encoder is dropped on return.
+fn bzz_compress_to<W: Write>(writer: W, data: &[u8], block_size_k: usize) -> Result<()> {
+    let failed = |e: DjvuError| DjvuError::compression("BZZ", data.len(), e);
+    let mut encoder = BsEncoder::new(writer, block_size_k).map_err(failed)?;
+    encoder
+        .write_all(data)
+        .and_then(|()| encoder.flush())
+        .map_err(|e| failed(DjvuError::Io(e)))
+}
+
+/// Decompresses a BZZ stream produced by [`bzz_compress`].
+///
+/// Mirrors `BsEncoder` step by step (ZP/MTF decoding, then the inverse
+/// Burrows-Wheeler transform) and reports malformed input as
+/// [`DjvuError::Stream`] instead of panicking.
+pub fn bzz_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut zp = ZDecoder::new(data, true)?;
+    let mut output = Vec::new();
+
+    loop {
+        let size = decode_raw(&mut zp, 24)? as usize;
+        if size == 0 {
+            return Ok(output);
+        }
+        if size > MAX_BLOCK_SIZE + OVERFLOW {
+            return Err(DjvuError::Stream(format!(
+                "BZZ block size {size} exceeds the maximum of {MAX_BLOCK_SIZE}"
+            )));
+        }
+
+        let mut fshift = 0u8;
+        if zp.decode_raw()? {
+            fshift += 1;
+            if zp.decode_raw()? {
+                fshift += 1;
+            }
+        }
+
+        // Move-to-front decoding; like the encoder, every block starts with
+        // fresh contexts
+        let mut contexts: Vec<BitContext> = vec![0; 300];
+        let mut mtf: Vec<u8> = (0..=255).collect();
+        let mut freq = [0u32; FREQMAX];
+        let mut fadd = 4u32;
+        let mut mtfno = 3usize;
+        let mut markerpos = None;
+        let mut block = vec![0u8; size];
+        for (i, slot) in block.iter_mut().enumerate() {
+            let ctxid = (CTXIDS - 1).min(mtfno);
+            mtfno = if zp.decode(&mut contexts[ctxid])? {
+                0
+            } else if zp.decode(&mut contexts[CTXIDS + ctxid])? {
+                1
+            } else {
+                // Ranges [2,4), [4,8), ... [128,256), each behind a flag context
+                // followed by the binary contexts for the offset in the range
+                let mut cx = 2 * CTXIDS;
+                let mut found = None;
+                for bits in 1..=7u8 {
+                    if zp.decode(&mut contexts[cx])? {
+                        let offset = decode_binary(&mut zp, &mut contexts[cx + 1..], bits)?;
+                        found = Some((1usize << bits) + offset);
+                        break;
+                    }
+                    cx += 1 + (1 << bits) - 1;
+                }
+                match found {
+                    Some(n) => n,
+                    None => {
+                        markerpos = Some(i);
+                        mtfno = 256;
+                        continue;
+                    }
+                }
+            };
+
+            let c = mtf[mtfno];
+            *slot = c;
+
+            fadd += fadd >> fshift;
+            if fadd > 0x10000000 {
+                fadd >>= 24;
+                for f in freq.iter_mut() {
+                    *f >>= 24;
+                }
+            }
+            let mut fc = fadd;
+            if mtfno < FREQMAX {
+                fc += freq[mtfno];
+            }
+            let mut k = mtfno;
+            while k >= FREQMAX {
+                mtf[k] = mtf[k - 1];
+                k -= 1;
+            }
+            while k > 0 && fc >= freq[k - 1] {
+                mtf[k] = mtf[k - 1];
+                freq[k] = freq[k - 1];
+                k -= 1;
+            }
+            mtf[k] = c;
+            freq[k] = fc;
+        }
+
+        // Inverse Burrows-Wheeler transform
+        let markerpos = match markerpos {
+            Some(pos) if pos >= 1 && pos < size => pos,
+            _ => {
+                return Err(DjvuError::Stream(
+                    "BZZ block has no valid end-of-block marker".to_string(),
+                ));
+            }
+        };
+        let mut count = [0u32; 256];
+        let mut posn = vec![0u32; size];
+        for (i, &c) in block.iter().enumerate() {
+            if i != markerpos {
+                posn[i] = ((c as u32) << 24) | (count[c as usize] & 0xff_ffff);
+                count[c as usize] += 1;
+            }
+        }
+        let mut last = 1;
+        for n in count.iter_mut() {
+            let tmp = *n;
+            *n = last;
+            last += tmp;
+        }
+
+        let mut decoded = vec![0u8; size - 1];
+        let mut i = 0usize;
+        for slot in decoded.iter_mut().rev() {
+            let n = posn[i];
+            let c = (n >> 24) as u8;
+            *slot = c;
+            i = (count[c as usize] + (n & 0xff_ffff)) as usize;
+            if i >= size {
+                return Err(DjvuError::Stream("Corrupted BZZ block".to_string()));
+            }
+        }
+        if i != markerpos {
+            return Err(DjvuError::Stream("Corrupted BZZ block".to_string()));
+        }
+        output.extend_from_slice(&decoded);
+    }
+}
+
+/// Inverse of `BsEncoder::encode_raw`.
+fn decode_raw(zp: &mut ZDecoder, bits: u8) -> Result<u32> {
+    let m = 1u32 << bits;
+    let mut n = 1u32;
+    while n < m {
+        n = (n << 1) | zp.decode_raw()? as u32;
+    }
+    Ok(n - m)
+}
+
+/// Inverse of `BsEncoder::encode_binary`.
+fn decode_binary(zp: &mut ZDecoder, ctx: &mut [BitContext], bits: u8) -> Result<usize> {
+    let m = 1u32 << bits;
+    let mut n = 1u32;
+    while n < m {
+        n = (n << 1) | zp.decode(&mut ctx[(n - 1) as usize])? as u32;
+    }
+    Ok((n - m) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bzz_round_trip() {
+        // `noise` spans two 10 KB blocks
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(40);
+        let noise: Vec<u8> = (0..10_500u32).map(|i| (i * 7919 % 251) as u8).collect();
+        for input in [&b""[..], b"a", b"\x00\x00\x00", &text, &noise] {
+            let compressed = bzz_compress(input, 10).unwrap();
+            assert_eq!(bzz_decompress(&compressed).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_bzz_failure_reports_input_length() {
+        /// Accepts a few bytes, then fails like a full disk
+        struct FailingWriter(usize);
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0 < buf.len() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "full"));
+                }
+                self.0 -= buf.len();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let noise: Vec<u8> = (0..12_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let err = bzz_compress_to(FailingWriter(16), &noise, 10).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                DjvuError::Compression {
+                    codec: "BZZ",
+                    input_len: 12_000,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        assert_eq!(err.kind(), crate::utils::error::ErrorKind::Encoding);
+        assert!(
+            err.to_string()
+                .starts_with("BZZ compression of 12000 bytes failed")
+        );
+    }
+
+    #[test]
+    fn test_bzz_decompress_rejects_garbage() {
+        assert!(bzz_decompress(&[0x12, 0x34, 0x56, 0x78, 0x9a]).is_err());
+    }
+}
@@ -3,8 +3,9 @@
 //! A byte stream abstraction for reading and writing DjVu data structures.
 //! This provides big-endian byte order operations needed for DjVu format.
 
-use crate::utils::error::{DjvuError, Result};
-use bytemuck::{Pod, Zeroable, cast_slice};
+use crate::utils::error::Result;
+use crate::utils::write_ext::{ReadBytesExtU24, WriteBytesExtU24};
+use bytemuck::{Pod, Zeroable};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
 
@@ -19,9 +20,7 @@ pub trait ByteStream: Read + Write {
     }
 
     fn read_u24(&mut self) -> Result<u32> {
-        let mut bytes = [0u8; 3];
-        self.read_exact(&mut bytes)?;
-        Ok(((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | (bytes[2] as u32))
+        Ok(ReadBytesExtU24::read_u24::<BigEndian>(self)?)
     }
 
     fn read_u32(&mut self) -> Result<u32> {
@@ -37,20 +36,7 @@ pub trait ByteStream: Read + Write {
     }
 
     fn write_u24(&mut self, value: u32) -> Result<()> {
-        if value > 0xFFFFFF {
-            eprintln!(
-                "ERROR: Trying to write u24 value {} which is too large (max={})",
-                value, 0xFFFFFF
-            );
-            return Err(DjvuError::InvalidArg("Value too large for u24".to_string()));
-        }
-        let bytes = [
-            ((value >> 16) & 0xFF) as u8,
-            ((value >> 8) & 0xFF) as u8,
-            (value & 0xFF) as u8,
-        ];
-        self.write_all(&bytes)?;
-        Ok(())
+        Ok(WriteBytesExtU24::write_u24::<BigEndian>(self, value)?)
     }
 
     fn write_u32(&mut self, value: u32) -> Result<()> {
@@ -62,61 +48,40 @@ pub trait ByteStream: Read + Write {
         Ok(())
     }
 
-    /// Efficiently write a slice of u16 values in big-endian format using bytemuck
+    /// Writes a slice of u16 values in big-endian format
     fn write_u16_slice(&mut self, values: &[u16]) -> Result<()> {
-        let be_values: Vec<BeU16> = values.iter().map(|&v| v.into()).collect();
-        let bytes: &[u8] = cast_slice(&be_values);
-        self.write_all(bytes)?;
-        Ok(())
+        Ok(WriteBytesExtU24::write_u16_slice::<BigEndian>(
+            self, values,
+        )?)
     }
 
-    /// Efficiently write a slice of u32 values in big-endian format using bytemuck
+    /// Writes a slice of u32 values in big-endian format
     fn write_u32_slice(&mut self, values: &[u32]) -> Result<()> {
-        let be_values: Vec<BeU32> = values.iter().map(|&v| v.into()).collect();
-        let bytes: &[u8] = cast_slice(&be_values);
-        self.write_all(bytes)?;
-        Ok(())
+        Ok(WriteBytesExtU24::write_u32_slice::<BigEndian>(
+            self, values,
+        )?)
     }
 
-    /// Efficiently write a slice of u24 values in big-endian format using bytemuck
+    /// Writes a slice of u24 values in big-endian format
     fn write_u24_slice(&mut self, values: &[u32]) -> Result<()> {
-        for &value in values {
-            if value > 0xFFFFFF {
-                eprintln!(
-                    "ERROR: Trying to write u24 slice value {} which is too large (max={})",
-                    value, 0xFFFFFF
-                );
-                return Err(DjvuError::InvalidArg("Value too large for u24".to_string()));
-            }
-        }
-        let be_values: Vec<BeU24> = values.iter().map(|&v| v.into()).collect();
-        let bytes: &[u8] = cast_slice(&be_values);
-        self.write_all(bytes)?;
-        Ok(())
+        Ok(WriteBytesExtU24::write_u24_slice::<BigEndian>(
+            self, values,
+        )?)
     }
 
-    /// Efficiently read a slice of u16 values in big-endian format using bytemuck
+    /// Reads a slice of u16 values in big-endian format
     fn read_u16_slice(&mut self, count: usize) -> Result<Vec<u16>> {
-        let mut buffer = vec![0u8; count * 2];
-        self.read_exact(&mut buffer)?;
-        let be_values: &[BeU16] = cast_slice(&buffer);
-        Ok(be_values.iter().map(|&v| v.into()).collect())
+        Ok(ReadBytesExtU24::read_u16_slice::<BigEndian>(self, count)?)
     }
 
-    /// Efficiently read a slice of u32 values in big-endian format using bytemuck
+    /// Reads a slice of u32 values in big-endian format
     fn read_u32_slice(&mut self, count: usize) -> Result<Vec<u32>> {
-        let mut buffer = vec![0u8; count * 4];
-        self.read_exact(&mut buffer)?;
-        let be_values: &[BeU32] = cast_slice(&buffer);
-        Ok(be_values.iter().map(|&v| v.into()).collect())
+        Ok(ReadBytesExtU24::read_u32_slice::<BigEndian>(self, count)?)
     }
 
-    /// Efficiently read a slice of u24 values in big-endian format using bytemuck
+    /// Reads a slice of u24 values in big-endian format
     fn read_u24_slice(&mut self, count: usize) -> Result<Vec<u32>> {
-        let mut buffer = vec![0u8; count * 3];
-        self.read_exact(&mut buffer)?;
-        let be_values: &[BeU24] = cast_slice(&buffer);
-        Ok(be_values.iter().map(|&v| v.into()).collect())
+        Ok(ReadBytesExtU24::read_u24_slice::<BigEndian>(self, count)?)
     }
 }
 
@@ -194,6 +159,41 @@ impl Write for MemoryStream {
     }
 }
 
+/// A ByteStream that discards everything written to it and only counts the
+/// bytes, for size pre-passes that don't need the encoded data
+#[derive(Debug, Default)]
+pub struct CountingStream {
+    count: usize,
+}
+
+impl CountingStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of bytes written so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl Read for CountingStream {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for CountingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.count += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Big-endian u16 that can be safely cast to/from bytes
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
@@ -251,3 +251,30 @@ impl From<BeU24> for u32 {
         ((value.0[0] as u32) << 16) | ((value.0[1] as u32) << 8) | (value.0[2] as u32)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::djvu_dir::{DjVmDir, File, FileType};
+
+    #[test]
+    fn test_counting_stream_matches_memory_stream() {
+        let dir = DjVmDir::new();
+        for i in 0..12 {
+            let id = format!("p{:04}.djvu", i + 1);
+            let file = File::new_with_offset(&id, &id, "", FileType::Page, 100 + i * 5000, 4999);
+            dir.insert_file(file, -1).unwrap();
+        }
+
+        let mut memory = MemoryStream::new();
+        dir.encode_explicit(&mut memory, true, true).unwrap();
+        let mut counting = CountingStream::new();
+        dir.encode_explicit(&mut counting, true, true).unwrap();
+        assert_eq!(counting.count(), memory.as_slice().len());
+
+        ByteStream::write_u24(&mut counting, 7).unwrap();
+        counting.write_string("abc").unwrap();
+        assert_eq!(counting.count(), memory.as_slice().len() + 6);
+        assert_eq!(ByteStream::read_u8(&mut counting).ok(), None);
+    }
+}
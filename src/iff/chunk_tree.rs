@@ -256,9 +256,9 @@ impl IffDocument {
                 std::str::from_utf8(&chunk_id_buf).unwrap_or("????")
             };
 
-            let payload = data_map.get(file_id).ok_or_else(|| {
-                DjvuError::Stream(format!("Missing data for file_id: {}", file_id))
-            })?;
+            let payload = data_map
+                .get(file_id)
+                .ok_or_else(|| DjvuError::NotFound(format!("Data for file_id {}", file_id)))?;
             let chunk_start = iff_writer.stream_position()?;
             iff_writer.put_chunk(chunk_id_str)?;
             iff_writer.write_all(&payload.to_vec()?)?;
@@ -148,9 +148,12 @@ impl DataPool {
     }
 
     /// Creates a new `DataPool` by opening a file at the given path.
+    ///
+    /// The length comes from the file's metadata; no data is read until the
+    /// pool itself is read.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = File::open(path)?;
-        let len = file.len();
+        let len = file.metadata()?.len();
         Ok(DataPool {
             source: Arc::new(Mutex::new(file)),
             start: 0,
@@ -184,6 +187,9 @@ impl DataPool {
     }
 
     /// Returns the total length of the data available in this pool.
+    ///
+    /// O(1) for every kind of pool: the bounds are fixed when the pool or
+    /// slice is created, so neither the source nor its lock is touched.
     #[inline]
     pub fn len(&self) -> u64 {
         self.end - self.start
@@ -310,3 +316,24 @@ impl Seek for DataPool {
         Ok(self.pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_slice_len_does_not_read() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        io::Write::write_all(&mut file, &[7u8; 1000]).unwrap();
+
+        let pool = DataPool::from_file(file.path()).unwrap();
+        let slice = pool.slice(100, Some(300)).unwrap();
+        assert_eq!(pool.len(), 1000);
+        assert_eq!(slice.len(), 300);
+
+        // The lengths are not re-derived from the file
+        file.as_file().set_len(0).unwrap();
+        assert_eq!(slice.len(), 300);
+        assert_eq!(slice.slice(50, None).unwrap().len(), 250);
+    }
+}
@@ -106,6 +106,8 @@ pub struct IffWriter<'a> {
     writer: Box<dyn WriteSeek + 'a>,
     // Stack of (size_field_position, payload_start_position, is_composite)
     chunk_stack: Vec<(u64, u64, bool)>,
+    // Set once the magic or any chunk has been written
+    started: bool,
 }
 
 impl<'a> IffWriter<'a> {
@@ -115,13 +117,24 @@ impl<'a> IffWriter<'a> {
         IffWriter {
             writer: Box::new(writer),
             chunk_stack: Vec::new(),
+            started: false,
         }
     }
 
     /// Writes the DjVu "AT&T" magic bytes to the start of the stream.
-    /// This should only be called once at the very beginning of the file.
+    ///
+    /// Fails with [`DjvuError::InvalidOperation`] if the magic or a chunk
+    /// has already been written. Each component is encoded with its own
+    /// writer and magic; bundled assembly strips the components' magic and
+    /// writes it once, ahead of `FORM:DJVM`.
     #[inline]
     pub fn write_magic_bytes(&mut self) -> Result<()> {
+        if self.started {
+            return Err(DjvuError::InvalidOperation(
+                "AT&T magic must be written once, before any chunk".into(),
+            ));
+        }
+        self.started = true;
         self.writer.write_all(&[0x41, 0x54, 0x26, 0x54])?;
         Ok(())
     }
@@ -130,6 +143,7 @@ impl<'a> IffWriter<'a> {
     /// The caller is responsible for calling `patch_chunk_size` later.
     pub fn write_chunk_header(&mut self, full_id: &str) -> Result<u64> {
         let (id, secondary_id) = Self::parse_full_id(full_id)?;
+        self.started = true;
         self.writer.write_all(&id)?;
         let size_pos = self.writer.stream_position()?;
         self.writer.write_u32::<BigEndian>(0)?; // Placeholder size
@@ -146,6 +160,7 @@ impl<'a> IffWriter<'a> {
         let end_pos = self.writer.stream_position()?;
         // The content size is everything from after the size field to the current position.
         let content_size = end_pos - (size_pos + 4);
+        let size_field = Self::size_field(content_size)?;
 
         // Add padding if content size is odd. The padding byte is not part of the size.
         if (content_size & 1) != 0 {
@@ -156,12 +171,31 @@ impl<'a> IffWriter<'a> {
 
         // Seek back, write the real size, and restore position.
         self.writer.seek(SeekFrom::Start(size_pos))?;
-        self.writer.write_u32::<BigEndian>(content_size as u32)?;
+        self.writer.write_u32::<BigEndian>(size_field)?;
         self.writer.seek(SeekFrom::Start(final_pos))?;
 
         Ok(())
     }
 
+    /// Writes the header of a chunk whose size is known ahead: `size`
+    /// payload bytes, counting the secondary id of a composite chunk.
+    ///
+    /// For a FORM whose parts are written elsewhere, e.g. components
+    /// streamed after the DJVM header. The chunk is not tracked, so it must
+    /// not be closed, and the caller owes exactly `size` bytes plus the pad
+    /// byte of an odd size. Fails like [`Self::close_chunk`] past 4 GiB.
+    pub fn put_chunk_sized(&mut self, full_id: &str, size: u64) -> Result<()> {
+        let (id, secondary_id) = Self::parse_full_id(full_id)?;
+        let size_field = Self::size_field(size)?;
+        self.started = true;
+        self.writer.write_all(&id)?;
+        self.writer.write_u32::<BigEndian>(size_field)?;
+        if let Some(sid) = secondary_id {
+            self.writer.write_all(&sid)?;
+        }
+        Ok(())
+    }
+
     /// Begins a new chunk with the given ID.
     ///
     /// For composite chunks, the ID should be in the format "FORM:DJVU".
@@ -170,6 +204,7 @@ impl<'a> IffWriter<'a> {
         let (id, secondary_id) = Self::parse_full_id(full_id)?;
         let is_composite = secondary_id.is_some();
 
+        self.started = true;
         self.writer.write_all(&id)?;
 
         // Store the position of the size field to be patched later.
@@ -195,6 +230,10 @@ impl<'a> IffWriter<'a> {
     ///
     /// For composite chunks, the size includes the 4-byte secondary id
     /// to match the DjVu specification and standard IFF format.
+    ///
+    /// An odd-length payload is always followed by exactly one zero pad
+    /// byte, which is not counted in the size field. Callers must not pad
+    /// chunks themselves.
     pub fn close_chunk(&mut self) -> Result<()> {
         let (size_pos, _payload_start_pos, _is_composite) = self
             .chunk_stack
@@ -207,6 +246,7 @@ impl<'a> IffWriter<'a> {
         // For composite chunks: include the secondary ID and all data
         // For simple chunks: include only the data
         let chunk_size_field = end_pos - (size_pos + 4);
+        let size_field = Self::size_field(chunk_size_field)?;
 
         // IFF: pad to even overall size, but byte is **not** counted
         if (chunk_size_field & 1) != 0 {
@@ -216,12 +256,22 @@ impl<'a> IffWriter<'a> {
 
         // Patch the size field and restore position
         self.writer.seek(SeekFrom::Start(size_pos))?;
-        self.writer
-            .write_u32::<BigEndian>(chunk_size_field as u32)?;
+        self.writer.write_u32::<BigEndian>(size_field)?;
         self.writer.seek(SeekFrom::Start(end_pos))?;
         Ok(())
     }
 
+    /// The 32-bit size field for `size` payload bytes. IFF has no wider
+    /// size field, so larger chunks fail instead of being truncated.
+    fn size_field(size: u64) -> Result<u32> {
+        u32::try_from(size).map_err(|_| {
+            DjvuError::InvalidOperation(format!(
+                "chunk of {} bytes exceeds the 4 GiB IFF size limit",
+                size
+            ))
+        })
+    }
+
     /// Returns the current nesting level (number of open chunks).
     pub fn nesting_level(&self) -> usize {
         self.chunk_stack.len()
@@ -262,6 +312,8 @@ impl<'a> IffWriter<'a> {
 /// An extension trait to provide helper methods for `IffWriter`.
 pub trait IffWriterExt {
     /// Writes a complete simple chunk (header, data, and padding) to the stream.
+    ///
+    /// Padding follows the same rule as [`IffWriter::close_chunk`].
     fn write_chunk(&mut self, id: [u8; 4], data: &[u8]) -> Result<()>;
 }
 
@@ -295,3 +347,128 @@ impl<'a> Seek for IffWriter<'a> {
         self.writer.seek(pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Discards data but keeps a position, to stand in for huge files
+    struct SparseWriter(u64);
+
+    impl Write for SparseWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SparseWriter {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0 = match pos {
+                SeekFrom::Start(p) => p,
+                SeekFrom::Current(d) => self.0.checked_add_signed(d).unwrap(),
+                SeekFrom::End(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "sparse writer has no end",
+                    ));
+                }
+            };
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_oversized_chunk_is_an_error() {
+        let mut iff = IffWriter::new(SparseWriter(0));
+        iff.put_chunk("FORM:DJVU").unwrap();
+        iff.put_chunk("BG44").unwrap();
+        // Jump past 4 GiB of payload
+        iff.seek(SeekFrom::Current(u32::MAX as i64 + 1)).unwrap();
+        let err = iff.close_chunk().unwrap_err();
+        assert!(matches!(err, DjvuError::InvalidOperation(_)), "{err}");
+        assert!(err.to_string().contains("4294967296 bytes"), "{err}");
+
+        let mut iff = IffWriter::new(SparseWriter(0));
+        let size_pos = iff.write_chunk_header("TXTa").unwrap();
+        iff.seek(SeekFrom::Current(u32::MAX as i64 + 2)).unwrap();
+        assert!(iff.patch_chunk_size(size_pos).is_err());
+        let mut iff = IffWriter::new(SparseWriter(0));
+        assert!(
+            iff.put_chunk_sized("FORM:DJVM", u32::MAX as u64 + 1)
+                .is_err()
+        );
+
+        // Just under the limit still fits
+        let mut iff = IffWriter::new(SparseWriter(0));
+        iff.put_chunk("BG44").unwrap();
+        iff.seek(SeekFrom::Current(u32::MAX as i64 - 1)).unwrap();
+        iff.close_chunk().unwrap();
+    }
+
+    #[test]
+    fn test_magic_is_written_once_before_chunks() {
+        let mut out = Vec::new();
+        let mut iff = IffWriter::new(Cursor::new(&mut out));
+        iff.write_magic_bytes().unwrap();
+        assert!(iff.write_magic_bytes().is_err());
+        iff.write_chunk(*b"INFO", b"").unwrap();
+        drop(iff);
+        assert_eq!(out, b"AT&TINFO\0\0\0\0");
+
+        let mut out = Vec::new();
+        let mut iff = IffWriter::new(Cursor::new(&mut out));
+        iff.put_chunk("FORM:DJVU").unwrap();
+        assert!(iff.write_magic_bytes().is_err());
+    }
+
+    #[test]
+    fn test_odd_chunk_gets_one_pad_byte() {
+        let mut out = Vec::new();
+        let mut iff = IffWriter::new(Cursor::new(&mut out));
+        iff.put_chunk("FORM:DJVU").unwrap();
+        iff.put_chunk("TXTa").unwrap();
+        iff.write_all(b"abc").unwrap();
+        iff.close_chunk().unwrap();
+        iff.write_chunk(*b"ANTa", b"x").unwrap();
+        iff.close_chunk().unwrap();
+        drop(iff);
+
+        // Continuation lines skip leading whitespace, so no spaces are embedded
+        let expected: &[u8] = b"FORM\0\0\0\x1aDJVU\
+            TXTa\0\0\0\x03abc\0\
+            ANTa\0\0\0\x01x\0";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_sized_chunk_header_is_written_as_given() {
+        let mut out = Vec::new();
+        let mut iff = IffWriter::new(Cursor::new(&mut out));
+        iff.write_magic_bytes().unwrap();
+        iff.put_chunk_sized("FORM:DJVM", 1000).unwrap();
+        assert_eq!(iff.nesting_level(), 0);
+        iff.write_chunk(*b"DIRM", b"abc").unwrap();
+        drop(iff);
+        assert_eq!(out, b"AT&TFORM\0\0\x03\xe8DJVMDIRM\0\0\0\x03abc\0");
+    }
+
+    #[test]
+    fn test_odd_composite_is_padded_once() {
+        let mut out = Vec::new();
+        let mut iff = IffWriter::new(Cursor::new(&mut out));
+        iff.put_chunk("FORM:DJVI").unwrap();
+        iff.write_all(b"odd").unwrap();
+        iff.close_chunk().unwrap();
+        drop(iff);
+
+        assert_eq!(&out[4..8], &7u32.to_be_bytes());
+        assert_eq!(out.len(), 8 + 7 + 1);
+        assert_eq!(out[15], 0);
+    }
+}
@@ -5,4 +5,4 @@ pub mod data_pool;
 pub mod iff;
 
 // Re-export commonly used types
-pub use byte_stream::{ByteStream, MemoryStream};
+pub use byte_stream::{ByteStream, CountingStream, MemoryStream};
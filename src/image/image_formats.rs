@@ -206,6 +206,32 @@ impl Pixmap {
             data,
         }
     }
+
+    /// Scales the pixmap to `new_width` x `new_height` with a box filter:
+    /// each output pixel averages, per channel, the source area it covers,
+    /// weighting partly covered pixels by their overlap. Suits downscaling
+    /// backgrounds and thumbnails. An empty source gives a black pixmap.
+    pub fn resize_area(&self, new_width: u32, new_height: u32) -> Pixmap {
+        if self.width == 0 || self.height == 0 {
+            return Pixmap::new(new_width, new_height);
+        }
+        resample_area(
+            (self.width, self.height),
+            (new_width, new_height),
+            |x, y| self.get_pixel(x, y),
+        )
+    }
+
+    /// Converts to a bilevel bitmap: pixels whose luminance (as in
+    /// [`Pixmap::to_bitmap`]) is below `level` become 0 (black), all others
+    /// 255 (white).
+    pub fn threshold(&self, level: u8) -> Bitmap {
+        let mut bitmap = self.to_bitmap();
+        for p in bitmap.pixels_mut() {
+            p.y = if p.y < level { 0 } else { 255 };
+        }
+        bitmap
+    }
 }
 
 // --- Bitmap Type (Grayscale Image Buffer) ---
@@ -293,6 +319,58 @@ impl Bitmap {
     pub fn as_raw_mut(&mut self) -> &mut [u8] {
         bytemuck::cast_slice_mut(&mut self.data)
     }
+
+    /// Expands to a gray pixmap with each pixel's value in all three
+    /// channels, so a bilevel bitmap becomes black (0) and white (255).
+    pub fn to_pixmap(&self) -> Pixmap {
+        let data = self
+            .data
+            .iter()
+            .map(|p| Pixel::new(p.y, p.y, p.y))
+            .collect();
+        Pixmap {
+            width: self.width,
+            height: self.height,
+            data,
+        }
+    }
+}
+
+/// Box-filters a `from`-sized image, read through `pixel`, down or up to
+/// `to`. Source and target are both mapped onto the same extent; each target
+/// pixel averages the source pixels it overlaps, weighted by the overlap, and
+/// rounds to nearest. `from` must not be empty.
+pub(crate) fn resample_area<F>(from: (u32, u32), to: (u32, u32), pixel: F) -> Pixmap
+where
+    F: Fn(u32, u32) -> Pixel,
+{
+    let ((w, h), (tw, th)) = (from, to);
+    // Source pixel `s` spans [s * t, (s + 1) * t) and target pixel `t`
+    // spans [t * s_len, (t + 1) * s_len), in units of 1 / (s_len * t_len)
+    let overlaps = |t: u32, len: u32, tlen: u32| {
+        let (lo, hi) = (t as u64 * len as u64, (t as u64 + 1) * len as u64);
+        let first = (lo / tlen as u64) as u32;
+        let last = (hi.div_ceil(tlen as u64) as u32).min(len);
+        (first..last).map(move |s| {
+            let (s_lo, s_hi) = (s as u64 * tlen as u64, (s as u64 + 1) * tlen as u64);
+            (s, s_hi.min(hi) - s_lo.max(lo))
+        })
+    };
+    let total = w as u64 * h as u64;
+    Pixmap::from_fn(tw, th, |tx, ty| {
+        let mut sum = [0u64; 3];
+        for (y, wy) in overlaps(ty, h, th) {
+            for (x, wx) in overlaps(tx, w, tw) {
+                let p = pixel(x, y);
+                let weight = wx * wy;
+                sum[0] += p.r as u64 * weight;
+                sum[1] += p.g as u64 * weight;
+                sum[2] += p.b as u64 * weight;
+            }
+        }
+        let avg = |s: u64| ((s + total / 2) / total) as u8;
+        Pixel::new(avg(sum[0]), avg(sum[1]), avg(sum[2]))
+    })
 }
 
 /// An extension trait for DjVu-specific image manipulation operations.
@@ -451,3 +529,60 @@ impl DjvuImageExt for Pixmap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold_then_expand_round_trip() {
+        let checker = Pixmap::from_fn(9, 7, |x, y| {
+            if (x + y) % 2 == 0 {
+                Pixel::black()
+            } else {
+                Pixel::white()
+            }
+        });
+        let bilevel = checker.threshold(128);
+        assert_eq!(bilevel.dimensions(), (9, 7));
+        assert_eq!(bilevel.get_pixel(0, 0).y, 0);
+        assert_eq!(bilevel.get_pixel(1, 0).y, 255);
+        assert_eq!(bilevel.to_pixmap().pixels(), checker.pixels());
+
+        // Gray levels land on either side of the threshold
+        let ramp = Pixmap::from_fn(256, 1, |x, _| Pixel::new(x as u8, x as u8, x as u8));
+        let expanded = ramp.threshold(100).to_pixmap();
+        assert_eq!(expanded.dimensions(), (256, 1));
+        assert!(
+            expanded.pixels()[..100]
+                .iter()
+                .all(|&p| p == Pixel::black())
+        );
+        assert!(
+            expanded.pixels()[100..]
+                .iter()
+                .all(|&p| p == Pixel::white())
+        );
+    }
+
+    #[test]
+    fn test_resize_area_averages_gradient() {
+        let gray = |v: u32| Pixel::new(v as u8, v as u8, v as u8);
+        let ramp = Pixmap::from_fn(6, 2, |x, _| gray(x * 40));
+
+        // Halving averages neighbouring pairs, rows included
+        let half = ramp.resize_area(3, 1);
+        assert_eq!(half.pixels(), &[gray(20), gray(100), gray(180)]);
+
+        // 6 -> 4 columns: each output covers one and a half source pixels
+        let uneven = ramp.resize_area(4, 2);
+        let expected: Vec<_> = [13, 67, 133, 187].into_iter().map(gray).collect();
+        assert_eq!(&uneven.pixels()[..4], &expected[..]);
+
+        // Upscaling by a whole factor replicates pixels exactly
+        let double = ramp.resize_area(12, 4);
+        assert_eq!(double.get_pixel(5, 3), gray(80));
+        assert_eq!(double.get_pixel(11, 0), gray(200));
+        assert_eq!(Pixmap::new(0, 0).resize_area(2, 2).dimensions(), (2, 2));
+    }
+}
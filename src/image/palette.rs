@@ -10,38 +10,11 @@
 
 use crate::image::image_formats::{Pixel, Pixmap};
 use crate::utils::error::{DjvuError, Result};
+use crate::utils::write_ext::{ReadBytesExtU24, WriteBytesExtU24};
 use bytemuck::{Pod, Zeroable, cast_slice};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Cursor, Read, Write};
-
-// --- Helper trait for u24 operations ---
-trait ReadWriteU24 {
-    fn read_u24<R: Read>(reader: &mut R) -> Result<u32>;
-    fn write_u24<W: Write>(writer: &mut W, value: u32) -> Result<()>;
-}
-
-struct U24Helper;
-
-impl ReadWriteU24 for U24Helper {
-    fn read_u24<R: Read>(reader: &mut R) -> Result<u32> {
-        let mut bytes = [0u8; 3];
-        reader.read_exact(&mut bytes)?;
-        Ok(((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | (bytes[2] as u32))
-    }
-
-    fn write_u24<W: Write>(writer: &mut W, value: u32) -> Result<()> {
-        if value > 0xFFFFFF {
-            return Err(DjvuError::InvalidArg("Value too large for u24".to_string()));
-        }
-        let bytes = [
-            ((value >> 16) & 0xFF) as u8,
-            ((value >> 8) & 0xFF) as u8,
-            (value & 0xFF) as u8,
-        ];
-        writer.write_all(&bytes)?;
-        Ok(())
-    }
-}
+use std::sync::OnceLock;
 
 // --- Bytemuck-compatible color types ---
 
@@ -126,6 +99,104 @@ impl Quantizer for NeuQuantQuantizer {
     }
 }
 
+// --- Nearest-Color Index ---
+
+/// Palettes with fewer colors than this are searched linearly.
+const KD_TREE_THRESHOLD: usize = 16;
+
+/// Sentinel child link for a missing subtree.
+const NO_CHILD: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct KdNode {
+    color: [i32; 3],
+    index: u16,
+    axis: u8,
+    left: u32,
+    right: u32,
+}
+
+/// A 3-d tree over the palette's RGB values for O(log n) nearest lookups.
+///
+/// Ties are broken toward the lower palette index so results match the
+/// linear scan in `Palette::color_to_index` exactly.
+#[derive(Debug, Clone)]
+struct KdTree {
+    nodes: Vec<KdNode>,
+    root: u32,
+}
+
+impl KdTree {
+    fn new(colors: &[Pixel]) -> Self {
+        let mut items: Vec<([i32; 3], u16)> = colors
+            .iter()
+            .enumerate()
+            .map(|(i, c)| ([c.r as i32, c.g as i32, c.b as i32], i as u16))
+            .collect();
+        let mut nodes = Vec::with_capacity(items.len());
+        let root = Self::build(&mut nodes, &mut items, 0);
+        KdTree { nodes, root }
+    }
+
+    fn build(nodes: &mut Vec<KdNode>, items: &mut [([i32; 3], u16)], depth: usize) -> u32 {
+        if items.is_empty() {
+            return NO_CHILD;
+        }
+        let axis = depth % 3;
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by_key(mid, |(c, _)| c[axis]);
+        let (color, index) = items[mid];
+
+        let id = nodes.len() as u32;
+        nodes.push(KdNode {
+            color,
+            index,
+            axis: axis as u8,
+            left: NO_CHILD,
+            right: NO_CHILD,
+        });
+        let (lower, rest) = items.split_at_mut(mid);
+        let left = Self::build(nodes, lower, depth + 1);
+        let right = Self::build(nodes, &mut rest[1..], depth + 1);
+        nodes[id as usize].left = left;
+        nodes[id as usize].right = right;
+        id
+    }
+
+    fn nearest(&self, color: &Pixel) -> u16 {
+        let query = [color.r as i32, color.g as i32, color.b as i32];
+        let mut best = (i32::MAX, u16::MAX);
+        self.search(self.root, &query, &mut best);
+        best.1
+    }
+
+    fn search(&self, node: u32, query: &[i32; 3], best: &mut (i32, u16)) {
+        if node == NO_CHILD {
+            return;
+        }
+        let n = &self.nodes[node as usize];
+        let dr = n.color[0] - query[0];
+        let dg = n.color[1] - query[1];
+        let db = n.color[2] - query[2];
+        let dist = dr * dr + dg * dg + db * db;
+        if (dist, n.index) < *best {
+            *best = (dist, n.index);
+        }
+
+        let diff = query[n.axis as usize] - n.color[n.axis as usize];
+        let (near, far) = if diff < 0 {
+            (n.left, n.right)
+        } else {
+            (n.right, n.left)
+        };
+        self.search(near, query, best);
+        // `<=` so equally distant colors with a lower index are still found
+        if diff * diff <= best.0 {
+            self.search(far, query, best);
+        }
+    }
+}
+
 // --- Palette Data Structure ---
 
 /// Represents a color palette for a DjVu image.
@@ -136,6 +207,9 @@ pub struct Palette {
     // The `colordata` array from the C++ version, for storing a sequence of color indices.
     // This is used for the foreground layer of compound documents.
     pub color_indices: Vec<u16>,
+    /// Nearest-color index, built on first use for palettes of at least
+    /// `KD_TREE_THRESHOLD` colors.
+    lookup: OnceLock<Option<KdTree>>,
 }
 
 impl Palette {
@@ -151,6 +225,7 @@ impl Palette {
         Palette {
             colors,
             color_indices: Vec::new(),
+            lookup: OnceLock::new(),
         }
     }
 
@@ -159,6 +234,7 @@ impl Palette {
         Palette {
             colors,
             color_indices: Vec::new(),
+            lookup: OnceLock::new(),
         }
     }
 
@@ -186,12 +262,21 @@ impl Palette {
             .unwrap_or(0)
     }
 
-    /// Efficiently converts a slice of RGB pixels to color indices using bytemuck operations.
+    /// Converts a slice of RGB pixels to nearest-color indices.
+    ///
+    /// Palettes of 16 colors or more are searched through a kd-tree built
+    /// on the first call; results are identical to `color_to_index`.
     pub fn pixels_to_indices(&self, pixels: &[Pixel]) -> Vec<u16> {
-        pixels
-            .iter()
-            .map(|pixel| self.color_to_index(pixel))
-            .collect()
+        let tree = self.lookup.get_or_init(|| {
+            (self.colors.len() >= KD_TREE_THRESHOLD).then(|| KdTree::new(&self.colors))
+        });
+        match tree {
+            Some(tree) => pixels.iter().map(|pixel| tree.nearest(pixel)).collect(),
+            None => pixels
+                .iter()
+                .map(|pixel| self.color_to_index(pixel))
+                .collect(),
+        }
     }
 
     pub fn indices_to_pixels(&self, indices: &[u16]) -> Vec<Pixel> {
@@ -256,18 +341,9 @@ impl Palette {
         writer.write_all(bgr_bytes)?;
 
         if !self.color_indices.is_empty() {
-            let data_size = self.color_indices.len();
-            if data_size > 0xFF_FFFF {
-                return Err(DjvuError::InvalidOperation(
-                    "Color index data size cannot exceed 24 bits".to_string(),
-                ));
-            }
-            U24Helper::write_u24(writer, data_size as u32)?;
-
-            // Write each u16 index in BigEndian
-            for &index in &self.color_indices {
-                writer.write_u16::<BigEndian>(index)?;
-            }
+            let data_size = u32::try_from(self.color_indices.len()).unwrap_or(u32::MAX);
+            WriteBytesExtU24::write_u24::<BigEndian>(writer, data_size)?;
+            WriteBytesExtU24::write_u16_slice::<BigEndian>(writer, &self.color_indices)?;
         }
 
         Ok(())
@@ -277,9 +353,10 @@ impl Palette {
     pub fn decode<R: Read>(reader: &mut R) -> Result<Self> {
         let version = reader.read_u8()?;
         if (version & 0x7F) != 0 {
-            return Err(DjvuError::Stream(
-                "Unsupported DjVuPalette version.".to_string(),
-            ));
+            return Err(DjvuError::Unsupported(format!(
+                "DjVuPalette version {}",
+                version & 0x7F
+            )));
         }
 
         let palette_size = reader.read_u16::<BigEndian>()? as usize;
@@ -291,21 +368,14 @@ impl Palette {
 
         let mut color_indices = Vec::new();
         if (version & 0x80) != 0 {
-            let data_size = U24Helper::read_u24(reader)? as usize;
-
-            // Read the byte slice and parse as BigEndian u16
-            let mut index_bytes = vec![0u8; data_size * 2];
-            reader.read_exact(&mut index_bytes)?;
-            let mut cursor = Cursor::new(&index_bytes);
-            for _ in 0..data_size {
-                let index = cursor.read_u16::<BigEndian>()?;
-                color_indices.push(index);
-            }
+            let data_size = ReadBytesExtU24::read_u24::<BigEndian>(reader)? as usize;
+            color_indices = ReadBytesExtU24::read_u16_slice::<BigEndian>(reader, data_size)?;
         }
 
         Ok(Palette {
             colors,
             color_indices,
+            lookup: OnceLock::new(),
         })
     }
 }
@@ -586,3 +656,45 @@ mod your_neuquant {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small deterministic generator so the test needs no extra dependencies.
+    fn lcg(state: &mut u32) -> u8 {
+        *state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (*state >> 24) as u8
+    }
+
+    #[test]
+    fn test_indexed_lookup_matches_brute_force() {
+        let mut state = 0x1234_5678;
+        for &size in &[1usize, 15, 16, 64, 300, 2000] {
+            let colors: Vec<Pixel> = (0..size)
+                .map(|_| Pixel::new(lcg(&mut state), lcg(&mut state), lcg(&mut state)))
+                .collect();
+            let palette = Palette::from_colors(colors.clone());
+
+            // Random queries plus the palette entries themselves (exact hits)
+            let mut queries: Vec<Pixel> = (0..500)
+                .map(|_| Pixel::new(lcg(&mut state), lcg(&mut state), lcg(&mut state)))
+                .collect();
+            queries.extend_from_slice(&colors);
+
+            let indexed = palette.pixels_to_indices(&queries);
+            for (query, &idx) in queries.iter().zip(&indexed) {
+                assert_eq!(idx, palette.color_to_index(query), "size {size}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicate_colors_pick_lowest_index() {
+        let mut colors = vec![Pixel::new(10, 10, 10); 20];
+        colors.push(Pixel::new(200, 0, 0));
+        let palette = Palette::from_colors(colors);
+        let indices = palette.pixels_to_indices(&[Pixel::new(12, 9, 10), Pixel::new(250, 0, 0)]);
+        assert_eq!(indices, vec![0, 20]);
+    }
+}
@@ -51,18 +51,28 @@ pub mod encode;
 pub mod iff;
 pub mod image;
 pub mod utils;
+pub mod validate;
 
 // Public builder API
-pub use doc::{DjvuBuilder, DjvuDocument, ImageLayer, LayerData, Page, PageBuilder};
+pub use doc::{
+    DjvuBuilder, DjvuDocument, DocumentMetadata, ImageLayer, LayerData, Page, PageBuilder,
+    PageNaming,
+};
+#[cfg(feature = "image")]
+pub use doc::{ImportOptions, OnLoadError};
 
 // Advanced types (for custom encoding workflows)
-pub use doc::{PageComponents, PageEncodeParams};
+pub use doc::{
+    BzzLevel, ColorMode, EncodeDiagnostic, Jb2Coding, MaskCodec, MaskMorphology, PageComponents,
+    PageEncodeParams,
+};
+pub use encode::jb2::ReadingOrder;
 
 // Image types
 pub use image::image_formats::{Bitmap, GrayPixel, Pixel, Pixmap};
 
 // Error types
-pub use utils::error::{DjvuError, Result};
+pub use utils::error::{DjvuError, EncodeStage, ErrorKind, Result};
 
 // Constants
 pub const DJVU_VERSION: &str = "0.1.0";
@@ -111,11 +121,11 @@ mod tests {
 
         let djvu_bytes = doc.finalize()?;
         assert!(djvu_bytes.starts_with(b"AT&TFORM"));
-        assert_eq!(&djvu_bytes[8..12], b"DJVM");
+        assert_eq!(&djvu_bytes[12..16], b"DJVM");
 
         // Parse DIRM chunk header
         let mut cursor = Cursor::new(&djvu_bytes);
-        cursor.set_position(12);
+        cursor.set_position(16);
         let mut id = [0u8; 4];
         cursor.read_exact(&mut id)?;
         assert_eq!(&id, b"DIRM");
@@ -124,39 +134,34 @@ mod tests {
         let dirm_data_end = dirm_data_start + dirm_size;
         let dirm_pad = dirm_size % 2;
 
-        // Read DIRM offsets (bundled header)
+        // Read DIRM offsets (bundled header): big-endian u32 each
         let dirm_data = &djvu_bytes[dirm_data_start..dirm_data_end];
         let version = dirm_data[0];
         assert!(version & 0x80 != 0, "DIRM should be bundled");
         let file_count = u16::from_be_bytes([dirm_data[1], dirm_data[2]]) as usize;
         assert_eq!(file_count, 2);
+        let mut offsets = Cursor::new(&dirm_data[3..3 + 4 * file_count]);
+        let offsets = (0..file_count)
+            .map(|_| offsets.read_u32::<BigEndian>().map(|o| o as usize))
+            .collect::<std::io::Result<Vec<_>>>()?;
 
-        let offsets_start = 3;
-        let first_offset = u32::from_be_bytes([
-            dirm_data[offsets_start],
-            dirm_data[offsets_start + 1],
-            dirm_data[offsets_start + 2],
-            dirm_data[offsets_start + 3],
-        ]) as usize;
-
-        // Parse NAVM chunk to locate the first page position
-        let navm_header_pos = dirm_data_end + dirm_pad;
-        let mut nav_cursor = Cursor::new(&djvu_bytes[navm_header_pos..]);
-        let mut nav_id = [0u8; 4];
-        nav_cursor.read_exact(&mut nav_id)?;
-        assert_eq!(&nav_id, b"NAVM");
-        let navm_size = nav_cursor.read_u32::<BigEndian>()? as usize;
-        let navm_data_end = navm_header_pos + 8 + navm_size;
-        let navm_pad = navm_size % 2;
-
-        let first_page_pos = navm_data_end + navm_pad;
-
-        // Offsets are relative to the start of "DJVM" (position 8)
-        let expected_offset = first_page_pos - 8;
+        // Without bookmarks there is no NAVM: the first page follows DIRM
+        let first_page_pos = dirm_data_end + dirm_pad;
+        assert_eq!(&djvu_bytes[first_page_pos..first_page_pos + 4], b"FORM");
+
+        // Offsets are absolute file positions, counting the AT&T magic
         assert_eq!(
-            first_offset, expected_offset,
+            offsets[0], first_page_pos,
             "DIRM offset should match page position"
         );
+        let first_size = u32::from_be_bytes(
+            djvu_bytes[first_page_pos + 4..first_page_pos + 8]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let second_page_pos = first_page_pos + 8 + first_size + first_size % 2;
+        assert_eq!(offsets[1], second_page_pos);
+        assert_eq!(&djvu_bytes[second_page_pos..second_page_pos + 4], b"FORM");
 
         Ok(())
     }
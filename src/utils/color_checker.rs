@@ -271,6 +271,31 @@ pub fn analyze_colors(ppm_data: &PpmData) -> ColorAnalysis {
     }
 }
 
+/// Largest channel spread (`max(r,g,b) - min(r,g,b)`) still treated as neutral gray.
+pub const GRAY_CHROMA_TOLERANCE: u8 = 8;
+
+/// Returns `true` if an interleaved RGB buffer is effectively grayscale.
+///
+/// A pixel counts as colored when its channel spread exceeds `tolerance`.
+/// Up to 0.1% colored pixels are allowed so scanner noise and JPEG fringes
+/// on an otherwise gray page don't force color encoding.
+pub fn is_grayscale_rgb(rgb: &[u8], tolerance: u8) -> bool {
+    let total = rgb.len() / 3;
+    let allowed = total / 1000;
+    let mut colored = 0usize;
+    for px in rgb.chunks_exact(3) {
+        let max = px[0].max(px[1]).max(px[2]);
+        let min = px[0].min(px[1]).min(px[2]);
+        if max - min > tolerance {
+            colored += 1;
+            if colored > allowed {
+                return false;
+            }
+        }
+    }
+    true
+}
+
 pub fn check_solid_color<P: AsRef<Path>>(
     ppm_path: P,
     expected_color: RgbColor,
@@ -305,6 +330,19 @@ mod tests {
         assert!(red.distance(&blue) > red.distance(&light_red));
     }
 
+    #[test]
+    fn test_is_grayscale_rgb() {
+        let gray: Vec<u8> = (0..=255u8)
+            .flat_map(|v| [v, v.saturating_add(3), v])
+            .collect();
+        assert!(is_grayscale_rgb(&gray, GRAY_CHROMA_TOLERANCE));
+
+        let mut tinted = gray.clone();
+        tinted[0] = 200;
+        tinted[1] = 20;
+        assert!(!is_grayscale_rgb(&tinted, GRAY_CHROMA_TOLERANCE));
+    }
+
     #[test]
     fn test_color_check_result() {
         let result = ColorCheckResult::ExactMatch {
@@ -11,34 +11,177 @@ pub enum DjvuError {
     InvalidArg(String),
     /// An invalid operation was attempted
     InvalidOperation(String),
+    /// A file, page or component looked up by id or number does not exist
+    NotFound(String),
+    /// The request is valid but uses a feature this crate does not implement
+    Unsupported(String),
     /// A validation error occurred
     ValidationError(String),
     /// A stream processing error occurred
     Stream(String),
     /// A custom error with a message
     Custom(String),
-    /// An encoding/decoding error occurred
-    EncodingError(String),
+    /// A codec (ZP, JB2) failed; the codec error is kept as the source
+    #[deprecated(
+        note = "codec failures during page encoding are reported as `Encoding`; \
+                match on `DjvuError::kind()` returning `ErrorKind::Encoding` instead"
+    )]
+    EncodingError(Box<dyn Error + Send + Sync>),
+    /// A general-purpose compressor (BZZ, MMR) failed on an input of
+    /// `input_len` bytes
+    Compression {
+        codec: &'static str,
+        input_len: usize,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// Page encoding failed while producing a specific chunk
+    Encoding {
+        stage: EncodeStage,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// Encoding was stopped through the cancellation flag in
+    /// [`PageEncodeParams::cancel`](crate::doc::page_encoder::PageEncodeParams::cancel)
+    Cancelled,
+}
+
+/// Broad category of a [`DjvuError`], returned by [`DjvuError::kind`].
+///
+/// Several variants carry free-form messages; branch on the kind rather
+/// than on the message text, which may change between releases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// Reading or writing the underlying file or stream failed
+    Io,
+    /// An argument was out of range or otherwise unusable
+    InvalidInput,
+    /// The call is not valid in the current state, e.g. finalizing a
+    /// document whose pages are not all added
+    InvalidState,
+    /// A file, page or component id does not exist
+    NotFound,
+    /// The request needs a feature this crate does not implement
+    Unsupported,
+    /// Input data is malformed or truncated
+    Malformed,
+    /// A document failed validation
+    Validation,
+    /// A codec failed while encoding
+    Encoding,
+    /// Encoding was cancelled
+    Cancelled,
+    /// Anything else
+    Other,
+}
+
+/// The part of page encoding that produced a [`DjvuError::Encoding`] error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeStage {
+    /// IW44 wavelet background or foreground (BG44/FG44)
+    Iw44,
+    /// JB2 bilevel page data (Sjbz)
+    Jb2,
+    /// MMR (G4) bilevel page data (Smmr)
+    Mmr,
+    /// Foreground color palette (FGbz)
+    Palette,
+    /// Annotations (ANTz)
+    Annotations,
+}
+
+impl fmt::Display for EncodeStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EncodeStage::Iw44 => "IW44 (BG44/FG44)",
+            EncodeStage::Jb2 => "JB2 (Sjbz)",
+            EncodeStage::Mmr => "MMR (Smmr)",
+            EncodeStage::Palette => "palette (FGbz)",
+            EncodeStage::Annotations => "annotations (ANTz)",
+        };
+        f.write_str(name)
+    }
+}
+
+impl DjvuError {
+    /// Returns the category of this error.
+    #[allow(deprecated)]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            DjvuError::Io(_) => ErrorKind::Io,
+            DjvuError::InvalidArg(_) => ErrorKind::InvalidInput,
+            DjvuError::InvalidOperation(_) => ErrorKind::InvalidState,
+            DjvuError::NotFound(_) => ErrorKind::NotFound,
+            DjvuError::Unsupported(_) => ErrorKind::Unsupported,
+            DjvuError::ValidationError(_) => ErrorKind::Validation,
+            DjvuError::Stream(_) => ErrorKind::Malformed,
+            DjvuError::Custom(_) => ErrorKind::Other,
+            DjvuError::EncodingError(_)
+            | DjvuError::Compression { .. }
+            | DjvuError::Encoding { .. } => ErrorKind::Encoding,
+            DjvuError::Cancelled => ErrorKind::Cancelled,
+        }
+    }
+
+    /// Wraps `err` as a [`DjvuError::Encoding`] tagged with `stage`.
+    pub fn encoding(stage: EncodeStage, err: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        DjvuError::Encoding {
+            stage,
+            source: err.into(),
+        }
+    }
+
+    /// Wraps `err` as a [`DjvuError::Compression`] of `input_len` bytes.
+    pub fn compression(
+        codec: &'static str,
+        input_len: usize,
+        err: impl Into<Box<dyn Error + Send + Sync>>,
+    ) -> Self {
+        DjvuError::Compression {
+            codec,
+            input_len,
+            source: err.into(),
+        }
+    }
 }
 
 impl fmt::Display for DjvuError {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             DjvuError::Io(err) => write!(f, "I/O error: {}", err),
             DjvuError::InvalidArg(msg) => write!(f, "Invalid argument: {}", msg),
             DjvuError::InvalidOperation(msg) => write!(f, "Invalid operation: {}", msg),
+            DjvuError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            DjvuError::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
             DjvuError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
             DjvuError::Stream(msg) => write!(f, "Stream error: {}", msg),
             DjvuError::Custom(msg) => write!(f, "Error: {}", msg),
-            DjvuError::EncodingError(msg) => write!(f, "Encoding error: {}", msg),
+            DjvuError::EncodingError(err) => write!(f, "Encoding error: {}", err),
+            DjvuError::Compression {
+                codec,
+                input_len,
+                source,
+            } => write!(
+                f,
+                "{} compression of {} bytes failed: {}",
+                codec, input_len, source
+            ),
+            DjvuError::Encoding { stage, source } => {
+                write!(f, "Encoding error in {}: {}", stage, source)
+            }
+            DjvuError::Cancelled => write!(f, "Encoding cancelled"),
         }
     }
 }
 
 impl Error for DjvuError {
+    #[allow(deprecated)]
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             DjvuError::Io(err) => Some(err),
+            DjvuError::EncodingError(source)
+            | DjvuError::Compression { source, .. }
+            | DjvuError::Encoding { source, .. } => Some(source.as_ref()),
             _ => None,
         }
     }
@@ -51,14 +194,16 @@ impl From<io::Error> for DjvuError {
 }
 
 impl From<crate::encode::jb2::error::Jb2Error> for DjvuError {
+    #[allow(deprecated)]
     fn from(err: crate::encode::jb2::error::Jb2Error) -> Self {
-        DjvuError::EncodingError(err.to_string())
+        DjvuError::EncodingError(Box::new(err))
     }
 }
 
 impl From<crate::encode::zc::ZCodecError> for DjvuError {
+    #[allow(deprecated)]
     fn from(err: crate::encode::zc::ZCodecError) -> Self {
-        DjvuError::EncodingError(err.to_string())
+        DjvuError::EncodingError(Box::new(err))
     }
 }
 
@@ -87,6 +232,11 @@ mod tests {
             "Invalid operation: test"
         );
 
+        assert_eq!(
+            DjvuError::Unsupported("test".to_string()).to_string(),
+            "Unsupported: test"
+        );
+
         assert_eq!(
             DjvuError::ValidationError("test".to_string()).to_string(),
             "Validation error: test"
@@ -101,5 +251,73 @@ mod tests {
             DjvuError::Custom("test".to_string()).to_string(),
             "Error: test"
         );
+
+        let err = DjvuError::encoding(EncodeStage::Jb2, "bad blit");
+        assert_eq!(err.to_string(), "Encoding error in JB2 (Sjbz): bad blit");
+        assert_eq!(err.source().unwrap().to_string(), "bad blit");
+
+        assert_eq!(DjvuError::Cancelled.to_string(), "Encoding cancelled");
+
+        assert_eq!(
+            DjvuError::NotFound("p0001.djvu".to_string()).to_string(),
+            "Not found: p0001.djvu"
+        );
+    }
+
+    #[test]
+    fn test_wrapped_io_error_is_reachable_through_source() {
+        use crate::encode::jb2::error::Jb2Error;
+        use crate::encode::zc::ZCodecError;
+
+        let io = io::Error::new(io::ErrorKind::WriteZero, "disk full");
+        let err = DjvuError::from(Jb2Error::from(ZCodecError::from(io)));
+        assert_eq!(err.kind(), ErrorKind::Encoding);
+        assert!(err.source().unwrap().is::<Jb2Error>());
+
+        let mut next = err.source();
+        let mut found = None;
+        while let Some(source) = next {
+            found = found.or(source.downcast_ref::<io::Error>());
+            next = source.source();
+        }
+        assert_eq!(found.unwrap().kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_kinds_of_common_failures() {
+        use crate::doc::djvu_dir::{DjVmDir, File, FileType};
+        use crate::{DjvuBuilder, PageEncodeParams};
+
+        let dir = DjVmDir::new();
+        dir.insert_file(File::new("p1.djvu", "p1.djvu", "", FileType::Page), -1)
+            .unwrap();
+        // Every lookup of something missing reports the same kind
+        for err in [
+            dir.set_file_title("missing", "x").unwrap_err(),
+            dir.move_file_to_page_pos("missing", 0).unwrap_err(),
+            dir.delete_file("missing").unwrap_err(),
+            dir.page_to_file(5).unwrap_err(),
+        ] {
+            assert_eq!(err.kind(), ErrorKind::NotFound, "{err}");
+        }
+
+        let doc = DjvuBuilder::new(1).build();
+        assert_eq!(doc.finalize().unwrap_err().kind(), ErrorKind::InvalidState);
+        let err = doc.add_raw_page(0, vec![0; 3]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{err}");
+
+        let params = PageEncodeParams {
+            cancel: Some(std::sync::Arc::new(true.into())),
+            ..PageEncodeParams::default()
+        };
+        assert_eq!(
+            params.check_cancelled().unwrap_err().kind(),
+            ErrorKind::Cancelled
+        );
+
+        let io = DjvuError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert_eq!(io.kind(), ErrorKind::Io);
+        let err = DjvuError::encoding(EncodeStage::Iw44, "bad");
+        assert_eq!(err.kind(), ErrorKind::Encoding);
     }
 }
@@ -8,4 +8,4 @@ pub mod progress;
 pub mod write_ext;
 
 // Re-export commonly used items
-pub use error::{DjvuError, Result};
+pub use error::{DjvuError, EncodeStage, Result};
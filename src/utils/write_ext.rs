@@ -1,25 +1,151 @@
-//! An extension trait for `std::io::Write` to add helpers for writing
-//! custom integer types, such as 24-bit integers.
+//! Extension traits for `std::io::Write` and `std::io::Read` with helpers for
+//! 24-bit integers and for whole slices of integers in either byte order.
+//!
+//! DjVu chunks mix big-endian fields (DIRM sizes, FGbz counts) with a few
+//! little-endian ones, so every helper takes a `byteorder::ByteOrder`. The
+//! 24-bit writers share one range check so callers cannot drift apart on
+//! what counts as too large.
 
-use std::io::{self, Write};
+use byteorder::ByteOrder;
+use std::io::{self, Read, Write};
 
-/// Extends `std::io::Write` with methods for writing 24-bit integers.
-pub trait WriteBytesExtU24: Write {
-    /// Writes a 24-bit unsigned integer to the underlying writer in big-endian format.
-    fn write_u24<B: byteorder::ByteOrder>(&mut self, n: u32) -> io::Result<()>;
+/// Largest value a 24-bit field can hold.
+pub const U24_MAX: u32 = 0xFF_FFFF;
+
+fn check_u24(n: u32) -> io::Result<()> {
+    if n > U24_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("value {n} too large for u24 (max {U24_MAX})"),
+        ));
+    }
+    Ok(())
 }
 
-impl<W: Write> WriteBytesExtU24 for W {
-    fn write_u24<B: byteorder::ByteOrder>(&mut self, n: u32) -> io::Result<()> {
-        // Ensure the value fits within 24 bits.
-        if n > 0xFFFFFF {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "value too large for u24",
-            ));
-        }
+/// Extends `std::io::Write` with methods for writing 24-bit integers and
+/// integer slices.
+pub trait WriteBytesExtU24: Write {
+    /// Writes a 24-bit unsigned integer, failing with `InvalidInput` if `n`
+    /// does not fit.
+    fn write_u24<B: ByteOrder>(&mut self, n: u32) -> io::Result<()> {
+        check_u24(n)?;
         let mut buf = [0; 3];
         B::write_u24(&mut buf, n);
         self.write_all(&buf)
     }
+
+    /// Writes every value as a 24-bit integer. Nothing is written if any
+    /// value does not fit.
+    fn write_u24_slice<B: ByteOrder>(&mut self, values: &[u32]) -> io::Result<()> {
+        values.iter().try_for_each(|&n| check_u24(n))?;
+        let mut buf = vec![0; values.len() * 3];
+        for (chunk, &n) in buf.chunks_exact_mut(3).zip(values) {
+            B::write_u24(chunk, n);
+        }
+        self.write_all(&buf)
+    }
+
+    /// Writes a slice of `u16` values.
+    fn write_u16_slice<B: ByteOrder>(&mut self, values: &[u16]) -> io::Result<()> {
+        let mut buf = vec![0; values.len() * 2];
+        B::write_u16_into(values, &mut buf);
+        self.write_all(&buf)
+    }
+
+    /// Writes a slice of `u32` values.
+    fn write_u32_slice<B: ByteOrder>(&mut self, values: &[u32]) -> io::Result<()> {
+        let mut buf = vec![0; values.len() * 4];
+        B::write_u32_into(values, &mut buf);
+        self.write_all(&buf)
+    }
+}
+
+impl<W: Write + ?Sized> WriteBytesExtU24 for W {}
+
+/// Extends `std::io::Read` with the readers matching [`WriteBytesExtU24`].
+pub trait ReadBytesExtU24: Read {
+    /// Reads a 24-bit unsigned integer.
+    fn read_u24<B: ByteOrder>(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 3];
+        self.read_exact(&mut buf)?;
+        Ok(B::read_u24(&buf))
+    }
+
+    /// Reads `count` 24-bit unsigned integers.
+    fn read_u24_slice<B: ByteOrder>(&mut self, count: usize) -> io::Result<Vec<u32>> {
+        let mut buf = vec![0; count * 3];
+        self.read_exact(&mut buf)?;
+        Ok(buf.chunks_exact(3).map(B::read_u24).collect())
+    }
+
+    /// Reads `count` `u16` values.
+    fn read_u16_slice<B: ByteOrder>(&mut self, count: usize) -> io::Result<Vec<u16>> {
+        let mut buf = vec![0; count * 2];
+        self.read_exact(&mut buf)?;
+        let mut values = vec![0; count];
+        B::read_u16_into(&buf, &mut values);
+        Ok(values)
+    }
+
+    /// Reads `count` `u32` values.
+    fn read_u32_slice<B: ByteOrder>(&mut self, count: usize) -> io::Result<Vec<u32>> {
+        let mut buf = vec![0; count * 4];
+        self.read_exact(&mut buf)?;
+        let mut values = vec![0; count];
+        B::read_u32_into(&buf, &mut values);
+        Ok(values)
+    }
+}
+
+impl<R: Read + ?Sized> ReadBytesExtU24 for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, LittleEndian};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_u24_boundaries() {
+        for n in [0, 1, 0x12_3456, U24_MAX] {
+            let mut buf = Vec::new();
+            buf.write_u24::<BigEndian>(n).unwrap();
+            assert_eq!(buf.len(), 3);
+            assert_eq!(Cursor::new(&buf).read_u24::<BigEndian>().unwrap(), n);
+        }
+
+        let mut buf = Vec::new();
+        buf.write_u24::<BigEndian>(U24_MAX).unwrap();
+        assert_eq!(buf, [0xFF, 0xFF, 0xFF]);
+        buf.clear();
+        buf.write_u24::<LittleEndian>(0x01_0203).unwrap();
+        assert_eq!(buf, [0x03, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn test_u24_overflow_is_rejected() {
+        let mut buf = Vec::new();
+        let err = buf.write_u24::<BigEndian>(U24_MAX + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = buf
+            .write_u24_slice::<BigEndian>(&[1, 2, U24_MAX + 1])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_slice_round_trip() {
+        let mut buf = Vec::new();
+        buf.write_u16_slice::<BigEndian>(&[1, 0xABCD]).unwrap();
+        buf.write_u24_slice::<LittleEndian>(&[0, U24_MAX]).unwrap();
+        buf.write_u32_slice::<BigEndian>(&[0xDEAD_BEEF]).unwrap();
+        assert_eq!(&buf[..4], &[0x00, 0x01, 0xAB, 0xCD]);
+        assert_eq!(&buf[10..], &[0xDE, 0xAD, 0xBE, 0xEF]);
+
+        let mut r = Cursor::new(&buf);
+        assert_eq!(r.read_u16_slice::<BigEndian>(2).unwrap(), [1, 0xABCD]);
+        assert_eq!(r.read_u24_slice::<LittleEndian>(2).unwrap(), [0, U24_MAX]);
+        assert_eq!(r.read_u32_slice::<BigEndian>(1).unwrap(), [0xDEAD_BEEF]);
+    }
 }
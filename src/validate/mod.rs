@@ -4,3 +4,7 @@
 //! the correctness of DjVu encoding without requiring a full decoder.
 
 // Note: Test modules have been moved to the main tests/ directory
+
+pub mod page;
+
+pub use page::{ValidationIssue, validate_page};
@@ -0,0 +1,471 @@
+//! Semantic checks for a single `FORM:DJVU` page.
+//!
+//! Structural IFF validity is not enough for a page to display correctly:
+//! layers must agree with the INFO chunk and coded streams must decode.
+//! [`validate_page`] checks what can be verified with the decoders this
+//! crate carries (ZP, JB2, BZZ).
+
+use crate::encode::jb2::decoder;
+use crate::encode::jb2::num_coder::{BIG_POSITIVE, NumCoder, NumContext};
+use crate::encode::jb2::symbol_dict::BitImage;
+use crate::encode::zc::ZDecoder;
+use crate::iff::bs_byte_stream::bzz_decompress;
+use crate::iff::iff::IffReaderExt;
+use std::fmt;
+use std::io::Cursor;
+
+/// JB2 record types needed to read a stream header.
+const START_OF_DATA: i32 = 0;
+const REQUIRED_DICT_OR_RESET: i32 = 9;
+const END_OF_DATA: i32 = 11;
+
+/// Largest IW44 subsampling factor a DjVu decoder accepts for BG44/FG44.
+const MAX_SUBSAMPLE: u32 = 12;
+
+/// A problem found in a page, tied to the chunk it was found in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    /// Chunk id, e.g. `"BG44"` or `"Sjbz"`
+    pub chunk: String,
+    pub message: String,
+}
+
+impl ValidationIssue {
+    fn new(chunk: &str, message: impl Into<String>) -> Self {
+        Self {
+            chunk: chunk.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.chunk, self.message)
+    }
+}
+
+/// Checks a `FORM:DJVU` page (with or without the `AT&T` magic).
+///
+/// Reports, per chunk:
+/// - a missing or misplaced INFO chunk;
+/// - BG44/FG44 image dimensions that are not INFO's size at any
+///   subsampling from 1 to 12;
+/// - BG44/FG44 chunks whose serial numbers do not run 0, 1, 2, ... (a gap
+///   or repeat stops progressive decoding), with the chunk's byte offset;
+/// - a Djbz or Sjbz stream that does not decode in full, every record up
+///   to the end-of-data record, or an Sjbz image size that differs from
+///   INFO. An Sjbz that inherits its dictionary from an included file is
+///   only checked up to its header, since the dictionary is not in the
+///   page;
+/// - an FGbz palette whose color indices fail to decompress or point past
+///   the end of the palette;
+/// - a chunk of odd size followed directly by the next chunk instead of a
+///   pad byte, with the chunk's byte offset. Reading resumes at the next
+///   chunk so the rest of the page is still checked.
+///
+/// An empty result means no problems were found.
+pub fn validate_page(form_djvu_bytes: &[u8]) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let body = form_djvu_bytes
+        .strip_prefix(b"AT&T")
+        .unwrap_or(form_djvu_bytes);
+    let magic_len = form_djvu_bytes.len() - body.len();
+    let mut reader = Cursor::new(body);
+
+    match reader.next_chunk() {
+        Ok(Some(form)) if form.is_composite && &form.secondary_id == b"DJVU" => {}
+        _ => {
+            issues.push(ValidationIssue::new("FORM", "not a FORM:DJVU page"));
+            return issues;
+        }
+    }
+
+    let mut info: Option<(u32, u32)> = None;
+    let mut first = true;
+    let mut bg44_serial = 0;
+    let mut fg44_serial = 0;
+    let mut dictionary: Option<Vec<BitImage>> = None;
+    loop {
+        let offset = magic_len + reader.position() as usize;
+        let chunk = match reader.next_chunk() {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                issues.push(ValidationIssue::new("FORM", format!("truncated: {e}")));
+                break;
+            }
+        };
+        let id = chunk.full_id();
+        let data = match reader.get_chunk_data(&chunk) {
+            Ok(data) => data,
+            Err(e) => {
+                issues.push(ValidationIssue::new(&id, format!("truncated: {e}")));
+                break;
+            }
+        };
+        if chunk.size % 2 != 0 {
+            // The reader skipped the pad byte; step back if a chunk id
+            // starts there instead
+            let end = reader.position() as usize - 1;
+            if body.get(end..end + 4).is_some_and(looks_like_chunk_id) {
+                issues.push(ValidationIssue::new(
+                    &id,
+                    format!(
+                        "chunk at offset {} has odd size {} but no pad byte before the next chunk",
+                        offset, chunk.size
+                    ),
+                ));
+                reader.set_position(end as u64);
+            }
+        }
+
+        match &chunk.id {
+            b"INFO" => {
+                if !first {
+                    issues.push(ValidationIssue::new("INFO", "INFO is not the first chunk"));
+                }
+                if data.len() < 4 {
+                    issues.push(ValidationIssue::new("INFO", "INFO chunk is too short"));
+                } else {
+                    info = Some((
+                        u16::from_be_bytes([data[0], data[1]]) as u32,
+                        u16::from_be_bytes([data[2], data[3]]) as u32,
+                    ));
+                }
+            }
+            b"BG44" | b"FG44" => {
+                let expected = if &chunk.id == b"BG44" {
+                    &mut bg44_serial
+                } else {
+                    &mut fg44_serial
+                };
+                check_iw44_serial(&id, &data, offset, expected, &mut issues);
+                if let Some(page) = info {
+                    check_iw44_size(&id, &data, page, &mut issues);
+                }
+            }
+            b"Djbz" => match decoder::decode(&data, &[]) {
+                Ok(dict) => dictionary = Some(dict.dictionary()),
+                Err(e) => issues.push(ValidationIssue::new(
+                    "Djbz",
+                    format!("does not decode: {e}"),
+                )),
+            },
+            b"Sjbz" => {
+                if let Some(page) = info {
+                    check_jb2(&data, page, dictionary.as_deref(), &mut issues);
+                }
+            }
+            b"FGbz" => check_palette(&data, &mut issues),
+            _ => {}
+        }
+        first = false;
+    }
+
+    if info.is_none() {
+        issues.push(ValidationIssue::new("INFO", "missing INFO chunk"));
+    }
+    issues
+}
+
+/// Whether `bytes` could be a chunk id: four printable ASCII characters,
+/// which a zero pad byte never starts.
+fn looks_like_chunk_id(bytes: &[u8]) -> bool {
+    bytes
+        .iter()
+        .all(|&b| b.is_ascii_alphanumeric() || b == b' ')
+}
+
+/// Checks that an IW44 chunk carries the next serial number of its layer.
+///
+/// `expected` is advanced past the serial actually found, so one gap or
+/// repeat is reported once rather than for every later chunk.
+fn check_iw44_serial(
+    id: &str,
+    data: &[u8],
+    offset: usize,
+    expected: &mut usize,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    let Some(&serial) = data.first() else {
+        issues.push(ValidationIssue::new(
+            id,
+            format!("chunk at offset {} is empty", offset),
+        ));
+        return;
+    };
+    if serial as usize != *expected {
+        issues.push(ValidationIssue::new(
+            id,
+            format!(
+                "chunk at offset {} has serial {}, expected {}",
+                offset, serial, expected
+            ),
+        ));
+    }
+    *expected = serial as usize + 1;
+}
+
+/// Compares the size in the first IW44 chunk's header with the page size.
+fn check_iw44_size(id: &str, data: &[u8], page: (u32, u32), issues: &mut Vec<ValidationIssue>) {
+    // Only the first chunk (serial 0) carries the image size:
+    // serial, slices, major, minor, width(2), height(2), ...
+    if data.first() != Some(&0) {
+        return;
+    }
+    if data.len() < 8 {
+        issues.push(ValidationIssue::new(id, "IW44 header is truncated"));
+        return;
+    }
+    let width = u16::from_be_bytes([data[4], data[5]]) as u32;
+    let height = u16::from_be_bytes([data[6], data[7]]) as u32;
+    let matches = (1..=MAX_SUBSAMPLE)
+        .any(|red| page.0.div_ceil(red) == width && page.1.div_ceil(red) == height);
+    if !matches {
+        issues.push(ValidationIssue::new(
+            id,
+            format!(
+                "image is {}x{} but INFO is {}x{} (no subsampling of 1..={} matches)",
+                width, height, page.0, page.1, MAX_SUBSAMPLE
+            ),
+        ));
+    }
+}
+
+/// Decodes an Sjbz stream, using the page's own Djbz if it has one, and
+/// compares its size with INFO.
+fn check_jb2(
+    data: &[u8],
+    page: (u32, u32),
+    dictionary: Option<&[BitImage]>,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    let header = (|| {
+        let mut zd = ZDecoder::new(data, true)?;
+        let mut coder = NumCoder::new();
+        let mut record_type: NumContext = 0;
+        let mut shape_count: NumContext = 0;
+        let mut image_size: NumContext = 0;
+
+        let mut inherited = 0;
+        let mut rtype = coder.decode_num(&mut zd, &mut record_type, START_OF_DATA, END_OF_DATA)?;
+        if rtype == REQUIRED_DICT_OR_RESET {
+            inherited = coder.decode_num(&mut zd, &mut shape_count, 0, BIG_POSITIVE)?;
+            rtype = coder.decode_num(&mut zd, &mut record_type, START_OF_DATA, END_OF_DATA)?;
+        }
+        if rtype != START_OF_DATA {
+            return Ok(None);
+        }
+        let width = coder.decode_num(&mut zd, &mut image_size, 0, BIG_POSITIVE)?;
+        let height = coder.decode_num(&mut zd, &mut image_size, 0, BIG_POSITIVE)?;
+        Ok::<_, crate::encode::jb2::error::Jb2Error>(Some((
+            inherited,
+            (width as u32, height as u32),
+        )))
+    })();
+
+    match header {
+        Ok(Some((_, size))) if size != page => issues.push(ValidationIssue::new(
+            "Sjbz",
+            format!(
+                "image is {}x{} but INFO is {}x{}",
+                size.0, size.1, page.0, page.1
+            ),
+        )),
+        // The dictionary comes from an included file
+        Ok(Some((inherited, _))) if inherited > 0 && dictionary.is_none() => {}
+        Ok(Some(_)) => {
+            if let Err(e) = decoder::decode(data, dictionary.unwrap_or_default()) {
+                issues.push(ValidationIssue::new(
+                    "Sjbz",
+                    format!("does not decode: {e}"),
+                ));
+            }
+        }
+        Ok(None) => issues.push(ValidationIssue::new(
+            "Sjbz",
+            "stream does not start with a start-of-image record",
+        )),
+        Err(e) => issues.push(ValidationIssue::new(
+            "Sjbz",
+            format!("does not decode: {e}"),
+        )),
+    }
+}
+
+/// Checks that every FGbz color index refers to a palette entry.
+fn check_palette(data: &[u8], issues: &mut Vec<ValidationIssue>) {
+    if data.len() < 3 {
+        issues.push(ValidationIssue::new("FGbz", "palette header is truncated"));
+        return;
+    }
+    let version = data[0];
+    let colors = u16::from_be_bytes([data[1], data[2]]) as usize;
+    let indices_start = 3 + colors * 3;
+    if data.len() < indices_start {
+        issues.push(ValidationIssue::new(
+            "FGbz",
+            format!("declares {} colors but holds fewer", colors),
+        ));
+        return;
+    }
+    if version & 0x80 == 0 {
+        return;
+    }
+
+    let Some(count) = data.get(indices_start..indices_start + 3) else {
+        issues.push(ValidationIssue::new("FGbz", "index count is truncated"));
+        return;
+    };
+    let count = u32::from_be_bytes([0, count[0], count[1], count[2]]) as usize;
+    let indices = match bzz_decompress(&data[indices_start + 3..]) {
+        Ok(indices) => indices,
+        Err(e) => {
+            issues.push(ValidationIssue::new(
+                "FGbz",
+                format!("color indices do not decode: {e}"),
+            ));
+            return;
+        }
+    };
+    if indices.len() != count * 2 {
+        issues.push(ValidationIssue::new(
+            "FGbz",
+            format!(
+                "declares {} color indices but holds {}",
+                count,
+                indices.len() / 2
+            ),
+        ));
+    }
+    if let Some(bad) = indices
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as usize)
+        .find(|&index| index >= colors)
+    {
+        issues.push(ValidationIssue::new(
+            "FGbz",
+            format!("color index {} is out of range for {} colors", bad, colors),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::doc::page_encoder::{PageComponents, PageEncodeParams};
+    use crate::encode::jb2::symbol_dict::BitImage;
+    use crate::image::image_formats::{Pixel, Pixmap};
+
+    fn compound_page() -> Vec<u8> {
+        let bg = Pixmap::from_fn(120, 80, |x, y| Pixel::new(x as u8 * 2, y as u8 * 3, 128));
+        let mut mask = BitImage::new(120, 80).unwrap();
+        for y in 20..50 {
+            for x in (10..110).filter(|x| x % 12 < 6) {
+                mask.set_usize(x, y, true);
+            }
+        }
+        PageComponents::new()
+            .with_background(bg)
+            .unwrap()
+            .with_foreground(mask)
+            .unwrap()
+            .encode(&PageEncodeParams::default(), 1, 300, 1, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_well_formed_page_has_no_issues() {
+        let page = compound_page();
+        assert!(page.windows(4).any(|w| w == b"Sjbz"));
+        assert!(page.windows(4).any(|w| w == b"FGbz"));
+        assert_eq!(validate_page(&page), Vec::new());
+    }
+
+    #[test]
+    fn test_truncated_sjbz_body_is_reported() {
+        let mut page = compound_page();
+        let sjbz = page.windows(4).position(|w| w == b"Sjbz").unwrap();
+        let size = u32::from_be_bytes(page[sjbz + 4..sjbz + 8].try_into().unwrap()) as usize;
+        // Keep the header records but drop the second half of the stream
+        let kept = size / 2;
+        let cut = size + size % 2 - (kept + kept % 2);
+        page.drain(sjbz + 8 + kept..sjbz + 8 + size + size % 2);
+        page.splice(
+            sjbz + 8 + kept..sjbz + 8 + kept,
+            std::iter::repeat_n(0, kept % 2),
+        );
+        page[sjbz + 4..sjbz + 8].copy_from_slice(&(kept as u32).to_be_bytes());
+        let form_size = u32::from_be_bytes(page[8..12].try_into().unwrap()) - cut as u32;
+        page[8..12].copy_from_slice(&form_size.to_be_bytes());
+
+        let issues = validate_page(&page);
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert_eq!(issues[0].chunk, "Sjbz");
+        assert!(issues[0].message.starts_with("does not decode"));
+    }
+
+    #[test]
+    fn test_tampered_bg44_size_is_reported() {
+        let mut page = compound_page();
+        let bg44 = page.windows(4).position(|w| w == b"BG44").unwrap();
+        // Chunk header (8) + serial, slices, major, minor, then width
+        let width = bg44 + 8 + 4;
+        page[width..width + 2].copy_from_slice(&77u16.to_be_bytes());
+
+        let issues = validate_page(&page);
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert_eq!(issues[0].chunk, "BG44");
+    }
+
+    #[test]
+    fn test_unpadded_odd_chunk_is_reported() {
+        let mut page = compound_page();
+        let info = page.windows(4).position(|w| w == b"INFO").unwrap();
+        // A 3-byte annotation chunk right after INFO, missing its pad byte
+        let inserted = info + 8 + 10;
+        let chunk = b"ANTa\0\0\0\x03abc";
+        page.splice(inserted..inserted, chunk.iter().copied());
+        let form_size = u32::from_be_bytes(page[8..12].try_into().unwrap()) + chunk.len() as u32;
+        page[8..12].copy_from_slice(&form_size.to_be_bytes());
+
+        let issues = validate_page(&page);
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert_eq!(issues[0].chunk, "ANTa");
+        assert_eq!(
+            issues[0].message,
+            format!(
+                "chunk at offset {} has odd size 3 but no pad byte before the next chunk",
+                inserted
+            )
+        );
+
+        // With the pad byte in place the page is clean again
+        page.insert(inserted + chunk.len(), 0);
+        page[8..12].copy_from_slice(&(form_size + 1).to_be_bytes());
+        assert_eq!(validate_page(&page), Vec::new());
+    }
+
+    #[test]
+    fn test_repeated_bg44_serial_is_reported() {
+        let mut page = compound_page();
+        let bg44 = page.windows(4).position(|w| w == b"BG44").unwrap();
+        let size = u32::from_be_bytes(page[bg44 + 4..bg44 + 8].try_into().unwrap()) as usize;
+        let len = 8 + size + size % 2;
+        // Repeat the BG44 chunk, serial 0 and all, right after itself
+        let copy = page[bg44..bg44 + len].to_vec();
+        let duplicate = bg44 + len;
+        page.splice(duplicate..duplicate, copy);
+        let form_size = u32::from_be_bytes(page[8..12].try_into().unwrap()) + len as u32;
+        page[8..12].copy_from_slice(&form_size.to_be_bytes());
+
+        let issues = validate_page(&page);
+        assert_eq!(issues.len(), 1, "{issues:?}");
+        assert_eq!(issues[0].chunk, "BG44");
+        assert_eq!(
+            issues[0].message,
+            format!("chunk at offset {} has serial 0, expected 1", duplicate)
+        );
+    }
+}
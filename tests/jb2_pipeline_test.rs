@@ -90,7 +90,8 @@ fn test_cc_extraction_and_encoding() {
     }
 
     // Convert to encoder format
-    let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, test_image.height as i32);
+    let (bitmaps, parents, blits) =
+        shapes_to_encoder_format(shapes, test_image.height as i32).unwrap();
 
     println!("\nEncoder format:");
     println!(
@@ -247,7 +248,7 @@ fn test_background_pbm_with_bzz() {
     println!("Extracted {} shapes", shapes.len());
 
     // Convert to encoder format
-    let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, height as i32);
+    let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, height as i32).unwrap();
 
     println!("{} blits to encode", blits.len());
 
@@ -417,7 +418,7 @@ fn test_background_pbm_encoding() {
     println!("Extracted {} shapes", shapes.len());
 
     // Convert to encoder format
-    let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, height as i32);
+    let (bitmaps, parents, blits) = shapes_to_encoder_format(shapes, height as i32).unwrap();
 
     println!("{} blits to encode", blits.len());
 